@@ -19,6 +19,22 @@ fn validate(
     btree: &mut BTree,
     db: &mut Database<impl Disk>,
 ) -> io::Result<bool> {
+    let inconsistencies = btree.verify(db)?;
+    for inconsistency in &inconsistencies {
+        eprintln!("{}", inconsistency);
+    }
+    if !inconsistencies.is_empty() {
+        return Ok(false);
+    }
+    // deleting every key has to bring the tree back down to a single leaf
+    if reference.is_empty() && btree.depth(db)? != 1 {
+        return Ok(false);
+    }
+    let len = btree.len(db)?;
+    if len != reference.len() as u64 || len != btree.keys(db)?.count() as u64 {
+        eprintln!("entry count {} with {} keys expected", len, reference.len());
+        return Ok(false);
+    }
     for (&key, value) in reference.iter() {
         if value != &btree.lookup(key, db)?.unwrap() {
             return Ok(false);
@@ -74,7 +90,7 @@ fn main() -> io::Result<()> {
         match &instruction {
             Instruction::Delete(key) => {
                 tree.delete(*key, &mut db)?;
-                reference.remove(&key);
+                reference.remove(key);
             }
             Instruction::Insert(key, data) => {
                 tree.insert(*key, data, &mut db)?;
@@ -1,4 +1,4 @@
-use criterion::{criterion_group, criterion_main, Criterion};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
 use data::{BTree, Database, Disk};
 use std::fs::OpenOptions;
 
@@ -22,8 +22,48 @@ fn btree_insert_n(n: u128) {
     }
 }
 
-fn btree_read_n(n: u128) {
+fn btree_insert_n_cached(n: u128) {
     let (mut db, mut tree) = btree();
+    tree.cache_rightmost_leaf(true);
+    for key in 0..n {
+        tree.insert(key, &[0, 1, 2, 3, 4], &mut db).unwrap();
+    }
+}
+
+fn btree_insert_read_n_u64(n: u128, embed_threshold: u64) {
+    let (mut db, mut tree) = btree();
+    db.set_embed_threshold(embed_threshold);
+    for key in 0..n {
+        tree.insert(key, &(key as u64).to_be_bytes(), &mut db)
+            .unwrap();
+    }
+    for key in 0..n {
+        tree.lookup(key, &mut db).unwrap();
+    }
+}
+
+fn btree_bulk_load_n(n: u128) {
+    let (mut db, _) = btree();
+    BTree::bulk_load(&mut db, (0..n).map(|key| (key, vec![0, 1, 2, 3, 4]))).unwrap();
+}
+
+fn btree_filled(n: u128) -> (Database<impl Disk>, BTree) {
+    let (mut db, mut tree) = btree();
+    for key in 0..n {
+        tree.insert(key, &[0, 1, 2, 3, 4], &mut db).unwrap();
+    }
+    (db, tree)
+}
+
+fn btree_delete_n((mut db, mut tree): (Database<impl Disk>, BTree), n: u128) {
+    for key in 0..n {
+        tree.delete(key, &mut db).unwrap();
+    }
+}
+
+fn btree_read_n(n: u128, cache_capacity: usize) {
+    let (db, mut tree) = btree();
+    let mut db = db.with_cache_capacity(cache_capacity);
     for key in 0..20 {
         tree.insert(key, &[0, 1, 2, 3, 4], &mut db).unwrap();
     }
@@ -42,11 +82,41 @@ fn criterion_benchmark(c: &mut Criterion) {
     c.bench_function("btree_insert 1,000", |b| {
         b.iter(|| btree_insert_n(1_000));
     });
+    c.bench_function("btree_insert 1,000 with the last leaf cached", |b| {
+        b.iter(|| btree_insert_n_cached(1_000));
+    });
+    c.bench_function("btree_insert and read 1,000 8 byte values embedded", |b| {
+        b.iter(|| btree_insert_read_n_u64(1_000, 8));
+    });
+    c.bench_function(
+        "btree_insert and read 1,000 8 byte values out of line",
+        |b| {
+            b.iter(|| btree_insert_read_n_u64(1_000, 0));
+        },
+    );
+    // every tree is filled in the same file, so only one can exist at a time
+    c.bench_function("btree_delete 100", |b| {
+        b.iter_batched(
+            || btree_filled(100),
+            |filled| btree_delete_n(filled, 100),
+            BatchSize::PerIteration,
+        );
+    });
+    c.bench_function("btree_delete 1,000", |b| {
+        b.iter_batched(
+            || btree_filled(1_000),
+            |filled| btree_delete_n(filled, 1_000),
+            BatchSize::PerIteration,
+        );
+    });
     c.bench_function("btree_read 100", |b| {
-        b.iter(|| btree_read_n(100));
+        b.iter(|| btree_read_n(100, 64));
     });
     c.bench_function("btree_read 1000", |b| {
-        b.iter(|| btree_read_n(1000));
+        b.iter(|| btree_read_n(1000, 64));
+    });
+    c.bench_function("btree_read 1000 without the block cache", |b| {
+        b.iter(|| btree_read_n(1000, 0));
     });
     // c.bench_function("btree_insert 10,000", |b| {
     //     b.iter(|| btree_insert_n(10_000));
@@ -54,6 +124,9 @@ fn criterion_benchmark(c: &mut Criterion) {
     //    c.bench_function("btree_insert 100,000", |b| {
     //        b.iter(|| btree_insert_n(100_000));
     //    });
+    c.bench_function("btree_bulk_load 100,000", |b| {
+        b.iter(|| btree_bulk_load_n(100_000));
+    });
 }
 
 criterion_group!(benches, criterion_benchmark);
@@ -0,0 +1,56 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use data::{BTree, Database, Disk, MmapDisk};
+use std::fs::{File, OpenOptions};
+
+const PATH: &str = "mmap-read-bench";
+// 100,000 values of 1KB each make a file of a little over 100MB
+const KEYS: u128 = 100_000;
+
+fn open() -> File {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(PATH)
+        .unwrap()
+}
+
+fn build() -> u64 {
+    let disk = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(PATH)
+        .unwrap();
+    let mut db = Database::initialize(disk).unwrap();
+    let tree =
+        BTree::bulk_load(&mut db, (0..KEYS).map(|key| (key, vec![key as u8; 1_000]))).unwrap();
+    db.flush().unwrap();
+    tree.offset()
+}
+
+/// Looks up `n` keys spread over the whole tree.
+fn btree_read_n(db: &mut Database<impl Disk>, tree: &BTree, n: u128) {
+    let mut key = 1u128;
+    for _ in 0..n {
+        key = (key * 6_364_136_223_846_793_005 + 1) % KEYS;
+        tree.lookup(key, db).unwrap();
+    }
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let tree = BTree::from_offset(build());
+    let mut file_db = Database::from_existing(open()).unwrap();
+    c.bench_function("btree_read 1,000 of 100MB from a File", |b| {
+        b.iter(|| btree_read_n(&mut file_db, &tree, 1_000));
+    });
+    let mut mmap_db = Database::from_existing(MmapDisk::new(open()).unwrap()).unwrap();
+    c.bench_function("btree_read 1,000 of 100MB from an MmapDisk", |b| {
+        b.iter(|| btree_read_n(&mut mmap_db, &tree, 1_000));
+    });
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,41 @@
+use data::{BTree, Database, Table};
+use serde::{Deserialize, Serialize};
+use std::io::{self, Cursor};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Expense {
+    name: String,
+    cents: i64,
+    category: String,
+}
+
+fn main() -> io::Result<()> {
+    let mut db = Database::initialize(Cursor::new(vec![]))?;
+    // expenses are keyed by their id, a uuid in practice
+    let mut expenses: Table<u128, Expense> = Table::new(BTree::init(&mut db)?);
+    let bought = [
+        (0x01, "groceries", 5_320, "food"),
+        (0x02, "rent", 120_000, "housing"),
+        (0x03, "coffee", 450, "food"),
+    ];
+    for &(id, name, cents, category) in bought.iter() {
+        let expense = Expense {
+            name: name.to_string(),
+            cents,
+            category: category.to_string(),
+        };
+        expenses.insert(id, &expense, &mut db)?;
+    }
+
+    if let Some(expense) = expenses.get(0x02, &mut db)? {
+        println!("0x02: {:?}", expense);
+    }
+    expenses.remove(0x02, &mut db)?;
+    let total: i64 = expenses
+        .range(.., &mut db)?
+        .iter()
+        .map(|(_, expense)| expense.cents)
+        .sum();
+    println!("spent {} cents besides rent", total);
+    Ok(())
+}
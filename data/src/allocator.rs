@@ -0,0 +1,52 @@
+use std::io;
+
+/// The operations on a database's blocks that an `Allocator` picks between.
+/// Freed blocks are kept on a list linked through the blocks themselves,
+/// newest first.
+pub trait Blocks {
+    /// Takes the most recently freed block off the free list, if there is
+    /// one.
+    fn pop_free(&mut self) -> io::Result<Option<u64>>;
+    /// Puts a block that's no longer used on the free list.
+    fn push_free(&mut self, offset: u64) -> io::Result<()>;
+    /// Grows the file by a block, failing with `ErrorKind::StorageFull` once
+    /// the database's block cap is reached.
+    fn append(&mut self) -> io::Result<u64>;
+}
+
+/// Decides where each new block a database needs comes from, and what
+/// happens to the blocks it gives up. Set one with
+/// `Database::set_allocator`. Whatever a strategy does, every block has to
+/// stay either in use or on the free list, or `Database::verify` and
+/// `vacuum` lose track of it.
+pub trait Allocator: Send + Sync {
+    fn allocate(&self, blocks: &mut dyn Blocks) -> io::Result<u64>;
+    fn free(&self, offset: u64, blocks: &mut dyn Blocks) -> io::Result<()> {
+        blocks.push_free(offset)
+    }
+}
+
+/// Reuses freed blocks before growing the file, the allocator a database
+/// uses unless given another.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReuseFreeBlocks;
+
+impl Allocator for ReuseFreeBlocks {
+    fn allocate(&self, blocks: &mut dyn Blocks) -> io::Result<u64> {
+        match blocks.pop_free()? {
+            Some(offset) => Ok(offset),
+            None => blocks.append(),
+        }
+    }
+}
+
+/// Always grows the file, leaving freed blocks on the free list for
+/// `vacuum` to drop. Writes only ever go to the end of the file.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AppendOnly;
+
+impl Allocator for AppendOnly {
+    fn allocate(&self, blocks: &mut dyn Blocks) -> io::Result<u64> {
+        blocks.append()
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// Images of recently read blocks, keyed by offset. Once it holds
+/// `capacity` blocks, the least recently used one makes way for the next.
+pub(crate) struct PageCache {
+    capacity: usize,
+    clock: u64,
+    blocks: HashMap<u64, (u64, Vec<u8>)>,
+    /// Offsets by when they were last used.
+    by_use: BTreeMap<u64, u64>,
+}
+
+impl PageCache {
+    pub fn new(capacity: usize) -> PageCache {
+        PageCache {
+            capacity,
+            clock: 0,
+            blocks: HashMap::new(),
+            by_use: BTreeMap::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.blocks.len() > capacity {
+            self.evict();
+        }
+    }
+
+    pub fn get(&mut self, offset: u64) -> Option<&Vec<u8>> {
+        let (last_used, image) = self.blocks.get_mut(&offset)?;
+        self.by_use.remove(last_used);
+        self.clock += 1;
+        *last_used = self.clock;
+        self.by_use.insert(self.clock, offset);
+        Some(image)
+    }
+
+    pub fn insert(&mut self, offset: u64, image: Vec<u8>) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.invalidate(offset);
+        if self.blocks.len() >= self.capacity {
+            self.evict();
+        }
+        self.clock += 1;
+        self.by_use.insert(self.clock, offset);
+        self.blocks.insert(offset, (self.clock, image));
+    }
+
+    pub fn invalidate(&mut self, offset: u64) {
+        if let Some((last_used, _)) = self.blocks.remove(&offset) {
+            self.by_use.remove(&last_used);
+        }
+    }
+
+    fn evict(&mut self) {
+        if let Some((_, offset)) = self.by_use.pop_first() {
+            self.blocks.remove(&offset);
+        }
+    }
+}
+
+#[cfg(test)]
+mod cache_tests {
+    use super::*;
+
+    #[test]
+    fn least_recently_used_block_is_evicted() {
+        let mut cache = PageCache::new(2);
+        cache.insert(0, vec![0]);
+        cache.insert(8, vec![8]);
+        assert_eq!(cache.get(0), Some(&vec![0]));
+        cache.insert(16, vec![16]);
+        assert_eq!(cache.get(8), None);
+        assert_eq!(cache.get(0), Some(&vec![0]));
+        assert_eq!(cache.get(16), Some(&vec![16]));
+
+        cache.set_capacity(1);
+        assert_eq!(cache.get(0), None);
+        cache.invalidate(16);
+        assert_eq!(cache.get(16), None);
+    }
+}
@@ -0,0 +1,24 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io;
+
+/// Turns typed values into bytes for `TreeEntry::set_encoded` and back for
+/// `TreeEntry::decoded`.
+pub trait Codec {
+    fn encode<T: Serialize>(&self, value: &T) -> io::Result<Vec<u8>>;
+    /// Fails with `ErrorKind::InvalidData` if `data` isn't a `T`.
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> io::Result<T>;
+}
+
+/// Values as JSON, the codec behind `set_serialized` and `deserialized`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Json;
+
+impl Codec for Json {
+    fn encode<T: Serialize>(&self, value: &T) -> io::Result<Vec<u8>> {
+        serde_json::to_vec(value).map_err(io::Error::other)
+    }
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> io::Result<T> {
+        serde_json::from_slice(data).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
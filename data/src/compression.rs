@@ -0,0 +1,43 @@
+use std::io;
+
+/// Turns values into a shorter form for storage and back. `decompress` has
+/// to undo `compress` exactly, and should fail with `ErrorKind::InvalidData`
+/// on bytes it didn't produce.
+pub trait Compressor: Send + Sync {
+    fn compress(&self, data: &[u8]) -> Vec<u8>;
+    fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+/// Raw deflate, the compressor a database uses unless given another.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Deflate;
+
+impl Compressor for Deflate {
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        miniz_oxide::deflate::compress_to_vec(data, 6)
+    }
+    fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        miniz_oxide::inflate::decompress_to_vec(data).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("compressed value is damaged: {}", err),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod compression_tests {
+    use super::*;
+
+    #[test]
+    fn deflate_round_trips() -> io::Result<()> {
+        let data = b"{\"name\": \"tree-data\", \"tags\": [\"a\", \"a\", \"a\"]}".repeat(20);
+        let compressed = Deflate.compress(&data);
+        assert!(compressed.len() < data.len());
+        assert_eq!(Deflate.decompress(&compressed)?, data);
+        let err = Deflate.decompress(&[0xff; 16]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        Ok(())
+    }
+}
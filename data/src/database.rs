@@ -1,70 +1,777 @@
-use crate::tree::TreeEntry;
-use crate::{BTree, Key};
+use crate::allocator::{Allocator, Blocks, ReuseFreeBlocks};
+use crate::compression::{Compressor, Deflate};
+use crate::error::bad_header;
+use crate::journal::Journal;
+use crate::page::{self, Inconsistency, LeafPageEntry, PageLayout, PageOffset};
+use crate::tree::{self, TreeEntry};
+use crate::MemoryDisk;
+use crate::{BTree, Key, PageInfo, Result, ValueTooLarge};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::HashSet;
 use std::convert::TryInto;
-use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-pub trait Disk: Read + Write + Seek {}
-impl<T: Read + Write + Seek> Disk for T {}
+/// Where a database keeps its blocks. Before 0.2 every `Read + Write +
+/// Seek` type was a disk; now each one says so with an `impl Disk`, which
+/// is what lets a `File` sync to stable storage while a `Cursor` only
+/// flushes. A type of your own that only reads, writes and seeks needs
+/// nothing more than an empty `impl Disk for MyDisk {}`.
+pub trait Disk: Read + Write + Seek {
+    /// Pushes everything written so far to stable storage. Disks that live
+    /// in memory have nowhere further to go than `flush`.
+    fn sync(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+    /// Makes the disk at least `len` bytes long, so that writes up to there
+    /// can't fail for want of room. Disks that grow as they're written to
+    /// can rely on writing a zero at the end; ones with a fixed size, or that
+    /// can set aside space up front, do better to say so here.
+    fn ensure_len(&mut self, len: u64) -> io::Result<()> {
+        if len > self.seek(SeekFrom::End(0))? {
+            self.seek(SeekFrom::Start(len - 1))?;
+            self.write_all(&[0])?;
+        }
+        Ok(())
+    }
+    /// Gives back everything past the first `len` bytes, for disks that can
+    /// shrink. The rest keep the bytes, which are never read again.
+    fn truncate(&mut self, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+}
+impl Disk for Cursor<Vec<u8>> {
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.get_mut().truncate(len as usize);
+        Ok(())
+    }
+}
+impl Disk for Cursor<&mut Vec<u8>> {
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.get_mut().truncate(len as usize);
+        Ok(())
+    }
+}
+impl Disk for File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.sync_all()
+    }
+    fn ensure_len(&mut self, len: u64) -> io::Result<()> {
+        if len > self.metadata()?.len() {
+            self.set_len(len)?;
+        }
+        Ok(())
+    }
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.set_len(len)
+    }
+}
+impl<T: Disk + ?Sized> Disk for &mut T {
+    fn sync(&mut self) -> io::Result<()> {
+        (**self).sync()
+    }
+    fn ensure_len(&mut self, len: u64) -> io::Result<()> {
+        (**self).ensure_len(len)
+    }
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        (**self).truncate(len)
+    }
+}
+impl<T: Disk + ?Sized> Disk for Box<T> {
+    fn sync(&mut self) -> io::Result<()> {
+        (**self).sync()
+    }
+    fn ensure_len(&mut self, len: u64) -> io::Result<()> {
+        (**self).ensure_len(len)
+    }
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        (**self).truncate(len)
+    }
+}
+
+/// A disk over something that can only be read, like a file opened
+/// without write access. Every write fails with
+/// `ErrorKind::PermissionDenied`.
+pub struct ReadOnly<R>(R);
+
+impl<R> ReadOnly<R> {
+    pub fn into_inner(self) -> R {
+        self.0
+    }
+}
+impl<R: Read> Read for ReadOnly<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+impl<R> Write for ReadOnly<R> {
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "database was opened read-only",
+        ))
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+impl<R: Seek> Seek for ReadOnly<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+impl<R: Read + Seek> Disk for ReadOnly<R> {}
 
 pub trait BlockAllocator {
     fn allocate_block(&mut self) -> io::Result<u64>;
     fn write(&mut self, offset: u64, data: &[u8]) -> io::Result<()>;
 }
 
+/// Byte order of the typed values written by helpers like
+/// `TreeEntry::set_i64`. Keys are always stored big-endian so that they sort
+/// the same way on disk as they do in memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Endianness {
+    Big,
+    Little,
+}
+
+impl Endianness {
+    fn to_meta(self) -> u64 {
+        match self {
+            Endianness::Big => 0,
+            Endianness::Little => 1,
+        }
+    }
+    fn from_meta(value: u64) -> io::Result<Endianness> {
+        match value {
+            0 => Ok(Endianness::Big),
+            1 => Ok(Endianness::Little),
+            n => Err(bad_header(format!(
+                "unknown value endianness {} in database header",
+                n
+            ))),
+        }
+    }
+    pub(crate) fn encode_u64(self, value: u64) -> [u8; 8] {
+        match self {
+            Endianness::Big => value.to_be_bytes(),
+            Endianness::Little => value.to_le_bytes(),
+        }
+    }
+    pub(crate) fn decode_u64(self, bytes: [u8; 8]) -> u64 {
+        match self {
+            Endianness::Big => u64::from_be_bytes(bytes),
+            Endianness::Little => u64::from_le_bytes(bytes),
+        }
+    }
+}
+
+/// Where a full leaf is split in two.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SplitPolicy {
+    /// Half the entries go each way, whatever the size of their values.
+    #[default]
+    ByCount,
+    /// The split falls where the entries and their values pass half the
+    /// bytes in use, so both halves are about half full even when value
+    /// sizes vary a lot.
+    BySize,
+}
+
+/// When the database asks the disk to make its writes durable, on top of
+/// `Database::flush`. A write here is one insert, delete or other change, or
+/// one `Transaction` however much it changes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    /// Only when `flush` is called.
+    #[default]
+    Never,
+    /// After every write.
+    EveryWrite,
+    /// After every `n` writes.
+    EveryN(u64),
+}
+
+/// Figures for capacity planning, from `Database::stats`. A file with many
+/// free blocks, or trees whose leaves are mostly empty, is a candidate for
+/// compaction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DatabaseStats {
+    pub block_size: u64,
+    /// Blocks handed out so far, including the meta block and the ones now
+    /// on the free list.
+    pub num_blocks_allocated: u64,
+    /// Blocks on the free list, waiting to be reused.
+    pub free_blocks: u64,
+    /// Length of the underlying disk in bytes.
+    pub file_size: u64,
+}
+
 pub struct Database<D: Disk> {
-    pub(crate) disk: D,
+    pub(crate) disk: Journal<D>,
     meta: DatabaseMeta,
+    max_blocks: Option<u64>,
+    max_value_len: Option<u64>,
+    defragment_threshold: Option<f64>,
+    /// Values at least this long are compressed as they're stored.
+    compress_from: Option<u64>,
+    pub(crate) compressor: Arc<dyn Compressor>,
+    allocator: Arc<dyn Allocator>,
+    split_policy: SplitPolicy,
+    /// Blocks handed out by `allocate_block` since the database was opened.
+    allocations: u64,
+    sync_policy: SyncPolicy,
+    /// Writes made since the disk was last synced for the sync policy.
+    unsynced_writes: u64,
+    /// How deep the `atomically` calls running outside a transaction go.
+    unlogged_depth: u32,
+    zero_free_space: bool,
+    /// Values no longer than this are embedded in their leaf entries.
+    embed_threshold: u64,
+    /// Bumped each time a write starts, so that a `Scan` can tell the tree
+    /// may have changed under it.
+    write_generation: u64,
+    /// Bumped each time blocks are freed, moved or rolled back, so that a
+    /// remembered page offset can tell the block may hold something else.
+    free_generation: u64,
 }
 
+#[derive(Clone)]
 struct DatabaseMeta {
     block_size_exp: u64,
     num_blocks_allocated: u64,
     root_btree_offset: u64,
+    value_endianness: Endianness,
+    /// The most recently freed block, which links to the one freed before
+    /// it, or 0 if there are none.
+    free_list_head: PageOffset,
+    /// The tree mapping table names to their roots, or 0 until the first
+    /// table is created.
+    catalog_offset: PageOffset,
+    /// When the file was created, in milliseconds since the Unix epoch.
+    created_at: u64,
+    /// How many times the file has been opened for writing since.
+    open_count: u64,
 }
 
 impl DatabaseMeta {
+    /// Every database file starts with these bytes, then the format version.
+    const MAGIC: [u8; 7] = *b"TREEDAT";
+    const FORMAT_VERSION: u8 = 9;
+    const DEFAULT_BLOCK_SIZE_EXP: u64 = 13;
+
     fn block_size(&self) -> u64 {
         2u64.pow(self.block_size_exp.try_into().unwrap())
     }
     fn persist(&self, disk: &mut impl Disk) -> io::Result<()> {
         disk.seek(SeekFrom::Start(0))?;
+        disk.write_all(&DatabaseMeta::MAGIC)?;
+        disk.write_u8(DatabaseMeta::FORMAT_VERSION)?;
         disk.write_u64::<BigEndian>(self.block_size_exp)?;
         disk.write_u64::<BigEndian>(self.num_blocks_allocated)?;
         disk.write_u64::<BigEndian>(self.root_btree_offset)?;
+        disk.write_u64::<BigEndian>(self.value_endianness.to_meta())?;
+        disk.write_u64::<BigEndian>(self.free_list_head)?;
+        disk.write_u64::<BigEndian>(self.catalog_offset)?;
+        disk.write_u64::<BigEndian>(self.created_at)?;
+        disk.write_u64::<BigEndian>(self.open_count)?;
         Ok(())
     }
 }
 
+/// Every snapshot written by `Database::dump` starts with these bytes, then
+/// the snapshot format's version.
+const DUMP_MAGIC: [u8; 8] = *b"TREEDUMP";
+const DUMP_VERSION: u8 = 1;
+
+impl Database<File> {
+    /// Opens the database file at `path`, creating it first if there's no
+    /// file there or it's empty. A file that has something in it but isn't
+    /// a database fails with `ErrorKind::InvalidData` instead of being
+    /// written over.
+    pub fn open_or_create<P: AsRef<Path>>(path: P) -> Result<Database<File>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        if file.metadata()?.len() == 0 {
+            Database::initialize(file)
+        } else {
+            Database::from_existing(file)
+        }
+    }
+}
+
+impl<R: Read + Seek> Database<ReadOnly<R>> {
+    /// Opens an existing database for reading only. Lookups and scans work
+    /// as usual, but anything that would change the file, including
+    /// creating a tree on first use, fails with
+    /// `ErrorKind::PermissionDenied`.
+    pub fn open_read_only(reader: R) -> Result<Self> {
+        Ok(Database::open_existing(ReadOnly(reader), false)?)
+    }
+}
+
 impl<D: Disk> Database<D> {
     pub fn block_size(&self) -> u64 {
         self.meta.block_size()
     }
-    pub fn from_existing(mut disk: D) -> io::Result<Self> {
-        let meta = Database::read_header(&mut disk)?;
-        Ok(Database { disk, meta })
+    /// Where things go in this file's pages.
+    pub fn page_layout(&self) -> PageLayout {
+        PageLayout::of(self.block_size())
+    }
+    pub fn value_endianness(&self) -> Endianness {
+        self.meta.value_endianness
+    }
+    pub fn num_blocks_allocated(&self) -> u64 {
+        self.meta.num_blocks_allocated
+    }
+    /// When the file was created, in milliseconds since the Unix epoch.
+    pub fn created_at(&self) -> u64 {
+        self.meta.created_at
+    }
+    /// How many times the file has been reopened with `from_existing` since
+    /// it was created. Opening it read-only doesn't count.
+    pub fn open_count(&self) -> u64 {
+        self.meta.open_count
+    }
+    /// How many blocks have been allocated since the database was opened,
+    /// reused ones included.
+    pub(crate) fn allocations(&self) -> u64 {
+        self.allocations
+    }
+    /// Caps the file at `max_blocks` blocks, including the meta block. Once
+    /// the cap is reached, operations that need a new block fail with
+    /// `ErrorKind::StorageFull`. `None` removes the cap.
+    pub fn set_max_blocks(&mut self, max_blocks: Option<u64>) {
+        self.max_blocks = max_blocks;
+    }
+    /// Refuses values longer than `max_value_len` bytes, failing the insert
+    /// with a `ValueTooLarge` error before anything is written, so that no
+    /// one client can fill the file with a single value. `None`, the
+    /// default, allows values of any length, long ones going to overflow
+    /// pages.
+    pub fn set_max_value_len(&mut self, max_value_len: Option<u64>) {
+        self.max_value_len = max_value_len;
+    }
+    /// Fails with `ValueTooLarge` if a value of `len` bytes is over the
+    /// limit set with `set_max_value_len`.
+    pub(crate) fn check_value_len(&self, len: u64) -> io::Result<()> {
+        match self.max_value_len {
+            Some(max) if len > max => Err(ValueTooLarge { len, max }.into()),
+            _ => Ok(()),
+        }
+    }
+    /// Has an insert into a leaf first pack its values back together when
+    /// the holes between them add up to more than `threshold` of a block,
+    /// rather than waiting until the value doesn't fit anywhere. Spreading
+    /// the work out this way keeps any one insert from stalling on it.
+    /// `None`, the default, only defragments when there's no other way.
+    pub fn set_defragment_threshold(&mut self, threshold: Option<f64>) {
+        self.defragment_threshold = threshold;
+    }
+    pub(crate) fn defragment_threshold(&self) -> Option<f64> {
+        self.defragment_threshold
+    }
+    /// Sets how full leaves are split. Existing leaves keep their shape.
+    pub fn set_split_policy(&mut self, policy: SplitPolicy) {
+        self.split_policy = policy;
+    }
+    /// Sets how often the disk is synced as writes are made. Syncing after
+    /// every write keeps each one durable, at the cost of waiting on the disk
+    /// each time.
+    pub fn set_sync_policy(&mut self, policy: SyncPolicy) {
+        self.sync_policy = policy;
+        self.unsynced_writes = 0;
+    }
+    /// Counts a finished write against the sync policy, syncing the disk if
+    /// one is due. Writes made inside another only count once the outermost
+    /// one is done.
+    fn count_write(&mut self) -> io::Result<()> {
+        if self.disk.in_transaction() || self.unlogged_depth > 0 {
+            return Ok(());
+        }
+        self.unsynced_writes += 1;
+        let due = match self.sync_policy {
+            SyncPolicy::Never => false,
+            SyncPolicy::EveryWrite => true,
+            SyncPolicy::EveryN(n) => self.unsynced_writes >= n,
+        };
+        if due {
+            self.unsynced_writes = 0;
+            self.disk.sync()?;
+        }
+        Ok(())
+    }
+    pub(crate) fn split_policy(&self) -> SplitPolicy {
+        self.split_policy
+    }
+    /// Compresses values of at least `min_len` bytes as they're stored,
+    /// keeping the compressed form only where it comes out shorter. Each
+    /// compressed value is flagged as such, so values read back the same
+    /// whatever this is later set to. `None`, the default, stores values as
+    /// they are.
+    pub fn set_compression(&mut self, min_len: Option<u64>) {
+        self.compress_from = min_len;
+    }
+    /// Replaces the `Deflate` compressor. Values already compressed with
+    /// another compressor can only be read with that one in place.
+    pub fn set_compressor(&mut self, compressor: impl Compressor + 'static) {
+        self.compressor = Arc::new(compressor);
+    }
+    /// Replaces the `ReuseFreeBlocks` allocator. It can be changed at any
+    /// time, since the free list it works from is kept in the file.
+    pub fn set_allocator(&mut self, allocator: impl Allocator + 'static) {
+        self.allocator = Arc::new(allocator);
+    }
+    /// Clears the unused bytes of a leaf each time it's written, so that
+    /// what's left of deleted or moved values doesn't linger in the file and
+    /// a leaf holding the same entries in the same places always has the
+    /// same image. Off by default, since it rewrites the whole leaf where
+    /// otherwise only the header changes.
+    pub fn set_zero_free_space(&mut self, enabled: bool) {
+        self.zero_free_space = enabled;
+    }
+    pub(crate) fn zeroes_free_space(&self) -> bool {
+        self.zero_free_space
+    }
+    /// Keeps values of up to `max_len` bytes in their leaf entries rather
+    /// than the leaf's value region, which saves the space and the holes of
+    /// tiny values like a nested tree's child offset. No more than 8 bytes
+    /// fit, which is the default; 0 turns embedding off. Values already
+    /// stored keep their place.
+    pub fn set_embed_threshold(&mut self, max_len: u64) {
+        self.embed_threshold = max_len.min(LeafPageEntry::MAX_EMBEDDED_LEN);
+    }
+    pub(crate) fn embed_threshold(&self) -> u64 {
+        self.embed_threshold
+    }
+    pub(crate) fn write_generation(&self) -> u64 {
+        self.write_generation
+    }
+    pub(crate) fn free_generation(&self) -> u64 {
+        self.free_generation
+    }
+    /// Whether a value of `len` bytes would be offered to the compressor.
+    pub(crate) fn compresses(&self, len: u64) -> bool {
+        self.compress_from.is_some_and(|min_len| len >= min_len)
+    }
+    /// `data` compressed, if the settings call for it and it's any shorter.
+    pub(crate) fn compress(&self, data: &[u8]) -> Option<Vec<u8>> {
+        if !self.compresses(data.len() as u64) {
+            return None;
+        }
+        let compressed = self.compressor.compress(data);
+        if compressed.len() < data.len() {
+            Some(compressed)
+        } else {
+            None
+        }
+    }
+    pub(crate) fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        self.compressor.decompress(data)
+    }
+    /// How many more blocks can be allocated before hitting the cap.
+    pub fn remaining_blocks(&self) -> Option<u64> {
+        self.max_blocks
+            .map(|max| max.saturating_sub(self.meta.num_blocks_allocated))
+    }
+    /// Estimates how many new blocks inserting values of the given lengths
+    /// would take. This errs on the high side, so comparing it against
+    /// `remaining_blocks` is a safe way to pre-flight an import before
+    /// running out of space halfway through it.
+    pub fn estimate_blocks_for(&self, entries: &[(Key, usize)]) -> u64 {
+        page::estimate_blocks(
+            entries.iter().map(|&(_, len)| len as u64),
+            self.block_size(),
+        )
+    }
+    /// Grows the file by up to `count` blocks in one go and puts them on the
+    /// free list, lowest first, so the allocations that follow are served
+    /// from there. Stops at the block cap. Returns how many were added.
+    pub(crate) fn reserve_blocks(&mut self, count: u64) -> io::Result<u64> {
+        let count = self
+            .remaining_blocks()
+            .map_or(count, |left| count.min(left));
+        if count == 0 {
+            return Ok(0);
+        }
+        self.atomically(|db| {
+            let first = db.meta.num_blocks_allocated;
+            db.disk.ensure_len((first + count) * db.block_size())?;
+            let mut reserved = Vec::with_capacity(count as usize);
+            for _ in 0..count {
+                reserved.push(db.append()?);
+            }
+            let allocator = Arc::clone(&db.allocator);
+            for &offset in reserved.iter().rev() {
+                allocator.free(offset, db)?;
+            }
+            log::debug!("RESERVE_BLOCKS [first={}][count={}]", first, count);
+            Ok(count)
+        })
+    }
+    /// Makes every write so far durable. For a `File` that means an
+    /// `fsync`, without which a crash can still lose data this database has
+    /// already written.
+    pub fn flush(&mut self) -> Result<()> {
+        Ok(self.disk.sync()?)
+    }
+    /// Opens the database already on `disk`.
+    ///
+    /// The open is counted in the header, so `disk` has to be writable: a
+    /// file opened with `File::open` fails here with the header write's
+    /// error. Use `open_read_only` for a disk that can't be written.
+    pub fn from_existing(disk: D) -> Result<Self> {
+        Ok(Database::open_existing(disk, true)?)
+    }
+
+    /// Like `from_existing`, but first runs `verify` over the whole file, so
+    /// that damage is found at startup instead of by whichever read happens
+    /// to reach it. The first problem is returned as an `InvalidData` error
+    /// that `Error::from` sorts into `Error::Corrupt`, naming the block it's
+    /// in. A file that fails isn't counted as opened. The check reads every
+    /// page, so it takes as long as reading the file would.
+    pub fn open_checked(disk: D) -> Result<Self> {
+        let mut db = Database::open_existing(disk, false)?;
+        let found = db.verify()?;
+        if let Some(first) = found.first() {
+            let reason = match found.len() {
+                1 => first.reason.clone(),
+                2 => format!("{}, and 1 more problem", first.reason),
+                n => format!("{}, and {} more problems", first.reason, n - 1),
+            };
+            return Err(page::corrupt_page(first.offset, &reason).into());
+        }
+        db.meta.open_count += 1;
+        db.meta.persist(&mut db.disk)?;
+        Ok(db)
+    }
+
+    /// Opens the database on `disk`, counting the open in the header if
+    /// `count_open` is set.
+    fn open_existing(mut disk: D, count_open: bool) -> io::Result<Self> {
+        let mut meta = Self::read_header(&mut disk)?;
+        if count_open {
+            meta.open_count += 1;
+            meta.persist(&mut disk).map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!(
+                        "could not count the open in the header, open read-only instead: {}",
+                        err
+                    ),
+                )
+            })?;
+        }
+        Ok(Database::with_meta(disk, meta))
+    }
+
+    /// Opens a database created with `initialize_with_wal`, first finishing
+    /// off any transaction that was committed to `wal` but not yet applied
+    /// when the process stopped.
+    pub fn from_existing_with_wal(mut disk: D, wal: impl Disk + Send + 'static) -> Result<Self> {
+        let mut wal: Box<dyn Disk + Send> = Box::new(wal);
+        Journal::recover(&mut disk, &mut wal)?;
+        let mut db = Database::from_existing(disk)?;
+        db.disk.set_wal(wal)?;
+        Ok(db)
+    }
+
+    pub fn initialize(disk: D) -> Result<Self> {
+        Database::initialize_with_endianness(disk, Endianness::Big)
+    }
+
+    /// Creates a database whose typed values are encoded with
+    /// `value_endianness`. The choice is stored in the header, so it can't
+    /// change once the file exists.
+    pub fn initialize_with_endianness(disk: D, value_endianness: Endianness) -> Result<Self> {
+        Ok(Database::create(
+            disk,
+            value_endianness,
+            DatabaseMeta::DEFAULT_BLOCK_SIZE_EXP,
+        )?)
+    }
+
+    /// Creates a database whose blocks are `block_size` bytes long rather
+    /// than the default 8192. It has to be a power of two that
+    /// `PageLayout::for_block_size` accepts, and can't change once the file
+    /// exists.
+    pub fn initialize_with_block_size(disk: D, block_size: u64) -> Result<Self> {
+        if PageLayout::for_block_size(block_size).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "block size {} is not a power of two from {} to {}",
+                    block_size,
+                    PageLayout::MIN_BLOCK_SIZE,
+                    PageLayout::MAX_BLOCK_SIZE
+                ),
+            )
+            .into());
+        }
+        let block_size_exp = block_size.trailing_zeros() as u64;
+        Ok(Database::create(disk, Endianness::Big, block_size_exp)?)
+    }
+
+    /// A database over `disk` with every setting at its default, whose
+    /// header has already been read or written as `meta`.
+    fn with_meta(disk: D, meta: DatabaseMeta) -> Self {
+        Database {
+            disk: Journal::new(disk, meta.block_size()),
+            meta,
+            max_blocks: None,
+            max_value_len: None,
+            defragment_threshold: None,
+            compress_from: None,
+            compressor: Arc::new(Deflate),
+            allocator: Arc::new(ReuseFreeBlocks),
+            split_policy: SplitPolicy::default(),
+            allocations: 0,
+            sync_policy: SyncPolicy::default(),
+            unsynced_writes: 0,
+            unlogged_depth: 0,
+            zero_free_space: false,
+            embed_threshold: LeafPageEntry::MAX_EMBEDDED_LEN,
+            write_generation: 0,
+            free_generation: 0,
+        }
+    }
+
+    fn create(mut disk: D, value_endianness: Endianness, block_size_exp: u64) -> io::Result<Self> {
+        let meta = Self::init_header(&mut disk, value_endianness, block_size_exp)?;
+        Ok(Database::with_meta(disk, meta))
+    }
+
+    /// Creates a database that writes every change to `wal` before making it
+    /// to `disk`. Each insert or delete is one transaction: after a crash,
+    /// `from_existing_with_wal` leaves either all of its writes on the disk
+    /// or none of them.
+    pub fn initialize_with_wal(disk: D, wal: impl Disk + Send + 'static) -> Result<Self> {
+        let mut db = Database::initialize(disk)?;
+        db.disk.set_wal(Box::new(wal))?;
+        Ok(db)
+    }
+
+    /// Keeps up to `blocks` recently read blocks in memory, so that pages
+    /// visited on every operation, like the root, aren't read from the disk
+    /// each time. 0 turns the cache off. It holds 64 blocks unless told
+    /// otherwise.
+    pub fn with_cache_capacity(mut self, blocks: usize) -> Self {
+        self.disk.set_cache_capacity(blocks);
+        self
+    }
+
+    /// Gives back the underlying disk.
+    pub fn into_disk(self) -> D {
+        self.disk.into_parts().0
+    }
+
+    /// Starts a transaction. Nothing written through the returned guard
+    /// reaches the disk until `Transaction::commit`, and if the guard is
+    /// dropped first, it's all thrown away.
+    pub fn transaction(&mut self) -> Transaction<'_, D> {
+        self.disk.begin();
+        let meta = self.meta.clone();
+        Transaction {
+            db: self,
+            meta,
+            done: false,
+        }
     }
 
-    pub fn initialize(mut disk: D) -> io::Result<Self> {
-        let meta = Self::init_header(&mut disk)?;
-        Ok(Database { disk, meta })
+    /// Runs `f` as a single transaction when there's a write-ahead log or a
+    /// transaction is already running, which it then joins. If `f` fails,
+    /// everything it wrote is dropped.
+    pub(crate) fn atomically<T>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> io::Result<T>,
+    ) -> io::Result<T> {
+        self.write_generation += 1;
+        if !self.disk.begin_atomic() {
+            self.unlogged_depth += 1;
+            let result = f(self);
+            self.unlogged_depth -= 1;
+            let value = result?;
+            self.count_write()?;
+            return Ok(value);
+        }
+        match f(self) {
+            Ok(value) => {
+                self.disk.commit()?;
+                self.count_write()?;
+                Ok(value)
+            }
+            Err(err) => {
+                self.disk.abort();
+                // allocations made in the transaction are gone with it
+                self.meta = Self::read_header(&mut self.disk)?;
+                Err(err)
+            }
+        }
     }
 
-    fn read_header(disk: &mut D) -> io::Result<DatabaseMeta> {
+    fn read_header(disk: &mut impl Disk) -> io::Result<DatabaseMeta> {
         disk.seek(SeekFrom::Start(0))?;
+        let mut magic = [0u8; 7];
+        disk.read_exact(&mut magic)?;
+        if magic != DatabaseMeta::MAGIC {
+            return Err(bad_header(
+                "not a tree-data database: the header has the wrong magic number",
+            ));
+        }
+        let version = disk.read_u8()?;
+        if version != DatabaseMeta::FORMAT_VERSION {
+            return Err(bad_header(format!(
+                "unsupported database format version {}, expected {}",
+                version,
+                DatabaseMeta::FORMAT_VERSION
+            )));
+        }
         let block_size_exp = disk.read_u64::<BigEndian>()?;
+        let block_size = block_size_exp
+            .try_into()
+            .ok()
+            .and_then(|exp: u32| 1u64.checked_shl(exp));
+        if block_size.and_then(PageLayout::for_block_size).is_none() {
+            return Err(bad_header(format!(
+                "unsupported block size 2^{}",
+                block_size_exp
+            )));
+        }
         let num_blocks_allocated = disk.read_u64::<BigEndian>()?;
         let root_btree_offset = disk.read_u64::<BigEndian>()?;
+        let value_endianness = Endianness::from_meta(disk.read_u64::<BigEndian>()?)?;
+        let free_list_head = disk.read_u64::<BigEndian>()?;
+        let catalog_offset = disk.read_u64::<BigEndian>()?;
+        let created_at = disk.read_u64::<BigEndian>()?;
+        let open_count = disk.read_u64::<BigEndian>()?;
         Ok(DatabaseMeta {
             block_size_exp,
             num_blocks_allocated,
             root_btree_offset,
+            value_endianness,
+            free_list_head,
+            catalog_offset,
+            created_at,
+            open_count,
         })
     }
 
-    fn init_header(disk: &mut D) -> io::Result<DatabaseMeta> {
+    fn init_header(
+        disk: &mut D,
+        value_endianness: Endianness,
+        block_size_exp: u64,
+    ) -> io::Result<DatabaseMeta> {
         disk.seek(SeekFrom::Start(0))?;
-        let block_size_exp = 13u64;
         // 1 for the meta block
         let num_blocks_allocated = 1u64;
         // init to 0: we lazily allocate
@@ -73,55 +780,1856 @@ impl<D: Disk> Database<D> {
             block_size_exp,
             num_blocks_allocated,
             root_btree_offset,
+            value_endianness,
+            free_list_head: 0,
+            catalog_offset: 0,
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis() as u64),
+            open_count: 0,
         };
         meta.persist(disk)?;
         Ok(meta)
     }
 
-    pub fn get(&mut self, key: Key) -> io::Result<TreeEntry<'_, D>> {
+    /// The root tree, or `None` if nothing has been stored in it yet. Unlike
+    /// `lookup_or_create`, this never writes to the file.
+    pub fn lookup_existing(&mut self) -> Result<Option<TreeEntry<'_, D>>> {
+        match self.meta.root_btree_offset {
+            0 => Ok(None),
+            offset => Ok(Some(TreeEntry { db: self, offset })),
+        }
+    }
+
+    #[deprecated(note = "use `lookup_or_create`, or `lookup_existing` to read without writing")]
+    pub fn lookup(&mut self) -> Result<TreeEntry<'_, D>> {
+        self.lookup_or_create()
+    }
+
+    /// The root tree, created first if the file doesn't have one yet, so the
+    /// first call on a new file writes to it.
+    pub fn lookup_or_create(&mut self) -> Result<TreeEntry<'_, D>> {
         if self.meta.root_btree_offset == 0 {
-            self.meta.root_btree_offset = BTree::init(self)?.offset()
+            self.atomically(|db| {
+                db.meta.root_btree_offset = BTree::init(db)?.offset();
+                db.meta.persist(&mut db.disk)
+            })?;
         }
         let offset = self.meta.root_btree_offset;
 
         Ok(TreeEntry { db: self, offset })
     }
-}
 
-impl<D: Disk> BlockAllocator for Database<D> {
-    fn allocate_block(&mut self) -> io::Result<u64> {
-        let block_size = self.meta.block_size();
-        let new_offset = block_size * self.meta.num_blocks_allocated;
-        self.meta.num_blocks_allocated += 1;
-        self.meta.persist(&mut self.disk)?;
-        Ok(new_offset)
+    #[deprecated(note = "use `get_or_create`, or `get_existing` to read without writing")]
+    pub fn get(&mut self, key: Key) -> Result<TreeEntry<'_, D>> {
+        self.get_or_create(key)
     }
 
-    fn write(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
-        self.disk.seek(SeekFrom::Start(offset))?;
-        self.disk.write_all(data)?;
+    /// `key`'s child tree under the root, created first along with the root
+    /// tree if they don't exist yet, so this can write even when only used
+    /// to read.
+    pub fn get_or_create(&mut self, key: Key) -> Result<TreeEntry<'_, D>> {
+        self.lookup_or_create()?.get_or_create(key)
+    }
+
+    /// `key`'s child tree under the root, or `None` if there isn't one yet.
+    /// Like `TreeEntry::get_existing`, this never writes to the file.
+    pub fn get_existing(&mut self, key: Key) -> Result<Option<TreeEntry<'_, D>>> {
+        match self.lookup_existing()? {
+            Some(root) => root.get_existing(key),
+            None => Ok(None),
+        }
+    }
+
+    /// Creates an empty table called `name`, failing with
+    /// `ErrorKind::AlreadyExists` if there already is one. Each table is a
+    /// `BTree` of its own, found by name through a catalog rather than
+    /// under a key of the root tree.
+    pub fn create_table(&mut self, name: &str) -> Result<TableHandle> {
+        Ok(self.atomically(|db| {
+            let mut catalog = db.catalog()?;
+            if catalog.lookup_bytes(name.as_bytes(), db)?.is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("table {:?} already exists", name),
+                ));
+            }
+            let tree = BTree::init(db)?;
+            catalog.insert_bytes(name.as_bytes(), &tree.offset().to_be_bytes(), db)?;
+            Ok(TableHandle {
+                name: name.to_string(),
+                tree,
+            })
+        })?)
+    }
+
+    /// Opens the table called `name`, failing with `ErrorKind::NotFound` if
+    /// it hasn't been created.
+    pub fn open_table(&mut self, name: &str) -> Result<TableHandle> {
+        let root = match self.meta.catalog_offset {
+            0 => None,
+            offset => BTree::from_offset(offset).lookup_bytes(name.as_bytes(), self)?,
+        };
+        match root {
+            Some(root) => Ok(TableHandle {
+                name: name.to_string(),
+                tree: BTree::from_offset(tree::read_be_u64(&root)),
+            }),
+            None => {
+                Err(io::Error::new(io::ErrorKind::NotFound, format!("no table {:?}", name)).into())
+            }
+        }
+    }
+
+    /// The tree of tables, created on first use.
+    fn catalog(&mut self) -> io::Result<BTree> {
+        if self.meta.catalog_offset == 0 {
+            self.meta.catalog_offset = BTree::init(self)?.offset();
+            self.meta.persist(&mut self.disk)?;
+        }
+        Ok(BTree::from_offset(self.meta.catalog_offset))
+    }
+
+    /// Every table, as its name and root offset.
+    fn tables(&mut self) -> io::Result<Vec<(Vec<u8>, PageOffset)>> {
+        if self.meta.catalog_offset == 0 {
+            return Ok(vec![]);
+        }
+        let catalog = BTree::from_offset(self.meta.catalog_offset);
+        Ok(catalog
+            .bytes_entries(self)?
+            .into_iter()
+            .map(|(name, root)| (name, tree::read_be_u64(&root)))
+            .collect())
+    }
+
+    /// Merges every table of `other` into this database, creating the tables
+    /// that don't exist here yet. When both databases hold a value under the
+    /// same key, the value from `other` wins.
+    pub fn merge_file(&mut self, other: &mut Database<impl Disk>) -> Result<()> {
+        self.atomically(|db| {
+            if other.meta.root_btree_offset != 0 {
+                db.lookup_or_create()?
+                    .merge_from(other.lookup_or_create()?)?;
+            }
+            for (name, root) in other.tables()? {
+                let mut catalog = db.catalog()?;
+                let mut table = match catalog.lookup_bytes(&name, db)? {
+                    Some(root) => BTree::from_offset(tree::read_be_u64(&root)),
+                    None => {
+                        let table = BTree::init(db)?;
+                        catalog.insert_bytes(&name, &table.offset().to_be_bytes(), db)?;
+                        table
+                    }
+                };
+                table.merge_from(db, &BTree::from_offset(root), other)?;
+            }
+            Ok(())
+        })?;
         Ok(())
     }
+
+    /// A hash of every value in the database together with its key path,
+    /// and of every table in the catalog, taken in key order. It depends only
+    /// on the contents, not on how pages are laid out, so a compacted or
+    /// merged copy hashes the same as the original.
+    pub fn content_hash(&mut self) -> Result<u64> {
+        let mut hasher = tree::ContentHasher::new();
+        let root = self.meta.root_btree_offset;
+        if root != 0 {
+            tree::hash_level(self, root, &mut vec![], &mut hasher)?;
+        }
+        // the catalog keeps its tables in the order of their names' hashes
+        let mut tables = self.tables()?;
+        tables.sort();
+        for (name, root) in tables {
+            tree::hash_table(self, &name, root, &mut hasher)?;
+        }
+        Ok(hasher.finish())
+    }
+
+    /// Writes a compacted copy of the database to `target` and returns it.
+    /// Every table and every tree reachable from the root is rebuilt there
+    /// with its leaves packed full, and nothing else is copied, so the copy
+    /// has no free blocks and is as short as its contents allow.
+    ///
+    /// A tree built directly with `BTree::init` can't be found from the
+    /// header, so it couldn't be copied. If the file holds a page of one,
+    /// the vacuum fails before anything is written to `target`; make such
+    /// trees with `create_table` instead. Blocks leaked by a crash hold no
+    /// page, and are dropped.
+    ///
+    /// This database isn't written to, so an interrupted vacuum leaves it as
+    /// it was. Swapping the copy in for the original is up to the caller.
+    pub fn vacuum_into<T: Disk>(&mut self, target: T) -> Result<Database<T>> {
+        if let Some(offset) = self.unlisted_tree_page()? {
+            return Err(io::Error::other(format!(
+                "page at offset {} is unreachable from the root tree and would be lost",
+                offset
+            ))
+            .into());
+        }
+        let mut vacuumed =
+            Database::create(target, self.value_endianness(), self.meta.block_size_exp)?;
+        let root = self.meta.root_btree_offset;
+        if root != 0 {
+            vacuumed.meta.root_btree_offset = tree::vacuum_level(self, root, &mut vacuumed)?;
+            vacuumed.meta.persist(&mut vacuumed.disk)?;
+        }
+        for (name, root) in self.tables()? {
+            let copy = BTree::from_offset(root).copy_packed(self, &mut vacuumed)?;
+            let mut catalog = vacuumed.catalog()?;
+            catalog.insert_bytes(&name, &copy.offset().to_be_bytes(), &mut vacuumed)?;
+        }
+        vacuumed.flush()?;
+        Ok(vacuumed)
+    }
+
+    /// A read-only copy of the whole file in memory, which later changes to
+    /// this database don't touch, so a long scan over it sees one
+    /// consistent state. It isn't a view sharing pages with the file: every
+    /// allocated block is read and held in memory for as long as the copy
+    /// lives, free ones included, so it costs time and memory in proportion
+    /// to the file's length and suits files that fit in memory with room to
+    /// spare. Copying in the middle of a transaction fails, as what the
+    /// transaction has written isn't settled.
+    pub fn copy_into_memory(&mut self) -> Result<MemoryCopy> {
+        if self.disk.in_transaction() {
+            return Err(io::Error::other(
+                "can't copy the database into memory in the middle of a transaction",
+            )
+            .into());
+        }
+        let len = self.meta.num_blocks_allocated * self.block_size();
+        let mut disk = MemoryDisk::new();
+        self.disk.seek(SeekFrom::Start(0))?;
+        io::copy(&mut (&mut self.disk).take(len), &mut disk)?;
+        let mut db = Database::open_read_only(disk)?;
+        db.compressor = Arc::clone(&self.compressor);
+        Ok(MemoryCopy { db })
+    }
+
+    /// Writes a snapshot of the database to `out`, to be read back with
+    /// `restore`. The snapshot is a vacuumed copy of the file, so freed
+    /// blocks are left out, behind a header tagging it with the snapshot
+    /// format's version. Like `vacuum_into`, it fails on a file holding a
+    /// tree it can't reach, and then nothing is written to `out`.
+    pub fn dump<W: Write>(&mut self, out: &mut W) -> Result<()> {
+        let image = self
+            .vacuum_into(MemoryDisk::new())?
+            .into_disk()
+            .into_bytes();
+        out.write_all(&DUMP_MAGIC)?;
+        out.write_u8(DUMP_VERSION)?;
+        out.write_u64::<BigEndian>(image.len() as u64)?;
+        out.write_all(&image)?;
+        Ok(out.flush()?)
+    }
+
+    /// Writes the snapshot in `input`, made by `dump`, to `disk` and opens
+    /// it. A snapshot in a format this version doesn't know fails with
+    /// `ErrorKind::InvalidData`, as does anything that isn't a snapshot.
+    pub fn restore<R: Read>(input: &mut R, mut disk: D) -> Result<Database<D>> {
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if magic != DUMP_MAGIC {
+            return Err(
+                io::Error::new(io::ErrorKind::InvalidData, "not a database snapshot").into(),
+            );
+        }
+        let version = input.read_u8()?;
+        if version != DUMP_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported snapshot version {}", version),
+            )
+            .into());
+        }
+        let len = input.read_u64::<BigEndian>()?;
+        disk.seek(SeekFrom::Start(0))?;
+        if io::copy(&mut input.take(len), &mut disk)? != len {
+            return Err(
+                io::Error::new(io::ErrorKind::UnexpectedEof, "snapshot is cut short").into(),
+            );
+        }
+        disk.flush()?;
+        Database::from_existing(disk)
+    }
+
+    /// The root offset of the nested tree found by following `path` from the
+    /// root tree, without creating anything along the way.
+    fn tree_offset(&mut self, path: &[Key]) -> io::Result<Option<PageOffset>> {
+        let mut offset = self.meta.root_btree_offset;
+        if offset == 0 {
+            return Ok(None);
+        }
+        for &key in path {
+            let mut entry = TreeEntry { db: self, offset };
+            offset = match entry.child_offset(key)? {
+                Some(offset) => offset,
+                None => return Ok(None),
+            };
+        }
+        Ok(Some(offset))
+    }
+
+    /// Block counts and the size of the file. Counting the free blocks walks
+    /// the whole free list.
+    pub fn stats(&mut self) -> Result<DatabaseStats> {
+        let mut free_blocks = 0;
+        let mut offset = self.meta.free_list_head;
+        while offset != 0 {
+            free_blocks += 1;
+            offset = page::read_free_block(offset, self)?;
+        }
+        Ok(DatabaseStats {
+            block_size: self.block_size(),
+            num_blocks_allocated: self.meta.num_blocks_allocated,
+            free_blocks,
+            file_size: self.disk.seek(SeekFrom::End(0))?,
+        })
+    }
+
+    /// What the page at `offset` holds, for tools that look inside the
+    /// file. Only reads. An offset that isn't an allocated block, or is the
+    /// header, fails with `ErrorKind::InvalidInput`.
+    pub fn inspect_page(&mut self, offset: u64) -> Result<PageInfo> {
+        Ok(page::inspect(offset, self)?)
+    }
+
+    /// Runs `BTree::verify` over every tree in the file, the tables and the
+    /// trees nested under the root alike, so that a block used by two of
+    /// them is caught too. Also checks that nothing on the free list is in
+    /// use. The children of a tree that fails its checks are skipped, since
+    /// the offsets leading to them can't be trusted.
+    pub fn verify(&mut self) -> Result<Vec<Inconsistency>> {
+        let mut seen = HashSet::new();
+        let mut found = vec![];
+        let mut levels = vec![];
+        if self.meta.root_btree_offset != 0 {
+            levels.push(self.meta.root_btree_offset);
+        }
+        while let Some(offset) = levels.pop() {
+            if BTree::from_offset(offset).verify_into(self, &mut seen, &mut found)? {
+                levels.extend(TreeEntry { db: self, offset }.child_offsets()?);
+            }
+        }
+        if self.meta.catalog_offset != 0 {
+            let catalog = BTree::from_offset(self.meta.catalog_offset);
+            if catalog.verify_into(self, &mut seen, &mut found)? {
+                for (_, root) in self.tables()? {
+                    BTree::from_offset(root).verify_into(self, &mut seen, &mut found)?;
+                }
+            }
+        }
+        let mut free = HashSet::new();
+        let mut offset = self.meta.free_list_head;
+        while offset != 0 {
+            let reason = if seen.contains(&offset) {
+                "is on the free list but in use"
+            } else if !free.insert(offset) {
+                "is on the free list twice"
+            } else {
+                match page::read_free_block(offset, self) {
+                    Ok(next) => {
+                        offset = next;
+                        continue;
+                    }
+                    Err(err) if page::is_corruption(&err) => "is on the free list but not free",
+                    Err(err) => return Err(err.into()),
+                }
+            };
+            found.push(Inconsistency {
+                offset,
+                reason: reason.to_string(),
+            });
+            break;
+        }
+        Ok(found)
+    }
+
+    /// Hands the block at `offset` back to the allocator, which by default
+    /// puts it on the free list so the next allocation hands it out again
+    /// instead of growing the file. Nothing may point at the block any more.
+    pub fn free_block(&mut self, offset: u64) -> Result<()> {
+        log::debug!("FREE_BLOCK [offset={}]", offset);
+        self.free_generation += 1;
+        Ok(Arc::clone(&self.allocator).free(offset, self)?)
+    }
+
+    /// Gives back the free blocks at the end of the file, lowering the
+    /// allocated block count past them and shrinking the disk to match, and
+    /// returns how many there were. Blocks freed further in stay on the free
+    /// list; `vacuum_into` is the way to reclaim those. The free list is
+    /// walked once and rewritten without the dropped blocks.
+    pub fn truncate_trailing_free(&mut self) -> Result<u64> {
+        let block_size = self.block_size();
+        let dropped = self.atomically(|db| {
+            let mut free = vec![];
+            let mut offset = db.meta.free_list_head;
+            while offset != 0 {
+                free.push(offset);
+                offset = page::read_free_block(offset, db)?;
+            }
+            let on_list: HashSet<PageOffset> = free.iter().cloned().collect();
+            let mut blocks = db.meta.num_blocks_allocated;
+            while blocks > 1 && on_list.contains(&((blocks - 1) * block_size)) {
+                blocks -= 1;
+            }
+            let dropped = db.meta.num_blocks_allocated - blocks;
+            if dropped == 0 {
+                return Ok(0);
+            }
+            let mut head = 0;
+            for &offset in free.iter().rev() {
+                if offset < blocks * block_size {
+                    page::write_free_block(offset, head, db)?;
+                    head = offset;
+                }
+            }
+            log::debug!("TRUNCATE_TRAILING_FREE [blocks={}]", dropped);
+            db.meta.free_list_head = head;
+            db.meta.num_blocks_allocated = blocks;
+            db.meta.persist(&mut db.disk)?;
+            Ok(dropped)
+        })?;
+        if dropped > 0 {
+            self.disk
+                .truncate(self.meta.num_blocks_allocated * block_size)?;
+        }
+        Ok(dropped)
+    }
+
+    /// Every block on the free list, in list order.
+    fn free_list(&mut self) -> io::Result<Vec<PageOffset>> {
+        let mut free = vec![];
+        let mut offset = self.meta.free_list_head;
+        while offset != 0 {
+            free.push(offset);
+            offset = page::read_free_block(offset, self)?;
+        }
+        Ok(free)
+    }
+
+    /// Replaces the free list with `blocks`, which must all be free, so
+    /// that the lowest is handed out first.
+    fn set_free_list(&mut self, mut blocks: Vec<PageOffset>) -> io::Result<()> {
+        self.free_generation += 1;
+        blocks.sort_unstable();
+        let mut head = 0;
+        for &offset in blocks.iter().rev() {
+            page::write_free_block(offset, head, self)?;
+            head = offset;
+        }
+        self.meta.free_list_head = head;
+        self.meta.persist(&mut self.disk)
+    }
+
+    /// Every page reachable from the root tree, following nested trees, or
+    /// from the catalog of tables.
+    fn live_pages(&mut self) -> io::Result<HashSet<PageOffset>> {
+        let mut live = HashSet::new();
+        if self.meta.catalog_offset != 0 {
+            let mut flat = vec![BTree::from_offset(self.meta.catalog_offset)];
+            flat.extend(
+                self.tables()?
+                    .into_iter()
+                    .map(|(_, root)| BTree::from_offset(root)),
+            );
+            for tree in flat {
+                live.extend(tree.pages(self)?);
+                live.extend(tree.overflow_pages(self)?);
+            }
+        }
+        let mut trees = vec![];
+        if self.meta.root_btree_offset != 0 {
+            trees.push(self.meta.root_btree_offset);
+        }
+        while let Some(offset) = trees.pop() {
+            let tree = BTree::from_offset(offset);
+            live.extend(tree.pages(self)?);
+            live.extend(tree.overflow_pages(self)?);
+            trees.extend(TreeEntry { db: self, offset }.child_offsets()?);
+        }
+        Ok(live)
+    }
+
+    /// The first block that isn't on the free list and that no table or tree
+    /// under the root leads to, but still holds a tree page.
+    fn unlisted_tree_page(&mut self) -> io::Result<Option<PageOffset>> {
+        let live = self.live_pages()?;
+        let free: HashSet<PageOffset> = self.free_list()?.into_iter().collect();
+        let block_size = self.block_size();
+        for block in 1..self.meta.num_blocks_allocated {
+            let offset = block * block_size;
+            if !live.contains(&offset)
+                && !free.contains(&offset)
+                && page::holds_tree_page(offset, self)?
+            {
+                return Ok(Some(offset));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Moves the pages of the nested tree at `path` (`&[]` for the root tree)
+    /// into the lowest free blocks of the file, root first, so that a tree
+    /// that's read a lot sits together near the start of the file.
+    ///
+    /// Only blocks on the free list are taken, and the blocks the tree moves
+    /// out of go onto it, so pages that nothing in the file leads to, like
+    /// those of a tree built directly with `BTree::init`, are left alone.
+    pub fn relocate_tree_to_front(&mut self, path: &[Key]) -> Result<()> {
+        Ok(self.atomically(|db| db.relocate_tree(path))?)
+    }
+
+    fn relocate_tree(&mut self, path: &[Key]) -> io::Result<()> {
+        self.free_generation += 1;
+        let tree = match self.tree_offset(path)? {
+            Some(offset) => BTree::from_offset(offset),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no tree at path {:?}", path),
+                ))
+            }
+        };
+        let pages = tree.pages(self)?;
+        let mut blocks = self.free_list()?;
+        blocks.extend(pages.iter().cloned());
+        blocks.sort_unstable();
+        let left_free = blocks.split_off(pages.len());
+        let moves: Vec<(PageOffset, PageOffset)> = pages.iter().cloned().zip(blocks).collect();
+        let new_root = tree.relocate(&moves, self)?.offset();
+        log::debug!("RELOCATE_TREE [from={}][to={}]", tree.offset(), new_root);
+        // the list still runs through the blocks just taken, so it's set
+        // before anything else gets a chance to allocate
+        self.set_free_list(left_free)?;
+
+        match path.split_last() {
+            None => {
+                self.meta.root_btree_offset = new_root;
+                self.meta.persist(&mut self.disk)
+            }
+            Some((&key, parent_path)) => {
+                // the parent was found on the way down, so it's still there
+                let offset = self.tree_offset(parent_path)?.unwrap();
+                TreeEntry { db: self, offset }.set_child_offset(key, new_root)
+            }
+        }
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::io::Cursor;
-    fn cursor() -> impl Disk {
-        Cursor::new(vec![])
+/// A table from `Database::create_table` or `Database::open_table`. It
+/// derefs to the table's `BTree`, which is used like any other.
+pub struct TableHandle {
+    name: String,
+    tree: BTree,
+}
+
+impl TableHandle {
+    pub fn name(&self) -> &str {
+        &self.name
     }
-    fn database() -> Database<impl Disk> {
-        Database::initialize(cursor()).unwrap()
+}
+
+impl Deref for TableHandle {
+    type Target = BTree;
+    fn deref(&self) -> &BTree {
+        &self.tree
     }
+}
 
-    #[test]
-    fn create_new_database() {
-        database();
+impl DerefMut for TableHandle {
+    fn deref_mut(&mut self) -> &mut BTree {
+        &mut self.tree
     }
+}
 
-    #[test]
-    fn insert_and_retrieve() -> io::Result<()> {
+/// The database at one moment, copied into memory by
+/// `Database::copy_into_memory`. It derefs to a read-only database, so it's
+/// read like any other.
+pub struct MemoryCopy {
+    db: Database<ReadOnly<MemoryDisk>>,
+}
+
+impl Deref for MemoryCopy {
+    type Target = Database<ReadOnly<MemoryDisk>>;
+    fn deref(&self) -> &Self::Target {
+        &self.db
+    }
+}
+
+impl DerefMut for MemoryCopy {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.db
+    }
+}
+
+/// A transaction started by `Database::transaction`. It derefs to the
+/// database, so everything a `Database` can do can be staged in it.
+pub struct Transaction<'a, D: Disk> {
+    db: &'a mut Database<D>,
+    /// The header as it was when the transaction started.
+    meta: DatabaseMeta,
+    done: bool,
+}
+
+impl<D: Disk> Transaction<'_, D> {
+    /// Applies everything written in the transaction. If that fails, none of
+    /// it is kept.
+    pub fn commit(mut self) -> Result<()> {
+        self.done = true;
+        if let Err(err) = self.db.disk.commit() {
+            self.db.meta = self.meta.clone();
+            return Err(err.into());
+        }
+        Ok(self.db.count_write()?)
+    }
+
+    /// Throws away everything written in the transaction.
+    pub fn rollback(self) {}
+}
+
+impl<D: Disk> Deref for Transaction<'_, D> {
+    type Target = Database<D>;
+    fn deref(&self) -> &Database<D> {
+        self.db
+    }
+}
+
+impl<D: Disk> DerefMut for Transaction<'_, D> {
+    fn deref_mut(&mut self) -> &mut Database<D> {
+        self.db
+    }
+}
+
+impl<D: Disk> Drop for Transaction<'_, D> {
+    fn drop(&mut self) {
+        if !self.done {
+            log::debug!("ROLLBACK");
+            self.db.write_generation += 1;
+            self.db.free_generation += 1;
+            self.db.disk.abort();
+            self.db.meta = self.meta.clone();
+        }
+    }
+}
+
+impl<D: Disk> Blocks for Database<D> {
+    fn pop_free(&mut self) -> io::Result<Option<u64>> {
+        if self.meta.free_list_head == 0 {
+            return Ok(None);
+        }
+        let offset = self.meta.free_list_head;
+        self.meta.free_list_head = page::read_free_block(offset, self)?;
+        self.meta.persist(&mut self.disk)?;
+        log::debug!("REUSE_BLOCK [offset={}]", offset);
+        Ok(Some(offset))
+    }
+
+    fn push_free(&mut self, offset: u64) -> io::Result<()> {
+        page::write_free_block(offset, self.meta.free_list_head, self)?;
+        self.meta.free_list_head = offset;
+        self.meta.persist(&mut self.disk)
+    }
+
+    fn append(&mut self) -> io::Result<u64> {
+        if self.remaining_blocks() == Some(0) {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                "database has reached its maximum number of blocks",
+            ));
+        }
+        let block_size = self.meta.block_size();
+        let new_offset = block_size * self.meta.num_blocks_allocated;
+        self.disk.ensure_len(new_offset + block_size)?;
+        self.meta.num_blocks_allocated += 1;
+        self.meta.persist(&mut self.disk)?;
+        Ok(new_offset)
+    }
+}
+
+impl<D: Disk> BlockAllocator for Database<D> {
+    fn allocate_block(&mut self) -> io::Result<u64> {
+        // the header counts the block before the caller writes its page, but
+        // nothing points at the block until then, so a crash in between
+        // leaks it without putting what was there before into a tree
+        let offset = Arc::clone(&self.allocator).allocate(self)?;
+        self.allocations += 1;
+        Ok(offset)
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.disk.seek(SeekFrom::Start(offset))?;
+        self.disk.write_all(data)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryDisk;
+    use std::io::Cursor;
+    fn cursor() -> impl Disk {
+        Cursor::new(vec![])
+    }
+    fn database() -> Database<impl Disk> {
+        Database::initialize(cursor()).unwrap()
+    }
+
+    #[test]
+    fn create_new_database() {
+        database();
+    }
+
+    #[test]
+    fn reopening_checks_the_magic_and_version() -> io::Result<()> {
+        let mut db = database();
+        db.get_or_create(1)?.set_u64(2, 3)?;
+        let mut disk = Database::from_existing(db.into_disk())?.into_disk();
+        disk.seek(SeekFrom::Start(7))?;
+        disk.write_u8(DatabaseMeta::FORMAT_VERSION + 1)?;
+        let err = Database::from_existing(disk).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let err = Database::from_existing(Cursor::new(vec![0x42; 64]))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    #[test]
+    fn block_sizes_are_checked_when_set_and_when_read() -> io::Result<()> {
+        for &block_size in [0, 100, 1000, 1 << 30].iter() {
+            let err = Database::initialize_with_block_size(Cursor::new(vec![]), block_size)
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+        let mut db = Database::initialize_with_block_size(Cursor::new(vec![]), 512)?;
+        for key in 0..200 {
+            db.get_or_create(1)?.set_u64(key, key as u64)?;
+        }
+        let mut db = Database::from_existing(db.into_disk())?;
+        assert_eq!(db.block_size(), 512);
+        assert_eq!(db.page_layout(), PageLayout::for_block_size(512).unwrap());
+        assert_eq!(db.get_existing(1)?.unwrap().get_u64(199)?, Some(199));
+        assert!(db.verify()?.is_empty());
+
+        // the exponent sits just past the magic and version
+        let mut disk = db.into_disk();
+        for &exp in [4u64, 40, 64].iter() {
+            disk.seek(SeekFrom::Start(8))?;
+            disk.write_u64::<BigEndian>(exp)?;
+            let err = Database::from_existing(&mut disk).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn flushed_file_can_be_reopened() -> io::Result<()> {
+        let path = std::env::temp_dir().join(format!("tree-data-flush-{}", std::process::id()));
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(&path)?;
+        let mut db = Database::initialize(file)?;
+        db.get_or_create(1)?.set_u64(2, 3)?;
+        db.flush()?;
+        drop(db);
+
+        let file = OpenOptions::new().read(true).write(true).open(&path)?;
+        let mut db = Database::from_existing(file)?;
+        let value = db.get_existing(1)?.unwrap().get_u64(2);
+        std::fs::remove_file(&path)?;
+        assert_eq!(value?, Some(3));
+        Ok(())
+    }
+
+    #[test]
+    fn the_header_keeps_the_creation_time_and_open_count() -> io::Result<()> {
+        let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let db = Database::initialize(Cursor::new(vec![]))?;
+        let created_at = db.created_at();
+        assert!(created_at >= before.as_millis() as u64);
+        assert_eq!(db.open_count(), 0);
+        let mut disk = db.into_disk();
+        for count in 1..=3 {
+            let db = Database::from_existing(disk)?;
+            assert_eq!(db.open_count(), count);
+            assert_eq!(db.created_at(), created_at);
+            disk = db.into_disk();
+        }
+        let bytes = disk.into_inner();
+        let db = Database::open_read_only(Cursor::new(&bytes[..]))?;
+        assert_eq!(db.open_count(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn files_opened_for_reading_only_open_read_only() -> io::Result<()> {
+        let path = std::env::temp_dir().join(format!("tree-data-reading-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut db = Database::open_or_create(&path)?;
+        db.get_or_create(1)?.set_u64(2, 3)?;
+        drop(db);
+        let err = Database::from_existing(File::open(&path)?).err().unwrap();
+        let mut db = Database::open_read_only(File::open(&path)?)?;
+        let value = db.get_existing(1)?.unwrap().get_u64(2);
+        std::fs::remove_file(&path)?;
+        assert!(err.to_string().contains("open read-only instead"));
+        assert_eq!(value?, Some(3));
+        Ok(())
+    }
+
+    #[test]
+    fn open_or_create_reopens_what_it_created() -> io::Result<()> {
+        let path = std::env::temp_dir().join(format!("tree-data-open-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut db = Database::open_or_create(&path)?;
+        db.get_or_create(1)?.set_u64(2, 3)?;
+        drop(db);
+        let mut db = Database::open_or_create(&path)?;
+        let value = db.get_existing(1)?.unwrap().get_u64(2);
+        drop(db);
+
+        std::fs::write(&path, b"not a database")?;
+        let err = Database::open_or_create(&path).err().unwrap();
+        let contents = std::fs::read(&path)?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(value?, Some(3));
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(contents, b"not a database");
+        Ok(())
+    }
+
+    #[test]
+    fn cached_blocks_follow_writes() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?.with_cache_capacity(3);
+        let mut tree = BTree::init(&mut db)?;
+        for key in 0..1000 {
+            tree.insert(key, &[1; 30], &mut db)?;
+            assert_eq!(tree.lookup(key / 2, &mut db)?, Some(vec![1; 30]));
+        }
+        for key in 0..1000 {
+            tree.insert(key, &[2; 30], &mut db)?;
+            tree.delete(key / 3, &mut db)?;
+        }
+
+        let mut uncached = Database::from_existing(db.into_disk())?.with_cache_capacity(0);
+        assert_eq!(tree.lookup(300, &mut uncached)?, None);
+        assert_eq!(tree.lookup(999, &mut uncached)?, Some(vec![2; 30]));
+        Ok(())
+    }
+
+    #[test]
+    fn committed_transaction_keeps_every_write() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tx = db.transaction();
+        tx.get_or_create(1)?.set_u64(2, 3)?;
+        tx.get_or_create(1)?.set_u64(4, 5)?;
+        tx.commit()?;
+
+        let mut db = Database::from_existing(db.into_disk())?;
+        assert_eq!(db.get_existing(1)?.unwrap().get_u64(2)?, Some(3));
+        assert_eq!(db.get_existing(1)?.unwrap().get_u64(4)?, Some(5));
+        Ok(())
+    }
+
+    #[test]
+    fn transaction_interrupted_by_a_panic_leaves_nothing_behind() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        db.get_or_create(1)?.set_u64(2, 3)?;
+        let blocks = db.num_blocks_allocated();
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let mut tx = db.transaction();
+            tx.get_or_create(1)?.set_u64(2, 4)?;
+            tx.get_or_create(5)?.set_u64(6, 7)?;
+            panic!("between two writes");
+            #[allow(unreachable_code)]
+            tx.commit()
+        }));
+        assert!(result.is_err());
+        assert_eq!(db.num_blocks_allocated(), blocks);
+
+        let mut db = Database::from_existing(db.into_disk())?;
+        assert_eq!(db.get_existing(1)?.unwrap().get_u64(2)?, Some(3));
+        assert_eq!(db.tree_offset(&[5])?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn wal_writes_reach_the_disk() -> io::Result<()> {
+        let mut db = Database::initialize_with_wal(Cursor::new(vec![]), Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        for key in 0..2000 {
+            tree.insert(key, &[key as u8; 20], &mut db)?;
+        }
+        assert_eq!(tree.delete(7, &mut db)?, Some(vec![7; 20]));
+
+        let mut db = Database::from_existing(db.into_disk())?;
+        assert_eq!(tree.lookup(7, &mut db)?, None);
+        assert_eq!(tree.lookup(1999, &mut db)?, Some(vec![1999u128 as u8; 20]));
+        assert!(tree.depth(&mut db)? > 1);
+        Ok(())
+    }
+
+    /// A database whose write of key 1 made it into the log, but not onto
+    /// the disk, as if the process died in between.
+    fn crashed_after_logging() -> io::Result<(Cursor<Vec<u8>>, Vec<u8>)> {
+        let mut db = Database::initialize_with_wal(Cursor::new(vec![]), Cursor::new(vec![]))?;
+        db.get_or_create(1)?.set_u64(2, 3)?;
+        db.disk.begin();
+        db.get_or_create(1)?.set_u64(2, 4)?;
+        db.disk.write_log()?;
+        let (disk, wal) = db.disk.into_parts();
+        let mut log = vec![];
+        let mut wal = wal.unwrap();
+        wal.seek(SeekFrom::Start(0))?;
+        wal.read_to_end(&mut log)?;
+        Ok((disk, log))
+    }
+
+    #[test]
+    fn committed_log_is_replayed_on_open() -> io::Result<()> {
+        let (disk, log) = crashed_after_logging()?;
+        assert_eq!(
+            Database::from_existing(disk.clone())?
+                .get_existing(1)?
+                .unwrap()
+                .get_u64(2)?,
+            Some(3)
+        );
+        let mut db = Database::from_existing_with_wal(disk, Cursor::new(log))?;
+        assert_eq!(db.get_existing(1)?.unwrap().get_u64(2)?, Some(4));
+        Ok(())
+    }
+
+    #[test]
+    fn damaged_log_is_ignored_on_open() -> io::Result<()> {
+        let (disk, mut log) = crashed_after_logging()?;
+        // a byte of the first page image, as if it was never fully written
+        log[40] ^= 0xff;
+
+        let mut db = Database::from_existing_with_wal(disk, Cursor::new(log))?;
+        assert_eq!(db.get_existing(1)?.unwrap().get_u64(2)?, Some(3));
+        Ok(())
+    }
+
+    #[test]
+    fn insert_and_retrieve() -> io::Result<()> {
+        Ok(())
+    }
+
+    fn growth_for(value_lens: &[usize]) -> io::Result<(u64, u64)> {
+        let mut db = database();
+        let mut tree = BTree::init(&mut db)?;
+        let entries: Vec<(Key, usize)> = value_lens
+            .iter()
+            .enumerate()
+            .map(|(key, &len)| (key as Key, len))
+            .collect();
+        let estimate = db.estimate_blocks_for(&entries);
+        let before = db.num_blocks_allocated();
+        for &(key, len) in &entries {
+            tree.insert(key, &vec![7; len], &mut db)?;
+        }
+        Ok((estimate, db.num_blocks_allocated() - before))
+    }
+
+    #[test]
+    fn block_estimate_is_close_to_actual_growth() -> io::Result<()> {
+        let small = vec![4; 5_000];
+        let large = vec![500; 500];
+        let mixed: Vec<usize> = (0..2_000).map(|i| (i * 37) % 300).collect();
+        for value_lens in &[small, large, mixed] {
+            let (estimate, actual) = growth_for(value_lens)?;
+            assert!(estimate >= actual, "{} < {}", estimate, actual);
+            assert!(estimate <= actual * 2, "{} > 2 * {}", estimate, actual);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn freed_blocks_are_reused() -> io::Result<()> {
+        let mut db = Database::initialize_with_block_size(Cursor::new(vec![]), 1024)?;
+        let mut tree = BTree::init(&mut db)?;
+        for key in 0..400 {
+            tree.insert(key, &[0; 40], &mut db)?;
+        }
+        let allocated = db.num_blocks_allocated();
+        for key in 0..400 {
+            tree.delete(key, &mut db)?;
+        }
+        for key in 0..400 {
+            tree.insert(key, &[1; 40], &mut db)?;
+        }
+        assert!(db.num_blocks_allocated() <= allocated + allocated / 10);
+        for key in 0..400 {
+            assert_eq!(tree.lookup(key, &mut db)?, Some(vec![1; 40]));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn relocating_a_tree_moves_it_to_the_lowest_free_blocks() -> io::Result<()> {
+        const SCRATCH: Key = 1;
+        const EXPENSES: Key = 2;
+        const AMOUNT: Key = 1;
+        let mut db = database();
+        for key in 0..3_000 {
+            db.get_or_create(SCRATCH)?.set_value(key, &[0; 40])?;
+        }
+        for expense_id in 0..300 {
+            db.get_or_create(EXPENSES)?
+                .get_or_create(expense_id)?
+                .set_u64(AMOUNT, expense_id as u64)?;
+        }
+        // emptied leaves are dropped from the scratch table, freeing blocks
+        // at the front of the file
+        for key in (0..3_000).rev() {
+            BTree::from_offset(db.tree_offset(&[SCRATCH])?.unwrap()).delete(key, &mut db)?;
+        }
+
+        let expenses = BTree::from_offset(db.tree_offset(&[EXPENSES])?.unwrap());
+        let before = expenses.pages(&mut db)?;
+        db.relocate_tree_to_front(&[EXPENSES])?;
+        let expenses = BTree::from_offset(db.tree_offset(&[EXPENSES])?.unwrap());
+        let after = expenses.pages(&mut db)?;
+
+        assert_eq!(before.len(), after.len());
+        assert!(after.iter().max() < before.iter().min());
+        let live = db.live_pages()?;
+        assert!(after.iter().all(|page| live.contains(page)));
+        let keys = expenses.keys(&mut db)?.collect::<Result<Vec<Key>>>()?;
+        assert_eq!(keys, (0..300).collect::<Vec<Key>>());
+        let keys_rev = expenses.keys_rev(&mut db)?.collect::<Result<Vec<Key>>>()?;
+        assert_eq!(keys_rev, (0..300).rev().collect::<Vec<Key>>());
+        for expense_id in 0..300 {
+            assert_eq!(
+                db.get_existing(EXPENSES)?
+                    .unwrap()
+                    .get_existing(expense_id)?
+                    .unwrap()
+                    .get_u64(AMOUNT)?,
+                Some(expense_id as u64)
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn relocating_leaves_unlisted_trees_alone() -> io::Result<()> {
+        let mut db = database();
+        let mut detached = BTree::init(&mut db)?;
+        for key in 0..2_000 {
+            detached.insert(key, &[key as u8; 40], &mut db)?;
+        }
+        db.get_or_create(1)?.set_u64(2, 3)?;
+        let free = db.stats()?.free_blocks;
+
+        db.relocate_tree_to_front(&[])?;
+        assert_eq!(db.stats()?.free_blocks, free);
+        for key in 2_000..2_500 {
+            db.get_or_create(key)?.set_u64(2, 3)?;
+        }
+        for key in 0..2_000 {
+            assert_eq!(detached.lookup(key, &mut db)?, Some(vec![key as u8; 40]));
+        }
+        assert_eq!(db.get_existing(1)?.unwrap().get_u64(2)?, Some(3));
+        assert_eq!(detached.verify(&mut db)?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn merge_file_combines_tables() -> io::Result<()> {
+        const USERS: Key = 1;
+        const EXPENSES: Key = 2;
+        const AMOUNT: Key = 1;
+        const NOTE: Key = 2;
+        let mut db = database();
+        db.get_or_create(USERS)?.set_value(1, b"alice")?;
+        db.get_or_create(USERS)?.set_value(2, b"bob")?;
+        db.get_or_create(EXPENSES)?
+            .get_or_create(100)?
+            .set_u64(AMOUNT, 5)?;
+        db.get_or_create(EXPENSES)?
+            .get_or_create(100)?
+            .set_value(NOTE, b"lunch")?;
+
+        let mut other = database();
+        other.get_or_create(USERS)?.set_value(2, b"robert")?;
+        other.get_or_create(USERS)?.set_value(3, b"carol")?;
+        other
+            .get_or_create(EXPENSES)?
+            .get_or_create(100)?
+            .set_u64(AMOUNT, 7)?;
+        other
+            .get_or_create(EXPENSES)?
+            .get_or_create(200)?
+            .set_u64(AMOUNT, 9)?;
+
+        db.merge_file(&mut other)?;
+        assert_eq!(
+            db.get_existing(USERS)?.unwrap().value(1)?,
+            Some(b"alice".to_vec())
+        );
+        assert_eq!(
+            db.get_existing(USERS)?.unwrap().value(2)?,
+            Some(b"robert".to_vec())
+        );
+        assert_eq!(
+            db.get_existing(USERS)?.unwrap().value(3)?,
+            Some(b"carol".to_vec())
+        );
+        assert_eq!(
+            db.get_existing(EXPENSES)?
+                .unwrap()
+                .get_existing(100)?
+                .unwrap()
+                .get_u64(AMOUNT)?,
+            Some(7)
+        );
+        assert_eq!(
+            db.get_existing(EXPENSES)?
+                .unwrap()
+                .get_existing(100)?
+                .unwrap()
+                .value(NOTE)?,
+            Some(b"lunch".to_vec())
+        );
+        assert_eq!(
+            db.get_existing(EXPENSES)?
+                .unwrap()
+                .get_existing(200)?
+                .unwrap()
+                .get_u64(AMOUNT)?,
+            Some(9)
+        );
+        assert_eq!(db.get_existing(EXPENSES)?.unwrap().child_count()?, 2);
+
+        // tables from the catalog, one on both sides and one only in `other`
+        db.create_table("accounts")?
+            .insert(1, b"checking", &mut db)?;
+        let mut accounts = other.create_table("accounts")?;
+        accounts.insert(1, b"savings", &mut other)?;
+        accounts.insert(2, b"brokerage", &mut other)?;
+        other
+            .create_table("budgets")?
+            .insert(1, b"food", &mut other)?;
+        db.merge_file(&mut other)?;
+        let accounts = db.open_table("accounts")?;
+        assert_eq!(
+            accounts.iter(&mut db)?.collect::<Result<Vec<_>>>()?,
+            vec![(1, b"savings".to_vec()), (2, b"brokerage".to_vec())]
+        );
+        let budgets = db.open_table("budgets")?;
+        assert_eq!(budgets.lookup(1, &mut db)?, Some(b"food".to_vec()));
+
+        // a file that holds nothing but catalog tables still gets merged
+        let mut tables_only = database();
+        tables_only
+            .create_table("loans")?
+            .insert(1, b"car", &mut tables_only)?;
+        db.merge_file(&mut tables_only)?;
+        let loans = db.open_table("loans")?;
+        assert_eq!(loans.lookup(1, &mut db)?, Some(b"car".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn content_hash_ignores_layout_but_not_values() -> io::Result<()> {
+        const USERS: Key = 1;
+        const EXPENSES: Key = 2;
+        const AMOUNT: Key = 1;
+        let mut db = database();
+        for user_id in (0..200).rev() {
+            db.get_or_create(USERS)?
+                .set_value(user_id, &[user_id as u8; 30])?;
+        }
+        for expense_id in 0..300 {
+            db.get_or_create(EXPENSES)?
+                .get_or_create(expense_id)?
+                .set_u64(AMOUNT, expense_id as u64)?;
+        }
+        let hash = db.content_hash()?;
+        assert_ne!(hash, database().content_hash()?);
+
+        // a merged copy is built in a different order, into different pages
+        let mut copy = database();
+        copy.get_or_create(EXPENSES)?.get_or_create(0)?;
+        copy.merge_file(&mut db)?;
+        assert_eq!(copy.content_hash()?, hash);
+
+        copy.get_or_create(EXPENSES)?
+            .get_or_create(150)?
+            .set_u64(AMOUNT, 151)?;
+        assert_ne!(copy.content_hash()?, hash);
+
+        // tables in the catalog count too
+        let mut accounts = db.create_table("accounts")?;
+        accounts.insert(3, &[3], &mut db)?;
+        let with_table = db.content_hash()?;
+        assert_ne!(with_table, hash);
+        accounts.insert(3, &[4], &mut db)?;
+        assert_ne!(db.content_hash()?, with_table);
+        Ok(())
+    }
+
+    #[test]
+    fn vacuum_packs_every_tree_into_a_fresh_file() -> io::Result<()> {
+        const USERS: Key = 1;
+        const EXPENSES: Key = 2;
+        const AMOUNT: Key = 1;
+        let mut db = database();
+        for user_id in 0..2_000 {
+            db.get_or_create(USERS)?
+                .set_value(user_id, &[user_id as u8; 60])?;
+        }
+        db.get_or_create(USERS)?.set_value(7, &[7; 20_000])?;
+        for expense_id in 0..300 {
+            db.get_or_create(EXPENSES)?
+                .get_or_create(expense_id)?
+                .set_u64(AMOUNT, expense_id as u64)?;
+        }
+        for user_id in (0..2_000).filter(|user_id| user_id % 4 != 0) {
+            db.get_or_create(USERS)?.delete_value(user_id)?;
+        }
+        let hash = db.content_hash()?;
+        let before = db.stats()?;
+
+        let mut vacuumed = db.vacuum_into(Cursor::new(vec![]))?;
+        assert_eq!(vacuumed.content_hash()?, hash);
+        let after = vacuumed.stats()?;
+        assert_eq!(after.free_blocks, 0);
+        assert!(after.num_blocks_allocated < before.num_blocks_allocated - before.free_blocks);
+        assert_eq!(
+            vacuumed
+                .get_existing(EXPENSES)?
+                .unwrap()
+                .get_existing(299)?
+                .unwrap()
+                .get_u64(AMOUNT)?,
+            Some(299)
+        );
+        assert_eq!(
+            vacuumed.get_existing(USERS)?.unwrap().value(8)?,
+            Some(vec![8; 60])
+        );
+
+        // the copy reopens, and the original is untouched
+        let mut reopened = Database::from_existing(vacuumed.into_disk())?;
+        assert_eq!(reopened.content_hash()?, hash);
+        assert_eq!(db.stats()?, before);
+        assert_eq!(db.content_hash()?, hash);
+        Ok(())
+    }
+
+    #[test]
+    fn vacuum_refuses_to_drop_a_tree_it_cannot_reach() -> io::Result<()> {
+        let mut db = database();
+        db.get_or_create(1)?.set_u64(2, 3)?;
+        let mut detached = BTree::init(&mut db)?;
+        for key in 0..500 {
+            detached.insert(key, &[1; 40], &mut db)?;
+        }
+        let err = db.vacuum_into(Cursor::new(vec![])).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert!(err.to_string().contains("would be lost"));
+
+        // once its pages are freed there's nothing left to lose
+        detached.free_all(&mut db)?;
+        let mut vacuumed = db.vacuum_into(Cursor::new(vec![]))?;
+        assert_eq!(vacuumed.get_existing(1)?.unwrap().get_u64(2)?, Some(3));
+        Ok(())
+    }
+
+    #[test]
+    fn memory_copies_keep_the_contents_they_were_taken_with() -> io::Result<()> {
+        const USERS: Key = 1;
+        let mut db = database();
+        db.set_compression(Some(100));
+        for user_id in 0..500 {
+            db.get_or_create(USERS)?
+                .set_value(user_id, &[user_id as u8; 200])?;
+        }
+        let mut copy = db.copy_into_memory()?;
+        for user_id in 0..250 {
+            db.get_or_create(USERS)?.delete_value(user_id)?;
+        }
+        db.get_or_create(USERS)?.set_value(300, &[0; 10])?;
+        db.get_or_create(USERS)?.set_value(1_000, &[1])?;
+
+        let mut users = copy.get_existing(USERS)?.unwrap();
+        let keys = users.keys()?.collect::<Result<Vec<Key>>>()?;
+        assert_eq!(keys, (0..500).collect::<Vec<Key>>());
+        assert_eq!(
+            copy.get_existing(USERS)?.unwrap().value(300)?,
+            Some(vec![44; 200])
+        );
+        assert_eq!(
+            db.get_existing(USERS)?.unwrap().value(300)?,
+            Some(vec![0; 10])
+        );
+        assert_eq!(db.get_existing(USERS)?.unwrap().value(0)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn copies_into_memory_are_refused_mid_transaction() -> io::Result<()> {
+        const USERS: Key = 1;
+        let mut db = database();
+        db.get_or_create(USERS)?.set_value(1, &[1])?;
+        let mut tx = db.transaction();
+        tx.get_or_create(USERS)?.set_value(2, &[2])?;
+        let err = tx.copy_into_memory().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert!(err.to_string().contains("transaction"));
+        tx.rollback();
+
+        let mut copy = db.copy_into_memory()?;
+        assert_eq!(copy.get_existing(USERS)?.unwrap().value(1)?, Some(vec![1]));
+        assert_eq!(copy.get_existing(USERS)?.unwrap().value(2)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn memory_copy_iteration_ignores_splits_in_the_live_database() -> io::Result<()> {
+        const USERS: Key = 1;
+        let mut db = Database::initialize_with_block_size(Cursor::new(vec![]), 1024)?;
+        for user_id in (0..200).step_by(2) {
+            db.get_or_create(USERS)?.set_value(user_id, &[1; 20])?;
+        }
+        let users = |db: &mut Database<_>| -> io::Result<BTree> {
+            Ok(BTree::from_offset(db.tree_offset(&[USERS])?.unwrap()))
+        };
+        let depth = users(&mut db)?.depth(&mut db)?;
+        let mut copy = db.copy_into_memory()?;
+        let mut copied_users = copy.get_existing(USERS)?.unwrap();
+        let mut keys = copied_users.keys()?;
+        let mut seen = keys.by_ref().take(5).collect::<Result<Vec<Key>>>()?;
+
+        // fill in the gaps between the copy's keys and add more past
+        // them, splitting the leaves it's walking and the root above them
+        for user_id in (1..200).step_by(2).chain(200..2_000) {
+            db.get_or_create(USERS)?.set_value(user_id, &[2; 20])?;
+        }
+        assert!(users(&mut db)?.depth(&mut db)? > depth);
+
+        seen.extend(keys.collect::<Result<Vec<Key>>>()?);
+        assert_eq!(seen, (0..200).step_by(2).collect::<Vec<Key>>());
+        Ok(())
+    }
+
+    #[test]
+    fn dumps_restore_to_the_same_contents() -> io::Result<()> {
+        const USERS: Key = 1;
+        let mut db = database();
+        for user_id in 0..1_000 {
+            db.get_or_create(USERS)?
+                .set_value(user_id, &[user_id as u8; 60])?;
+        }
+        for user_id in (0..1_000).step_by(2) {
+            db.get_or_create(USERS)?.delete_value(user_id)?;
+        }
+        db.create_table("accounts")?.insert(3, &[3], &mut db)?;
+        let hash = db.content_hash()?;
+
+        let mut dump = vec![];
+        db.dump(&mut dump)?;
+        assert!((dump.len() as u64) < db.stats()?.file_size);
+        let mut restored = Database::restore(&mut dump.as_slice(), Cursor::new(vec![]))?;
+        assert_eq!(restored.content_hash()?, hash);
+        assert_eq!(restored.stats()?.free_blocks, 0);
+        let accounts = restored.open_table("accounts")?;
+        assert_eq!(accounts.lookup(3, &mut restored)?, Some(vec![3]));
+
+        // the copy keeps the original's block size
+        let mut small = Database::initialize_with_block_size(Cursor::new(vec![]), 512)?;
+        for user_id in 0..200 {
+            small
+                .get_or_create(USERS)?
+                .set_value(user_id, &[user_id as u8; 60])?;
+        }
+        let mut small_dump = vec![];
+        small.dump(&mut small_dump)?;
+        let mut restored = Database::restore(&mut small_dump.as_slice(), Cursor::new(vec![]))?;
+        assert_eq!(restored.block_size(), 512);
+        assert_eq!(restored.content_hash()?, small.content_hash()?);
+        assert!(restored.verify()?.is_empty());
+
+        let err = Database::restore(&mut &dump[..100], Cursor::new(vec![]))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        dump[8] = DUMP_VERSION + 1;
+        let err = Database::restore(&mut dump.as_slice(), Cursor::new(vec![]))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    #[test]
+    fn dumps_fail_rather_than_leave_a_tree_out() -> io::Result<()> {
+        let mut db = database();
+        db.get_or_create(1)?.set_u64(2, 3)?;
+        let mut detached = BTree::init(&mut db)?;
+        detached.insert(1, &[1; 40], &mut db)?;
+        let mut dump = vec![];
+        assert!(db.dump(&mut dump).is_err());
+        assert!(dump.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn tables_are_found_by_name() -> io::Result<()> {
+        let mut db = database();
+        let mut users = db.create_table("users")?;
+        let mut expenses = db.create_table("expenses")?;
+        assert_eq!(users.name(), "users");
+        for key in 0..1_000 {
+            users.insert(key, &[1; 40], &mut db)?;
+            expenses.insert(key, &[2; 40], &mut db)?;
+        }
+        let err = db.create_table("users").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        let err = db.open_table("budgets").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        db.get_or_create(1)?.set_u64(2, 3)?;
+
+        // tables count as live when blocks are reclaimed
+        db.relocate_tree_to_front(&[])?;
+        let mut db = Database::from_existing(db.into_disk())?;
+        let users = db.open_table("users")?;
+        let expenses = db.open_table("expenses")?;
+        assert_eq!(users.lookup(999, &mut db)?, Some(vec![1; 40]));
+        assert_eq!(expenses.lookup(999, &mut db)?, Some(vec![2; 40]));
+
+        let mut vacuumed = db.vacuum_into(Cursor::new(vec![]))?;
+        let users = vacuumed.open_table("users")?;
+        assert_eq!(users.len(&mut vacuumed)?, 1_000);
+        assert_eq!(users.lookup(500, &mut vacuumed)?, Some(vec![1; 40]));
+        assert_eq!(vacuumed.get_existing(1)?.unwrap().get_u64(2)?, Some(3));
+        Ok(())
+    }
+
+    #[test]
+    fn verify_walks_every_tree_and_the_free_list() -> io::Result<()> {
+        const USERS: Key = 1;
+        let mut db = database();
+        for user_id in 0..1_000 {
+            db.get_or_create(USERS)?
+                .get_or_create(user_id)?
+                .set_u64(2, user_id as u64)?;
+        }
+        let mut table = db.create_table("expenses")?;
+        for key in 0..1_000 {
+            table.insert(key, &[0; 50], &mut db)?;
+        }
+        for key in 0..500 {
+            table.delete(key, &mut db)?;
+        }
+        assert_eq!(db.verify()?, vec![]);
+
+        // a table's leaf put on the free list is still in use
+        let leaf = table.pages(&mut db)?[1];
+        db.free_block(leaf)?;
+        let found = db.verify()?;
+        assert!(found.contains(&Inconsistency {
+            offset: leaf,
+            reason: "is on the free list but in use".to_string(),
+        }));
+        Ok(())
+    }
+
+    #[test]
+    fn allocation_fails_past_max_blocks() -> io::Result<()> {
+        let mut db = database();
+        let mut tree = BTree::init(&mut db)?;
+        db.set_max_blocks(Some(db.num_blocks_allocated() + 2));
+        assert_eq!(db.remaining_blocks(), Some(2));
+
+        let batch: Vec<(Key, usize)> = (0..1_000).map(|key| (key, 100)).collect();
+        assert!(db.estimate_blocks_for(&batch) > db.remaining_blocks().unwrap());
+
+        let err = (0..1_000)
+            .map(|key| tree.insert(key, &[0; 100], &mut db))
+            .find_map(Result::err)
+            .expect("inserts should have run out of space");
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        Ok(())
+    }
+
+    #[test]
+    fn read_only_databases_can_be_read_but_not_written() -> io::Result<()> {
+        const EXPENSES: Key = 0;
+        const AMOUNT: Key = 1;
+        let mut db = Database::initialize(MemoryDisk::new())?;
+        let mut tree = BTree::init(&mut db)?;
+        for key in 0..500 {
+            tree.insert(key, &[key as u8; 30], &mut db)?;
+        }
+        db.get_or_create(EXPENSES)?
+            .get_or_create(100)?
+            .set_u64(AMOUNT, 5)?;
+        let bytes = db.into_disk().into_bytes();
+
+        let mut db = Database::open_read_only(Cursor::new(&bytes[..]))?;
+        assert_eq!(tree.lookup(250, &mut db)?, Some(vec![250; 30]));
+        assert_eq!(tree.keys(&mut db)?.count(), 500);
+        assert_eq!(
+            db.get_existing(EXPENSES)?
+                .unwrap()
+                .get_existing(100)?
+                .unwrap()
+                .get_u64(AMOUNT)?,
+            Some(5)
+        );
+
+        let err = tree.insert(500, &[0; 30], &mut db).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        let err = db
+            .get_or_create(EXPENSES)?
+            .get_or_create(100)?
+            .set_u64(AMOUNT, 9)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        // even looking up a row that doesn't exist yet would create it
+        let err = db
+            .get_or_create(EXPENSES)?
+            .get_or_create(200)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        Ok(())
+    }
+
+    #[test]
+    fn lookup_existing_reads_without_creating_a_root() -> io::Result<()> {
+        let bytes = Database::initialize(MemoryDisk::new())?
+            .into_disk()
+            .into_bytes();
+        let mut db = Database::open_read_only(Cursor::new(&bytes[..]))?;
+        assert!(db.lookup_existing()?.is_none());
+        let err = db.lookup_or_create().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+        let mut db = Database::initialize(MemoryDisk::new())?;
+        db.get_or_create(1)?.set_value(2, &[3])?;
+        let bytes = db.into_disk().into_bytes();
+        let mut db = Database::open_read_only(Cursor::new(&bytes[..]))?;
+        let root = db.lookup_existing()?.unwrap();
+        assert_eq!(root.get_existing(1)?.unwrap().value(2)?, Some(vec![3]));
+        Ok(())
+    }
+
+    #[test]
+    fn values_over_the_limit_are_refused() -> io::Result<()> {
+        let mut db = database();
+        db.set_max_value_len(Some(100));
+        let mut tree = BTree::init(&mut db)?;
+        tree.insert(1, &[1; 100], &mut db)?;
+        let err = tree.insert(2, &[2; 101], &mut db).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let too_large = ValueTooLarge { len: 101, max: 100 };
+        assert!(matches!(err, crate::Error::ValueTooLarge(err) if err == too_large));
+        let err = tree
+            .insert_many(&[(3, &[3][..]), (4, &[4; 200][..])], &mut db)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(tree.keys(&mut db)?.count(), 1);
+
+        db.set_max_value_len(None);
+        tree.insert(2, &[2; 20_000], &mut db)?;
+        assert_eq!(tree.lookup(2, &mut db)?, Some(vec![2; 20_000]));
+        Ok(())
+    }
+
+    #[test]
+    fn a_crash_before_a_new_page_is_written_only_leaks_its_block() -> io::Result<()> {
+        // whatever was on the disk before is left in the new block
+        let mut db = Database::initialize(Cursor::new(vec![0xaa; 1 << 18]))?;
+        let mut tree = db.create_table("rows")?;
+        for key in 0..200 {
+            tree.insert(key, &[key as u8; 40], &mut db)?;
+        }
+        let blocks = db.num_blocks_allocated();
+        // crash after the block is counted, before its page is written
+        db.allocate_block()?;
+        let mut db = Database::from_existing(db.into_disk())?;
+        assert_eq!(db.num_blocks_allocated(), blocks + 1);
+        assert_eq!(tree.verify(&mut db)?, vec![]);
+        assert_eq!(db.verify()?, vec![]);
+        assert_eq!(tree.keys(&mut db)?.count(), 200);
+        let vacuumed = db.vacuum_into(Cursor::new(vec![]))?;
+        assert!(vacuumed.num_blocks_allocated() <= blocks);
+        Ok(())
+    }
+
+    #[test]
+    fn append_only_allocation_never_reuses_blocks() -> io::Result<()> {
+        let mut db = database();
+        db.set_allocator(crate::AppendOnly);
+        let mut tree = BTree::init(&mut db)?;
+        for key in 0..1_000 {
+            tree.insert(key, &[0; 100], &mut db)?;
+        }
+        for key in 0..500 {
+            tree.delete(key, &mut db)?;
+        }
+        let freed = db.stats()?;
+        assert!(freed.free_blocks > 0);
+        for key in 0..500 {
+            tree.insert(key, &[0; 100], &mut db)?;
+        }
+        // the freed blocks are all still on the free list, untouched
+        let after = db.stats()?;
+        assert_eq!(after.free_blocks, freed.free_blocks);
+        assert!(after.num_blocks_allocated > freed.num_blocks_allocated);
+        assert_eq!(db.verify()?, vec![]);
+
+        db.set_allocator(crate::ReuseFreeBlocks);
+        tree.insert(1_000, &[0; 20_000], &mut db)?;
+        assert!(db.stats()?.free_blocks < after.free_blocks);
+        Ok(())
+    }
+
+    #[test]
+    fn stats_count_allocated_and_free_blocks() -> io::Result<()> {
+        let mut db = database();
+        let stats = db.stats()?;
+        assert_eq!((stats.num_blocks_allocated, stats.free_blocks), (1, 0));
+        let mut tree = BTree::init(&mut db)?;
+        for key in 0..1_000 {
+            tree.insert(key, &[0; 100], &mut db)?;
+        }
+        for key in 0..1_000 {
+            tree.delete(key, &mut db)?;
+        }
+        let stats = db.stats()?;
+        assert_eq!(stats.block_size, 8192);
+        assert_eq!(stats.num_blocks_allocated, db.num_blocks_allocated());
+        assert_eq!(
+            stats.file_size,
+            stats.num_blocks_allocated * stats.block_size
+        );
+        // everything but the meta block and the tree's one remaining leaf
+        assert_eq!(stats.free_blocks, stats.num_blocks_allocated - 2);
+        Ok(())
+    }
+
+    #[test]
+    fn writes_stay_within_the_allocated_blocks() -> io::Result<()> {
+        const MAX_BLOCKS: u64 = 6;
+        let disk = MemoryDisk::bounded(MAX_BLOCKS * 8192);
+        let mut db = Database::initialize(disk)?;
+        assert_eq!(db.block_size(), 8192);
+        db.set_max_blocks(Some(MAX_BLOCKS));
+        let mut tree = BTree::init(&mut db)?;
+
+        // a write past the last block would fail with WriteZero first
+        let err = (0..1_000)
+            .map(|key| tree.insert(key, &[0; 100], &mut db))
+            .find_map(Result::err)
+            .expect("inserts should have run out of space");
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        Ok(())
+    }
+
+    /// A disk that counts how often it's asked to sync.
+    #[derive(Default)]
+    struct CountingSyncs {
+        inner: Cursor<Vec<u8>>,
+        syncs: u32,
+    }
+    impl Read for CountingSyncs {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+    impl Write for CountingSyncs {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.inner.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+    impl Seek for CountingSyncs {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+    impl Disk for CountingSyncs {
+        fn sync(&mut self) -> io::Result<()> {
+            self.syncs += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn the_sync_policy_decides_how_often_writes_are_synced() -> io::Result<()> {
+        let syncs_after_ten_inserts = |policy| -> io::Result<u32> {
+            let mut disk = CountingSyncs::default();
+            let mut db = Database::initialize(&mut disk)?;
+            let mut tree = BTree::init(&mut db)?;
+            db.set_sync_policy(policy);
+            for key in 0..10 {
+                tree.insert(key, &[key as u8; 100], &mut db)?;
+            }
+            drop(db);
+            Ok(disk.syncs)
+        };
+        assert_eq!(syncs_after_ten_inserts(SyncPolicy::Never)?, 0);
+        assert_eq!(syncs_after_ten_inserts(SyncPolicy::EveryWrite)?, 10);
+        assert_eq!(syncs_after_ten_inserts(SyncPolicy::EveryN(3))?, 3);
+
+        let mut disk = CountingSyncs::default();
+        let mut db = Database::initialize(&mut disk)?;
+        let mut tree = BTree::init(&mut db)?;
+        db.set_sync_policy(SyncPolicy::EveryWrite);
+        let mut tx = db.transaction();
+        for key in 0..10 {
+            tree.insert(key, b"value", &mut tx)?;
+        }
+        tx.commit()?;
+        drop(db);
+        assert_eq!(disk.syncs, 1);
+        Ok(())
+    }
+
+    /// A disk that only grows through `ensure_len`, like a block device or a
+    /// preallocated file. Writes past its end fail.
+    #[derive(Default)]
+    struct FixedSize(Cursor<Vec<u8>>);
+    impl Read for FixedSize {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+    impl Write for FixedSize {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.0.position() + buf.len() as u64 > self.0.get_ref().len() as u64 {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "write past the end of a fixed-size disk",
+                ));
+            }
+            self.0.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    impl Seek for FixedSize {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.0.seek(pos)
+        }
+    }
+    impl Disk for FixedSize {
+        fn ensure_len(&mut self, len: u64) -> io::Result<()> {
+            let data = self.0.get_mut();
+            if len as usize > data.len() {
+                data.resize(len as usize, 0);
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn fixed_size_disks_are_grown_before_blocks_are_added() -> io::Result<()> {
+        let mut disk = FixedSize(Cursor::new(vec![0; 4096]));
+        let mut db = Database::initialize(&mut disk)?;
+        let mut tree = BTree::init(&mut db)?;
+        for key in 0..500 {
+            tree.insert(key, &[key as u8; 100], &mut db)?;
+        }
+        let mut tx = db.transaction();
+        for key in 500..1_000 {
+            tree.insert(key, &[key as u8; 100], &mut tx)?;
+        }
+        tx.commit()?;
+        let blocks = db.num_blocks_allocated();
+        let block_size = db.block_size();
+        drop(db);
+        assert_eq!(disk.0.get_ref().len() as u64, blocks * block_size);
+
+        let mut db = Database::from_existing(&mut disk)?;
+        assert_eq!(tree.len(&mut db)?, 1_000);
+        assert_eq!(tree.lookup(999, &mut db)?, Some(vec![231; 100]));
+        Ok(())
+    }
+
+    #[test]
+    fn trailing_free_blocks_are_cut_off_the_file() -> io::Result<()> {
+        let mut db = database();
+        let mut kept = BTree::init(&mut db)?;
+        kept.insert(1, b"kept", &mut db)?;
+        let mut tree = BTree::init(&mut db)?;
+        for key in 0..3_000 {
+            tree.insert(key, &[key as u8; 40], &mut db)?;
+        }
+        assert_eq!(db.truncate_trailing_free()?, 0);
+        let blocks = db.num_blocks_allocated();
+        tree.delete_range(0, 3_000, &mut db)?;
+        let free = db.stats()?.free_blocks;
+
+        let dropped = db.truncate_trailing_free()?;
+        assert!(dropped > 0 && dropped <= free);
+        let stats = db.stats()?;
+        assert_eq!(stats.num_blocks_allocated, blocks - dropped);
+        assert_eq!(stats.free_blocks, free - dropped);
+        assert_eq!(
+            stats.file_size,
+            stats.num_blocks_allocated * stats.block_size
+        );
+        assert_eq!(db.verify()?, vec![]);
+
+        for key in 0..3_000 {
+            tree.insert(key, &[key as u8; 40], &mut db)?;
+        }
+        let mut db = Database::from_existing(db.into_disk())?;
+        assert_eq!(tree.len(&mut db)?, 3_000);
+        assert_eq!(tree.verify(&mut db)?, vec![]);
+        assert_eq!(kept.lookup(1, &mut db)?, Some(b"kept".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn checked_opens_fail_fast_on_damage() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        for key in 0..1_000 {
+            db.get_or_create(1)?.set_u64(key, key as u64)?;
+        }
+        let disk = db.into_disk();
+        let mut db = Database::open_checked(disk)?;
+        assert_eq!(db.open_count(), 1);
+        assert_eq!(db.get_existing(1)?.unwrap().get_u64(999)?, Some(999));
+
+        // scribble over a page of the nested tree below its root
+        let tree = db.get_existing(1)?.unwrap().tree();
+        let page = *tree.pages(&mut db)?.last().unwrap();
+        assert_ne!(page, tree.offset());
+        let mut disk = db.into_disk();
+        disk.seek(SeekFrom::Start(page + 30))?;
+        disk.write_all(&[0xff; 8])?;
+        let err = Database::open_checked(disk.clone()).err().unwrap();
+        let message = err.to_string();
+        assert_eq!(message.matches(&page.to_string()).count(), 1, "{}", message);
+        match err {
+            crate::Error::Corrupt { offset, .. } => assert_eq!(offset, page),
+            err => panic!("expected a corrupt page, found {:?}", err),
+        }
+        // the failed open wasn't counted, and a plain open still succeeds
+        assert_eq!(Database::from_existing(disk)?.open_count(), 2);
         Ok(())
     }
 }
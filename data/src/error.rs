@@ -0,0 +1,177 @@
+use crate::ValueTooLarge;
+use std::error;
+use std::fmt;
+use std::io;
+
+/// The failures a caller may want to tell apart, say to answer a request
+/// with the right status. The public operations return it, while a `Disk`
+/// speaks in `io::Error`s; `Error::from` sorts one of those into the case it
+/// stands for, and going back with `io::Error::from` gives an error of the
+/// same kind and message.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// A page failed its checks.
+    Corrupt {
+        /// Where the page starts in the file.
+        offset: u64,
+        /// Which check it failed.
+        reason: String,
+    },
+    /// The file doesn't start with the header of a database this version
+    /// can read.
+    BadHeader(String),
+    /// A value was longer than the database's `max_value_len` allows.
+    ValueTooLarge(ValueTooLarge),
+    /// Anything else, the disk failing included.
+    Io(io::Error),
+}
+
+/// What the public operations return.
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// The kind of the `io::Error` this error converts to.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Error::Corrupt { .. } | Error::BadHeader(_) => io::ErrorKind::InvalidData,
+            Error::ValueTooLarge(_) => io::ErrorKind::InvalidInput,
+            Error::Io(err) => err.kind(),
+        }
+    }
+}
+
+/// The payload of the `io::Error` for a page that failed its checks.
+#[derive(Clone, Debug)]
+pub(crate) struct CorruptPage {
+    pub offset: u64,
+    pub reason: String,
+}
+
+impl fmt::Display for CorruptPage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "corrupt page at offset {}: {}", self.offset, self.reason)
+    }
+}
+
+impl error::Error for CorruptPage {}
+
+/// The payload of the `io::Error` for a header that can't be read.
+#[derive(Clone, Debug)]
+pub(crate) struct BadHeader(pub String);
+
+impl fmt::Display for BadHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl error::Error for BadHeader {}
+
+pub(crate) fn bad_header(reason: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, BadHeader(reason.into()))
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        if let Some(payload) = err.get_ref() {
+            if let Some(page) = payload.downcast_ref::<CorruptPage>() {
+                return Error::Corrupt {
+                    offset: page.offset,
+                    reason: page.reason.clone(),
+                };
+            }
+            if let Some(header) = payload.downcast_ref::<BadHeader>() {
+                return Error::BadHeader(header.0.clone());
+            }
+            if let Some(&too_large) = payload.downcast_ref::<ValueTooLarge>() {
+                return Error::ValueTooLarge(too_large);
+            }
+        }
+        Error::Io(err)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        match err {
+            Error::Corrupt { offset, reason } => {
+                io::Error::new(io::ErrorKind::InvalidData, CorruptPage { offset, reason })
+            }
+            Error::BadHeader(reason) => bad_header(reason),
+            Error::ValueTooLarge(too_large) => too_large.into(),
+            Error::Io(err) => err,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Corrupt { offset, reason } => {
+                write!(f, "corrupt page at offset {}: {}", offset, reason)
+            }
+            Error::BadHeader(reason) => f.write_str(reason),
+            Error::ValueTooLarge(too_large) => too_large.fmt(f),
+            Error::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod error_tests {
+    use super::*;
+    use crate::{BTree, Database};
+    use std::io::{Cursor, Seek, SeekFrom, Write};
+
+    #[test]
+    fn failures_come_back_in_their_cases() -> io::Result<()> {
+        let err = Database::from_existing(Cursor::new(vec![0x42; 64]))
+            .err()
+            .unwrap();
+        assert!(matches!(err, Error::BadHeader(_)));
+
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        tree.insert(1, &[1; 10], &mut db)?;
+        db.set_max_value_len(Some(10));
+        let err = tree.insert(2, &[2; 11], &mut db).unwrap_err();
+        let too_large = ValueTooLarge { len: 11, max: 10 };
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(matches!(err, Error::ValueTooLarge(err) if err == too_large));
+
+        // scribble over the leaf so its checksum no longer matches
+        let mut disk = db.into_disk();
+        disk.seek(SeekFrom::Start(tree.offset() + 30))?;
+        disk.write_all(&[0xff; 8])?;
+        let mut db = Database::from_existing(disk)?;
+        let err = tree.lookup(1, &mut db).unwrap_err();
+        match &err {
+            Error::Corrupt { offset, .. } => assert_eq!(*offset, tree.offset()),
+            err => panic!("expected a corrupt page, found {:?}", err),
+        }
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let message = err.to_string();
+        let err = io::Error::from(err);
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), message);
+
+        // going back to an `io::Error` and out again keeps the case
+        assert!(matches!(Error::from(err), Error::Corrupt { .. }));
+
+        let err = io::Error::new(io::ErrorKind::PermissionDenied, "no");
+        let err = Error::from(err);
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(matches!(err, Error::Io(_)));
+        Ok(())
+    }
+}
@@ -0,0 +1,234 @@
+use crate::{BTree, Database, Disk, Key, Result, TableHandle};
+use std::convert::TryInto;
+use std::io;
+use std::ops::RangeBounds;
+
+/// Makes an index key out of a row's value.
+type Extract = dyn Fn(&[u8]) -> Key + Send + Sync;
+
+/// A secondary index over a table: a table of its own mapping a key taken
+/// from each row's value, like an expense's amount, to the keys of the rows
+/// that have it. Being a table, it's found by name like any other, and
+/// carried over by `Database::vacuum_into` and `dump`. Rows written through
+/// `put` and `remove` keep the index up to date in the same transaction;
+/// rows written to the table some other way are missed. Each index key
+/// holds its rows with `BTree::insert_dup`, in the order they were added.
+pub struct Index {
+    table: TableHandle,
+    extract: Box<Extract>,
+}
+
+impl Index {
+    /// Creates an empty index in a new table called `name`, keyed by what
+    /// `extract` makes of each row's value. Fails with
+    /// `ErrorKind::AlreadyExists` if there already is a table by that name.
+    pub fn create<D: Disk>(
+        db: &mut Database<D>,
+        name: &str,
+        extract: impl Fn(&[u8]) -> Key + Send + Sync + 'static,
+    ) -> Result<Index> {
+        Ok(Index::with_table(db.create_table(name)?, extract))
+    }
+
+    /// Opens the index made with `create` in the table called `name`.
+    /// `extract` has to be the same as it was made with, or rows already
+    /// indexed won't be found again when they change.
+    pub fn open<D: Disk>(
+        db: &mut Database<D>,
+        name: &str,
+        extract: impl Fn(&[u8]) -> Key + Send + Sync + 'static,
+    ) -> Result<Index> {
+        Ok(Index::with_table(db.open_table(name)?, extract))
+    }
+
+    fn with_table(
+        table: TableHandle,
+        extract: impl Fn(&[u8]) -> Key + Send + Sync + 'static,
+    ) -> Index {
+        Index {
+            table,
+            extract: Box::new(extract),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.table.name()
+    }
+
+    pub fn tree(&self) -> &BTree {
+        &self.table
+    }
+
+    /// Stores `value` under `key` in `table`, moving the row's index entry
+    /// if it was there before under another index key.
+    pub fn put<D: Disk>(
+        &mut self,
+        table: &mut BTree,
+        key: Key,
+        value: &[u8],
+        db: &mut Database<D>,
+    ) -> Result<()> {
+        db.atomically(|db| {
+            if let Some(old) = table.lookup(key, db)? {
+                self.table
+                    .delete_dup((self.extract)(&old), &key.to_be_bytes(), db)?;
+            }
+            table.insert(key, value, db)?;
+            self.table
+                .insert_dup((self.extract)(value), &key.to_be_bytes(), db)?;
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    /// Removes `key` from `table` and the index, returning its value.
+    pub fn remove<D: Disk>(
+        &mut self,
+        table: &mut BTree,
+        key: Key,
+        db: &mut Database<D>,
+    ) -> Result<Option<Vec<u8>>> {
+        Ok(db.atomically(|db| {
+            let old = table.delete(key, db)?;
+            if let Some(old) = &old {
+                self.table
+                    .delete_dup((self.extract)(old), &key.to_be_bytes(), db)?;
+            }
+            Ok(old)
+        })?)
+    }
+
+    /// Indexes every row already in `table`, for an index made after the
+    /// table had rows. The index should be empty to begin with.
+    pub fn fill<D: Disk>(&mut self, table: &BTree, db: &mut Database<D>) -> Result<()> {
+        let rows = table.iter(db)?.collect::<Result<Vec<_>>>()?;
+        Ok(db.atomically(|db| {
+            for (key, value) in rows {
+                self.table
+                    .insert_dup((self.extract)(&value), &key.to_be_bytes(), db)?;
+            }
+            Ok(())
+        })?)
+    }
+
+    /// The keys of the rows whose index key is `index_key`.
+    pub fn get<D: Disk>(&self, index_key: Key, db: &mut Database<D>) -> Result<Vec<Key>> {
+        self.table
+            .get_all(index_key, db)?
+            .iter()
+            .map(|row| Ok(decode_row(row)?))
+            .collect()
+    }
+
+    /// The rows whose index keys fall in `range`, as pairs of index key and
+    /// row key, in index key order.
+    pub fn range<D: Disk>(
+        &self,
+        range: impl RangeBounds<Key>,
+        db: &mut Database<D>,
+    ) -> Result<Vec<(Key, Key)>> {
+        let mut scan = self.table.scan(range);
+        let mut found = vec![];
+        while let Some(key) = scan.next_key(db) {
+            let index_key = key?;
+            for row in self.get(index_key, db)? {
+                found.push((index_key, row));
+            }
+        }
+        Ok(found)
+    }
+}
+
+fn decode_row(row: &[u8]) -> io::Result<Key> {
+    let bytes = row.try_into().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "expected a 16 byte row key in the index, found {} bytes",
+                row.len()
+            ),
+        )
+    })?;
+    Ok(Key::from_be_bytes(bytes))
+}
+
+#[cfg(test)]
+mod index_tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// An expense row: its amount in cents, then its name.
+    fn expense(cents: u64, name: &str) -> Vec<u8> {
+        let mut row = cents.to_be_bytes().to_vec();
+        row.extend_from_slice(name.as_bytes());
+        row
+    }
+
+    fn by_amount(row: &[u8]) -> Key {
+        u64::from_be_bytes(row[..8].try_into().unwrap()) as Key
+    }
+
+    #[test]
+    fn the_index_follows_puts_and_removes() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut expenses = db.create_table("expenses")?;
+        let mut amounts = Index::create(&mut db, "expenses_by_amount", by_amount)?;
+        amounts.put(&mut expenses, 1, &expense(500, "lunch"), &mut db)?;
+        amounts.put(&mut expenses, 2, &expense(120_000, "rent"), &mut db)?;
+        amounts.put(&mut expenses, 3, &expense(500, "taxi"), &mut db)?;
+        amounts.put(&mut expenses, 4, &expense(250, "coffee"), &mut db)?;
+
+        assert_eq!(amounts.get(500, &mut db)?, vec![1, 3]);
+        assert_eq!(
+            amounts.range(500.., &mut db)?,
+            vec![(500, 1), (500, 3), (120_000, 2)]
+        );
+
+        // changing a row's amount moves it in the index
+        amounts.put(&mut expenses, 1, &expense(900, "lunch"), &mut db)?;
+        assert_eq!(amounts.get(500, &mut db)?, vec![3]);
+        assert_eq!(
+            amounts.range(..1_000, &mut db)?,
+            vec![(250, 4), (500, 3), (900, 1)]
+        );
+
+        assert_eq!(
+            amounts.remove(&mut expenses, 3, &mut db)?,
+            Some(expense(500, "taxi"))
+        );
+        assert_eq!(amounts.remove(&mut expenses, 3, &mut db)?, None);
+        assert_eq!(amounts.get(500, &mut db)?, Vec::<Key>::new());
+        assert_eq!(expenses.len(&mut db)?, 3);
+
+        // an index made afterwards from the same rows comes out the same
+        let mut filled = Index::create(&mut db, "filled_by_amount", by_amount)?;
+        filled.fill(&expenses, &mut db)?;
+        assert_eq!(filled.range(.., &mut db)?, amounts.range(.., &mut db)?);
+        Ok(())
+    }
+
+    #[test]
+    fn an_index_is_a_table_that_outlives_vacuums() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut expenses = db.create_table("expenses")?;
+        let mut amounts = Index::create(&mut db, "expenses_by_amount", by_amount)?;
+        for id in 0..300 {
+            amounts.put(&mut expenses, id, &expense(id as u64 % 7, "item"), &mut db)?;
+        }
+        let err = Index::create(&mut db, "expenses_by_amount", by_amount)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        db.get_or_create(1)?.set_u64(2, 3)?;
+        db.relocate_tree_to_front(&[])?;
+
+        let mut vacuumed = db.vacuum_into(Cursor::new(vec![]))?;
+        let amounts = Index::open(&mut vacuumed, "expenses_by_amount", by_amount)?;
+        assert_eq!(amounts.name(), "expenses_by_amount");
+        assert_eq!(
+            amounts.get(3, &mut vacuumed)?,
+            (0..300).filter(|id| id % 7 == 3).collect::<Vec<Key>>()
+        );
+        Ok(())
+    }
+}
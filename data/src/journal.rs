@@ -0,0 +1,370 @@
+use crate::cache::PageCache;
+use crate::page::crc32;
+use crate::Disk;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::cmp::min;
+use std::collections::BTreeMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// The disk as the database sees it. Outside of a transaction, reads and
+/// writes go straight through. Writes made during one are held back as
+/// shadow copies of the blocks they touch, which reads are served from,
+/// until it commits. If there's a write-ahead log, they're written to the log
+/// and made durable there before they're applied.
+///
+/// Blocks read from the disk are kept in a `PageCache`, and dropped from it
+/// again when they're written to.
+///
+/// The log holds at most one transaction, written from its start: a record
+/// per block (`PAGE_RECORD`, offset, length, image), then `COMMIT_RECORD`
+/// and a CRC32 of everything before it. Once the transaction is applied, the
+/// first byte is overwritten with `EMPTY_LOG`.
+pub(crate) struct Journal<D> {
+    inner: D,
+    wal: Option<Box<dyn Disk + Send>>,
+    block_size: u64,
+    pos: u64,
+    /// Where `inner` is known to be positioned, to save seeking it before
+    /// every read and write.
+    inner_pos: Option<u64>,
+    depth: usize,
+    /// Set when a nested transaction is aborted, so the outer one can't
+    /// commit its half-done writes.
+    poisoned: bool,
+    pending: BTreeMap<u64, Vec<u8>>,
+    cache: PageCache,
+}
+
+impl<D: Disk> Journal<D> {
+    const EMPTY_LOG: u8 = 0;
+    const PAGE_RECORD: u8 = 1;
+    const COMMIT_RECORD: u8 = 2;
+    const DEFAULT_CACHE_CAPACITY: usize = 64;
+
+    pub fn new(inner: D, block_size: u64) -> Journal<D> {
+        Journal {
+            inner,
+            wal: None,
+            block_size,
+            pos: 0,
+            inner_pos: None,
+            depth: 0,
+            poisoned: false,
+            pending: BTreeMap::new(),
+            cache: PageCache::new(Journal::<D>::DEFAULT_CACHE_CAPACITY),
+        }
+    }
+
+    /// Starts logging transactions to `wal`, discarding anything in it.
+    pub fn set_wal(&mut self, mut wal: Box<dyn Disk + Send>) -> io::Result<()> {
+        Journal::<D>::mark_applied(&mut wal)?;
+        self.wal = Some(wal);
+        Ok(())
+    }
+
+    /// Applies the transaction `wal` holds to `inner`, if it was committed
+    /// but perhaps not applied before a crash.
+    pub fn recover(inner: &mut D, wal: &mut Box<dyn Disk + Send>) -> io::Result<()> {
+        if let Some(pages) = Journal::<D>::read_log(wal)? {
+            log::debug!("WAL_REPLAY [pages={}]", pages.len());
+            Journal::apply_pages(inner, Some(wal), pages)?;
+        }
+        Ok(())
+    }
+
+    pub fn set_cache_capacity(&mut self, blocks: usize) {
+        self.cache.set_capacity(blocks);
+    }
+
+    pub fn into_parts(self) -> (D, Option<Box<dyn Disk + Send>>) {
+        (self.inner, self.wal)
+    }
+
+    /// Starts a transaction, or joins the one already running.
+    pub fn begin(&mut self) {
+        self.depth += 1;
+    }
+
+    /// Like `begin`, but only if there's a reason to hold writes back: a log
+    /// to make them crash safe, or a transaction already running. Returns
+    /// whether it did.
+    pub fn begin_atomic(&mut self) -> bool {
+        if self.wal.is_none() && self.depth == 0 {
+            return false;
+        }
+        self.begin();
+        true
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.depth > 0
+    }
+
+    /// Ends the innermost transaction, committing everything written since
+    /// the outermost one began once that one ends too.
+    pub fn commit(&mut self) -> io::Result<()> {
+        self.depth -= 1;
+        if self.depth > 0 {
+            return Ok(());
+        }
+        if std::mem::take(&mut self.poisoned) {
+            self.pending.clear();
+            return Err(io::Error::other(
+                "transaction rolled back after a write in it failed",
+            ));
+        }
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        if self.wal.is_some() {
+            if let Err(err) = self.write_log() {
+                self.pending.clear();
+                return Err(err);
+            }
+        }
+        let pages = std::mem::take(&mut self.pending);
+        self.inner_pos = None;
+        for &offset in pages.keys() {
+            self.cache.invalidate(offset);
+        }
+        Journal::apply_pages(&mut self.inner, self.wal.as_mut(), pages)
+    }
+
+    /// Ends the innermost transaction without committing it. Once the
+    /// outermost one ends, everything written in it is thrown away.
+    pub fn abort(&mut self) {
+        self.depth -= 1;
+        if self.depth > 0 {
+            self.poisoned = true;
+            return;
+        }
+        self.poisoned = false;
+        self.pending.clear();
+    }
+
+    pub(crate) fn write_log(&mut self) -> io::Result<()> {
+        let mut log = vec![];
+        for (&offset, image) in &self.pending {
+            log.write_u8(Journal::<D>::PAGE_RECORD)?;
+            log.write_u64::<BigEndian>(offset)?;
+            log.write_u64::<BigEndian>(image.len() as u64)?;
+            log.extend_from_slice(image);
+        }
+        let checksum = crc32(&log);
+        log.write_u8(Journal::<D>::COMMIT_RECORD)?;
+        log.write_u32::<BigEndian>(checksum)?;
+        let wal = self.wal.as_mut().unwrap();
+        wal.seek(SeekFrom::Start(0))?;
+        wal.write_all(&log)?;
+        wal.sync()
+    }
+
+    /// Writes committed pages to the disk proper, then marks the log as
+    /// applied.
+    fn apply_pages(
+        inner: &mut D,
+        wal: Option<&mut Box<dyn Disk + Send>>,
+        pages: BTreeMap<u64, Vec<u8>>,
+    ) -> io::Result<()> {
+        if let Some((&offset, image)) = pages.iter().next_back() {
+            inner.ensure_len(offset + image.len() as u64)?;
+        }
+        for (offset, image) in pages {
+            inner.seek(SeekFrom::Start(offset))?;
+            inner.write_all(&image)?;
+        }
+        match wal {
+            Some(wal) => {
+                inner.sync()?;
+                Journal::<D>::mark_applied(wal)
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn mark_applied(wal: &mut Box<dyn Disk + Send>) -> io::Result<()> {
+        wal.seek(SeekFrom::Start(0))?;
+        wal.write_u8(Journal::<D>::EMPTY_LOG)?;
+        wal.sync()
+    }
+
+    /// The pages of the transaction in `wal`, if it holds a complete one.
+    /// Anything cut short or failing its checksum was never committed.
+    fn read_log(wal: &mut Box<dyn Disk + Send>) -> io::Result<Option<BTreeMap<u64, Vec<u8>>>> {
+        let mut log = vec![];
+        wal.seek(SeekFrom::Start(0))?;
+        wal.read_to_end(&mut log)?;
+        let mut pages = BTreeMap::new();
+        let mut rest = &log[..];
+        loop {
+            let record_start = log.len() - rest.len();
+            match rest.read_u8() {
+                Ok(Journal::<D>::PAGE_RECORD) => {}
+                Ok(Journal::<D>::COMMIT_RECORD) => {
+                    let committed = rest
+                        .read_u32::<BigEndian>()
+                        .is_ok_and(|checksum| checksum == crc32(&log[..record_start]));
+                    return Ok(if committed { Some(pages) } else { None });
+                }
+                _ => return Ok(None),
+            }
+            let header = (rest.read_u64::<BigEndian>(), rest.read_u64::<BigEndian>());
+            let (offset, len) = match header {
+                (Ok(offset), Ok(len)) if len <= rest.len() as u64 => (offset, len as usize),
+                _ => return Ok(None),
+            };
+            pages.insert(offset, rest[..len].to_vec());
+            rest = &rest[len..];
+        }
+    }
+
+    fn block_start(&self, pos: u64) -> u64 {
+        pos - pos % self.block_size
+    }
+
+    fn seek_inner(&mut self) -> io::Result<()> {
+        if self.inner_pos != Some(self.pos) {
+            self.inner.seek(SeekFrom::Start(self.pos))?;
+        }
+        Ok(())
+    }
+
+    /// The block starting at `block` as it is on the disk, which may be
+    /// short if it's the last one.
+    fn cached_block(&mut self, block: u64) -> io::Result<&Vec<u8>> {
+        if self.cache.get(block).is_none() {
+            let mut image = Vec::with_capacity(self.block_size as usize);
+            self.inner.seek(SeekFrom::Start(block))?;
+            (&mut self.inner)
+                .take(self.block_size)
+                .read_to_end(&mut image)?;
+            self.inner_pos = None;
+            self.cache.insert(block, image);
+        }
+        Ok(self.cache.get(block).unwrap())
+    }
+
+    /// The pending image of the block starting at `block`, read in from the
+    /// disk the first time it's written to.
+    fn pending_block(&mut self, block: u64) -> io::Result<&mut Vec<u8>> {
+        if !self.pending.contains_key(&block) {
+            let mut image = Vec::with_capacity(self.block_size as usize);
+            self.inner.seek(SeekFrom::Start(block))?;
+            (&mut self.inner)
+                .take(self.block_size)
+                .read_to_end(&mut image)?;
+            self.inner_pos = None;
+            image.resize(self.block_size as usize, 0);
+            self.pending.insert(block, image);
+        }
+        Ok(self.pending.get_mut(&block).unwrap())
+    }
+}
+
+impl<D: Disk> Read for Journal<D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() && !self.cache.is_enabled() {
+            self.seek_inner()?;
+            let read = self.inner.read(buf)?;
+            self.pos += read as u64;
+            self.inner_pos = Some(self.pos);
+            return Ok(read);
+        }
+        let block = self.block_start(self.pos);
+        let within = (self.pos - block) as usize;
+        let len = min(buf.len(), self.block_size as usize - within);
+        let read = match self.pending.get(&block) {
+            Some(image) => {
+                buf[..len].copy_from_slice(&image[within..within + len]);
+                len
+            }
+            None if self.cache.is_enabled() => {
+                let image = self.cached_block(block)?;
+                let len = min(len, image.len().saturating_sub(within));
+                buf[..len].copy_from_slice(&image[within..within + len]);
+                len
+            }
+            None => {
+                self.seek_inner()?;
+                let read = self.inner.read(&mut buf[..len])?;
+                self.inner_pos = Some(self.pos + read as u64);
+                read
+            }
+        };
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl<D: Disk> Write for Journal<D> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.depth == 0 {
+            self.seek_inner()?;
+            let written = self.inner.write(buf)?;
+            if written > 0 {
+                let last = self.pos + written as u64 - 1;
+                let mut block = self.block_start(self.pos);
+                while block <= last {
+                    self.cache.invalidate(block);
+                    block += self.block_size;
+                }
+            }
+            self.pos += written as u64;
+            self.inner_pos = Some(self.pos);
+            return Ok(written);
+        }
+        let block = self.block_start(self.pos);
+        let within = (self.pos - block) as usize;
+        let len = min(buf.len(), self.block_size as usize - within);
+        self.pending_block(block)?[within..within + len].copy_from_slice(&buf[..len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<D: Disk> Seek for Journal<D> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(pos) => pos,
+            SeekFrom::Current(delta) => (self.pos as i64 + delta) as u64,
+            SeekFrom::End(delta) => {
+                let pending_end = self
+                    .pending
+                    .keys()
+                    .next_back()
+                    .map_or(0, |block| block + self.block_size);
+                let inner_end = self.inner.seek(SeekFrom::End(0))?;
+                self.inner_pos = Some(inner_end);
+                let end = std::cmp::max(inner_end, pending_end);
+                (end as i64 + delta) as u64
+            }
+        };
+        Ok(self.pos)
+    }
+}
+
+impl<D: Disk> Disk for Journal<D> {
+    fn sync(&mut self) -> io::Result<()> {
+        self.inner.sync()
+    }
+    /// During a transaction the blocks it writes are held back, and the
+    /// disk is grown to fit them when they're applied.
+    fn ensure_len(&mut self, len: u64) -> io::Result<()> {
+        if self.depth > 0 {
+            return Ok(());
+        }
+        self.inner_pos = None;
+        self.inner.ensure_len(len)
+    }
+    /// Blocks a running transaction may still write to are kept.
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        if self.depth > 0 {
+            return Ok(());
+        }
+        self.inner_pos = None;
+        self.inner.truncate(len)
+    }
+}
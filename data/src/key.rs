@@ -0,0 +1,42 @@
+use crate::Key;
+
+/// Encodes `n` so that keys compare the way the integers do. Flipping the
+/// sign bit moves the negatives below the positives, which are otherwise
+/// sorted after them as large unsigned numbers. The key always fits in the
+/// low 64 bits.
+pub fn encode_i64(n: i64) -> Key {
+    ((n as u64) ^ (1 << 63)) as Key
+}
+
+/// Undoes `encode_i64`. Bits above the low 64 are ignored.
+pub fn decode_i64(key: Key) -> i64 {
+    ((key as u64) ^ (1 << 63)) as i64
+}
+
+#[cfg(test)]
+mod key_tests {
+    use super::*;
+    use crate::{BTree, Database, Result};
+    use std::io::{self, Cursor};
+
+    #[test]
+    fn i64_keys_keep_their_order() -> io::Result<()> {
+        let numbers = [3, -1, i64::MAX, 0, -5, i64::MIN, 42, -42];
+        for &n in numbers.iter() {
+            assert_eq!(decode_i64(encode_i64(n)), n);
+        }
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        for &n in numbers.iter() {
+            tree.insert(encode_i64(n), &n.to_be_bytes(), &mut db)?;
+        }
+        let found = tree
+            .keys(&mut db)?
+            .map(|key| key.map(decode_i64))
+            .collect::<Result<Vec<_>>>()?;
+        let mut sorted = numbers.to_vec();
+        sorted.sort_unstable();
+        assert_eq!(found, sorted);
+        Ok(())
+    }
+}
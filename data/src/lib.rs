@@ -1,10 +1,50 @@
+mod allocator;
+mod cache;
+#[cfg(feature = "serde")]
+mod codec;
+mod compression;
 mod database;
+mod error;
+mod index;
+mod journal;
+mod key;
+mod memory_disk;
+#[cfg(feature = "mmap")]
+mod mmap_disk;
 mod page;
+mod shared;
+#[cfg(feature = "serde")]
+mod table;
 mod tree;
 
 pub type Key = u128;
 
+pub use allocator::{Allocator, AppendOnly, Blocks, ReuseFreeBlocks};
+#[cfg(feature = "serde")]
+pub use codec::{Codec, Json};
+pub use compression::{Compressor, Deflate};
 use database::BlockAllocator;
 pub use database::Database;
+pub use database::DatabaseStats;
 pub use database::Disk;
-pub use page::BTree;
+pub use database::Endianness;
+pub use database::MemoryCopy;
+pub use database::ReadOnly;
+pub use database::SplitPolicy;
+pub use database::SyncPolicy;
+pub use database::TableHandle;
+pub use database::Transaction;
+pub use error::{Error, Result};
+pub use index::Index;
+pub use key::{decode_i64, encode_i64};
+pub use memory_disk::MemoryDisk;
+#[cfg(feature = "mmap")]
+pub use mmap_disk::MmapDisk;
+pub use page::{
+    BTree, Inconsistency, InsertOutcome, Iter, Keys, LeafEntryInfo, PageInfo, PageLayout,
+    PrefixScan, Scan, TreeCursor, TreeStats, ValueSource, ValueTooLarge,
+};
+pub use shared::SharedDatabase;
+#[cfg(feature = "serde")]
+pub use table::Table;
+pub use tree::EntryKind;
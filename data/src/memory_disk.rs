@@ -0,0 +1,101 @@
+use crate::Disk;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+
+/// A disk held in memory. Unlike a bare `Cursor<Vec<u8>>`, it can be given
+/// a capacity, past which writes fail with `ErrorKind::WriteZero` instead of
+/// growing the buffer, so a write that lands further out than intended
+/// shows up as an error.
+#[derive(Debug, Default)]
+pub struct MemoryDisk {
+    bytes: Cursor<Vec<u8>>,
+    capacity: Option<u64>,
+}
+
+impl MemoryDisk {
+    /// An empty disk that grows as far as it's written.
+    pub fn new() -> MemoryDisk {
+        MemoryDisk::default()
+    }
+    /// An empty disk that holds at most `capacity` bytes.
+    pub fn bounded(capacity: u64) -> MemoryDisk {
+        MemoryDisk {
+            capacity: Some(capacity),
+            ..MemoryDisk::default()
+        }
+    }
+    pub fn capacity(&self) -> Option<u64> {
+        self.capacity
+    }
+    /// Everything written so far.
+    pub fn as_bytes(&self) -> &[u8] {
+        self.bytes.get_ref()
+    }
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes.into_inner()
+    }
+}
+
+impl Read for MemoryDisk {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.bytes.read(buf)
+    }
+}
+
+impl Write for MemoryDisk {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(capacity) = self.capacity {
+            let end = self.bytes.position() + buf.len() as u64;
+            if end > capacity {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    format!(
+                        "write of {} bytes at {} goes past the disk's capacity of {}",
+                        buf.len(),
+                        self.bytes.position(),
+                        capacity
+                    ),
+                ));
+            }
+        }
+        self.bytes.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryDisk {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.bytes.seek(pos)
+    }
+}
+
+impl Disk for MemoryDisk {
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.bytes.get_mut().truncate(len as usize);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod memory_disk_tests {
+    use super::*;
+
+    #[test]
+    fn writes_past_the_capacity_fail() -> io::Result<()> {
+        let mut disk = MemoryDisk::bounded(8);
+        disk.write_all(&[1, 2, 3, 4, 5, 6])?;
+        let err = disk.write_all(&[7, 8, 9]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+        // nothing of the failed write made it in
+        assert_eq!(disk.as_bytes(), &[1, 2, 3, 4, 5, 6]);
+
+        disk.seek(SeekFrom::Start(4))?;
+        disk.write_all(&[0; 4])?;
+        let mut buf = vec![];
+        disk.seek(SeekFrom::Start(0))?;
+        disk.read_to_end(&mut buf)?;
+        assert_eq!(buf, vec![1, 2, 3, 4, 0, 0, 0, 0]);
+        Ok(())
+    }
+}
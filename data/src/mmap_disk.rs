@@ -0,0 +1,160 @@
+use crate::Disk;
+use memmap2::MmapMut;
+use std::cmp::min;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// A disk over a memory-mapped file, for large databases that are mostly
+/// read. Reads copy straight out of the mapping instead of making a system
+/// call each, and writes within the file go through the mapping as well. A
+/// write past the end grows the file and maps it again, which is slow, so
+/// a database that grows a lot is better off on a plain `File`.
+///
+/// Nothing else may change or truncate the file while it's mapped, this
+/// process included.
+pub struct MmapDisk {
+    file: File,
+    map: Option<MmapMut>,
+    pos: u64,
+}
+
+impl MmapDisk {
+    /// Maps `file`, which has to be open for reading and writing.
+    pub fn new(file: File) -> io::Result<MmapDisk> {
+        let mut disk = MmapDisk {
+            file,
+            map: None,
+            pos: 0,
+        };
+        disk.remap()?;
+        Ok(disk)
+    }
+    pub fn into_file(self) -> File {
+        self.file
+    }
+    fn len(&self) -> u64 {
+        self.map.as_ref().map_or(0, |map| map.len() as u64)
+    }
+    fn remap(&mut self) -> io::Result<()> {
+        self.map = None;
+        if self.file.metadata()?.len() > 0 {
+            // Safety: the mapping is only reached through this disk, and the
+            // caller promises nothing else changes the file underneath it.
+            self.map = Some(unsafe { MmapMut::map_mut(&self.file)? });
+        }
+        Ok(())
+    }
+}
+
+impl Read for MmapDisk {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let map = match &self.map {
+            Some(map) if self.pos < map.len() as u64 => map,
+            _ => return Ok(0),
+        };
+        let start = self.pos as usize;
+        let len = min(buf.len(), map.len() - start);
+        buf[..len].copy_from_slice(&map[start..start + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for MmapDisk {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let end = self.pos + buf.len() as u64;
+        if end > self.len() {
+            self.file.set_len(end)?;
+            self.remap()?;
+        }
+        let start = self.pos as usize;
+        if let Some(map) = &mut self.map {
+            map[start..start + buf.len()].copy_from_slice(buf);
+        }
+        self.pos = end;
+        Ok(buf.len())
+    }
+    /// Writes through a shared mapping are seen by anyone reading the file
+    /// straight away, so there's nothing to push short of `sync`.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MmapDisk {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => self.len().checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+impl Disk for MmapDisk {
+    fn sync(&mut self) -> io::Result<()> {
+        if let Some(map) = &self.map {
+            map.flush()?;
+        }
+        self.file.sync_all()
+    }
+    fn ensure_len(&mut self, len: u64) -> io::Result<()> {
+        if len > self.len() {
+            self.file.set_len(len)?;
+            self.remap()?;
+        }
+        Ok(())
+    }
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)?;
+        self.remap()
+    }
+}
+
+#[cfg(test)]
+mod mmap_disk_tests {
+    use super::*;
+    use crate::{BTree, Database};
+    use std::fs::OpenOptions;
+
+    #[test]
+    fn databases_reopen_through_a_mapping() -> io::Result<()> {
+        let path = std::env::temp_dir().join(format!("tree-data-mmap-{}", std::process::id()));
+        let open = || {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)
+        };
+        let offset = {
+            let mut db = Database::initialize(MmapDisk::new(open()?)?)?;
+            let mut tree = BTree::init(&mut db)?;
+            for key in 0..2_000 {
+                tree.insert(key, &[key as u8; 60], &mut db)?;
+            }
+            tree.insert(5_000, &[9; 30_000], &mut db)?;
+            db.flush()?;
+            tree.offset()
+        };
+        let len = std::fs::metadata(&path)?.len();
+        let mut db = Database::from_existing(MmapDisk::new(open()?)?)?;
+        let tree = BTree::from_offset(offset);
+        assert_eq!(tree.lookup(1_234, &mut db)?, Some(vec![1_234u16 as u8; 60]));
+        assert_eq!(tree.lookup(5_000, &mut db)?, Some(vec![9; 30_000]));
+        assert_eq!(db.disk.seek(SeekFrom::End(0))?, len);
+        drop(db);
+        std::fs::remove_file(&path)
+    }
+}
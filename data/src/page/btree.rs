@@ -1,10 +1,50 @@
-use super::{InternalPage, Key, LeafPage, Page, PageOffset};
-use crate::{Database, Disk};
+use super::bucket::{self, Bucket};
+use super::dups;
+use super::{InternalPage, Key, LeafPage, LeafPageEntry, OverflowRef, Page, PageOffset};
+use crate::{BlockAllocator, Database, Disk, Error, Result};
 
-use std::io;
+use byteorder::{BigEndian, ReadBytesExt};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::{Bound, RangeBounds};
+
+/// Fetches the real bytes of values stored as references with
+/// `BTree::insert_reference`, e.g. from an object store.
+pub trait ValueSource {
+    fn fetch(&self, reference: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+/// The shape of a tree, from `BTree::stats`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TreeStats {
+    /// Levels in the tree, counting the leaves.
+    pub height: usize,
+    pub leaf_count: u64,
+    pub entry_count: u64,
+    /// The share of each leaf's block taken up by its header and values,
+    /// averaged over the leaves, from 0 to 1.
+    pub average_leaf_fill: f64,
+}
+
+/// What an insert did to the shape of the tree, from
+/// `BTree::insert_with_outcome`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InsertOutcome {
+    /// Pages split to make room, the root included.
+    pub split_count: u32,
+    /// Blocks taken for new pages, whether from the free list or the end of
+    /// the file. Overflow pages count.
+    pub allocated_blocks: u32,
+}
 
 pub struct BTree {
     root: PageOffset,
+    caches_rightmost: bool,
+    /// The last leaf of the tree as this handle last saw it, when
+    /// `cache_rightmost_leaf` has turned the cache on, with the database's
+    /// free generation at the time.
+    rightmost: Option<(PageOffset, u64)>,
 }
 
 impl BTree {
@@ -12,51 +52,460 @@ impl BTree {
         self.root
     }
     pub fn from_offset(offset: PageOffset) -> BTree {
-        Self { root: offset }
+        Self {
+            root: offset,
+            caches_rightmost: false,
+            rightmost: None,
+        }
+    }
+    pub fn init<D: Disk>(disk: &mut Database<D>) -> Result<BTree> {
+        let root = disk.atomically(LeafPage::init)?;
+        Ok(BTree::from_offset(root.offset()))
     }
-    pub fn init<D: Disk>(disk: &mut Database<D>) -> io::Result<BTree> {
-        let root = LeafPage::init(disk)?;
-        Ok(BTree {
-            root: root.offset(),
+
+    /// Turns on remembering the tree's last leaf, so that inserting a key
+    /// above every key in the tree goes straight there instead of
+    /// descending from the root, as long as the leaf has room. Worth it when
+    /// keys mostly arrive in increasing order, like timestamps or counters.
+    /// The cache is dropped whenever a block of the file is freed, by this
+    /// handle or any other, so it never leads into a block that has since
+    /// been given to another tree.
+    pub fn cache_rightmost_leaf(&mut self, enabled: bool) {
+        self.caches_rightmost = enabled;
+        self.rightmost = None;
+    }
+
+    /// Builds a tree out of `entries`, which must be sorted by key with no key
+    /// repeated. The leaves are filled left to right and the internal levels
+    /// built on top of them, so each page is written once instead of being
+    /// split into shape the way `insert` would. Out-of-order keys fail with
+    /// `ErrorKind::InvalidInput`.
+    pub fn bulk_load<D: Disk>(
+        db: &mut Database<D>,
+        entries: impl Iterator<Item = (Key, Vec<u8>)>,
+    ) -> Result<BTree> {
+        Ok(BTree::bulk_load_flagged(
+            db,
+            entries.map(|(key, value)| (key, value, 0)),
+        )?)
+    }
+    pub(crate) fn bulk_load_flagged<D: Disk>(
+        db: &mut Database<D>,
+        entries: impl Iterator<Item = (Key, Vec<u8>, u8)>,
+    ) -> io::Result<BTree> {
+        let mut entries = entries.peekable();
+        if entries.peek().is_none() {
+            return Ok(BTree::init(db)?);
+        }
+        db.atomically(|db| {
+            let mut len = 0;
+            let entries = entries.inspect(|_| len += 1);
+            let mut level = LeafPage::bulk_load(db, entries)?;
+            while level.len() > 1 {
+                level = InternalPage::bulk_load(db, &level)?;
+            }
+            let tree = BTree::from_offset(level[0].1);
+            tree.set_len(len, db)?;
+            Ok(tree)
         })
     }
 
-    pub fn insert<D: Disk>(
+    pub fn insert<D: Disk>(&mut self, key: Key, data: &[u8], db: &mut Database<D>) -> Result<()> {
+        self.insert_with_flags(key, data, 0, db)?;
+        Ok(())
+    }
+
+    /// Like `insert`, but reports how many pages were split and blocks
+    /// allocated to make room for the value.
+    pub fn insert_with_outcome<D: Disk>(
+        &mut self,
+        key: Key,
+        data: &[u8],
+        db: &mut Database<D>,
+    ) -> Result<InsertOutcome> {
+        Ok(self.insert_with_flags(key, data, 0, db)?)
+    }
+
+    /// Stores everything left in `reader` under `key`, returning how long
+    /// it was. A value too long to store inline goes straight to overflow
+    /// pages as it's read, so it's never held in memory whole. Streamed
+    /// values aren't compressed.
+    pub fn write_from<D: Disk>(
+        &mut self,
+        key: Key,
+        reader: &mut impl Read,
+        db: &mut Database<D>,
+    ) -> Result<u64> {
+        Ok(db.atomically(|db| {
+            let max_inline = LeafPage::max_inline_len(db.block_size());
+            let mut head = vec![];
+            reader
+                .by_ref()
+                .take(max_inline + 1)
+                .read_to_end(&mut head)?;
+            if head.len() as u64 <= max_inline {
+                self.insert(key, &head, db)?;
+                return Ok(head.len() as u64);
+            }
+            let overflow = OverflowRef::write_from(&head, reader, db)?;
+            let flags = LeafPageEntry::FLAG_OVERFLOW;
+            self.insert_stored(key, &overflow.to_bytes(), flags, db)?;
+            Ok(overflow.len)
+        })?)
+    }
+
+    /// Inserts every entry of `entries` as `insert` would, but in key order,
+    /// so that a run of keys landing in the same leaf shares one descent
+    /// from the root. Where a key appears more than once, the last value
+    /// wins.
+    pub fn insert_many<D: Disk>(
+        &mut self,
+        entries: &[(Key, &[u8])],
+        db: &mut Database<D>,
+    ) -> Result<()> {
+        for (_, data) in entries {
+            db.check_value_len(data.len() as u64)?;
+        }
+        let mut sorted: Vec<&(Key, &[u8])> = entries.iter().collect();
+        sorted.sort_by_key(|&&(key, _)| key);
+        Ok(db.atomically(|db| {
+            let page_size = db.block_size();
+            let mut rest = &sorted[..];
+            while let Some(&&(key, _)) = rest.first() {
+                let (mut leaf, upper) = self.find_leaf_bounded(key, db)?;
+                let len = self.stored_len(db)?;
+                let keys_before = leaf.keys().len();
+                let mut inserted = 0;
+                for &&(key, data) in rest {
+                    let len = data.len() as u64;
+                    let fits = len <= LeafPage::max_inline_len(page_size)
+                        && !db.compresses(len)
+                        && leaf.can_accommodate(len, page_size);
+                    if upper.is_some_and(|upper| key > upper) || !fits {
+                        break;
+                    }
+                    leaf.upsert_value(key, data, 0, db)?;
+                    inserted += 1;
+                }
+                if inserted > 0 {
+                    let added = leaf.keys().len() - keys_before;
+                    self.set_len(len + added as u64, db)?;
+                } else {
+                    // the leaf is full or the value overflows or gets
+                    // compressed, which takes the whole insert path to sort
+                    // out
+                    let &(key, data) = rest[0];
+                    self.insert(key, data, db)?;
+                    inserted = 1;
+                }
+                rest = &rest[inserted..];
+            }
+            Ok(())
+        })?)
+    }
+
+    /// Readies the database for about `estimated_entries` more entries
+    /// averaging `avg_value_len` bytes, ahead of a burst of inserts. The
+    /// blocks they're estimated to need, less any already free, are added
+    /// to the free list up front, so the burst takes them from there instead
+    /// of growing the file a block at a time. The tree itself isn't changed,
+    /// and an allocator that never reuses free blocks, like `AppendOnly`,
+    /// leaves them for `vacuum_into` to drop. Returns how many blocks were
+    /// added.
+    pub fn reserve<D: Disk>(
+        &self,
+        estimated_entries: u64,
+        avg_value_len: u64,
+        db: &mut Database<D>,
+    ) -> Result<u64> {
+        let needed = super::estimate_blocks(
+            std::iter::repeat_n(avg_value_len, estimated_entries as usize),
+            db.block_size(),
+        );
+        let free = db.stats()?.free_blocks;
+        Ok(db.reserve_blocks(needed.saturating_sub(free))?)
+    }
+
+    /// Whether inserting a value of `data_len` bytes under `key` would split
+    /// a page, and so allocate blocks and write more than the one leaf. It
+    /// follows the path `insert` would take, reading only. A value that
+    /// would be compressed is taken at its full length, so this can answer
+    /// `true` for an insert that turns out not to split.
+    pub fn insert_would_split<D: Disk>(
+        &self,
+        key: Key,
+        data_len: u64,
+        db: &mut Database<D>,
+    ) -> Result<bool> {
+        let page_size = db.block_size();
+        let stored_len = if data_len > LeafPage::max_inline_len(page_size) {
+            OverflowRef::SIZE as u64
+        } else {
+            data_len
+        };
+        let mut page = Page::load(self.root, db)?;
+        loop {
+            if !page.can_accommodate(stored_len, page_size) {
+                return Ok(true);
+            }
+            match page {
+                Page::Internal(internal) => {
+                    let child = internal.pointer(internal.child_index(key));
+                    page = Page::load(child, db)?;
+                }
+                Page::Leaf(_) => return Ok(false),
+            }
+        }
+    }
+
+    /// Replaces the value under `key` with whatever `f` makes of the current
+    /// one, which it's given as `None` if there's no such key yet. Where the
+    /// result fits back into the same leaf, reading and writing share one
+    /// descent from the root; otherwise the write goes through `insert`.
+    pub fn update_with<D: Disk>(
+        &mut self,
+        key: Key,
+        f: impl FnOnce(Option<&[u8]>) -> Vec<u8>,
+        db: &mut Database<D>,
+    ) -> Result<()> {
+        Ok(db.atomically(|db| {
+            let leaf = self.find_leaf(key, db)?;
+            let current = leaf.lookup_value_alloc(key, db)?;
+            let data = f(current.as_deref());
+            self.store_in_leaf(leaf, key, &data, current.is_none(), db)
+        })?)
+    }
+
+    /// Adds `delta` to the 8 byte integer under `key`, taken to be 0 if
+    /// there's no value yet, and returns the new total. The integer is in
+    /// the database's value endianness, as `TreeEntry::set_i64` writes it. A
+    /// value of any other length fails with `InvalidData`, and a total that
+    /// would overflow with `InvalidInput`, leaving the value as it was.
+    pub fn add_i64<D: Disk>(&mut self, key: Key, delta: i64, db: &mut Database<D>) -> Result<i64> {
+        Ok(db.atomically(|db| {
+            let leaf = self.find_leaf(key, db)?;
+            let current = leaf.lookup_value_alloc(key, db)?;
+            let endianness = db.value_endianness();
+            let total = match &current {
+                Some(data) => {
+                    let bytes = data.as_slice().try_into().map_err(|_| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("expected an 8 byte value, found {} bytes", data.len()),
+                        )
+                    })?;
+                    endianness.decode_u64(bytes) as i64
+                }
+                None => 0,
+            };
+            let total = total.checked_add(delta).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("adding {} to {} overflows", delta, total),
+                )
+            })?;
+            let bytes = endianness.encode_u64(total as u64);
+            self.store_in_leaf(leaf, key, &bytes, current.is_none(), db)?;
+            Ok(total)
+        })?)
+    }
+
+    /// Adds `extra` to the end of the value under `key`, or stores it as the
+    /// value if there's none. Where the leaf has free space right after the
+    /// value, only `extra` is written; otherwise the whole value is read,
+    /// extended and stored again.
+    pub fn append<D: Disk>(&mut self, key: Key, extra: &[u8], db: &mut Database<D>) -> Result<()> {
+        Ok(db.atomically(|db| {
+            let mut leaf = self.find_leaf(key, db)?;
+            if let Some(entry) = leaf.entry(key) {
+                let len = entry.value_len + extra.len() as u64;
+                db.check_value_len(len)?;
+                if len <= LeafPage::max_inline_len(db.block_size())
+                    && !db.compresses(len)
+                    && leaf.extend_value(key, extra, db)?
+                {
+                    return Ok(());
+                }
+            }
+            let current = leaf.lookup_value_alloc(key, db)?;
+            let is_new = current.is_none();
+            let mut value = current.unwrap_or_default();
+            value.extend_from_slice(extra);
+            self.store_in_leaf(leaf, key, &value, is_new, db)
+        })?)
+    }
+
+    /// Writes `new` under `key` if the value there now is `expected`, with
+    /// `None` meaning the key must be absent. Returns whether it was written.
+    /// Like `update_with`, the check and the write share one descent where
+    /// the value fits back into the leaf.
+    pub fn compare_and_set<D: Disk>(
+        &mut self,
+        key: Key,
+        expected: Option<&[u8]>,
+        new: &[u8],
+        db: &mut Database<D>,
+    ) -> Result<bool> {
+        Ok(db.atomically(|db| {
+            let leaf = self.find_leaf(key, db)?;
+            let current = leaf.lookup_value_alloc(key, db)?;
+            if current.as_deref() != expected {
+                return Ok(false);
+            }
+            self.store_in_leaf(leaf, key, new, current.is_none(), db)?;
+            Ok(true)
+        })?)
+    }
+
+    /// Stores `data` under `key` in `leaf`, the leaf `key` belongs in, going
+    /// through `insert` instead if it won't fit there as it is.
+    fn store_in_leaf<D: Disk>(
         &mut self,
+        mut leaf: LeafPage,
         key: Key,
         data: &[u8],
+        is_new: bool,
         db: &mut Database<D>,
     ) -> io::Result<()> {
+        let len = data.len() as u64;
+        db.check_value_len(len)?;
+        let page_size = db.block_size();
+        if len <= LeafPage::max_inline_len(page_size)
+            && !db.compresses(len)
+            && leaf.can_accommodate(len, page_size)
+        {
+            let tree_len = self.stored_len(db)?;
+            leaf.upsert_value(key, data, 0, db)?;
+            self.set_len(tree_len + is_new as u64, db)
+        } else {
+            Ok(self.insert(key, data, db)?)
+        }
+    }
+
+    /// Stores `reference` under `key` marked as a reference, so that
+    /// `lookup_resolved` hands it to a `ValueSource` instead of returning it.
+    /// `lookup` still returns the reference bytes as they are.
+    pub fn insert_reference<D: Disk>(
+        &mut self,
+        key: Key,
+        reference: &[u8],
+        db: &mut Database<D>,
+    ) -> Result<()> {
+        self.insert_with_flags(key, reference, LeafPageEntry::FLAG_REFERENCE, db)?;
+        Ok(())
+    }
+
+    fn insert_with_flags<D: Disk>(
+        &mut self,
+        key: Key,
+        data: &[u8],
+        flags: u8,
+        db: &mut Database<D>,
+    ) -> io::Result<InsertOutcome> {
+        let allocations = db.allocations();
+        let split_count = db.atomically(|db| {
+            let (stored, flags) = LeafPage::stored_form(data, flags, db)?;
+            self.insert_stored(key, &stored, flags, db)
+        })?;
+        Ok(InsertOutcome {
+            split_count,
+            allocated_blocks: (db.allocations() - allocations) as u32,
+        })
+    }
+
+    /// Inserts `data` into a leaf exactly as given. It must already be short
+    /// enough to store inline. Returns how many pages were split.
+    fn insert_stored<D: Disk>(
+        &mut self,
+        key: Key,
+        data: &[u8],
+        flags: u8,
+        db: &mut Database<D>,
+    ) -> io::Result<u32> {
+        // splitting the root rewrites its block, count and all, so the count
+        // is read first and written back after
+        let len = self.stored_len(db)?;
+        if self.insert_rightmost(key, data, flags, db)? {
+            self.set_len(len + 1, db)?;
+            return Ok(0);
+        }
         let root = Page::load(self.root, db)?;
-        if root.can_accommodate(data.len() as u64, db.block_size()) {
-            self.btree_insert_nonfull(root, key, data, db)?;
+        let mut splits = 0;
+        let added = if root.can_accommodate(data.len() as u64, db.block_size()) {
+            self.btree_insert_nonfull(root, key, data, flags, &mut splits, db)?
         } else {
             log::debug!("ROOT_FULL [root={}]", self.root);
-            let mut page = InternalPage::init(db, self.root)?;
-            self.root = page.offset();
-            log::debug!("NEW_ROOT_OFFSET [offset={}]", page.offset());
-            self.btree_split_child(&mut page, 0, db)?;
-            self.btree_insert_nonfull(page.into(), key, data, db)?;
+            // The root never moves: nested trees and the database header only
+            // remember this offset. Instead, the old root is copied into a
+            // fresh block and the root block becomes its parent.
+            let moved = db.allocate_block()?;
+            Page::copy_block(self.root, moved, db)?;
+            let mut page = InternalPage::init_at(db, self.root, moved)?;
+            log::debug!("OLD_ROOT_MOVED [offset={}]", moved);
+            self.btree_split_child(&mut page, 0, key, data.len() as u64, db)?;
+            splits += 1;
+            self.btree_insert_nonfull(page.into(), key, data, flags, &mut splits, db)?
+        };
+        self.set_len(len + added as u64, db)?;
+        Ok(splits)
+    }
+
+    /// Inserts `key` into the cached last leaf if it's above every key there
+    /// and fits without a split, returning whether it did. The cache is
+    /// dropped once any block has been freed since it was filled, and when
+    /// its offset no longer holds the tree's last leaf.
+    fn insert_rightmost<D: Disk>(
+        &mut self,
+        key: Key,
+        data: &[u8],
+        flags: u8,
+        db: &mut Database<D>,
+    ) -> io::Result<bool> {
+        let offset = match self.rightmost {
+            Some((offset, generation)) if generation == db.free_generation() => offset,
+            Some(_) => {
+                self.rightmost = None;
+                return Ok(false);
+            }
+            None => return Ok(false),
+        };
+        let mut leaf = match Page::load(offset, db) {
+            Ok(Page::Leaf(leaf)) if leaf.next_leaf_offset().is_none() => leaf,
+            _ => {
+                self.rightmost = None;
+                return Ok(false);
+            }
+        };
+        let above_all = leaf.keys().last().is_some_and(|last| key > last.key);
+        if !above_all || !leaf.can_accommodate(data.len() as u64, db.block_size()) {
+            return Ok(false);
         }
-        Ok(())
+        leaf.upsert_value(key, data, flags, db)?;
+        Ok(true)
     }
 
+    /// Returns whether `key` is new to the tree, adding any pages it splits
+    /// on the way down to `splits`.
     fn btree_insert_nonfull<D: Disk>(
         &mut self,
         page: Page,
         key: Key,
         data: &[u8],
+        flags: u8,
+        splits: &mut u32,
         db: &mut Database<D>,
-    ) -> io::Result<()> {
+    ) -> io::Result<bool> {
         match page {
             Page::Leaf(mut page) => {
-                page.upsert_value(key, data, db)?;
+                let keys_before = page.keys().len();
+                page.upsert_value(key, data, flags, db)?;
+                if self.caches_rightmost && page.next_leaf_offset().is_none() {
+                    self.rightmost = Some((page.offset(), db.free_generation()));
+                }
+                Ok(page.keys().len() > keys_before)
             }
             Page::Internal(mut page) => {
-                let i = match page.keys().binary_search(&key) {
-                    Ok(val) => val,
-                    Err(val) => val,
-                };
+                let i = page.child_index(key);
                 let child = Page::load(page.pointer(i), db)?;
                 log::debug!(
                     "INSERT_NONFULL_INTERNAL [offset={}][i={}][child.offset={}]",
@@ -68,23 +517,34 @@ impl BTree {
                     child
                 } else {
                     log::debug!("SPLIT_NONROOT [i={}][page.offset={}]", i, page.offset());
-                    let (left_child, right_child) = self.btree_split_child(&mut page, i, db)?;
+                    let (left_child, right_child) =
+                        self.btree_split_child(&mut page, i, key, data.len() as u64, db)?;
+                    *splits += 1;
+                    // The split put its separator at keys[i]; route on it the
+                    // same way `child_index` would, equal keys going left.
                     if key > page.key(i) {
                         right_child
                     } else {
                         left_child
                     }
                 };
-                self.btree_insert_nonfull(child, key, data, db)?;
+                // Splits happen on the way down, so the child taken has room
+                // and nothing below it has to reach back up to this page.
+                debug_assert!(child.can_accommodate(data.len() as u64, db.block_size()));
+                self.btree_insert_nonfull(child, key, data, flags, splits, db)
             }
-        };
-        Ok(())
+        }
     }
 
+    /// Splits the child at `insert_idx` of `node` to make room for `key`
+    /// with a `data_len` byte value, so that the half it goes to can take
+    /// it without splitting again.
     fn btree_split_child<D: Disk>(
         &self,
         node: &mut InternalPage,
         insert_idx: usize,
+        key: Key,
+        data_len: u64,
         db: &mut Database<D>,
     ) -> io::Result<(Page, Page)> {
         let left_sibling = Page::load(node.pointer(insert_idx), db)?;
@@ -95,7 +555,7 @@ impl BTree {
                     left_sibling.offset(),
                     left_sibling.keys().len()
                 );
-                let new_right_sibling = left_sibling.split_in_half(db)?;
+                let new_right_sibling = left_sibling.split_in_half(key, data_len, db)?;
                 node.safe_insert(
                     insert_idx,
                     left_sibling.keys().last().unwrap().key,
@@ -116,88 +576,2221 @@ impl BTree {
         }
     }
 
-    fn btree_search<D: Disk>(
+    /// Descends to the leaf whose key range covers `key`.
+    fn find_leaf<D: Disk>(&self, key: Key, db: &mut Database<D>) -> io::Result<LeafPage> {
+        Ok(self.find_leaf_bounded(key, db)?.0)
+    }
+    /// Like `find_leaf`, but also returns the largest key the leaf covers,
+    /// or `None` if it's the last leaf.
+    pub(crate) fn find_leaf_bounded<D: Disk>(
         &self,
-        page: Page,
         key: Key,
         db: &mut Database<D>,
-    ) -> io::Result<Option<Vec<u8>>> {
-        match page {
-            Page::Internal(page) => {
-                let i = match page.keys().binary_search(&key) {
-                    Ok(num) => num,
-                    Err(num) => num,
-                };
-                //                eprintln!(
-                //                    "LOOKUP_RECUR [offset={}][i={}][page.pointers[i]={}]",
-                //                    page.offset(),
-                //                    i,
-                //                    page.pointers()[i]
-                //                );
-                let child = Page::load(page.pointers()[i], db)?;
-                self.btree_search(child, key, db)
+    ) -> io::Result<(LeafPage, Option<Key>)> {
+        let mut page = Page::load(self.root, db)?;
+        let mut upper = None;
+        loop {
+            match page {
+                Page::Internal(internal) => {
+                    let i = internal.child_index(key);
+                    // a separator further down is always the tighter bound
+                    if i < internal.keys().len() {
+                        upper = Some(internal.key(i));
+                    }
+                    page = Page::load(internal.pointer(i), db)?;
+                }
+                Page::Leaf(leaf) => return Ok((leaf, upper)),
+            }
+        }
+    }
+    /// Calls `f` on every leaf of the tree, in key order, following the
+    /// leaf chain from the leftmost leaf.
+    pub(crate) fn for_each_leaf<D: Disk, F>(&self, db: &mut Database<D>, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(&LeafPage, &mut Database<D>) -> io::Result<()>,
+    {
+        let mut leaf = Some(self.leftmost_leaf(db)?);
+        while let Some(page) = leaf {
+            f(&page, db)?;
+            leaf = page.next_leaf(db)?;
+        }
+        Ok(())
+    }
+    /// Inserts every entry of `other`, which lives in `other_db`, into this
+    /// tree. Where both trees hold the same key, `other`'s value wins.
+    pub fn merge_from<D: Disk, O: Disk>(
+        &mut self,
+        db: &mut Database<D>,
+        other: &BTree,
+        other_db: &mut Database<O>,
+    ) -> Result<()> {
+        Ok(other.for_each_leaf(other_db, |leaf, other_db| {
+            for entry in leaf.keys() {
+                let value = leaf.read_value(entry, other_db)?;
+                self.insert_with_flags(entry.key, &value, entry.flags, db)?;
             }
-            Page::Leaf(page) => {
-                //                eprintln!("LOOKUP_RECUR_LEAF [offset={}]", page.offset());
-                page.lookup_value_alloc(key, &mut db.disk)
+            Ok(())
+        })?)
+    }
+    /// Iterates over the entries of the tree in ascending key order. Each
+    /// value is read from the leaf as the walk passes it.
+    pub fn iter<'d, D: Disk>(&self, db: &'d mut Database<D>) -> Result<Iter<'d, D>> {
+        let leaf_page = self.leftmost_leaf(db)?;
+        Ok(Iter {
+            walk: LeafWalk::new(db, leaf_page, false),
+        })
+    }
+    /// Like `iter`, but from the largest key down.
+    pub fn iter_rev<'d, D: Disk>(&self, db: &'d mut Database<D>) -> Result<Iter<'d, D>> {
+        let leaf_page = self.rightmost_leaf(db)?;
+        Ok(Iter {
+            walk: LeafWalk::new(db, leaf_page, true),
+        })
+    }
+    /// Iterates over the keys of the tree in ascending order.
+    pub fn keys<'d, D: Disk>(&self, db: &'d mut Database<D>) -> Result<Keys<'d, D>> {
+        let leaf_page = self.leftmost_leaf(db)?;
+        Ok(Keys {
+            walk: LeafWalk::new(db, leaf_page, false),
+        })
+    }
+    /// Iterates over the keys of the tree in descending order.
+    pub fn keys_rev<'d, D: Disk>(&self, db: &'d mut Database<D>) -> Result<Keys<'d, D>> {
+        let leaf_page = self.rightmost_leaf(db)?;
+        Ok(Keys {
+            walk: LeafWalk::new(db, leaf_page, true),
+        })
+    }
+    /// A cursor positioned just before the first entry whose key is at
+    /// least `key`, so that `next` returns that entry.
+    pub fn cursor_at<'d, D: Disk>(
+        &self,
+        key: Key,
+        db: &'d mut Database<D>,
+    ) -> Result<TreeCursor<'d, D>> {
+        let leaf_page = self.find_leaf(key, db)?;
+        let idx = match leaf_page
+            .keys()
+            .binary_search_by_key(&key, |entry| entry.key)
+        {
+            Ok(idx) => idx,
+            Err(idx) => idx,
+        };
+        Ok(TreeCursor { db, leaf_page, idx })
+    }
+    /// A walk in key order over the entries whose keys fall in `range`, which
+    /// borrows the database only for each step rather than for as long as
+    /// it lasts. Other reads, and writes, can come between the steps; after a
+    /// write the walk finds its place again from the last key it returned.
+    pub fn scan(&self, range: impl RangeBounds<Key>) -> Scan {
+        let first = match range.start_bound() {
+            Bound::Included(&key) => Some(key),
+            Bound::Excluded(&key) => key.checked_add(1),
+            Bound::Unbounded => Some(Key::MIN),
+        };
+        let last = match range.end_bound() {
+            Bound::Included(&key) => Some(key),
+            Bound::Excluded(&key) => key.checked_sub(1),
+            Bound::Unbounded => Some(Key::MAX),
+        };
+        Scan {
+            root: self.root,
+            next: first.filter(|&first| last.is_some_and(|last| first <= last)),
+            last: last.unwrap_or(Key::MIN),
+            leaf_page: None,
+            idx: 0,
+            generation: 0,
+        }
+    }
+    /// Iterates in key order over the entries whose keys start with
+    /// `prefix`, reading a key as its 16 big-endian bytes. Keys compare the
+    /// same way as those bytes do, so the matches are exactly the keys from
+    /// `prefix` followed by zeroes up to `prefix` followed by `0xff`s, and
+    /// the scan is a walk over that range. A key built by packing a short
+    /// string into its leading bytes, like `"user:"` and then an id, can be
+    /// scanned by the string. Byte string keys stored with `insert_bytes`
+    /// are hashed, so they can't be. A prefix longer than a key fails with
+    /// `ErrorKind::InvalidInput`.
+    pub fn prefix_scan<'d, D: Disk>(
+        &self,
+        prefix: &[u8],
+        db: &'d mut Database<D>,
+    ) -> Result<PrefixScan<'d, D>> {
+        let key_len = std::mem::size_of::<Key>();
+        if prefix.len() > key_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("a {} byte prefix is longer than a key", prefix.len()),
+            )
+            .into());
+        }
+        let mut first = [0u8; 16];
+        first[..prefix.len()].copy_from_slice(prefix);
+        let mut last = [0xffu8; 16];
+        last[..prefix.len()].copy_from_slice(prefix);
+        Ok(PrefixScan {
+            db,
+            scan: self.scan(Key::from_be_bytes(first)..=Key::from_be_bytes(last)),
+        })
+    }
+    pub(crate) fn leftmost_leaf<D: Disk>(&self, db: &mut Database<D>) -> io::Result<LeafPage> {
+        let mut page = Page::load(self.root, db)?;
+        loop {
+            match page {
+                Page::Internal(internal) => page = Page::load(internal.pointer(0), db)?,
+                Page::Leaf(leaf) => return Ok(leaf),
             }
         }
     }
-    pub fn lookup<D: Disk>(&self, key: Key, db: &mut Database<D>) -> io::Result<Option<Vec<u8>>> {
-        let page = Page::load(self.root, db)?;
-        return self.btree_search(page, key, db);
+    /// Number of entries in the tree. The count is kept in the root, so
+    /// this doesn't read the leaves.
+    pub fn len<D: Disk>(&self, db: &mut Database<D>) -> Result<u64> {
+        let block_size = db.block_size();
+        db.disk.seek(SeekFrom::Start(self.root))?;
+        let image = Page::read_verified(&mut db.disk, block_size)?;
+        let pos = Page::entry_count_pos(block_size) as usize;
+        Ok((&image[pos..]).read_u64::<BigEndian>()?)
     }
-    pub fn delete<D: Disk>(&mut self, key: Key, db: &mut Database<D>) -> io::Result<()> {
-        let root = Page::load(self.root, db)?;
-        match root {
-            Page::Leaf(mut leaf) => {
-                leaf.delete_value(key, &mut db.disk)?;
+    /// Like `len`, but only reads the count, for writes that load the root
+    /// and so check it anyway.
+    fn stored_len<D: Disk>(&self, db: &mut Database<D>) -> io::Result<u64> {
+        Page::read_entry_count(self.root, db)
+    }
+    /// Writes the entry count into the root, patching its checksum, since
+    /// that covers the count.
+    fn set_len<D: Disk>(&self, len: u64, db: &mut Database<D>) -> io::Result<()> {
+        Page::write_entry_count(self.root, len, db)
+    }
+    pub fn is_empty<D: Disk>(&self, db: &mut Database<D>) -> Result<bool> {
+        Ok(self.len(db)? == 0)
+    }
+    fn rightmost_leaf<D: Disk>(&self, db: &mut Database<D>) -> io::Result<LeafPage> {
+        let mut page = Page::load(self.root, db)?;
+        loop {
+            match page {
+                Page::Internal(internal) => {
+                    let last = internal.pointers()[internal.pointers().len() - 1];
+                    page = Page::load(last, db)?;
+                }
+                Page::Leaf(leaf) => return Ok(leaf),
+            }
+        }
+    }
+    /// The smallest key in the tree, or `None` if it's empty.
+    pub fn min_key<D: Disk>(&self, db: &mut Database<D>) -> Result<Option<Key>> {
+        Ok(self
+            .leftmost_leaf(db)?
+            .keys()
+            .first()
+            .map(|entry| entry.key))
+    }
+    /// The largest key in the tree, or `None` if it's empty.
+    pub fn max_key<D: Disk>(&self, db: &mut Database<D>) -> Result<Option<Key>> {
+        Ok(self
+            .rightmost_leaf(db)?
+            .keys()
+            .last()
+            .map(|entry| entry.key))
+    }
+    /// Number of levels in the tree, counting the leaves, so a tree whose
+    /// root is a leaf has depth 1.
+    pub fn depth<D: Disk>(&self, db: &mut Database<D>) -> Result<usize> {
+        let mut depth = 1;
+        let mut page = Page::load(self.root, db)?;
+        while let Page::Internal(internal) = page {
+            depth += 1;
+            page = Page::load(internal.pointer(0), db)?;
+        }
+        Ok(depth)
+    }
+    /// Rebuilds this tree in `target` with `bulk_load`, keeping each entry's
+    /// flags, and returns the copy. The entries are held in memory while
+    /// they're copied.
+    pub(crate) fn copy_packed<D: Disk, T: Disk>(
+        &self,
+        db: &mut Database<D>,
+        target: &mut Database<T>,
+    ) -> io::Result<BTree> {
+        let entries = self.flagged_entries(db)?;
+        BTree::bulk_load_flagged(target, entries.into_iter())
+    }
+    /// Copies the tree into new blocks of the same database and returns the
+    /// copy, which is independent of the original from then on. Values are
+    /// copied byte for byte, so a `TreeEntry` level copied this way would
+    /// point at the same child trees as the original, and freeing them from
+    /// either side would leave the other pointing at free blocks; levels are
+    /// copied with `TreeEntry::copy_child` instead. The entries are held in
+    /// memory while they're copied.
+    pub fn deep_copy<D: Disk>(&self, db: &mut Database<D>) -> Result<BTree> {
+        let entries = self.flagged_entries(db)?;
+        Ok(BTree::bulk_load_flagged(db, entries.into_iter())?)
+    }
+    pub(crate) fn flagged_entries<D: Disk>(
+        &self,
+        db: &mut Database<D>,
+    ) -> io::Result<Vec<(Key, Vec<u8>, u8)>> {
+        let mut entries = vec![];
+        self.for_each_leaf(db, |leaf, db| {
+            for entry in leaf.keys() {
+                let value = leaf.read_value(entry, db)?;
+                entries.push((entry.key, value, entry.flags));
+            }
+            Ok(())
+        })?;
+        Ok(entries)
+    }
+    /// Packs the values of every leaf that has holes in it back together,
+    /// so that later inserts don't have to stop and do it.
+    pub fn defragment_all<D: Disk>(&self, db: &mut Database<D>) -> Result<()> {
+        Ok(db.atomically(|db| {
+            let mut leaf = Some(self.leftmost_leaf(db)?);
+            while let Some(mut page) = leaf {
+                if page.fragmented_len() > 0 {
+                    page.defragment(db)?;
+                }
+                leaf = page.next_leaf(db)?;
             }
-            Page::Internal(mut internal) => {
-                internal.delete_value(key, db)?;
-                if internal.keys().is_empty() {
-                    self.root = internal.pointer(0);
+            Ok(())
+        })?)
+    }
+    /// The height of the tree, and how many leaves and entries it has and
+    /// how full those leaves are. Every leaf is read to get the figures.
+    pub fn stats<D: Disk>(&self, db: &mut Database<D>) -> Result<TreeStats> {
+        let block_size = db.block_size();
+        let (mut leaf_count, mut entry_count, mut used) = (0, 0, 0);
+        self.for_each_leaf(db, |leaf, _| {
+            leaf_count += 1;
+            entry_count += leaf.keys().len() as u64;
+            used += leaf.used_space();
+            Ok(())
+        })?;
+        Ok(TreeStats {
+            height: self.depth(db)?,
+            leaf_count,
+            entry_count,
+            average_leaf_fill: used as f64 / (leaf_count * block_size) as f64,
+        })
+    }
+    /// Offsets of the overflow pages holding the tree's large values.
+    pub(crate) fn overflow_pages<D: Disk>(
+        &self,
+        db: &mut Database<D>,
+    ) -> io::Result<Vec<PageOffset>> {
+        let mut pages = vec![];
+        self.for_each_leaf(db, |leaf, db| {
+            for entry in leaf.keys() {
+                if let Some(overflow) = leaf.overflow_ref(entry, &mut db.disk)? {
+                    pages.extend(overflow.pages(db.block_size(), &mut db.disk)?);
                 }
             }
+            Ok(())
+        })?;
+        Ok(pages)
+    }
+    /// Offsets of every page in the tree, parents before their children.
+    pub(crate) fn pages<D: Disk>(&self, db: &mut Database<D>) -> io::Result<Vec<PageOffset>> {
+        let mut pages = vec![self.root];
+        let mut i = 0;
+        while i < pages.len() {
+            if let Page::Internal(internal) = Page::load(pages[i], db)? {
+                pages.extend_from_slice(internal.pointers());
+            }
+            i += 1;
         }
-        Ok(())
+        Ok(pages)
     }
-}
-
-#[cfg(test)]
-mod btree_tests {
-    use super::*;
-    use std::io::Cursor;
-
-    #[test]
-    fn btrees_can_have_a_little_test() -> io::Result<()> {
-        let mut db = Database::initialize(Cursor::new(vec![]))?;
-        let mut tree = BTree::init(&mut db)?;
-        let key = 1;
-        let data = &[1, 2, 3, 4];
-        tree.insert(key, data, &mut db)?;
-        assert_eq!(&tree.lookup(key, &mut db)?.unwrap(), data);
-        let mut data = vec![0];
-        for i in 1..128 {
-            data.push(i);
+    /// Rewrites every page of the tree at the offset `moves` pairs it with,
+    /// fixing up child pointers on the way. All pages are read before any is
+    /// written, so the new offsets are free to overlap the old ones. Returns
+    /// the tree at its new root.
+    pub(crate) fn relocate<D: Disk>(
+        &self,
+        moves: &[(PageOffset, PageOffset)],
+        db: &mut Database<D>,
+    ) -> io::Result<BTree> {
+        let new_offsets: HashMap<PageOffset, PageOffset> = moves.iter().cloned().collect();
+        let relocated = |offset: PageOffset| *new_offsets.get(&offset).unwrap_or(&offset);
+        let len = self.stored_len(db)?;
+        enum Moved {
+            Internal(InternalPage),
+            Leaf(Vec<u8>),
         }
-        for key in 1..8_000 {
-            data[0] = (key % 40) as u8;
-            eprintln!("INSERT [{}]", key);
-            tree.insert(key, &data, &mut db)?;
-            eprintln!("LOOKUP [{}]", key);
+        let mut pages = Vec::with_capacity(moves.len());
+        for &(from, to) in moves {
+            let moved = match Page::load(from, db)? {
+                Page::Internal(internal) => Moved::Internal(internal.moved_to(to, relocated)),
+                Page::Leaf(leaf) => {
+                    let mut image = Page::read_block(from, db)?;
+                    let siblings = [
+                        (LeafPage::NEXT_LEAF_POS, leaf.next_leaf_offset()),
+                        (LeafPage::PREV_LEAF_POS, leaf.prev_leaf_offset()),
+                    ];
+                    for (pos, sibling) in siblings {
+                        if let Some(sibling) = sibling {
+                            image[pos..pos + 8].copy_from_slice(&relocated(sibling).to_be_bytes());
+                        }
+                    }
+                    Page::seal(&mut image);
+                    Moved::Leaf(image)
+                }
+            };
+            pages.push((to, moved));
+        }
+        for (to, moved) in pages {
+            log::debug!("RELOCATE_PAGE [to={}]", to);
+            match moved {
+                Moved::Internal(internal) => internal.persist(db)?,
+                Moved::Leaf(image) => db.write(to, &image)?,
+            }
+        }
+        let tree = BTree::from_offset(relocated(self.root));
+        tree.set_len(len, db)?;
+        Ok(tree)
+    }
+    pub fn lookup<D: Disk>(&self, key: Key, db: &mut Database<D>) -> Result<Option<Vec<u8>>> {
+        let leaf = self.find_leaf(key, db)?;
+        Ok(leaf.lookup_value_alloc(key, db)?)
+    }
+    /// Copies the value stored under `key` to `out`, returning its length,
+    /// or `None` if there's no such key. Values in overflow pages are copied
+    /// a page at a time, so they're never held in memory whole.
+    pub fn read_into<D: Disk>(
+        &self,
+        key: Key,
+        out: &mut impl Write,
+        db: &mut Database<D>,
+    ) -> Result<Option<u64>> {
+        let leaf = self.find_leaf(key, db)?;
+        match leaf.entry(key) {
+            Some(entry) => Ok(leaf.copy_value_to(entry, out, db).map(Some)?),
+            None => Ok(None),
+        }
+    }
 
-            match tree.lookup(key, &mut db)? {
-                Some(found) => assert_eq!(found, data),
-                None => panic!("Failed to lookup key {}", key),
+    /// Whether `key` is in the tree. Unlike `lookup`, this never reads the
+    /// value.
+    pub fn contains_key<D: Disk>(&self, key: Key, db: &mut Database<D>) -> Result<bool> {
+        Ok(self.find_leaf(key, db)?.has_key(key))
+    }
+    /// Like `lookup`, but values stored with `insert_reference` are fetched
+    /// through `resolver`. Inline values never touch the resolver.
+    pub fn lookup_resolved<V: ValueSource, D: Disk>(
+        &self,
+        key: Key,
+        resolver: &V,
+        db: &mut Database<D>,
+    ) -> Result<Option<Vec<u8>>> {
+        let leaf = self.find_leaf(key, db)?;
+        let entry = match leaf.entry(key) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let value = leaf.read_value(entry, db)?;
+        if entry.has_flag(LeafPageEntry::FLAG_REFERENCE) {
+            Ok(resolver.fetch(&value).map(Some)?)
+        } else {
+            Ok(Some(value))
+        }
+    }
+    /// Removes `key` from the tree, returning the value it held, or `None`
+    /// if the key wasn't present.
+    pub fn delete<D: Disk>(&mut self, key: Key, db: &mut Database<D>) -> Result<Option<Vec<u8>>> {
+        // merging leaves can free the last one
+        self.rightmost = None;
+        Ok(db.atomically(|db| {
+            // pulling a child up into the root overwrites the count
+            let len = self.stored_len(db)?;
+            let root = Page::load(self.root, db)?;
+            let value = match root {
+                Page::Leaf(mut leaf) => leaf.take_value(key, db)?,
+                Page::Internal(mut internal) => {
+                    let value = internal.delete_value(key, db)?;
+                    self.pull_up_only_children(internal, db)?;
+                    value
+                }
             };
+            if value.is_some() {
+                self.set_len(len - 1, db)?;
+            }
+            Ok(value)
+        })?)
+    }
+    /// Removes every key from `start` up to but not including `end`,
+    /// returning how many there were. Leaves and subtrees wholly inside the
+    /// range are freed in one walk, which reads each of their pages once to
+    /// count its entries and find its overflow pages, and the pages either
+    /// end of it are rebalanced once everything is gone.
+    pub fn delete_range<D: Disk>(
+        &mut self,
+        start: Key,
+        end: Key,
+        db: &mut Database<D>,
+    ) -> Result<u64> {
+        if start >= end {
+            return Ok(0);
         }
-        for key in 10..8_000 {
-            eprintln!("DELETE [{}]", key);
-            tree.delete(key, &mut db)?;
-            match tree.lookup(key, &mut db)? {
-                Some(_) => panic!("Key was not actually deleted {}", key),
-                None => {}
+        self.rightmost = None;
+        Ok(db.atomically(|db| {
+            let len = self.stored_len(db)?;
+            let removed = match Page::load(self.root, db)? {
+                Page::Leaf(mut leaf) => leaf.delete_range(start, end, db)?,
+                Page::Internal(mut internal) => {
+                    let left = self.find_leaf(start, db)?.offset();
+                    let right = self.find_leaf(end - 1, db)?.offset();
+                    let removed = internal.delete_range(start, end, None, None, db)?;
+                    // the leaves between the two ends are gone
+                    if left != right {
+                        LeafPage::link(left, right, db)?;
+                    }
+                    internal.rebalance_range(start, end, db)?;
+                    self.pull_up_only_children(internal, db)?;
+                    removed
+                }
+            };
+            self.set_len(len - removed, db)?;
+            Ok(removed)
+        })?)
+    }
+    /// While the root has a single child, copies that child over it, for as
+    /// many levels as it takes. This overwrites the entry count.
+    fn pull_up_only_children<D: Disk>(
+        &self,
+        mut root: InternalPage,
+        db: &mut Database<D>,
+    ) -> io::Result<()> {
+        while root.keys().is_empty() {
+            let child = root.pointer(0);
+            Page::copy_block(child, self.root, db)?;
+            db.free_block(child)?;
+            root = match Page::load(self.root, db)? {
+                Page::Internal(internal) => internal,
+                Page::Leaf(_) => break,
+            };
+        }
+        Ok(())
+    }
+    /// Frees every block of the tree, overflow pages included, and returns
+    /// how many entries it held. Each page is read once.
+    pub(crate) fn free_all<D: Disk>(&self, db: &mut Database<D>) -> io::Result<u64> {
+        BTree::free_subtree(self.root, db)
+    }
+    fn free_subtree<D: Disk>(offset: PageOffset, db: &mut Database<D>) -> io::Result<u64> {
+        let len = match Page::load(offset, db)? {
+            Page::Leaf(leaf) => {
+                for entry in leaf.keys() {
+                    if let Some(overflow) = leaf.overflow_ref(entry, &mut db.disk)? {
+                        for page in overflow.pages(db.block_size(), &mut db.disk)? {
+                            db.free_block(page)?;
+                        }
+                    }
+                }
+                leaf.keys().len() as u64
+            }
+            Page::Internal(internal) => {
+                let mut len = 0;
+                for &child in internal.pointers() {
+                    len += BTree::free_subtree(child, db)?;
+                }
+                len
+            }
+        };
+        db.free_block(offset)?;
+        Ok(len)
+    }
+
+    /// Adds `data` to the values under `key`, which holds a list of them
+    /// rather than just one, for uses like an index where many entries
+    /// share a key. The values are kept in the order they were added, a
+    /// value added twice is kept twice, and `get_all` returns them in that
+    /// order. A key should only ever be written through `insert_dup` and
+    /// `delete_dup`, as its value is the encoded list.
+    pub fn insert_dup<D: Disk>(
+        &mut self,
+        key: Key,
+        data: &[u8],
+        db: &mut Database<D>,
+    ) -> Result<()> {
+        self.append(key, &dups::record(data), db)
+    }
+    /// Every value added under `key` with `insert_dup`, oldest first, or
+    /// none if there are none.
+    pub fn get_all<D: Disk>(&self, key: Key, db: &mut Database<D>) -> Result<Vec<Vec<u8>>> {
+        match self.lookup(key, db)? {
+            Some(buf) => Ok(dups::decode(&buf)?),
+            None => Ok(vec![]),
+        }
+    }
+    /// Removes the oldest of the values under `key` equal to `data`,
+    /// returning whether there was one. The key goes once its last value
+    /// does.
+    pub fn delete_dup<D: Disk>(
+        &mut self,
+        key: Key,
+        data: &[u8],
+        db: &mut Database<D>,
+    ) -> Result<bool> {
+        Ok(db.atomically(|db| {
+            let mut values = self.get_all(key, db)?;
+            let idx = match values.iter().position(|value| value == data) {
+                Some(idx) => idx,
+                None => return Ok(false),
+            };
+            values.remove(idx);
+            if values.is_empty() {
+                self.delete(key, db)?;
+            } else {
+                self.insert(key, &dups::encode(&values), db)?;
+            }
+            Ok(true)
+        })?)
+    }
+
+    /// Stores `data` under a byte string key of any length, such as a
+    /// string's bytes. The key is hashed into the `Key` space, and keys that
+    /// hash alike share a bucket holding each full key, so a collision can't
+    /// mix up their values. Byte string keys and plain `Key`s share the tree's
+    /// keyspace, so a tree should only hold one kind.
+    pub fn insert_bytes<D: Disk>(
+        &mut self,
+        key: &[u8],
+        data: &[u8],
+        db: &mut Database<D>,
+    ) -> Result<()> {
+        Ok(self.insert_hashed(bucket::key_hash(key), key, data, db)?)
+    }
+    fn insert_hashed<D: Disk>(
+        &mut self,
+        hash: Key,
+        key: &[u8],
+        data: &[u8],
+        db: &mut Database<D>,
+    ) -> io::Result<()> {
+        let mut bucket = self.bucket(hash, db)?;
+        bucket.insert(key, data);
+        Ok(self.insert(hash, &bucket.encode(), db)?)
+    }
+    /// Looks up a value stored with `insert_bytes`.
+    pub fn lookup_bytes<D: Disk>(
+        &self,
+        key: &[u8],
+        db: &mut Database<D>,
+    ) -> Result<Option<Vec<u8>>> {
+        Ok(self.lookup_hashed(bucket::key_hash(key), key, db)?)
+    }
+    fn lookup_hashed<D: Disk>(
+        &self,
+        hash: Key,
+        key: &[u8],
+        db: &mut Database<D>,
+    ) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.bucket(hash, db)?.get(key).map(|value| value.to_vec()))
+    }
+    /// Every byte string key stored with `insert_bytes`, with its value, in
+    /// no particular order.
+    pub(crate) fn bytes_entries<D: Disk>(
+        &self,
+        db: &mut Database<D>,
+    ) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut entries = vec![];
+        for entry in self.iter(db)? {
+            let (_, bucket) = entry?;
+            entries.extend(Bucket::decode(&bucket)?.into_entries());
+        }
+        Ok(entries)
+    }
+    /// Removes a value stored with `insert_bytes`, returning it.
+    pub fn delete_bytes<D: Disk>(
+        &mut self,
+        key: &[u8],
+        db: &mut Database<D>,
+    ) -> Result<Option<Vec<u8>>> {
+        Ok(self.delete_hashed(bucket::key_hash(key), key, db)?)
+    }
+    fn delete_hashed<D: Disk>(
+        &mut self,
+        hash: Key,
+        key: &[u8],
+        db: &mut Database<D>,
+    ) -> io::Result<Option<Vec<u8>>> {
+        let mut bucket = self.bucket(hash, db)?;
+        let value = match bucket.remove(key) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        if bucket.is_empty() {
+            self.delete(hash, db)?;
+        } else {
+            self.insert(hash, &bucket.encode(), db)?;
+        }
+        Ok(Some(value))
+    }
+    fn bucket<D: Disk>(&self, hash: Key, db: &mut Database<D>) -> io::Result<Bucket> {
+        match self.lookup(hash, db)? {
+            Some(buf) => Bucket::decode(&buf),
+            None => Ok(Bucket::default()),
+        }
+    }
+}
+
+/// Walks the entries of a tree's leaves in key order, or in reverse.
+struct LeafWalk<'d, D: Disk> {
+    db: &'d mut Database<D>,
+    leaf_page: Option<LeafPage>,
+    /// How many entries of `leaf_page` have been stepped past.
+    taken: usize,
+    reverse: bool,
+}
+impl<'d, D: Disk> LeafWalk<'d, D> {
+    fn new(db: &'d mut Database<D>, leaf_page: LeafPage, reverse: bool) -> Self {
+        LeafWalk {
+            db,
+            leaf_page: Some(leaf_page),
+            taken: 0,
+            reverse,
+        }
+    }
+    /// Moves on to the next entry, returning its index in `leaf_page`.
+    fn step(&mut self) -> Option<io::Result<usize>> {
+        loop {
+            let leaf_page = self.leaf_page.as_ref()?;
+            let len = leaf_page.keys().len();
+            if self.taken < len {
+                self.taken += 1;
+                let idx = if self.reverse {
+                    len - self.taken
+                } else {
+                    self.taken - 1
+                };
+                return Some(Ok(idx));
+            }
+            let sibling = if self.reverse {
+                leaf_page.prev_leaf(self.db)
+            } else {
+                leaf_page.next_leaf(self.db)
+            };
+            match sibling {
+                Ok(sibling) => {
+                    self.leaf_page = sibling;
+                    self.taken = 0;
+                }
+                Err(err) => {
+                    self.leaf_page = None;
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
+
+/// Iterator over the entries of a `BTree`, from `BTree::iter` or
+/// `BTree::iter_rev`. It starts at the leftmost or rightmost leaf under the
+/// root it was created from and follows the leaf chain, reading each value
+/// as it passes it.
+pub struct Iter<'d, D: Disk> {
+    walk: LeafWalk<'d, D>,
+}
+impl<'d, D: Disk> Iterator for Iter<'d, D> {
+    type Item = Result<(Key, Vec<u8>)>;
+    fn next(&mut self) -> Option<Result<(Key, Vec<u8>)>> {
+        let idx = match self.walk.step()? {
+            Ok(idx) => idx,
+            Err(err) => return Some(Err(err.into())),
+        };
+        let leaf_page = self.walk.leaf_page.as_ref()?;
+        let entry = &leaf_page.keys()[idx];
+        let value = leaf_page.read_value(entry, self.walk.db);
+        Some(value.map(|value| (entry.key, value)).map_err(Error::from))
+    }
+}
+
+/// A position between two entries of a tree, which can move either way.
+/// `next` returns the entry after the position and `prev` the one before
+/// it, so a `next` followed by a `prev` returns the same entry twice.
+pub struct TreeCursor<'d, D: Disk> {
+    db: &'d mut Database<D>,
+    leaf_page: LeafPage,
+    /// Index in `leaf_page` of the entry after the position.
+    idx: usize,
+}
+impl<'d, D: Disk> TreeCursor<'d, D> {
+    /// Moves back over the entry before the position and returns it.
+    pub fn prev(&mut self) -> Option<Result<(Key, Vec<u8>)>> {
+        while self.idx == 0 {
+            match self.leaf_page.prev_leaf(self.db) {
+                Ok(Some(prev)) => {
+                    self.idx = prev.keys().len();
+                    self.leaf_page = prev;
+                }
+                Ok(None) => return None,
+                Err(err) => return Some(Err(err.into())),
+            }
+        }
+        self.idx -= 1;
+        Some(self.entry(self.idx))
+    }
+    fn entry(&mut self, idx: usize) -> Result<(Key, Vec<u8>)> {
+        let entry = &self.leaf_page.keys()[idx];
+        let value = self.leaf_page.read_value(entry, self.db)?;
+        Ok((entry.key, value))
+    }
+}
+impl<'d, D: Disk> Iterator for TreeCursor<'d, D> {
+    type Item = Result<(Key, Vec<u8>)>;
+    /// Moves forward over the entry after the position and returns it.
+    fn next(&mut self) -> Option<Result<(Key, Vec<u8>)>> {
+        while self.idx == self.leaf_page.keys().len() {
+            match self.leaf_page.next_leaf(self.db) {
+                Ok(Some(next)) => {
+                    self.leaf_page = next;
+                    self.idx = 0;
+                }
+                Ok(None) => return None,
+                Err(err) => return Some(Err(err.into())),
+            }
+        }
+        self.idx += 1;
+        Some(self.entry(self.idx - 1))
+    }
+}
+
+/// A walk over part of a tree, from `BTree::scan`. Unlike `Iter` it holds
+/// no borrow of the database, which is passed to each step instead.
+pub struct Scan {
+    root: PageOffset,
+    /// The smallest key still to come, or `None` once the walk is over.
+    next: Option<Key>,
+    /// The largest key in the range.
+    last: Key,
+    /// The leaf holding `next`, as of `generation`.
+    leaf_page: Option<LeafPage>,
+    /// Index in `leaf_page` of the first entry at or after `next`.
+    idx: usize,
+    generation: u64,
+}
+impl Scan {
+    /// The next entry in the range, if there is one.
+    pub fn next<D: Disk>(&mut self, db: &mut Database<D>) -> Option<Result<(Key, Vec<u8>)>> {
+        match self.step(db) {
+            Ok(Some((leaf_page, idx))) => {
+                let entry = &leaf_page.keys()[idx];
+                Some(
+                    leaf_page
+                        .read_value(entry, db)
+                        .map(|value| (entry.key, value))
+                        .map_err(Error::from),
+                )
+            }
+            Ok(None) => None,
+            Err(err) => Some(Err(err.into())),
+        }
+    }
+    /// Like `next`, but only the key, without reading the value.
+    pub fn next_key<D: Disk>(&mut self, db: &mut Database<D>) -> Option<Result<Key>> {
+        match self.step(db) {
+            Ok(Some((leaf_page, idx))) => Some(Ok(leaf_page.keys()[idx].key)),
+            Ok(None) => None,
+            Err(err) => Some(Err(err.into())),
+        }
+    }
+    /// Moves past the next entry in the range, returning the leaf it's in
+    /// and its index there.
+    fn step<D: Disk>(&mut self, db: &mut Database<D>) -> io::Result<Option<(&LeafPage, usize)>> {
+        let next = match self.next {
+            Some(next) => next,
+            None => return Ok(None),
+        };
+        if self.leaf_page.is_none() || self.generation != db.write_generation() {
+            // the leaf may have been split, merged or freed since
+            self.leaf_page = None;
+            let leaf_page = BTree::from_offset(self.root).find_leaf(next, db)?;
+            self.idx = leaf_page.keys().partition_point(|entry| entry.key < next);
+            self.leaf_page = Some(leaf_page);
+            self.generation = db.write_generation();
+        }
+        let mut leaf_page = self.leaf_page.take().unwrap();
+        while self.idx == leaf_page.keys().len() {
+            match leaf_page.next_leaf(db)? {
+                Some(sibling) => {
+                    leaf_page = sibling;
+                    self.idx = 0;
+                }
+                None => {
+                    self.next = None;
+                    return Ok(None);
+                }
+            }
+        }
+        let key = leaf_page.keys()[self.idx].key;
+        if key > self.last {
+            self.next = None;
+            return Ok(None);
+        }
+        self.next = key.checked_add(1).filter(|&next| next <= self.last);
+        self.idx += 1;
+        let leaf_page = self.leaf_page.insert(leaf_page);
+        Ok(Some((leaf_page, self.idx - 1)))
+    }
+}
+
+/// The entries under a prefix, from `BTree::prefix_scan`.
+pub struct PrefixScan<'d, D: Disk> {
+    db: &'d mut Database<D>,
+    /// The walk over the keys with the prefix, which stops on the first key
+    /// past them before reading its value.
+    scan: Scan,
+}
+impl<'d, D: Disk> Iterator for PrefixScan<'d, D> {
+    type Item = Result<(Key, Vec<u8>)>;
+    fn next(&mut self) -> Option<Result<(Key, Vec<u8>)>> {
+        self.scan.next(self.db)
+    }
+}
+
+/// Iterator over the keys of a `BTree`, in order or in reverse. It starts at
+/// the leftmost or rightmost leaf under the root it was created from and
+/// follows the leaf chain, without reading any values.
+pub struct Keys<'d, D: Disk> {
+    walk: LeafWalk<'d, D>,
+}
+impl<'d, D: Disk> Iterator for Keys<'d, D> {
+    type Item = Result<Key>;
+    fn next(&mut self) -> Option<Result<Key>> {
+        let idx = match self.walk.step()? {
+            Ok(idx) => idx,
+            Err(err) => return Some(Err(err.into())),
+        };
+        let leaf_page = self.walk.leaf_page.as_ref()?;
+        Some(Ok(leaf_page.keys()[idx].key))
+    }
+}
+
+#[cfg(test)]
+mod btree_tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::convert::TryInto;
+    use std::io::Cursor;
+
+    #[test]
+    fn btrees_can_have_a_little_test() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        let key = 1;
+        let data = &[1, 2, 3, 4];
+        tree.insert(key, data, &mut db)?;
+        assert_eq!(&tree.lookup(key, &mut db)?.unwrap(), data);
+        let mut data = vec![0];
+        for i in 1..128 {
+            data.push(i);
+        }
+        for key in 1..8_000 {
+            data[0] = (key % 40) as u8;
+            eprintln!("INSERT [{}]", key);
+            tree.insert(key, &data, &mut db)?;
+            eprintln!("LOOKUP [{}]", key);
+
+            match tree.lookup(key, &mut db)? {
+                Some(found) => assert_eq!(found, data),
+                None => panic!("Failed to lookup key {}", key),
+            };
+        }
+        for key in 10..8_000 {
+            eprintln!("DELETE [{}]", key);
+            tree.delete(key, &mut db)?;
+            if tree.lookup(key, &mut db)?.is_some() {
+                panic!("Key was not actually deleted {}", key);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn delete_returns_the_removed_value() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        assert_eq!(tree.delete(1, &mut db)?, None);
+        for key in 0..2_000u128 {
+            tree.insert(key, &key.to_be_bytes(), &mut db)?;
+        }
+        for key in (0..2_000u128).step_by(3) {
+            assert_eq!(tree.delete(key, &mut db)?, Some(key.to_be_bytes().to_vec()));
+            assert_eq!(tree.delete(key, &mut db)?, None);
+        }
+        assert_eq!(tree.delete(5_000, &mut db)?, None);
+        assert_eq!(tree.lookup(1, &mut db)?, Some(1u128.to_be_bytes().to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn len_counts_every_entry() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        assert_eq!(tree.len(&mut db)?, 0);
+        assert!(tree.is_empty(&mut db)?);
+        for key in 0..3_000u128 {
+            tree.insert(key, &[0; 20], &mut db)?;
+        }
+        tree.insert(5, &[1; 20], &mut db)?;
+        assert_eq!(tree.len(&mut db)?, 3_000);
+        for key in (0..3_000u128).step_by(3) {
+            tree.delete(key, &mut db)?;
+        }
+        assert_eq!(tree.len(&mut db)?, 2_000);
+        assert!(!tree.is_empty(&mut db)?);
+
+        // the count is under the root's checksum, so a flipped bit shows
+        let pos = tree.offset() + Page::entry_count_pos(db.block_size()) + 7;
+        db.write(pos, &[(2_000u64 as u8) ^ 1])?;
+        for err in [
+            tree.len(&mut db).unwrap_err(),
+            tree.insert(1, &[1], &mut db).unwrap_err(),
+        ] {
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn counters_add_up_and_refuse_to_overflow() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        assert_eq!(tree.add_i64(1, 250, &mut db)?, 250);
+        assert_eq!(tree.add_i64(1, -1_000, &mut db)?, -750);
+        assert_eq!(
+            tree.lookup(1, &mut db)?,
+            Some((-750i64).to_be_bytes().to_vec())
+        );
+
+        tree.insert(2, &(i64::MAX - 1).to_be_bytes(), &mut db)?;
+        let err = tree.add_i64(2, 2, &mut db).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(tree.add_i64(2, 1, &mut db)?, i64::MAX);
+
+        tree.insert(3, b"abc", &mut db)?;
+        let err = tree.add_i64(3, 1, &mut db).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(tree.lookup(3, &mut db)?, Some(b"abc".to_vec()));
+
+        let mut db =
+            Database::initialize_with_endianness(Cursor::new(vec![]), crate::Endianness::Little)?;
+        let mut tree = BTree::init(&mut db)?;
+        tree.add_i64(1, 7, &mut db)?;
+        assert_eq!(tree.lookup(1, &mut db)?, Some(7i64.to_le_bytes().to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn appends_extend_values_in_place_when_there_is_room() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        // an embedded value has nowhere to grow into
+        db.set_embed_threshold(0);
+        let mut tree = BTree::init(&mut db)?;
+        tree.insert(1, &[1; 100], &mut db)?;
+        tree.insert(2, b"log:", &mut db)?;
+        // the first value sat just above the second, so its space is now free
+        tree.delete(1, &mut db)?;
+        let offset = tree.find_leaf(2, &mut db)?.entry(2).unwrap().offset;
+        tree.append(2, b" started", &mut db)?;
+        tree.append(2, b" ok", &mut db)?;
+        let leaf = tree.find_leaf(2, &mut db)?;
+        assert_eq!(leaf.entry(2).unwrap().offset, offset);
+        assert_eq!(tree.lookup(2, &mut db)?, Some(b"log: started ok".to_vec()));
+
+        // past the freed space the value has to move
+        tree.append(2, &[b'!'; 200], &mut db)?;
+        let mut expected = b"log: started ok".to_vec();
+        expected.extend_from_slice(&[b'!'; 200]);
+        assert_eq!(tree.lookup(2, &mut db)?, Some(expected.clone()));
+        // and past what fits inline it goes to overflow pages
+        tree.append(2, &[b'?'; 10_000], &mut db)?;
+        expected.extend_from_slice(&[b'?'; 10_000]);
+        assert_eq!(tree.lookup(2, &mut db)?, Some(expected));
+
+        tree.append(3, b"new", &mut db)?;
+        assert_eq!(tree.lookup(3, &mut db)?, Some(b"new".to_vec()));
+        assert_eq!(tree.len(&mut db)?, 2);
+        assert_eq!(tree.verify(&mut db)?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn increasing_keys_go_straight_to_the_cached_last_leaf() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        tree.cache_rightmost_leaf(true);
+        for key in 0..5_000 {
+            tree.insert(key, &[key as u8; 40], &mut db)?;
+        }
+        let last = tree.find_leaf(4_999, &mut db)?.offset();
+        assert_eq!(tree.rightmost.map(|(offset, _)| offset), Some(last));
+        // keys below the maximum still take the usual path
+        tree.insert(2_500, b"middle", &mut db)?;
+        assert_eq!(tree.len(&mut db)?, 5_000);
+        assert_eq!(tree.lookup(2_500, &mut db)?, Some(b"middle".to_vec()));
+        assert_eq!(tree.lookup(4_999, &mut db)?, Some(vec![4_999u32 as u8; 40]));
+
+        // another handle deletes the tail, and the cached leaf's block goes
+        // to a new tree, which looks just like a last leaf
+        let mut other = BTree::from_offset(tree.offset());
+        other.delete_range(1_000, 5_000, &mut db)?;
+        while BTree::init(&mut db)?.offset() != last {}
+        let mut reused = BTree::from_offset(last);
+        reused.insert(1, b"other", &mut db)?;
+        for key in 10_000..10_500 {
+            tree.insert(key, &[1; 40], &mut db)?;
+        }
+        assert_eq!(tree.len(&mut db)?, 1_500);
+        assert_eq!(tree.keys(&mut db)?.count(), 1_500);
+        assert_eq!(tree.verify(&mut db)?, vec![]);
+        assert_eq!(reused.len(&mut db)?, 1);
+        assert_eq!(reused.keys(&mut db)?.count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn reserved_blocks_are_used_by_the_inserts_that_follow() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        let reserved = tree.reserve(3_000, 40, &mut db)?;
+        assert!(reserved > 0);
+        let blocks = db.num_blocks_allocated();
+        assert_eq!(db.stats()?.free_blocks, reserved);
+        assert_eq!(db.stats()?.file_size, blocks * db.block_size());
+        // with the blocks still free, there's nothing more to add
+        assert_eq!(tree.reserve(3_000, 40, &mut db)?, 0);
+
+        for key in 0..3_000 {
+            tree.insert(key, &[key as u8; 40], &mut db)?;
+        }
+        assert_eq!(db.num_blocks_allocated(), blocks);
+        assert_eq!(tree.len(&mut db)?, 3_000);
+        assert_eq!(tree.verify(&mut db)?, vec![]);
+        assert_eq!(db.verify()?, vec![]);
+
+        db.set_max_blocks(Some(blocks + 2));
+        assert_eq!(tree.reserve(100_000, 40, &mut db)?, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn scans_carry_on_across_other_reads_and_writes() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        for key in (0..2_000).step_by(2) {
+            tree.insert(key, &[key as u8; 40], &mut db)?;
+        }
+        let mut evens = tree.scan(100..1_000);
+        let mut tail = tree.scan(1_990..);
+        let mut seen = vec![];
+        while let Some(entry) = evens.next(&mut db) {
+            let (key, value) = entry?;
+            assert_eq!(value, vec![key as u8; 40]);
+            seen.push(key);
+            // reads and writes in between: inserts behind the scan, which it
+            // doesn't go back for, and deletes ahead of it, which it skips
+            assert_eq!(tree.lookup(key, &mut db)?, Some(value));
+            tree.insert(key - 1, &[0; 200], &mut db)?;
+            tree.delete(key + 4, &mut db)?;
+        }
+        let expected: Vec<Key> = (100..1_000)
+            .step_by(2)
+            .filter(|key| key % 8 == 4 || key % 8 == 6)
+            .collect();
+        assert_eq!(seen, expected);
+
+        let mut keys = vec![];
+        while let Some(key) = tail.next_key(&mut db) {
+            keys.push(key?);
+        }
+        assert_eq!(keys, vec![1_990, 1_992, 1_994, 1_996, 1_998]);
+        assert!(tail.next_key(&mut db).is_none());
+        assert!(tree.scan(5..5).next(&mut db).is_none());
+        assert_eq!(tree.verify(&mut db)?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn duplicate_values_keep_the_order_they_were_added_in() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        assert_eq!(tree.get_all(1_500, &mut db)?, Vec::<Vec<u8>>::new());
+        for value in [&b"lunch"[..], b"rent", b"", b"lunch"] {
+            tree.insert_dup(1_500, value, &mut db)?;
+        }
+        tree.insert_dup(900, b"coffee", &mut db)?;
+        let all = tree.get_all(1_500, &mut db)?;
+        assert_eq!(
+            all,
+            vec![
+                b"lunch".to_vec(),
+                b"rent".to_vec(),
+                vec![],
+                b"lunch".to_vec()
+            ]
+        );
+        assert_eq!(tree.len(&mut db)?, 2);
+
+        assert!(tree.delete_dup(1_500, b"lunch", &mut db)?);
+        assert!(!tree.delete_dup(1_500, b"taxi", &mut db)?);
+        let all = tree.get_all(1_500, &mut db)?;
+        assert_eq!(all, vec![b"rent".to_vec(), vec![], b"lunch".to_vec()]);
+
+        // enough values to spill into overflow pages
+        for i in 0..1_000u32 {
+            tree.insert_dup(7, &i.to_be_bytes(), &mut db)?;
+        }
+        let all = tree.get_all(7, &mut db)?;
+        assert_eq!(all.len(), 1_000);
+        assert_eq!(all[999], 999u32.to_be_bytes().to_vec());
+
+        assert!(tree.delete_dup(900, b"coffee", &mut db)?);
+        assert_eq!(tree.lookup(900, &mut db)?, None);
+        tree.insert(3, b"not a list", &mut db)?;
+        let err = tree.get_all(3, &mut db).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    #[test]
+    fn insert_outcomes_report_splits_and_allocations() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        let blocks = db.num_blocks_allocated();
+        let mut outcomes = vec![];
+        for key in 0..3_000 {
+            outcomes.push(tree.insert_with_outcome(key, &[0; 40], &mut db)?);
+        }
+        assert_eq!(outcomes[0], InsertOutcome::default());
+        let splits: Vec<_> = outcomes
+            .iter()
+            .filter(|outcome| outcome.split_count > 0)
+            .collect();
+        // the root leaf moves out of the root block before it splits
+        assert_eq!(
+            *splits[0],
+            InsertOutcome {
+                split_count: 1,
+                allocated_blocks: 2,
+            }
+        );
+        assert_eq!(
+            *splits[1],
+            InsertOutcome {
+                split_count: 1,
+                allocated_blocks: 1,
+            }
+        );
+        let allocated: u64 = outcomes
+            .iter()
+            .map(|outcome| outcome.allocated_blocks as u64)
+            .sum();
+        assert_eq!(allocated, db.num_blocks_allocated() - blocks);
+
+        let outcome = tree.insert_with_outcome(5, &[1; 20_000], &mut db)?;
+        assert_eq!(outcome.split_count, 0);
+        assert_eq!(outcome.allocated_blocks, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn deep_copies_do_not_share_changes() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        db.set_compression(Some(1_000));
+        let mut tree = BTree::init(&mut db)?;
+        for key in 0..2_000u128 {
+            tree.insert(key, &[key as u8; 40], &mut db)?;
+        }
+        tree.insert(5, &[9; 50_000], &mut db)?;
+        tree.insert_reference(6, b"s3://bucket/6", &mut db)?;
+        let noise: Vec<u8> = (0..30_000u32).map(|i| (i * 7919 % 251) as u8).collect();
+        tree.insert(7, &noise, &mut db)?;
+        let mut copy = tree.deep_copy(&mut db)?;
+        assert_ne!(copy.offset(), tree.offset());
+        assert_eq!(copy.verify(&mut db)?, vec![]);
+        assert_eq!(copy.len(&mut db)?, 2_000);
+        let original = tree.iter(&mut db)?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(copy.iter(&mut db)?.collect::<Result<Vec<_>>>()?, original);
+
+        // the copy's overflow pages are its own, so freeing them leaves the
+        // original's value alone
+        copy.delete_range(0, 1_000, &mut db)?;
+        copy.insert(5, &[1], &mut db)?;
+        tree.insert(1_500, &[2], &mut db)?;
+        assert_eq!(tree.len(&mut db)?, 2_000);
+        assert_eq!(tree.lookup(5, &mut db)?, Some(vec![9; 50_000]));
+        assert_eq!(tree.lookup(7, &mut db)?, Some(noise));
+        assert_eq!(
+            copy.lookup(1_500, &mut db)?,
+            Some(vec![1_500u128 as u8; 40])
+        );
+        assert_eq!(tree.verify(&mut db)?, vec![]);
+        assert_eq!(copy.verify(&mut db)?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn delete_range_removes_just_the_keys_in_the_range() -> io::Result<()> {
+        let mut db = Database::initialize_with_block_size(Cursor::new(vec![]), 1024)?;
+        let mut tree = BTree::init(&mut db)?;
+        let mut reference = std::collections::BTreeMap::new();
+        for key in 0..1_000u128 {
+            let value = if key % 125 == 7 {
+                vec![key as u8; 2_000]
+            } else {
+                vec![key as u8; 40]
+            };
+            tree.insert(key, &value, &mut db)?;
+            reference.insert(key, value);
+        }
+        let blocks = db.num_blocks_allocated();
+        for &(start, end) in &[(250, 750), (10, 11), (0, 5), (990, 1_200), (2, 1)] {
+            let removed = tree.delete_range(start, end, &mut db)?;
+            let expected: Vec<Key> = reference
+                .range(start..end.max(start))
+                .map(|(&key, _)| key)
+                .collect();
+            for key in &expected {
+                reference.remove(key);
+            }
+            assert_eq!(removed, expected.len() as u64);
+            assert_eq!(tree.verify(&mut db)?, vec![]);
+        }
+        assert_eq!(tree.len(&mut db)?, reference.len() as u64);
+        let remaining = tree.iter(&mut db)?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(remaining, reference.into_iter().collect::<Vec<_>>());
+
+        // the freed leaves and overflow pages are used again
+        for key in 250..750u128 {
+            tree.insert(key, &[0; 40], &mut db)?;
+        }
+        assert!(db.num_blocks_allocated() <= blocks);
+
+        assert_eq!(tree.delete_range(0, Key::MAX, &mut db)?, 984);
+        assert_eq!(tree.depth(&mut db)?, 1);
+        assert!(tree.is_empty(&mut db)?);
+
+        // in a deeper tree the ranges end in different subtrees
+        let entries: Vec<(Key, Vec<u8>)> = (0..6_000).map(|key| (key, vec![1; 20])).collect();
+        let borrowed: Vec<(Key, &[u8])> = entries
+            .iter()
+            .map(|(key, value)| (*key, value.as_slice()))
+            .collect();
+        tree.insert_many(&borrowed, &mut db)?;
+        assert_eq!(tree.depth(&mut db)?, 3);
+        assert_eq!(tree.delete_range(673, 4_065, &mut db)?, 3_392);
+        assert_eq!(tree.delete_range(10, 680, &mut db)?, 663);
+        assert_eq!(tree.delete_range(5_000, 7_000, &mut db)?, 1_000);
+        assert_eq!(tree.verify(&mut db)?, vec![]);
+        let keys = tree.keys(&mut db)?.collect::<Result<Vec<_>>>()?;
+        let expected: Vec<Key> = (0..10).chain(4_065..5_000).collect();
+        assert_eq!(keys, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn the_entry_count_follows_every_kind_of_write() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        let entries: Vec<(Key, Vec<u8>)> = (0..2_000).map(|key| (key * 2, vec![1; 20])).collect();
+        let borrowed: Vec<(Key, &[u8])> = entries
+            .iter()
+            .map(|(key, value)| (*key, value.as_slice()))
+            .collect();
+        tree.insert_many(&borrowed, &mut db)?;
+        assert_eq!(tree.len(&mut db)?, 2_000);
+        tree.insert_many(&[(1, &[2; 20]), (2, &[2; 20]), (3, &[2; 20])], &mut db)?;
+        assert_eq!(tree.len(&mut db)?, 2_002);
+        tree.update_with(5, |_| vec![3; 20], &mut db)?;
+        tree.update_with(6, |_| vec![3; 20], &mut db)?;
+        tree.insert(7, &[4; 20_000], &mut db)?;
+        assert_eq!(tree.len(&mut db)?, 2_004);
+        assert_eq!(tree.delete(9, &mut db)?, None);
+        for key in 0..4_000 {
+            tree.delete(key, &mut db)?;
+        }
+        assert_eq!(tree.depth(&mut db)?, 1);
+        assert_eq!(tree.len(&mut db)?, 0);
+        assert_eq!(tree.verify(&mut db)?, vec![]);
+
+        let loaded = BTree::bulk_load(&mut db, (0..3_000).map(|key| (key, vec![5; 20])))?;
+        assert_eq!(loaded.len(&mut db)?, 3_000);
+        assert_eq!(loaded.verify(&mut db)?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn min_and_max_keys_follow_the_edges() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        assert_eq!(tree.min_key(&mut db)?, None);
+        assert_eq!(tree.max_key(&mut db)?, None);
+        for key in (100..3_100u128).rev() {
+            tree.insert(key, &[0; 20], &mut db)?;
+        }
+        assert!(tree.depth(&mut db)? > 1);
+        assert_eq!(tree.min_key(&mut db)?, Some(100));
+        assert_eq!(tree.max_key(&mut db)?, Some(3_099));
+        for key in (100..3_100u128).filter(|key| key % 1000 != 500) {
+            tree.delete(key, &mut db)?;
+        }
+        assert_eq!(tree.min_key(&mut db)?, Some(500));
+        assert_eq!(tree.max_key(&mut db)?, Some(2_500));
+        Ok(())
+    }
+
+    /// Every key that's a separator in one of the tree's internal pages.
+    fn separators(tree: &BTree, db: &mut Database<impl Disk>) -> io::Result<Vec<Key>> {
+        let mut separators = vec![];
+        for page in tree.pages(db)? {
+            if let Page::Internal(internal) = Page::load(page, db)? {
+                separators.extend_from_slice(internal.keys());
+            }
+        }
+        Ok(separators)
+    }
+
+    // btree-fuzzer never produced an `instructions` file that sends a key
+    // to the wrong side of a split, so this builds that case directly
+    #[test]
+    fn reinserting_separator_keys_keeps_every_entry() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        for key in 0..5_000u128 {
+            tree.insert(key, &[1; 30], &mut db)?;
+        }
+        let separators = separators(&tree, &mut db)?;
+        assert!(!separators.is_empty());
+        // values big enough that the leaf holding each separator splits
+        // right as it's replaced
+        for &key in &separators {
+            tree.insert(key, &[2; 600], &mut db)?;
+        }
+        for key in 0..5_000u128 {
+            let expected = if separators.contains(&key) {
+                vec![2; 600]
+            } else {
+                vec![1; 30]
+            };
+            assert_eq!(tree.lookup(key, &mut db)?, Some(expected));
+        }
+        assert_eq!(tree.len(&mut db)?, 5_000);
+        Ok(())
+    }
+
+    #[test]
+    fn bulk_loaded_trees_hold_every_entry() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let blob: Vec<u8> = (0..20_000).map(|i| (i % 251) as u8).collect();
+        let value = |key: Key| {
+            if key.is_multiple_of(1_000) {
+                blob.clone()
+            } else {
+                vec![key as u8; (key % 50) as usize]
+            }
+        };
+        let mut tree = BTree::bulk_load(&mut db, (0..5_000).map(|key| (key, value(key))))?;
+        assert_eq!(tree.depth(&mut db)?, 2);
+        assert_eq!(tree.len(&mut db)?, 5_000);
+        let entries = tree.iter(&mut db)?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            entries,
+            (0..5_000).map(|key| (key, value(key))).collect::<Vec<_>>()
+        );
+        let keys_rev = tree.keys_rev(&mut db)?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys_rev, (0..5_000).rev().collect::<Vec<_>>());
+        assert_eq!(tree.overflow_pages(&mut db)?.len(), 5 * 3);
+
+        // the packed pages take further inserts and deletes as usual
+        for key in (0..5_000).step_by(3) {
+            assert_eq!(tree.delete(key, &mut db)?, Some(value(key)));
+        }
+        for key in (1..5_000).step_by(7) {
+            tree.insert(key, &[1, 2, 3], &mut db)?;
+        }
+        for key in 0..5_000u128 {
+            let expected = match key {
+                _ if key % 7 == 1 => Some(vec![1, 2, 3]),
+                _ if key.is_multiple_of(3) => None,
+                _ => Some(value(key)),
+            };
+            assert_eq!(tree.lookup(key, &mut db)?, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn bulk_load_rejects_unsorted_keys() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let tree = BTree::bulk_load(&mut db, std::iter::empty())?;
+        assert!(tree.is_empty(&mut db)?);
+        let entries = vec![(1, vec![1]), (3, vec![3]), (2, vec![2])];
+        let err = BTree::bulk_load(&mut db, entries.into_iter())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        Ok(())
+    }
+
+    #[test]
+    fn insert_many_matches_inserting_one_by_one() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut batched = BTree::init(&mut db)?;
+        let mut single = BTree::init(&mut db)?;
+        let blob = vec![7u8; 10_000];
+        let values: Vec<Vec<u8>> = (0..3_000u128)
+            .map(|i| match i {
+                _ if i % 500 == 499 => blob.clone(),
+                _ => vec![i as u8; (i % 40) as usize],
+            })
+            .collect();
+        // keys out of order, with some repeated
+        let entries: Vec<(Key, &[u8])> = values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| ((i as Key * 7_919) % 2_000, &value[..]))
+            .collect();
+        for chunk in entries.chunks(700) {
+            batched.insert_many(chunk, &mut db)?;
+            for &(key, value) in chunk {
+                single.insert(key, value, &mut db)?;
+            }
+        }
+        let batched_entries = batched.iter(&mut db)?.collect::<Result<Vec<_>>>()?;
+        let single_entries = single.iter(&mut db)?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(batched_entries.len(), 2_000);
+        assert_eq!(batched_entries, single_entries);
+        Ok(())
+    }
+
+    #[test]
+    fn stats_describe_the_shape_of_the_tree() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        let stats = tree.stats(&mut db)?;
+        assert_eq!(
+            (stats.height, stats.leaf_count, stats.entry_count),
+            (1, 1, 0)
+        );
+        assert!(stats.average_leaf_fill < 0.01);
+
+        for key in 0..2_000 {
+            tree.insert(key, &[0; 50], &mut db)?;
+        }
+        let stats = tree.stats(&mut db)?;
+        assert_eq!(stats.height, tree.depth(&mut db)?);
+        assert_eq!(stats.entry_count, 2_000);
+        let leaves = tree.pages(&mut db)?.len() as u64 - 1;
+        assert_eq!(stats.leaf_count, leaves);
+        // sequential inserts leave every leaf but the last half full
+        assert!(stats.average_leaf_fill > 0.4 && stats.average_leaf_fill < 0.6);
+
+        let packed = BTree::bulk_load(&mut db, (0..2_000).map(|key| (key, vec![0; 50])))?;
+        assert!(packed.stats(&mut db)?.average_leaf_fill > 0.95);
+        Ok(())
+    }
+
+    #[test]
+    fn defragment_all_closes_every_hole() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        let value = |key: Key| vec![key as u8; if key.is_multiple_of(2) { 200 } else { 20 }];
+        for key in 0..2_000 {
+            tree.insert(key, &value(key), &mut db)?;
+        }
+        for key in (0..2_000).step_by(4) {
+            tree.delete(key, &mut db)?;
+        }
+        let fragmented = |tree: &BTree, db: &mut Database<_>| -> io::Result<u64> {
+            let mut len = 0;
+            tree.for_each_leaf(db, |leaf, _| {
+                len += leaf.fragmented_len();
+                Ok(())
+            })?;
+            Ok(len)
+        };
+        assert!(fragmented(&tree, &mut db)? > 0);
+
+        tree.defragment_all(&mut db)?;
+        assert_eq!(fragmented(&tree, &mut db)?, 0);
+        for key in 0..2_000 {
+            let expected = if key % 4 == 0 { None } else { Some(value(key)) };
+            assert_eq!(tree.lookup(key, &mut db)?, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn empty_values_stay_clear_of_a_growing_header() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        tree.insert(0, &[7; 100], &mut db)?;
+        tree.insert(1, &[], &mut db)?;
+        tree.insert(2, &[7; 100], &mut db)?;
+        tree.insert(3, &[7; 100], &mut db)?;
+        tree.delete(0, &mut db)?;
+        // packing the leaf used to leave the empty value between the others
+        tree.defragment_all(&mut db)?;
+        tree.delete(2, &mut db)?;
+        tree.delete(3, &mut db)?;
+        for key in 100..345 {
+            tree.insert(key, &[1; 8], &mut db)?;
+        }
+        assert_eq!(tree.lookup(1, &mut db)?, Some(vec![]));
+        assert_eq!(tree.len(&mut db)?, 246);
+        assert_eq!(tree.verify(&mut db)?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn contains_key_matches_lookup() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        assert!(!tree.contains_key(1, &mut db)?);
+        for key in (0..2_000u128).step_by(2) {
+            tree.insert(key, &[0; 100], &mut db)?;
+        }
+        tree.insert(1, b"", &mut db)?;
+        for key in 0..2_000u128 {
+            let expected = key % 2 == 0 || key == 1;
+            assert_eq!(tree.contains_key(key, &mut db)?, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn deleting_everything_collapses_to_a_single_leaf() -> io::Result<()> {
+        let mut db = Database::initialize_with_block_size(Cursor::new(vec![]), 1024)?;
+        let mut tree = BTree::init(&mut db)?;
+        let value = [7u8; 100];
+        for key in 0..2_000 {
+            tree.insert(key, &value, &mut db)?;
+        }
+        assert_eq!(tree.depth(&mut db)?, 3);
+        // delete from both ends and the middle so every kind of sibling
+        // gets merged into
+        for key in (0..2_000).filter(|key| key % 2 == 0) {
+            tree.delete(key, &mut db)?;
+        }
+        for key in (1_000..2_000).rev().filter(|key| key % 2 == 1) {
+            tree.delete(key, &mut db)?;
+        }
+        let remaining: Vec<Key> = tree.keys(&mut db)?.collect::<Result<_>>()?;
+        assert_eq!(remaining, (1..1_000).step_by(2).collect::<Vec<_>>());
+        for &key in &remaining {
+            assert_eq!(tree.lookup(key, &mut db)?, Some(value.to_vec()));
+            tree.delete(key, &mut db)?;
+        }
+        assert_eq!(tree.depth(&mut db)?, 1);
+        assert_eq!(tree.keys(&mut db)?.count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn underfull_leaves_borrow_from_their_siblings() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        let value = [7u8; 200];
+        for key in 0..1_000 {
+            tree.insert(key, &value, &mut db)?;
+        }
+        let pages_before = tree.pages(&mut db)?.len();
+        // thin out the front of the tree; its leaves must pull entries
+        // over from the rest rather than sit nearly empty
+        for key in 0..300 {
+            tree.delete(key, &mut db)?;
+        }
+        let page_size = db.block_size();
+        tree.for_each_leaf(&mut db, |leaf, _| {
+            assert!(!leaf.is_underfull(page_size));
+            Ok(())
+        })?;
+        assert!(tree.pages(&mut db)?.len() < pages_before);
+        for key in 300..1_000 {
+            assert_eq!(tree.lookup(key, &mut db)?, Some(value.to_vec()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn deleting_from_the_edge_leaves_keeps_the_separators_sound() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        for key in 0..3_000 {
+            tree.insert(key, &[key as u8; 40], &mut db)?;
+        }
+        assert_eq!(tree.depth(&mut db)?, 2);
+        // the first child is rebalanced with its right neighbour and the last
+        // with its left, the two cases a parent has to pick apart
+        for round in 0..10 {
+            for _ in 0..100 {
+                let first = tree.min_key(&mut db)?.unwrap();
+                assert!(tree.delete(first, &mut db)?.is_some());
+                let last = tree.max_key(&mut db)?.unwrap();
+                assert!(tree.delete(last, &mut db)?.is_some());
+            }
+            assert_eq!(tree.verify(&mut db)?, vec![], "after round {}", round);
+        }
+        let remaining: Vec<Key> = tree.keys(&mut db)?.collect::<Result<_>>()?;
+        assert_eq!(remaining, (1_000..2_000).collect::<Vec<_>>());
+        for key in remaining {
+            assert_eq!(tree.lookup(key, &mut db)?, Some(vec![key as u8; 40]));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn large_values_spill_into_overflow_pages() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        let blob: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+        for key in 0..100 {
+            tree.insert(key, &[key as u8; 40], &mut db)?;
+        }
+        tree.insert(50, &blob, &mut db)?;
+        assert_eq!(tree.lookup(50, &mut db)?, Some(blob.clone()));
+        assert_eq!(tree.overflow_pages(&mut db)?.len(), 9);
+        for key in (0..100).filter(|&key| key != 50) {
+            assert_eq!(tree.lookup(key, &mut db)?, Some(vec![key as u8; 40]));
+        }
+
+        // overwriting with an inline value drops the chain
+        tree.insert(50, &[1, 2, 3], &mut db)?;
+        assert_eq!(tree.lookup(50, &mut db)?, Some(vec![1, 2, 3]));
+        assert!(tree.overflow_pages(&mut db)?.is_empty());
+
+        tree.insert(50, &blob, &mut db)?;
+        assert_eq!(tree.delete(50, &mut db)?, Some(blob));
+        assert!(tree.overflow_pages(&mut db)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn byte_keys_survive_hash_collisions() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        let long_key = "user:".repeat(100);
+        tree.insert_bytes(long_key.as_bytes(), b"long", &mut db)?;
+        tree.insert_bytes(b"short", b"short", &mut db)?;
+        assert_eq!(
+            tree.lookup_bytes(long_key.as_bytes(), &mut db)?,
+            Some(b"long".to_vec())
+        );
+        assert_eq!(
+            tree.lookup_bytes(b"short", &mut db)?,
+            Some(b"short".to_vec())
+        );
+        assert_eq!(tree.lookup_bytes(b"missing", &mut db)?, None);
+
+        // force two keys into the same bucket
+        let hash = 42;
+        tree.insert_hashed(hash, b"alice", b"1", &mut db)?;
+        tree.insert_hashed(hash, b"bob", b"2", &mut db)?;
+        tree.insert_hashed(hash, b"alice", b"3", &mut db)?;
+        assert_eq!(
+            tree.lookup_hashed(hash, b"alice", &mut db)?,
+            Some(b"3".to_vec())
+        );
+        assert_eq!(
+            tree.lookup_hashed(hash, b"bob", &mut db)?,
+            Some(b"2".to_vec())
+        );
+        assert_eq!(tree.lookup_hashed(hash, b"carol", &mut db)?, None);
+
+        assert_eq!(
+            tree.delete_hashed(hash, b"alice", &mut db)?,
+            Some(b"3".to_vec())
+        );
+        assert_eq!(
+            tree.lookup_hashed(hash, b"bob", &mut db)?,
+            Some(b"2".to_vec())
+        );
+        assert_eq!(
+            tree.delete_hashed(hash, b"bob", &mut db)?,
+            Some(b"2".to_vec())
+        );
+        assert_eq!(tree.lookup(hash, &mut db)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn flipped_bits_fail_the_page_checksum() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        for key in 0..1_000 {
+            tree.insert(key, &[3; 20], &mut db)?;
+        }
+        let leaf = tree.find_leaf(500, &mut db)?.offset();
+        for &offset in &[tree.offset(), leaf] {
+            let image = Page::read_block(offset, &mut db)?;
+            let mut flipped = image.clone();
+            flipped[100] ^= 0x10;
+            db.write(offset, &flipped)?;
+            let err = tree.lookup(500, &mut db).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(err.to_string().contains(&offset.to_string()));
+            db.write(offset, &image)?;
+        }
+        assert_eq!(tree.lookup(500, &mut db)?, Some(vec![3; 20]));
+        Ok(())
+    }
+
+    struct MockStore {
+        fetched: RefCell<Vec<Vec<u8>>>,
+    }
+
+    impl ValueSource for MockStore {
+        fn fetch(&self, reference: &[u8]) -> io::Result<Vec<u8>> {
+            self.fetched.borrow_mut().push(reference.to_vec());
+            let mut blob = b"blob:".to_vec();
+            blob.extend_from_slice(reference);
+            Ok(blob)
+        }
+    }
+
+    #[test]
+    fn lookup_resolved_only_fetches_references() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        let store = MockStore {
+            fetched: RefCell::new(vec![]),
+        };
+        tree.insert(1, b"inline", &mut db)?;
+        tree.insert_reference(2, b"s3://bucket/2", &mut db)?;
+        tree.insert(3, b"also inline", &mut db)?;
+
+        assert_eq!(
+            tree.lookup_resolved(1, &store, &mut db)?,
+            Some(b"inline".to_vec())
+        );
+        assert_eq!(
+            tree.lookup_resolved(2, &store, &mut db)?,
+            Some(b"blob:s3://bucket/2".to_vec())
+        );
+        assert_eq!(
+            tree.lookup_resolved(3, &store, &mut db)?,
+            Some(b"also inline".to_vec())
+        );
+        assert_eq!(tree.lookup_resolved(4, &store, &mut db)?, None);
+        assert_eq!(*store.fetched.borrow(), vec![b"s3://bucket/2".to_vec()]);
+
+        // plain lookups hand back the reference itself
+        assert_eq!(tree.lookup(2, &mut db)?, Some(b"s3://bucket/2".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn keys_follow_the_leaf_chain() -> io::Result<()> {
+        let mut db = Database::initialize_with_block_size(Cursor::new(vec![]), 1024)?;
+        let mut tree = BTree::init(&mut db)?;
+        for key in (0..1_000).rev() {
+            tree.insert(key, &[1, 2, 3, 4], &mut db)?;
+        }
+        let keys = tree.keys(&mut db)?.collect::<Result<Vec<Key>>>()?;
+        assert_eq!(keys, (0..1_000).collect::<Vec<Key>>());
+        Ok(())
+    }
+
+    #[test]
+    fn leaves_form_a_doubly_linked_list() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        for key in (0..2_000).map(|i| (i * 7_919) % 2_003) {
+            tree.insert(key, &[7; 40], &mut db)?;
+        }
+        let mut forward = vec![tree.leftmost_leaf(&mut db)?];
+        while let Some(next) = forward.last().unwrap().next_leaf(&mut db)? {
+            forward.push(next);
+        }
+        assert!(forward.len() >= 5);
+        assert_eq!(forward[0].prev_leaf_offset(), None);
+        for pair in forward.windows(2) {
+            assert_eq!(pair[0].next_leaf_offset(), Some(pair[1].offset()));
+            assert_eq!(pair[1].prev_leaf_offset(), Some(pair[0].offset()));
+        }
+
+        let mut backward = vec![tree.rightmost_leaf(&mut db)?];
+        while let Some(prev) = backward.last().unwrap().prev_leaf(&mut db)? {
+            backward.push(prev);
+        }
+        let backward: Vec<_> = backward.iter().rev().map(|leaf| leaf.offset()).collect();
+        let forward: Vec<_> = forward.iter().map(|leaf| leaf.offset()).collect();
+        assert_eq!(backward, forward);
+        Ok(())
+    }
+
+    #[test]
+    fn iter_yields_every_entry_in_order() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        assert_eq!(tree.iter(&mut db)?.count(), 0);
+
+        let value_for = |key: Key| vec![key as u8; (key % 50) as usize];
+        for key in (0..3_000).map(|i| (i * 7_919) % 10_007) {
+            tree.insert(key, &value_for(key), &mut db)?;
+        }
+        tree.insert(10_500, &[9; 5_000], &mut db)?;
+        let entries = tree.iter(&mut db)?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(entries.len(), 3_001);
+        assert!(entries.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        for (key, value) in &entries[..3_000] {
+            assert_eq!(value, &value_for(*key));
+        }
+        assert_eq!(entries[3_000], (10_500, vec![9; 5_000]));
+        Ok(())
+    }
+
+    #[test]
+    fn reverse_iteration_is_the_exact_reverse() -> io::Result<()> {
+        let mut db = Database::initialize_with_block_size(Cursor::new(vec![]), 1024)?;
+        let mut tree = BTree::init(&mut db)?;
+        assert_eq!(tree.keys_rev(&mut db)?.count(), 0);
+        for key in (0..400).map(|i| (i * 7_919) % 1_009) {
+            tree.insert(key, &key.to_be_bytes(), &mut db)?;
+        }
+        // splits, merges and redistributions all relink the leaves
+        for key in (0..1_009).filter(|key| key % 5 != 0 && key % 7 != 0) {
+            tree.delete(key, &mut db)?;
+        }
+        for key in 2_000..2_100 {
+            tree.insert(key, &key.to_be_bytes(), &mut db)?;
+        }
+
+        let mut keys = tree.keys(&mut db)?.collect::<Result<Vec<Key>>>()?;
+        keys.reverse();
+        let keys_rev = tree.keys_rev(&mut db)?.collect::<Result<Vec<Key>>>()?;
+        assert_eq!(keys_rev, keys);
+        let mut entries = tree.iter(&mut db)?.collect::<Result<Vec<_>>>()?;
+        entries.reverse();
+        let entries_rev = tree.iter_rev(&mut db)?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(entries_rev, entries);
+        Ok(())
+    }
+
+    #[test]
+    fn cursors_move_both_ways_across_leaves() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        for key in (0..6_000u128).step_by(2) {
+            tree.insert(key, &key.to_be_bytes(), &mut db)?;
+        }
+        let entry = |key: Key| (key, key.to_be_bytes().to_vec());
+
+        // a page of 1000 entries after key 1001 spans several leaves
+        let mut cursor = tree.cursor_at(1_001, &mut db)?;
+        let page = (&mut cursor).take(1_000).collect::<Result<Vec<_>>>()?;
+        assert_eq!(page.first(), Some(&entry(1_002)));
+        assert_eq!(page.last(), Some(&entry(3_000)));
+        assert_eq!(cursor.prev().transpose()?, Some(entry(3_000)));
+        let back = (0..999)
+            .map(|_| cursor.prev().unwrap())
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(back.last(), Some(&entry(1_002)));
+        assert_eq!(cursor.prev().transpose()?, Some(entry(1_000)));
+
+        let mut cursor = tree.cursor_at(0, &mut db)?;
+        assert!(cursor.prev().is_none());
+        assert_eq!(cursor.next().transpose()?, Some(entry(0)));
+        let mut cursor = tree.cursor_at(6_000, &mut db)?;
+        assert!(cursor.next().is_none());
+        assert_eq!(cursor.prev().transpose()?, Some(entry(5_998)));
+        Ok(())
+    }
+
+    #[test]
+    fn keys_are_yielded_in_order() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        assert_eq!(tree.keys(&mut db)?.count(), 0);
+
+        let mut expected: Vec<Key> = (0..3_000).map(|i| (i * 7_919) % 10_007).collect();
+        for &key in &expected {
+            tree.insert(key, &[1, 2, 3], &mut db)?;
+        }
+        expected.sort();
+        let keys = tree.keys(&mut db)?.collect::<Result<Vec<Key>>>()?;
+        assert_eq!(keys, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn merge_from_prefers_the_other_tree() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        let mut other_db = Database::initialize(Cursor::new(vec![]))?;
+        let mut other = BTree::init(&mut other_db)?;
+        tree.insert(1, &[1], &mut db)?;
+        tree.insert(2, &[2], &mut db)?;
+        other.insert(2, &[20], &mut other_db)?;
+        other.insert_reference(3, &[30], &mut other_db)?;
+
+        tree.merge_from(&mut db, &other, &mut other_db)?;
+        assert_eq!(tree.lookup(1, &mut db)?, Some(vec![1]));
+        assert_eq!(tree.lookup(2, &mut db)?, Some(vec![20]));
+        let leaf = tree.find_leaf(3, &mut db)?;
+        assert!(leaf
+            .entry(3)
+            .unwrap()
+            .has_flag(LeafPageEntry::FLAG_REFERENCE));
+        Ok(())
+    }
+
+    #[test]
+    fn long_values_are_stored_compressed_once_enabled() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        let value = b"{\"id\": 1, \"tags\": [\"red\", \"green\", \"blue\"]}".repeat(400);
+        tree.insert(1, &value, &mut db)?;
+        db.set_compression(Some(64));
+        tree.insert(2, &value, &mut db)?;
+        tree.insert(3, &value[..32], &mut db)?;
+
+        let leaf = tree.find_leaf(1, &mut db)?;
+        let plain = leaf.entry(1).unwrap();
+        assert!(!plain.has_flag(LeafPageEntry::FLAG_COMPRESSED));
+        assert!(plain.has_flag(LeafPageEntry::FLAG_OVERFLOW));
+        let compressed = leaf.entry(2).unwrap();
+        assert!(compressed.has_flag(LeafPageEntry::FLAG_COMPRESSED));
+        assert!(!compressed.has_flag(LeafPageEntry::FLAG_OVERFLOW));
+        assert!(compressed.value_len < value.len() as u64);
+        assert!(!leaf
+            .entry(3)
+            .unwrap()
+            .has_flag(LeafPageEntry::FLAG_COMPRESSED));
+
+        // reading doesn't depend on the setting
+        db.set_compression(None);
+        for key in 1..=2 {
+            assert_eq!(tree.lookup(key, &mut db)?, Some(value.clone()));
+        }
+        let values = tree.iter(&mut db)?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(values[1], (2, value.clone()));
+        Ok(())
+    }
+
+    #[test]
+    fn prefix_scans_return_the_keys_starting_with_the_prefix() -> io::Result<()> {
+        fn key(name: &[u8], id: u8) -> Key {
+            let mut bytes = [0; 16];
+            bytes[..name.len()].copy_from_slice(name);
+            bytes[15] = id;
+            Key::from_be_bytes(bytes)
+        }
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        for id in 0..200 {
+            for name in [&b"user:"[..], b"users", b"usea:", b"user;"].iter() {
+                tree.insert(key(name, id), &[id], &mut db)?;
+            }
+        }
+
+        let found = tree
+            .prefix_scan(b"user:", &mut db)?
+            .collect::<Result<Vec<_>>>()?;
+        let expected: Vec<_> = (0..200).map(|id| (key(b"user:", id), vec![id])).collect();
+        assert_eq!(found, expected);
+        assert_eq!(tree.prefix_scan(b"user", &mut db)?.count(), 600);
+        assert_eq!(tree.prefix_scan(b"", &mut db)?.count(), 800);
+        assert_eq!(tree.prefix_scan(b"nobody", &mut db)?.count(), 0);
+        let err = tree.prefix_scan(&[0; 17], &mut db).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        Ok(())
+    }
+
+    #[test]
+    fn prefix_scans_stop_before_reading_the_next_value() -> io::Result<()> {
+        let key = |name: &[u8; 5], id: u8| {
+            let mut bytes = [0; 16];
+            bytes[..5].copy_from_slice(name);
+            bytes[15] = id;
+            Key::from_be_bytes(bytes)
+        };
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        for id in 0..10 {
+            tree.insert(key(b"user:", id), &[id], &mut db)?;
+        }
+        let blob: Vec<u8> = (0..16 * 1024).map(|i| (i % 251) as u8).collect();
+        tree.insert(key(b"user;", 0), &blob, &mut db)?;
+        let overflow = tree.overflow_pages(&mut db)?[0];
+
+        // a value that can't be read, just past the prefix
+        let mut disk = db.into_disk();
+        disk.get_mut()[overflow as usize] ^= 0xff;
+        let mut db = Database::from_existing(disk)?;
+        assert_eq!(tree.prefix_scan(b"user:", &mut db)?.count(), 10);
+        assert!(tree
+            .prefix_scan(b"user;", &mut db)?
+            .any(|entry| entry.is_err()));
+        Ok(())
+    }
+
+    #[test]
+    fn split_predictions_match_what_inserts_do() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        let mut splits = 0;
+        for key in 0..1_500 {
+            let len = if key % 100 == 0 { 5_000 } else { 60 };
+            let would_split = tree.insert_would_split(key, len, &mut db)?;
+            let pages = tree.pages(&mut db)?.len();
+            tree.insert(key, &vec![0; len as usize], &mut db)?;
+            let split = tree.pages(&mut db)?.len() > pages;
+            assert_eq!(would_split, split, "inserting key {}", key);
+            splits += split as usize;
+        }
+        assert!(splits > 10);
+        Ok(())
+    }
+
+    #[test]
+    fn updates_see_the_current_value() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        let add = |amount: u64| {
+            move |current: Option<&[u8]>| {
+                let total =
+                    current.map_or(0, |bytes| u64::from_be_bytes(bytes.try_into().unwrap()));
+                (total + amount).to_be_bytes().to_vec()
+            }
+        };
+        for key in 0..500 {
+            tree.insert(key, &[0; 40], &mut db)?;
+        }
+        for amount in 1..=10 {
+            tree.update_with(1_000, add(amount), &mut db)?;
+        }
+        assert_eq!(
+            tree.lookup(1_000, &mut db)?,
+            Some(55u64.to_be_bytes().to_vec())
+        );
+
+        // growing past what fits inline moves the value to overflow pages
+        tree.update_with(250, |current| current.unwrap().repeat(500), &mut db)?;
+        assert_eq!(tree.lookup(250, &mut db)?, Some(vec![0; 20_000]));
+        tree.update_with(250, |current| current.unwrap()[..3].to_vec(), &mut db)?;
+        assert_eq!(tree.lookup(250, &mut db)?, Some(vec![0; 3]));
+        assert_eq!(tree.verify(&mut db)?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn compare_and_set_only_writes_over_the_expected_value() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        for key in 0..500 {
+            tree.insert(key, &[0; 40], &mut db)?;
+        }
+        // creating a key only works once
+        assert!(tree.compare_and_set(1_000, None, b"created", &mut db)?);
+        assert!(!tree.compare_and_set(1_000, None, b"again", &mut db)?);
+        assert_eq!(tree.lookup(1_000, &mut db)?, Some(b"created".to_vec()));
+        assert_eq!(tree.len(&mut db)?, 501);
+
+        assert!(!tree.compare_and_set(1_000, Some(b"stale"), b"lost", &mut db)?);
+        assert!(tree.compare_and_set(1_000, Some(b"created"), b"updated", &mut db)?);
+        assert_eq!(tree.lookup(1_000, &mut db)?, Some(b"updated".to_vec()));
+        assert!(!tree.compare_and_set(2_000, Some(b"updated"), b"lost", &mut db)?);
+        assert_eq!(tree.lookup(2_000, &mut db)?, None);
+
+        let long = vec![7; 20_000];
+        assert!(tree.compare_and_set(250, Some(&[0; 40]), &long, &mut db)?);
+        assert!(tree.compare_and_set(250, Some(&long), &[1], &mut db)?);
+        assert_eq!(tree.lookup(250, &mut db)?, Some(vec![1]));
+        assert_eq!(tree.len(&mut db)?, 501);
+        assert_eq!(tree.verify(&mut db)?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn values_stream_in_and_out() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        let long: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        assert_eq!(tree.write_from(1, &mut &long[..], &mut db)?, 100_000);
+        assert_eq!(tree.write_from(2, &mut &b"short"[..], &mut db)?, 5);
+
+        let mut out = vec![];
+        assert_eq!(tree.read_into(1, &mut out, &mut db)?, Some(100_000));
+        assert_eq!(out, long);
+        out.clear();
+        assert_eq!(tree.read_into(2, &mut out, &mut db)?, Some(5));
+        assert_eq!(out, b"short");
+        assert_eq!(tree.read_into(3, &mut out, &mut db)?, None);
+        assert_eq!(tree.lookup(1, &mut db)?, Some(long));
+
+        // a stream over the limit frees what it wrote
+        db.set_max_value_len(Some(50_000));
+        let before = db.stats()?;
+        let err = tree
+            .write_from(3, &mut &[3; 60_000][..], &mut db)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let after = db.stats()?;
+        assert_eq!(
+            after.num_blocks_allocated - after.free_blocks,
+            before.num_blocks_allocated - before.free_blocks
+        );
+        assert_eq!(tree.read_into(3, &mut out, &mut db)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn deleting_down_to_one_key_leaves_a_single_leaf() -> io::Result<()> {
+        let mut db = Database::initialize_with_block_size(Cursor::new(vec![]), 1024)?;
+        let mut tree = BTree::init(&mut db)?;
+        // 601 is prime, so both multipliers step through every key once,
+        // in two different scrambled orders
+        for key in (0..600).map(|i| (i * 7_919) % 601) {
+            tree.insert(key, &[3; 300], &mut db)?;
+        }
+        assert!(tree.depth(&mut db)? >= 3);
+        for key in (0..601)
+            .map(|i| (i * 4_973) % 601)
+            .filter(|&key| key != 123)
+        {
+            tree.delete(key, &mut db)?;
+        }
+        assert_eq!(tree.depth(&mut db)?, 1);
+        assert_eq!(
+            tree.iter(&mut db)?.collect::<Result<Vec<_>>>()?,
+            vec![(123, vec![3; 300])]
+        );
+        assert_eq!(tree.verify(&mut db)?, vec![]);
+
+        let mut tree = BTree::bulk_load(&mut db, (0..1_500).map(|key| (key, vec![4; 300])))?;
+        assert_eq!(tree.depth(&mut db)?, 3);
+        for key in 1..1_500 {
+            tree.delete(key, &mut db)?;
+        }
+        assert_eq!(tree.depth(&mut db)?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn deep_trees_of_large_values_split_on_the_way_down() -> io::Result<()> {
+        for &policy in [crate::SplitPolicy::ByCount, crate::SplitPolicy::BySize].iter() {
+            let mut db = Database::initialize_with_block_size(Cursor::new(vec![]), 1024)?;
+            db.set_split_policy(policy);
+            let mut tree = BTree::init(&mut db)?;
+            let largest = db.page_layout().max_leaf_value_len as usize;
+            // values as long as a leaf stores scattered among short ones, so
+            // that halving a leaf by count can leave one half nearly full
+            let value = |key: u128| {
+                let len = if key * 2_654_435_761 % 97 % 3 == 1 {
+                    largest
+                } else {
+                    3
+                };
+                vec![key as u8; len]
+            };
+            let keys: Vec<u128> = (0..5_000).map(|i| i * 1_009 % 5_003).collect();
+            for &key in &keys {
+                tree.insert(key, &value(key), &mut db)?;
+            }
+            assert!(tree.depth(&mut db)? >= 3);
+            assert!(tree.verify(&mut db)?.is_empty());
+            for &key in &keys {
+                assert_eq!(tree.lookup(key, &mut db)?, Some(value(key)));
             }
         }
         Ok(())
@@ -0,0 +1,79 @@
+use super::Key;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use std::io::{self, Read};
+
+/// 128 bit FNV-1a, used to place byte string keys in the `Key` space.
+pub(crate) fn key_hash(key: &[u8]) -> Key {
+    let mut hash: u128 = 0x6c62_272e_07bb_0142_62b8_2175_6295_c58d;
+    for &byte in key {
+        hash ^= u128::from(byte);
+        hash = hash.wrapping_mul(0x0000_0000_0100_0000_0000_0000_0000_013b);
+    }
+    hash
+}
+
+/// Every byte string key that hashes to the same `Key`, with its value.
+/// A bucket is stored as the value under that hash, as a sequence of key
+/// length, key, value length, value.
+#[derive(Default)]
+pub(crate) struct Bucket {
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Bucket {
+    pub fn decode(mut buf: &[u8]) -> io::Result<Bucket> {
+        let mut entries = vec![];
+        while !buf.is_empty() {
+            let key = Bucket::read_field(&mut buf)?;
+            let value = Bucket::read_field(&mut buf)?;
+            entries.push((key, value));
+        }
+        Ok(Bucket { entries })
+    }
+    fn read_field(buf: &mut &[u8]) -> io::Result<Vec<u8>> {
+        let len = buf.read_u64::<BigEndian>()?;
+        if len > buf.len() as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "byte key bucket is cut short",
+            ));
+        }
+        let mut field = vec![0; len as usize];
+        buf.read_exact(&mut field)?;
+        Ok(field)
+    }
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        for (key, value) in &self.entries {
+            for field in &[key, value] {
+                buf.write_u64::<BigEndian>(field.len() as u64).unwrap();
+                buf.extend_from_slice(field);
+            }
+        }
+        buf
+    }
+    pub fn into_entries(self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.entries
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.entries
+            .iter()
+            .find(|(entry_key, _)| entry_key.as_slice() == key)
+            .map(|(_, value)| value.as_slice())
+    }
+    pub fn insert(&mut self, key: &[u8], data: &[u8]) {
+        self.remove(key);
+        self.entries.push((key.to_vec(), data.to_vec()));
+    }
+    pub fn remove(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        let idx = self
+            .entries
+            .iter()
+            .position(|(entry_key, _)| entry_key.as_slice() == key)?;
+        Some(self.entries.remove(idx).1)
+    }
+}
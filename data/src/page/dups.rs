@@ -0,0 +1,34 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use std::io::{self, Read};
+
+/// The values stored under one key by `BTree::insert_dup`, as a sequence of
+/// value length, value, oldest first. Adding a value only appends to what's
+/// there.
+pub(crate) fn record(value: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(8 + value.len());
+    buf.write_u64::<BigEndian>(value.len() as u64).unwrap();
+    buf.extend_from_slice(value);
+    buf
+}
+
+pub(crate) fn encode(values: &[Vec<u8>]) -> Vec<u8> {
+    values.iter().flat_map(|value| record(value)).collect()
+}
+
+pub(crate) fn decode(mut buf: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+    let mut values = vec![];
+    while !buf.is_empty() {
+        let len = buf.read_u64::<BigEndian>()?;
+        if len > buf.len() as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "list of duplicate values is cut short",
+            ));
+        }
+        let mut value = vec![0; len as usize];
+        buf.read_exact(&mut value)?;
+        values.push(value);
+    }
+    Ok(values)
+}
@@ -0,0 +1,123 @@
+use std::cmp::max;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The unused parts of a leaf's value region. Values are packed down from
+/// the end of the page, so there's the gap between the header and the lowest
+/// value, which the header grows into, and the holes above it left by values
+/// that were deleted or moved out. Holes are handed out best-fit and merge
+/// with their neighbours as they're freed.
+#[derive(Debug, Default)]
+pub(crate) struct FreeSpace {
+    /// Where the lowest value starts.
+    data_start: u64,
+    /// Holes by where they start, with their lengths.
+    holes: BTreeMap<u64, u64>,
+    /// The same holes by length, then start.
+    by_len: BTreeSet<(u64, u64)>,
+    /// The lengths of all the holes together.
+    hole_len: u64,
+}
+
+impl FreeSpace {
+    /// The free space of a value region ending at `end`, with values taking
+    /// up the given offsets and lengths.
+    pub fn new(end: u64, values: impl Iterator<Item = (u64, u64)>) -> FreeSpace {
+        let mut values: Vec<(u64, u64)> = values.filter(|&(_, len)| len > 0).collect();
+        values.sort_unstable();
+        let mut space = FreeSpace {
+            data_start: values.first().map_or(end, |&(offset, _)| offset),
+            ..FreeSpace::default()
+        };
+        let mut covered_to = space.data_start;
+        for (offset, len) in values {
+            if offset > covered_to {
+                space.add_hole(covered_to, offset - covered_to);
+            }
+            covered_to = max(covered_to, offset + len);
+        }
+        if end > covered_to {
+            space.add_hole(covered_to, end - covered_to);
+        }
+        space
+    }
+
+    pub fn data_start(&self) -> u64 {
+        self.data_start
+    }
+
+    /// Bytes lost to holes, which only defragmenting gets back in one piece.
+    pub fn hole_len(&self) -> u64 {
+        self.hole_len
+    }
+
+    /// Takes `len` bytes from the smallest hole they fit in.
+    pub fn take_hole(&mut self, len: u64) -> Option<u64> {
+        let &(hole_len, start) = self.by_len.range((len, 0)..).next()?;
+        self.remove_hole(start, hole_len);
+        if hole_len > len {
+            self.add_hole(start + len, hole_len - len);
+        }
+        Some(start)
+    }
+
+    /// Takes `len` bytes from just below the lowest value, as long as that
+    /// leaves them clear of a header ending at `header_end`.
+    pub fn take_below(&mut self, len: u64, header_end: u64) -> Option<u64> {
+        if self.data_start < header_end.checked_add(len)? {
+            return None;
+        }
+        self.data_start -= len;
+        Some(self.data_start)
+    }
+
+    /// Takes `len` bytes from the hole starting at `offset`, if there's one
+    /// that long.
+    pub fn take_at(&mut self, offset: u64, len: u64) -> bool {
+        match self.holes.get(&offset) {
+            Some(&hole_len) if hole_len >= len => {
+                self.remove_hole(offset, hole_len);
+                if hole_len > len {
+                    self.add_hole(offset + len, hole_len - len);
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Gives back the `len` bytes at `offset`.
+    pub fn release(&mut self, offset: u64, len: u64) {
+        if len == 0 {
+            return;
+        }
+        let (mut start, mut len) = (offset, len);
+        if let Some((&before, &before_len)) = self.holes.range(..start).next_back() {
+            if before + before_len == start {
+                self.remove_hole(before, before_len);
+                start = before;
+                len += before_len;
+            }
+        }
+        if let Some(&after_len) = self.holes.get(&(start + len)) {
+            self.remove_hole(start + len, after_len);
+            len += after_len;
+        }
+        if start == self.data_start {
+            self.data_start += len;
+        } else {
+            self.add_hole(start, len);
+        }
+    }
+
+    fn add_hole(&mut self, start: u64, len: u64) {
+        self.holes.insert(start, len);
+        self.by_len.insert((len, start));
+        self.hole_len += len;
+    }
+
+    fn remove_hole(&mut self, start: u64, len: u64) {
+        self.holes.remove(&start);
+        self.by_len.remove(&(len, start));
+        self.hole_len -= len;
+    }
+}
@@ -0,0 +1,179 @@
+use super::{read_free_block, Key, LeafPageEntry, OverflowRef, Page, PageOffset};
+use crate::{Database, Disk};
+
+use byteorder::ReadBytesExt;
+use std::io::{self, Seek, SeekFrom};
+
+/// An entry of a leaf, as `Database::inspect_page` shows it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LeafEntryInfo {
+    pub key: Key,
+    /// Where the stored value starts, from the start of the page, or `None`
+    /// for a value embedded in the entry.
+    pub offset: Option<u64>,
+    /// The length of the stored value, which for a value in overflow pages
+    /// or a compressed one isn't the length of the value itself.
+    pub value_len: u64,
+    pub flags: u8,
+    /// The stored value, when it's embedded in the entry rather than kept
+    /// in the page's value region.
+    pub embedded: Option<Vec<u8>>,
+}
+
+/// What a block of the file holds, from `Database::inspect_page`. It's a
+/// copy of what was read; nothing here changes the file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PageInfo {
+    Leaf {
+        entries: Vec<LeafEntryInfo>,
+        prev: Option<PageOffset>,
+        next: Option<PageOffset>,
+    },
+    /// `pointers[i]` leads to the keys up to and including `keys[i]`, and
+    /// the last pointer to everything above the last key.
+    Internal {
+        keys: Vec<Key>,
+        pointers: Vec<PageOffset>,
+    },
+    /// A page of a value too long to store in its leaf.
+    Overflow {
+        next: Option<PageOffset>,
+        /// The number of the value's bytes this page holds.
+        len: u64,
+    },
+    /// A block on the free list.
+    Free { next: Option<PageOffset> },
+}
+
+fn non_zero(offset: PageOffset) -> Option<PageOffset> {
+    Some(offset).filter(|&offset| offset != 0)
+}
+
+pub(crate) fn inspect<D: Disk>(offset: PageOffset, db: &mut Database<D>) -> io::Result<PageInfo> {
+    let block_size = db.block_size();
+    if offset == 0
+        || !offset.is_multiple_of(block_size)
+        || offset / block_size >= db.num_blocks_allocated()
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not the offset of an allocated page", offset),
+        ));
+    }
+    db.disk.seek(SeekFrom::Start(offset))?;
+    let info = match db.disk.read_u8()? {
+        Page::OVERFLOW_TAG => {
+            let (next, len) = OverflowRef::read_header(offset, &mut db.disk)?;
+            PageInfo::Overflow {
+                next: non_zero(next),
+                len,
+            }
+        }
+        Page::FREE_TAG => PageInfo::Free {
+            next: non_zero(read_free_block(offset, db)?),
+        },
+        _ => match Page::load(offset, db)? {
+            Page::Leaf(leaf) => PageInfo::Leaf {
+                entries: leaf
+                    .keys()
+                    .iter()
+                    .map(|entry| {
+                        let embedded = entry.has_flag(LeafPageEntry::FLAG_EMBEDDED);
+                        LeafEntryInfo {
+                            key: entry.key,
+                            offset: Some(entry.offset).filter(|_| !embedded),
+                            value_len: entry.value_len,
+                            flags: entry.flags,
+                            embedded: if embedded {
+                                Some(
+                                    entry.offset.to_be_bytes()[..entry.value_len as usize].to_vec(),
+                                )
+                            } else {
+                                None
+                            },
+                        }
+                    })
+                    .collect(),
+                prev: leaf.prev_leaf_offset(),
+                next: leaf.next_leaf_offset(),
+            },
+            Page::Internal(internal) => PageInfo::Internal {
+                keys: internal.keys().to_vec(),
+                pointers: internal.pointers().to_vec(),
+            },
+        },
+    };
+    Ok(info)
+}
+
+#[cfg(test)]
+mod inspect_tests {
+    use super::*;
+    use crate::BTree;
+    use std::io::Cursor;
+
+    #[test]
+    fn pages_of_every_kind_can_be_inspected() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        for key in 0..200 {
+            tree.insert(key, &[key as u8; 40], &mut db)?;
+        }
+        tree.insert(500, &[5; 10_000], &mut db)?;
+
+        let pointers = match db.inspect_page(tree.offset())? {
+            PageInfo::Internal { keys, pointers } => {
+                assert_eq!(pointers.len(), keys.len() + 1);
+                pointers
+            }
+            info => panic!("expected the root to be internal, found {:?}", info),
+        };
+        let last = *pointers.last().unwrap();
+        let entries = match db.inspect_page(pointers[0])? {
+            PageInfo::Leaf {
+                entries,
+                prev,
+                next,
+            } => {
+                assert_eq!((prev, next), (None, Some(pointers[1])));
+                entries
+            }
+            info => panic!("expected a leaf, found {:?}", info),
+        };
+        assert_eq!(entries[0].key, 0);
+        assert_eq!(entries[0].value_len, 40);
+        assert!(entries[0].offset.is_some() && entries[0].embedded.is_none());
+
+        tree.insert(1_000, &[7; 8], &mut db)?;
+        let leaf = *match db.inspect_page(tree.offset())? {
+            PageInfo::Internal { pointers, .. } => pointers,
+            info => panic!("expected the root to be internal, found {:?}", info),
+        }
+        .last()
+        .unwrap();
+        match db.inspect_page(leaf)? {
+            PageInfo::Leaf { entries, .. } => {
+                let entry = entries.last().unwrap();
+                assert_eq!(entry.key, 1_000);
+                assert_eq!(entry.offset, None);
+                assert_eq!(entry.embedded, Some(vec![7; 8]));
+            }
+            info => panic!("expected a leaf, found {:?}", info),
+        }
+
+        let overflow = tree.overflow_pages(&mut db)?[0];
+        match db.inspect_page(overflow)? {
+            PageInfo::Overflow { len, .. } => assert!(len > 0),
+            info => panic!("expected an overflow page, found {:?}", info),
+        }
+        tree.delete(500, &mut db)?;
+        assert!(matches!(db.inspect_page(overflow)?, PageInfo::Free { .. }));
+        assert!(matches!(db.inspect_page(last)?, PageInfo::Leaf { .. }));
+
+        for bad in [0, 1, db.block_size() * db.num_blocks_allocated()].iter() {
+            let err = db.inspect_page(*bad).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+        Ok(())
+    }
+}
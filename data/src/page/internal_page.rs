@@ -1,9 +1,9 @@
-use super::{Key, Page, PageOffset};
+use super::{corrupt_page, BTree, Key, LeafPage, Page, PageOffset};
 use crate::{BlockAllocator, Database, Disk};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
 use std::{
-    io::{self, SeekFrom},
+    io::{self, Seek},
     mem::size_of,
 };
 
@@ -36,6 +36,28 @@ impl InternalPage {
     pub fn pointer(&self, i: usize) -> PageOffset {
         self.pointers[i]
     }
+    /// The index of the pointer whose subtree covers `key`. `pointers[i]`
+    /// holds every key up to and including `keys[i]`, so a key equal to a
+    /// separator belongs to the child on its left, and anything past the
+    /// last separator to the last child.
+    pub fn child_index(&self, key: Key) -> usize {
+        match self.keys.binary_search(&key) {
+            Ok(i) | Err(i) => i,
+        }
+    }
+    /// A copy of this page at `offset`, with every child pointer passed
+    /// through `relocate`. Nothing is written until it's persisted.
+    pub fn moved_to(
+        &self,
+        offset: PageOffset,
+        relocate: impl Fn(PageOffset) -> PageOffset,
+    ) -> InternalPage {
+        InternalPage {
+            offset,
+            keys: self.keys.clone(),
+            pointers: self.pointers.iter().map(|&ptr| relocate(ptr)).collect(),
+        }
+    }
     pub fn safe_insert<D: Disk>(
         &mut self,
         i: usize,
@@ -52,21 +74,30 @@ impl InternalPage {
         self.pointers.remove(i + 1);
         self.persist(db)
     }
-    fn max_children_capacity(page_size: u64) -> u64 {
+    pub fn max_children_capacity(page_size: u64) -> u64 {
         // Solve[pageSize==head+n*childSize+(n-1)*keySize,n]
         let head_size = Self::header_size();
         let child_ptr_size = size_of::<PageOffset>() as u64;
         let key_size = size_of::<Key>() as u64;
-        (page_size + key_size - head_size) / (child_ptr_size + key_size)
+        (Page::content_end(page_size) + key_size - head_size) / (child_ptr_size + key_size)
     }
     pub fn can_accommodate(&self, page_size: u64) -> bool {
         (self.pointers.len() as u64) < InternalPage::max_children_capacity(page_size)
     }
-    fn header_size() -> u64 {
+    /// Whether this page should take children from a sibling after a
+    /// delete, on the same quarter-full threshold as leaves.
+    pub fn is_underfull(&self, page_size: u64) -> bool {
+        (self.pointers.len() as u64) < InternalPage::max_children_capacity(page_size) / 4
+    }
+    pub fn header_size() -> u64 {
         size_of::<u8>() as u64 + size_of::<u64>() as u64
     }
-    pub fn init<D: Disk>(db: &mut Database<D>, pointer: PageOffset) -> io::Result<InternalPage> {
-        let offset = db.allocate_block()?;
+    /// Writes a fresh internal page with a single child over an existing block.
+    pub fn init_at<D: Disk>(
+        db: &mut Database<D>,
+        offset: PageOffset,
+        pointer: PageOffset,
+    ) -> io::Result<InternalPage> {
         let page = InternalPage {
             offset,
             keys: vec![],
@@ -75,6 +106,32 @@ impl InternalPage {
         page.persist(db)?;
         Ok(page)
     }
+    /// Builds a level of internal pages over `children`, given as the last
+    /// key and the offset of each child in key order. The children are
+    /// spread evenly over as few pages as will hold them. Returns the last
+    /// key and the offset of every new page, in key order.
+    pub(crate) fn bulk_load<D: Disk>(
+        db: &mut Database<D>,
+        children: &[(Key, PageOffset)],
+    ) -> io::Result<Vec<(Key, PageOffset)>> {
+        let max_children = InternalPage::max_children_capacity(db.block_size()) as usize;
+        let pages = children.len().div_ceil(max_children);
+        let mut level = Vec::with_capacity(pages);
+        let mut rest = children;
+        for pages_left in (1..=pages).rev() {
+            let (group, tail) = rest.split_at(rest.len().div_ceil(pages_left));
+            rest = tail;
+            let (&(last_key, _), init) = group.split_last().unwrap();
+            let page = InternalPage {
+                offset: db.allocate_block()?,
+                keys: init.iter().map(|&(key, _)| key).collect(),
+                pointers: group.iter().map(|&(_, pointer)| pointer).collect(),
+            };
+            page.persist(db)?;
+            level.push((last_key, page.offset));
+        }
+        Ok(level)
+    }
     pub fn split_in_half<D: Disk>(
         &mut self,
         db: &mut Database<D>,
@@ -94,39 +151,224 @@ impl InternalPage {
         self.persist(db)?;
         Ok((new_right_sibling, key))
     }
-    pub fn delete_value<D: Disk>(&mut self, key: Key, db: &mut Database<D>) -> io::Result<()> {
-        let i = match self.keys.binary_search(&key) {
-            Ok(val) => val,
-            Err(val) => val,
-        };
+    pub fn delete_value<D: Disk>(
+        &mut self,
+        key: Key,
+        db: &mut Database<D>,
+    ) -> io::Result<Option<Vec<u8>>> {
+        let i = self.child_index(key);
         log::debug!("INTERNAL_DELETE_VALUE [i={}][ptr={}]", i, self.pointer(i));
-        let child = Page::load(self.pointer(i), db)?;
-        match child {
-            Page::Leaf(mut leaf) => {
+        let page_size = db.block_size();
+        let mut child = Page::load(self.pointer(i), db)?;
+        let (value, underfull) = match &mut child {
+            Page::Leaf(leaf) => {
                 log::debug!("DELETE_LEAF_VALUE");
-                leaf.delete_value(key, &mut db.disk)?;
-                if leaf.keys().is_empty() {
-                    let idx_to_remove = if i == 0 { 0 } else { i - 1 };
-                    self.safe_remove(idx_to_remove, db)?;
-                }
+                let value = leaf.take_value(key, db)?;
+                (value, leaf.is_underfull(page_size))
+            }
+            Page::Internal(internal) => {
+                let value = internal.delete_value(key, db)?;
+                (value, internal.is_underfull(page_size))
             }
-            Page::Internal(mut internal) => {
-                internal.delete_value(key, db)?;
-                if internal.keys.is_empty() {
-                    self.pointers[i] = internal.pointer(0);
-                    self.persist(db)?;
+        };
+        if value.is_some() && underfull {
+            self.rebalance_child(i, child, db)?;
+        }
+        Ok(value)
+    }
+    /// Removes every key from `start` up to but not including `end` below
+    /// this page, whose keys lie above `lower` and no higher than `upper`.
+    /// Only the children holding `start` and `end - 1` are descended into;
+    /// the ones between are freed without being read. The leaf chain and
+    /// page fill are left for the caller to fix up, with `LeafPage::link`
+    /// and `rebalance_range`. Returns how many keys were removed.
+    pub(crate) fn delete_range<D: Disk>(
+        &mut self,
+        start: Key,
+        end: Key,
+        lower: Option<Key>,
+        upper: Option<Key>,
+        db: &mut Database<D>,
+    ) -> io::Result<u64> {
+        let starts_here = lower.is_none_or(|lower| start > lower);
+        let ends_here = upper.is_none_or(|upper| end - 1 <= upper);
+        let covered_from = if starts_here {
+            self.child_index(start) + 1
+        } else {
+            0
+        };
+        let covered_to = if ends_here {
+            self.child_index(end - 1)
+        } else {
+            self.pointers.len()
+        };
+        let mut removed = 0;
+        if covered_from < covered_to {
+            // the separator left between the two ends, or on the side that
+            // has no end, still bounds what remains
+            let keys = if covered_to == self.pointers.len() {
+                covered_from - 1..covered_to - 1
+            } else {
+                covered_from..covered_to
+            };
+            for pointer in self.pointers.drain(covered_from..covered_to) {
+                removed += BTree::from_offset(pointer).free_all(db)?;
+            }
+            self.keys.drain(keys);
+            self.persist(db)?;
+        }
+        let mut ends = vec![];
+        if starts_here {
+            ends.push(self.child_index(start));
+        }
+        if ends_here && ends.last() != Some(&self.child_index(end - 1)) {
+            ends.push(self.child_index(end - 1));
+        }
+        for i in ends {
+            let child_lower = if i == 0 {
+                lower
+            } else {
+                Some(self.keys[i - 1])
+            };
+            let child_upper = self.keys.get(i).copied().or(upper);
+            removed += match Page::load(self.pointer(i), db)? {
+                Page::Leaf(mut leaf) => leaf.delete_range(start, end, db)?,
+                Page::Internal(mut internal) => {
+                    internal.delete_range(start, end, child_lower, child_upper, db)?
+                }
+            };
+        }
+        Ok(removed)
+    }
+    /// Rebalances the children either end of a range emptied by
+    /// `delete_range`, from the bottom up.
+    pub(crate) fn rebalance_range<D: Disk>(
+        &mut self,
+        start: Key,
+        end: Key,
+        db: &mut Database<D>,
+    ) -> io::Result<()> {
+        let page_size = db.block_size();
+        let first = self.child_index(start);
+        let last = self.child_index(end - 1);
+        // the right end first, so that merging it into the left one doesn't
+        // move the left one
+        for i in (first..=last).rev() {
+            let mut child = Page::load(self.pointer(i), db)?;
+            let underfull = match &mut child {
+                Page::Leaf(leaf) => leaf.is_underfull(page_size),
+                Page::Internal(internal) => {
+                    internal.rebalance_range(start, end, db)?;
+                    internal.is_underfull(page_size)
                 }
+            };
+            if underfull {
+                self.rebalance_child(i, child, db)?;
             }
         }
-
         Ok(())
     }
+    /// Evens out the child at `i`, already loaded as `child`, with a
+    /// neighbour, merging the two if they fit in one page. Only the
+    /// neighbour is read from disk, and this page is only written if its
+    /// keys change.
+    fn rebalance_child<D: Disk>(
+        &mut self,
+        i: usize,
+        child: Page,
+        db: &mut Database<D>,
+    ) -> io::Result<()> {
+        if self.pointers.len() < 2 {
+            return Ok(());
+        }
+        let (left_idx, left, right) = if i == 0 {
+            (0, child, Page::load(self.pointer(1), db)?)
+        } else {
+            (i - 1, Page::load(self.pointer(i - 1), db)?, child)
+        };
+        let separator = match (left, right) {
+            (Page::Leaf(mut left), Page::Leaf(mut right)) => {
+                LeafPage::rebalance(&mut left, &mut right, db)?
+            }
+            (Page::Internal(mut left), Page::Internal(mut right)) => {
+                InternalPage::rebalance(&mut left, &mut right, self.key(left_idx), db)?
+            }
+            _ => {
+                return Err(corrupt_page(
+                    self.offset,
+                    "children are at different depths",
+                ))
+            }
+        };
+        match separator {
+            Some(key) if key == self.keys[left_idx] => Ok(()),
+            Some(key) => {
+                self.keys[left_idx] = key;
+                self.persist(db)
+            }
+            None => {
+                let dropped = self.pointer(left_idx + 1);
+                self.safe_remove(left_idx, db)?;
+                Ok(db.free_block(dropped)?)
+            }
+        }
+    }
+    /// Evens out two neighbouring internal pages, `separator` being the key
+    /// between them in their parent. Returns the new separator, or `None` if
+    /// everything was merged into `left`.
+    fn rebalance<D: Disk>(
+        left: &mut InternalPage,
+        right: &mut InternalPage,
+        mut separator: Key,
+        db: &mut Database<D>,
+    ) -> io::Result<Option<Key>> {
+        let max_children = InternalPage::max_children_capacity(db.block_size()) as usize;
+        if left.pointers.len() + right.pointers.len() <= max_children {
+            log::debug!(
+                "INTERNAL_MERGE [left={}][right={}]",
+                left.offset,
+                right.offset
+            );
+            left.keys.push(separator);
+            left.keys.append(&mut right.keys);
+            left.pointers.append(&mut right.pointers);
+            left.persist(db)?;
+            return Ok(None);
+        }
+        // rotate children through the separator until the two are even
+        while left.pointers.len() + 1 < right.pointers.len() {
+            left.keys.push(separator);
+            left.pointers.push(right.pointers.remove(0));
+            separator = right.keys.remove(0);
+        }
+        while right.pointers.len() + 1 < left.pointers.len() {
+            right.keys.insert(0, separator);
+            right.pointers.insert(0, left.pointers.pop().unwrap());
+            separator = left.keys.pop().unwrap();
+        }
+        left.persist(db)?;
+        right.persist(db)?;
+        Ok(Some(separator))
+    }
     pub fn load<D: Disk>(db: &mut Database<D>) -> io::Result<InternalPage> {
-        let disk = &mut db.disk;
-        let offset = disk.seek(SeekFrom::Current(0))?;
+        let page_size = db.block_size();
+        let offset = db.disk.stream_position()?;
+        let image = Page::read_verified(&mut db.disk, page_size)?;
+        let disk = &mut &image[..];
         let tag = disk.read_u8()?;
-        assert_eq!(tag, Page::INTERNAL_TAG);
+        if tag != Page::INTERNAL_TAG {
+            return Err(corrupt_page(
+                offset,
+                &format!("expected an internal page, found tag {}", tag),
+            ));
+        }
         let keys_len = disk.read_u64::<BigEndian>()? as usize;
+        if keys_len as u64 >= InternalPage::max_children_capacity(page_size) {
+            return Err(corrupt_page(
+                offset,
+                &format!("key count {} does not fit in the page", keys_len),
+            ));
+        }
         let mut keys = Vec::with_capacity(keys_len);
         for _ in 0..keys_len {
             keys.push(disk.read_u128::<BigEndian>()?);
@@ -141,21 +383,24 @@ impl InternalPage {
             pointers,
         })
     }
+    /// Writes out the whole page, so the checksum covers nothing left over
+    /// from whatever the block held before.
     pub fn persist<D: Disk>(&self, db: &mut Database<D>) -> io::Result<()> {
-        assert!(InternalPage::max_children_capacity(db.block_size()) >= self.pointers.len() as u64);
-        let disk = &mut db.disk;
-        disk.seek(SeekFrom::Start(self.offset))?;
-        let keys_len = self.keys.len();
-        disk.write_u8(Page::INTERNAL_TAG)?;
-        disk.write_u64::<BigEndian>(keys_len as u64)?;
-        assert_eq!(self.pointers.len(), keys_len + 1);
+        let page_size = db.block_size();
+        assert!(InternalPage::max_children_capacity(page_size) >= self.pointers.len() as u64);
+        assert_eq!(self.pointers.len(), self.keys.len() + 1);
+        let mut image = Vec::with_capacity(page_size as usize);
+        image.write_u8(Page::INTERNAL_TAG)?;
+        image.write_u64::<BigEndian>(self.keys.len() as u64)?;
         for &key in self.keys.iter() {
-            disk.write_u128::<BigEndian>(key)?;
+            image.write_u128::<BigEndian>(key)?;
         }
         for &ptr in self.pointers.iter() {
-            disk.write_u64::<BigEndian>(ptr)?;
+            image.write_u64::<BigEndian>(ptr)?;
         }
-        Ok(())
+        image.resize(page_size as usize, 0);
+        Page::seal(&mut image);
+        db.write(self.offset, &image)
     }
 }
 
@@ -167,4 +412,19 @@ mod internal_page_tests {
         assert_eq!(InternalPage::max_children_capacity(2048), 85);
         assert_eq!(InternalPage::max_children_capacity(4096), 170);
     }
+    #[test]
+    fn separator_keys_route_to_the_left_child() {
+        let page = InternalPage {
+            offset: 0,
+            keys: vec![10, 20, 30],
+            pointers: vec![100, 200, 300, 400],
+        };
+        assert_eq!(page.child_index(0), 0);
+        assert_eq!(page.child_index(10), 0);
+        assert_eq!(page.child_index(11), 1);
+        assert_eq!(page.child_index(20), 1);
+        assert_eq!(page.child_index(30), 2);
+        assert_eq!(page.child_index(31), 3);
+        assert_eq!(page.child_index(Key::MAX), 3);
+    }
 }
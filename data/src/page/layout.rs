@@ -0,0 +1,81 @@
+use super::{InternalPage, LeafPage, LeafPageEntry, OverflowRef, Page};
+
+/// Where things go in the pages of a file with a given block size, for
+/// tools that read files without going through a `Database`. All of it
+/// follows from the block size.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PageLayout {
+    pub block_size: u64,
+    /// Where the contents of a leaf or internal page have to stop, ahead of
+    /// its entry count and checksum.
+    pub page_end: u64,
+    /// The length of a leaf's header before its entries.
+    pub leaf_header_len: u64,
+    /// The length of each entry in a leaf's header.
+    pub leaf_entry_len: u64,
+    /// The longest value a leaf stores itself rather than in overflow pages.
+    pub max_leaf_value_len: u64,
+    /// The longest value that fits in its leaf entry.
+    pub max_embedded_len: u64,
+    /// The length of an internal page's header before its keys and pointers.
+    pub internal_header_len: u64,
+    /// The most children an internal page holds.
+    pub max_children: u64,
+    /// The length of an overflow page's header before its share of the value.
+    pub overflow_header_len: u64,
+}
+
+impl PageLayout {
+    /// The smallest block size a file can have, which still leaves room for
+    /// a few entries in a leaf and a few children in an internal page.
+    pub const MIN_BLOCK_SIZE: u64 = 256;
+    /// The largest block size a file can have.
+    pub const MAX_BLOCK_SIZE: u64 = 1 << 24;
+
+    /// The layout of pages `block_size` bytes long, or `None` if that isn't
+    /// a power of two between `MIN_BLOCK_SIZE` and `MAX_BLOCK_SIZE`.
+    pub fn for_block_size(block_size: u64) -> Option<PageLayout> {
+        let supported = block_size.is_power_of_two()
+            && (PageLayout::MIN_BLOCK_SIZE..=PageLayout::MAX_BLOCK_SIZE).contains(&block_size);
+        if supported {
+            Some(PageLayout::of(block_size))
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn of(block_size: u64) -> PageLayout {
+        PageLayout {
+            block_size,
+            page_end: Page::content_end(block_size),
+            leaf_header_len: LeafPage::header_len_for(0),
+            leaf_entry_len: LeafPageEntry::size_of_entry(),
+            max_leaf_value_len: LeafPage::max_inline_len(block_size),
+            max_embedded_len: LeafPageEntry::MAX_EMBEDDED_LEN,
+            internal_header_len: InternalPage::header_size(),
+            max_children: InternalPage::max_children_capacity(block_size),
+            overflow_header_len: OverflowRef::header_len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod layout_tests {
+    use super::*;
+
+    #[test]
+    fn only_powers_of_two_in_range_have_a_layout() {
+        for &block_size in [0, 128, 255, 257, 3000, 1 << 25].iter() {
+            assert_eq!(PageLayout::for_block_size(block_size), None);
+        }
+        let smallest = PageLayout::for_block_size(PageLayout::MIN_BLOCK_SIZE).unwrap();
+        // an overflowed value's reference has to fit in the leaf
+        assert!(smallest.max_leaf_value_len >= OverflowRef::SIZE as u64);
+        assert!(smallest.max_children >= 3);
+
+        let layout = PageLayout::for_block_size(4096).unwrap();
+        assert_eq!(layout.max_children, 170);
+        assert_eq!(layout.leaf_entry_len, 33);
+        assert_eq!(layout.page_end, 4096 - 12);
+    }
+}
@@ -1,9 +1,11 @@
-use super::{Key, Page, PageOffset};
-use crate::{BlockAllocator, Database, Disk};
+use super::free_space::FreeSpace;
+use super::{corrupt_page, Key, OverflowRef, Page, PageOffset, ValueTooLarge};
+use crate::{BlockAllocator, Database, Disk, SplitPolicy};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
 use std::{
-    io::{self, SeekFrom},
+    borrow::Cow,
+    io::{self, Read, Seek, SeekFrom, Write},
     mem::size_of,
 };
 
@@ -12,21 +14,80 @@ pub struct LeafPageEntry {
     pub key: Key,
     pub offset: PageOffset,
     pub value_len: u64,
+    pub flags: u8,
 }
 
 impl LeafPageEntry {
-    const fn size_of_entry() -> u64 {
+    /// The value is a reference to be resolved by a `ValueSource` rather
+    /// than the value itself.
+    pub const FLAG_REFERENCE: u8 = 0x01;
+    /// The value lives in a chain of overflow pages and the entry holds an
+    /// `OverflowRef` to it.
+    pub const FLAG_OVERFLOW: u8 = 0x02;
+    /// The value was compressed by the database's `Compressor`, and
+    /// `value_len` is the compressed length.
+    pub const FLAG_COMPRESSED: u8 = 0x04;
+    /// The value is kept in the entry itself, in the bytes `offset` would
+    /// otherwise take, and has no place in the value region.
+    pub const FLAG_EMBEDDED: u8 = 0x08;
+    /// The longest value that can be embedded in an entry.
+    pub const MAX_EMBEDDED_LEN: u64 = size_of::<PageOffset>() as u64;
+
+    pub const fn size_of_entry() -> u64 {
         // this function is const, so it doesn't
         // really matter what work we do here
         let key_size = std::mem::size_of::<Key>() as u64;
         let other_size = std::mem::size_of::<u64>() as u64;
-        key_size + other_size * 2
+        let flags_size = std::mem::size_of::<u8>() as u64;
+        key_size + other_size * 2 + flags_size
+    }
+    pub fn has_flag(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+    /// Bytes the value takes up in the page's value region.
+    pub fn stored_len(&self) -> u64 {
+        if self.has_flag(LeafPageEntry::FLAG_EMBEDDED) {
+            0
+        } else {
+            self.value_len
+        }
+    }
+    /// An entry for `stored`, embedding it when it's no longer than
+    /// `embed_threshold`.
+    fn embedding(key: Key, stored: &[u8], flags: u8, embed_threshold: u64) -> LeafPageEntry {
+        let len = stored.len() as u64;
+        let flags = flags & !LeafPageEntry::FLAG_EMBEDDED;
+        if len == 0 || len > embed_threshold.min(LeafPageEntry::MAX_EMBEDDED_LEN) {
+            return LeafPageEntry {
+                key,
+                offset: 0,
+                value_len: len,
+                flags,
+            };
+        }
+        let mut packed = [0u8; size_of::<PageOffset>()];
+        packed[..stored.len()].copy_from_slice(stored);
+        LeafPageEntry {
+            key,
+            offset: PageOffset::from_be_bytes(packed),
+            value_len: len,
+            flags: flags | LeafPageEntry::FLAG_EMBEDDED,
+        }
     }
 }
 
 pub struct LeafPage {
     offset: u64,
+    page_size: u64,
+    /// The leaf holding the next keys up, or 0 for the last leaf.
+    next_leaf: PageOffset,
+    /// The leaf holding the next keys down, or 0 for the first leaf.
+    prev_leaf: PageOffset,
     keys: Vec<LeafPageEntry>,
+    free: FreeSpace,
+    /// The whole page as it stands on the disk, kept up to date with every
+    /// write so the checksum can be worked out without reading it back.
+    image: Vec<u8>,
 }
 
 impl LeafPage {
@@ -36,175 +97,700 @@ impl LeafPage {
     pub fn keys(&self) -> &[LeafPageEntry] {
         &self.keys
     }
-    fn seek_to_offset(&self, disk: &mut impl Disk) -> io::Result<()> {
-        disk.seek(SeekFrom::Start(self.offset))?;
-        Ok(())
+    /// Where `next_leaf` sits in the page image.
+    pub const NEXT_LEAF_POS: usize = size_of::<u8>() + size_of::<u64>();
+    /// Where `prev_leaf` sits in the page image.
+    pub const PREV_LEAF_POS: usize = LeafPage::NEXT_LEAF_POS + size_of::<u64>();
+
+    pub fn next_leaf_offset(&self) -> Option<PageOffset> {
+        match self.next_leaf {
+            0 => None,
+            offset => Some(offset),
+        }
+    }
+    pub fn prev_leaf_offset(&self) -> Option<PageOffset> {
+        match self.prev_leaf {
+            0 => None,
+            offset => Some(offset),
+        }
+    }
+    pub fn next_leaf<D: Disk>(&self, db: &mut Database<D>) -> io::Result<Option<LeafPage>> {
+        match self.next_leaf_offset() {
+            Some(offset) => self.sibling(offset, db).map(Some),
+            None => Ok(None),
+        }
     }
-    pub(crate) fn persist_header(&self, disk: &mut impl Disk) -> io::Result<()> {
+    pub fn prev_leaf<D: Disk>(&self, db: &mut Database<D>) -> io::Result<Option<LeafPage>> {
+        match self.prev_leaf_offset() {
+            Some(offset) => self.sibling(offset, db).map(Some),
+            None => Ok(None),
+        }
+    }
+    fn sibling<D: Disk>(&self, offset: PageOffset, db: &mut Database<D>) -> io::Result<LeafPage> {
+        match Page::load(offset, db)? {
+            Page::Leaf(leaf) => Ok(leaf),
+            Page::Internal(_) => Err(corrupt_page(
+                self.offset,
+                &format!("sibling leaf {} is an internal page", offset),
+            )),
+        }
+    }
+    /// Points the `prev_leaf` of the leaf at `offset` at `prev_leaf`.
+    fn relink_prev<D: Disk>(
+        offset: PageOffset,
+        prev_leaf: PageOffset,
+        db: &mut Database<D>,
+    ) -> io::Result<()> {
+        let page_size = db.block_size();
+        db.disk.seek(SeekFrom::Start(offset))?;
+        let mut leaf = LeafPage::read_header(&mut db.disk, page_size)?;
+        leaf.prev_leaf = prev_leaf;
+        let len = leaf.keys.len();
+        leaf.persist_header_offset(db, len)
+    }
+    /// Makes the leaves at `left` and `right` neighbours in the leaf chain.
+    pub(crate) fn link<D: Disk>(
+        left: PageOffset,
+        right: PageOffset,
+        db: &mut Database<D>,
+    ) -> io::Result<()> {
+        let page_size = db.block_size();
+        db.disk.seek(SeekFrom::Start(left))?;
+        let mut leaf = LeafPage::read_header(&mut db.disk, page_size)?;
+        leaf.next_leaf = right;
+        let len = leaf.keys.len();
+        leaf.persist_header_offset(db, len)?;
+        LeafPage::relink_prev(right, left, db)
+    }
+    /// Writes `data` at `pos` within the page, both to the disk and to the
+    /// image kept in memory.
+    fn write_at<D: Disk>(&mut self, pos: u64, data: &[u8], db: &mut Database<D>) -> io::Result<()> {
+        let start = pos as usize;
+        self.image[start..start + data.len()].copy_from_slice(data);
+        db.write(self.offset + pos, data)
+    }
+    pub(crate) fn persist_header<D: Disk>(&mut self, db: &mut Database<D>) -> io::Result<()> {
         log::debug!(
             "PERSIST_HEADER [offset={}][keys_len={}]",
             self.offset,
             self.keys.len()
         );
-        self.persist_header_offset(disk, 0)
-    }
-    fn persist_header_offset(&self, disk: &mut impl Disk, offset: usize) -> io::Result<()> {
-        self.seek_to_offset(disk)?;
-        disk.write_u8(Page::LEAF_TAG)?;
-        disk.write_u64::<BigEndian>(self.keys.len() as u64)?;
-        disk.seek(SeekFrom::Current(
-            (offset as u64 * LeafPageEntry::size_of_entry()) as i64,
-        ))?;
-        for entry in self.keys.iter().skip(offset) {
-            disk.write_u128::<BigEndian>(entry.key)?;
-            disk.write_u64::<BigEndian>(entry.offset)?;
-            disk.write_u64::<BigEndian>(entry.value_len)?;
+        // one write for the whole header, so a crash can't leave the
+        // count and the entries out of step with each other
+        let mut buf = Vec::with_capacity(self.header_len() as usize);
+        self.write_header_prefix(&mut buf)?;
+        LeafPage::write_entries(&mut buf, &self.keys)?;
+        self.write_at(0, &buf, db)?;
+        self.persist_checksum(db)
+    }
+    /// Like `persist_header`, but only writes the entries from `offset` on,
+    /// for when the ones before it are already on the disk.
+    fn persist_header_offset<D: Disk>(
+        &mut self,
+        db: &mut Database<D>,
+        offset: usize,
+    ) -> io::Result<()> {
+        // The entries go out before the count that covers them: if we crash
+        // in between, the old count never claims entries that weren't written.
+        let mut buf = vec![];
+        LeafPage::write_entries(&mut buf, &self.keys[offset..])?;
+        self.write_at(LeafPage::header_len_for(offset as u64), &buf, db)?;
+
+        buf.clear();
+        self.write_header_prefix(&mut buf)?;
+        self.write_at(0, &buf, db)?;
+        self.persist_checksum(db)
+    }
+    /// Checksums the page from its image in memory. Only the entry count is
+    /// read back, as the tree keeps that in its root page and writes it on
+    /// its own. If the database zeroes free space, whatever lies outside the
+    /// header and the values is cleared on the way and the whole image
+    /// written back.
+    fn persist_checksum<D: Disk>(&mut self, db: &mut Database<D>) -> io::Result<()> {
+        let entry_count = Page::entry_count_pos(self.page_size) as usize
+            ..Page::checksum_pos(self.page_size) as usize;
+        db.disk
+            .seek(SeekFrom::Start(self.offset + entry_count.start as u64))?;
+        db.disk.read_exact(&mut self.image[entry_count.clone()])?;
+        if !db.zeroes_free_space() {
+            Page::seal(&mut self.image);
+            let checksum = entry_count.end;
+            return db.write(self.offset + checksum as u64, &self.image[checksum..]);
+        }
+        let mut canonical = vec![0u8; self.image.len()];
+        let header_len = self.header_len() as usize;
+        canonical[..header_len].copy_from_slice(&self.image[..header_len]);
+        canonical[entry_count.clone()].copy_from_slice(&self.image[entry_count]);
+        // an embedded value's offset is its bytes, not a place in the page
+        for entry in self
+            .keys
+            .iter()
+            .filter(|entry| !entry.has_flag(LeafPageEntry::FLAG_EMBEDDED))
+        {
+            let value = entry.offset as usize..(entry.offset + entry.value_len) as usize;
+            canonical[value.clone()].copy_from_slice(&self.image[value]);
+        }
+        Page::seal(&mut canonical);
+        self.image = canonical;
+        db.write(self.offset, &self.image)
+    }
+    fn write_header_prefix(&self, buf: &mut Vec<u8>) -> io::Result<()> {
+        buf.write_u8(Page::LEAF_TAG)?;
+        buf.write_u64::<BigEndian>(self.keys.len() as u64)?;
+        buf.write_u64::<BigEndian>(self.next_leaf)?;
+        buf.write_u64::<BigEndian>(self.prev_leaf)
+    }
+    fn write_entries(buf: &mut Vec<u8>, entries: &[LeafPageEntry]) -> io::Result<()> {
+        for entry in entries {
+            buf.write_u128::<BigEndian>(entry.key)?;
+            buf.write_u64::<BigEndian>(entry.offset)?;
+            buf.write_u64::<BigEndian>(entry.value_len)?;
+            buf.write_u8(entry.flags)?;
         }
         Ok(())
     }
-    pub(crate) fn read_header(disk: &mut impl Disk) -> io::Result<LeafPage> {
-        let offset = disk.seek(SeekFrom::Current(0))?;
-        assert_eq!(disk.read_u8()?, Page::LEAF_TAG);
+    /// Reads the header of the leaf at the current disk position. The page
+    /// has to match its checksum, and the count and entries are checked
+    /// against each other and against `page_size` before being trusted, so
+    /// a torn header write is reported as `InvalidData` instead of handing
+    /// back garbage entries.
+    pub(crate) fn read_header(disk: &mut impl Disk, page_size: u64) -> io::Result<LeafPage> {
+        let offset = disk.stream_position()?;
+        let image = Page::read_verified(disk, page_size)?;
+        let disk = &mut &image[..];
+        let tag = disk.read_u8()?;
+        if tag != Page::LEAF_TAG {
+            return Err(corrupt_page(
+                offset,
+                &format!("expected a leaf page, found tag {}", tag),
+            ));
+        }
         let len = disk.read_u64::<BigEndian>()?;
-        let mut buf = Vec::with_capacity(len as usize);
+        let next_leaf = disk.read_u64::<BigEndian>()?;
+        let prev_leaf = disk.read_u64::<BigEndian>()?;
+        for sibling in [next_leaf, prev_leaf] {
+            if sibling % page_size != 0 {
+                return Err(corrupt_page(
+                    offset,
+                    &format!("sibling leaf {} is not on a block boundary", sibling),
+                ));
+            }
+        }
+        if len > page_size / LeafPageEntry::size_of_entry() {
+            return Err(corrupt_page(
+                offset,
+                &format!("entry count {} does not fit in the page", len),
+            ));
+        }
+        let header_len = LeafPage::header_len_for(len);
+        let mut buf: Vec<LeafPageEntry> = Vec::with_capacity(len as usize);
         for _ in 0..len {
             let key = disk.read_u128::<BigEndian>()?;
-            let offset = disk.read_u64::<BigEndian>()?;
+            let value_offset = disk.read_u64::<BigEndian>()?;
             let value_len = disk.read_u64::<BigEndian>()?;
+            let flags = disk.read_u8()?;
+            if let Some(prev) = buf.last() {
+                if prev.key >= key {
+                    return Err(corrupt_page(offset, "entries are not sorted by key"));
+                }
+            }
+            // an empty value takes no room, so where it claims to be doesn't
+            // matter, and older leaves may have put it anywhere
+            let in_range = if flags & LeafPageEntry::FLAG_EMBEDDED != 0 {
+                value_len <= LeafPageEntry::MAX_EMBEDDED_LEN
+            } else if value_len == 0 {
+                true
+            } else {
+                value_offset >= header_len
+                    && value_offset
+                        .checked_add(value_len)
+                        .is_some_and(|end| end <= Page::content_end(page_size))
+            };
+            if !in_range {
+                return Err(corrupt_page(
+                    offset,
+                    &format!("value of key {} lies outside the data region", key),
+                ));
+            }
             buf.push(LeafPageEntry {
                 key,
-                offset,
+                offset: value_offset,
                 value_len,
+                flags,
             });
         }
-        Ok(LeafPage { offset, keys: buf })
+        let free = LeafPage::free_space_of(&buf, page_size);
+        Ok(LeafPage {
+            offset,
+            page_size,
+            next_leaf,
+            prev_leaf,
+            keys: buf,
+            free,
+            image,
+        })
     }
 
-    fn header_len(&self) -> u64 {
-        LeafPageEntry::size_of_entry() * self.keys.len() as u64
-            + std::mem::size_of::<u64>() as u64
+    pub fn header_len_for(keys_len: u64) -> u64 {
+        LeafPageEntry::size_of_entry() * keys_len
+            + size_of::<u64>() as u64 * 3
             + size_of::<u8>() as u64
     }
 
+    fn free_space_of(entries: &[LeafPageEntry], page_size: u64) -> FreeSpace {
+        FreeSpace::new(
+            Page::content_end(page_size),
+            entries
+                .iter()
+                .map(|entry| (entry.offset, entry.stored_len())),
+        )
+    }
+
+    fn header_len(&self) -> u64 {
+        LeafPage::header_len_for(self.keys.len() as u64)
+    }
+
+    /// The longest value stored in the leaf itself. Anything longer goes to
+    /// overflow pages, so that a leaf always has room for a few entries.
+    pub fn max_inline_len(page_size: u64) -> u64 {
+        (Page::content_end(page_size) - LeafPage::header_len_for(0)) / 4
+            - LeafPageEntry::size_of_entry()
+    }
+
+    /// Bytes of the page taken up by the header and values together.
+    fn space_for<'a>(entries: impl ExactSizeIterator<Item = &'a LeafPageEntry>) -> u64 {
+        let header_len = LeafPage::header_len_for(entries.len() as u64);
+        header_len + entries.map(LeafPageEntry::stored_len).sum::<u64>()
+    }
+
+    /// Bytes of the page taken up by the header and the values.
+    pub fn used_space(&self) -> u64 {
+        LeafPage::space_for(self.keys.iter())
+    }
+
+    /// Whether deleting from this page should pull in entries from a
+    /// sibling. A quarter rather than half full, since pages come out of a
+    /// split only about half full and would otherwise start rebalancing on
+    /// their first delete.
+    pub fn is_underfull(&self, page_size: u64) -> bool {
+        self.used_space() < page_size / 4
+    }
+
+    /// The longest value that fits in the page as a new entry, or `None`
+    /// if there isn't even room for the entry itself.
+    fn room_for_entry(&self, page_size: u64) -> Option<u64> {
+        let header_len = LeafPage::header_len_for(self.keys.len() as u64 + 1);
+        let values_len: u64 = self.keys.iter().map(LeafPageEntry::stored_len).sum();
+        Page::content_end(page_size).checked_sub(header_len + values_len)
+    }
+
+    /// The longest value that fits in the page as a new entry.
+    pub fn room_for_value(&self, page_size: u64) -> u64 {
+        self.room_for_entry(page_size).unwrap_or(0)
+    }
+
     pub fn can_accommodate(&self, data_len: u64, page_size: u64) -> bool {
-        if self.keys.is_empty() {
-            return true;
+        self.room_for_entry(page_size)
+            .is_some_and(|room| data_len <= room)
+    }
+
+    pub fn has_key(&self, key: Key) -> bool {
+        self.entry(key).is_some()
+    }
+
+    pub fn entry(&self, key: Key) -> Option<&LeafPageEntry> {
+        self.keys
+            .binary_search_by_key(&key, |entry| entry.key)
+            .ok()
+            .map(|idx| &self.keys[idx])
+    }
+
+    /// Reads the bytes stored in the page for an entry, which for overflowed
+    /// values is the `OverflowRef` rather than the value.
+    fn read_stored_value(
+        &self,
+        entry: &LeafPageEntry,
+        disk: &mut impl Disk,
+    ) -> io::Result<Vec<u8>> {
+        if entry.has_flag(LeafPageEntry::FLAG_EMBEDDED) {
+            return Ok(entry.offset.to_be_bytes()[..entry.value_len as usize].to_vec());
         }
-        let space_taken_up: u64 = self.keys.iter().map(|entry| entry.value_len).sum();
-        let space_in_page_for_data = {
-            let header_stop_offset = self.header_len();
-            page_size - header_stop_offset
+        let mut buf = vec![0; entry.value_len as usize];
+        disk.seek(SeekFrom::Start(self.offset + entry.offset))?;
+        disk.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    pub(crate) fn overflow_ref(
+        &self,
+        entry: &LeafPageEntry,
+        disk: &mut impl Disk,
+    ) -> io::Result<Option<OverflowRef>> {
+        if !entry.has_flag(LeafPageEntry::FLAG_OVERFLOW) {
+            return Ok(None);
+        }
+        match OverflowRef::from_bytes(&self.read_stored_value(entry, disk)?) {
+            Some(overflow) => Ok(Some(overflow)),
+            None => Err(corrupt_page(
+                self.offset,
+                &format!("bad overflow reference for key {}", entry.key),
+            )),
+        }
+    }
+
+    /// Reads an entry's value, following it to its overflow pages and
+    /// decompressing it as its flags say.
+    pub(crate) fn read_value<D: Disk>(
+        &self,
+        entry: &LeafPageEntry,
+        db: &mut Database<D>,
+    ) -> io::Result<Vec<u8>> {
+        let stored = match self.overflow_ref(entry, &mut db.disk)? {
+            Some(overflow) => overflow.read(overflow.len, &mut db.disk)?,
+            None => self.read_stored_value(entry, &mut db.disk)?,
         };
-        let space_available = space_in_page_for_data - space_taken_up;
-        return space_available >= data_len + LeafPageEntry::size_of_entry();
+        if entry.has_flag(LeafPageEntry::FLAG_COMPRESSED) {
+            return db.decompress(&stored);
+        }
+        Ok(stored)
     }
 
-    pub(crate) fn lookup_value(
+    /// Copies an entry's value to `out`, returning its length. Values in
+    /// overflow pages are copied a page at a time rather than read whole.
+    pub(crate) fn copy_value_to<D: Disk>(
+        &self,
+        entry: &LeafPageEntry,
+        out: &mut impl Write,
+        db: &mut Database<D>,
+    ) -> io::Result<u64> {
+        // compressed values can only be inflated whole
+        if !entry.has_flag(LeafPageEntry::FLAG_COMPRESSED) {
+            if let Some(overflow) = self.overflow_ref(entry, &mut db.disk)? {
+                return overflow.copy_to(out, &mut db.disk);
+            }
+        }
+        let value = self.read_value(entry, db)?;
+        out.write_all(&value)?;
+        Ok(value.len() as u64)
+    }
+
+    pub(crate) fn lookup_value<D: Disk>(
         &self,
         key: Key,
         data: &mut Vec<u8>,
-        disk: &mut impl Disk,
+        db: &mut Database<D>,
     ) -> io::Result<Option<u64>> {
-        self.seek_to_offset(disk)?;
-        let entry = self.keys.iter().find(|entry| entry.key == key);
-        let entry = match entry {
+        let entry = match self.entry(key) {
             Some(entry) => entry,
             None => return Ok(None),
         };
+        *data = self.read_value(entry, db)?;
+        Ok(Some(data.len() as u64))
+    }
 
-        disk.seek(SeekFrom::Current(entry.offset as i64))?;
-        data.resize(entry.value_len as usize, 0);
-        disk.read_exact(&mut data[..])?;
-        return Ok(Some(entry.value_len));
+    /// Reads at most `buf.len()` leading bytes of an entry's value, returning
+    /// how many were read.
+    pub(crate) fn read_value_prefix<D: Disk>(
+        &self,
+        entry: &LeafPageEntry,
+        buf: &mut [u8],
+        db: &mut Database<D>,
+    ) -> io::Result<usize> {
+        if entry.has_flag(LeafPageEntry::FLAG_COMPRESSED) {
+            // there's no telling where the prefix ends without inflating
+            // the whole value
+            let value = self.read_value(entry, db)?;
+            let len = std::cmp::min(buf.len(), value.len());
+            buf[..len].copy_from_slice(&value[..len]);
+            return Ok(len);
+        }
+        let disk = &mut db.disk;
+        if let Some(overflow) = self.overflow_ref(entry, disk)? {
+            let prefix = overflow.read(buf.len() as u64, disk)?;
+            buf[..prefix.len()].copy_from_slice(&prefix);
+            return Ok(prefix.len());
+        }
+        let len = std::cmp::min(buf.len() as u64, entry.value_len) as usize;
+        if entry.has_flag(LeafPageEntry::FLAG_EMBEDDED) {
+            buf[..len].copy_from_slice(&entry.offset.to_be_bytes()[..len]);
+            return Ok(len);
+        }
+        disk.seek(SeekFrom::Start(self.offset + entry.offset))?;
+        disk.read_exact(&mut buf[..len])?;
+        Ok(len)
     }
 
-    pub(crate) fn lookup_value_alloc(
+    pub(crate) fn lookup_value_alloc<D: Disk>(
         &self,
         key: Key,
-        disk: &mut impl Disk,
+        db: &mut Database<D>,
     ) -> io::Result<Option<Vec<u8>>> {
         let mut buf = vec![];
-        Ok(self.lookup_value(key, &mut buf, disk)?.map(move |_| buf))
+        Ok(self.lookup_value(key, &mut buf, db)?.map(move |_| buf))
     }
 
-    pub(crate) fn delete_value(&mut self, key: Key, disk: &mut impl Disk) -> io::Result<bool> {
-        self.seek_to_offset(disk)?;
-        if self.keys.is_empty() {
-            return Ok(false);
+    /// What a leaf keeps for `data`: compressed, if the database compresses
+    /// values that long and it comes out shorter, and then moved out to
+    /// overflow pages if it's still too long to store inline. Returns the
+    /// bytes to store with `flags` updated to describe them. Values over the
+    /// database's `max_value_len` are refused.
+    pub(crate) fn stored_form<'a, D: Disk>(
+        data: &'a [u8],
+        flags: u8,
+        db: &mut Database<D>,
+    ) -> io::Result<(Cow<'a, [u8]>, u8)> {
+        db.check_value_len(data.len() as u64)?;
+        let mut flags = flags & !(LeafPageEntry::FLAG_OVERFLOW | LeafPageEntry::FLAG_COMPRESSED);
+        let mut stored = Cow::Borrowed(data);
+        if let Some(compressed) = db.compress(data) {
+            stored = Cow::Owned(compressed);
+            flags |= LeafPageEntry::FLAG_COMPRESSED;
         }
-        let mut key_idx = None;
-        for (i, entry) in self.keys.iter().enumerate() {
-            if entry.key == key {
-                key_idx = Some(i);
-                break;
+        if stored.len() as u64 > LeafPage::max_inline_len(db.block_size()) {
+            let overflow = OverflowRef::write(&stored, db)?;
+            stored = Cow::Owned(overflow.to_bytes().to_vec());
+            flags |= LeafPageEntry::FLAG_OVERFLOW;
+        }
+        Ok((stored, flags))
+    }
+
+    /// Removes `key` from the page, handing back the value it held.
+    pub(crate) fn take_value<D: Disk>(
+        &mut self,
+        key: Key,
+        db: &mut Database<D>,
+    ) -> io::Result<Option<Vec<u8>>> {
+        let value = match self.entry(key) {
+            Some(entry) => self.read_value(entry, db)?,
+            None => return Ok(None),
+        };
+        self.delete_value(key, db)?;
+        Ok(Some(value))
+    }
+
+    /// Removes `key` from the page, freeing its overflow pages if it has any.
+    pub(crate) fn delete_value<D: Disk>(
+        &mut self,
+        key: Key,
+        db: &mut Database<D>,
+    ) -> io::Result<bool> {
+        let key_idx = match self.keys.binary_search_by_key(&key, |entry| entry.key) {
+            Ok(idx) => idx,
+            Err(_) => return Ok(false),
+        };
+        let overflow = self.overflow_ref(&self.keys[key_idx], &mut db.disk)?;
+        let entry = self.keys.remove(key_idx);
+        self.free.release(entry.offset, entry.stored_len());
+        // the entries before the removed one haven't moved
+        self.persist_header_offset(db, key_idx)?;
+        if let Some(overflow) = overflow {
+            for offset in overflow.pages(db.block_size(), &mut db.disk)? {
+                db.free_block(offset)?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Removes every key from `start` up to but not including `end`,
+    /// freeing their overflow pages. Returns how many were removed.
+    pub(crate) fn delete_range<D: Disk>(
+        &mut self,
+        start: Key,
+        end: Key,
+        db: &mut Database<D>,
+    ) -> io::Result<u64> {
+        let lo = self.keys.partition_point(|entry| entry.key < start);
+        let hi = self.keys.partition_point(|entry| entry.key < end);
+        if lo == hi {
+            return Ok(0);
+        }
+        let mut overflow_pages = vec![];
+        for entry in &self.keys[lo..hi] {
+            if let Some(overflow) = self.overflow_ref(entry, &mut db.disk)? {
+                overflow_pages.extend(overflow.pages(db.block_size(), &mut db.disk)?);
             }
         }
-        let key_idx = match key_idx {
-            Some(val) => val,
-            None => return Ok(false),
+        for entry in self.keys.drain(lo..hi) {
+            self.free.release(entry.offset, entry.stored_len());
+        }
+        self.persist_header_offset(db, lo)?;
+        for offset in overflow_pages {
+            db.free_block(offset)?;
+        }
+        Ok((hi - lo) as u64)
+    }
+
+    /// Writes `extra` onto the end of `key`'s value where it lies, if the
+    /// value is stored plainly in the page and the space after it is free.
+    /// Returns whether it did.
+    pub(crate) fn extend_value<D: Disk>(
+        &mut self,
+        key: Key,
+        extra: &[u8],
+        db: &mut Database<D>,
+    ) -> io::Result<bool> {
+        let idx = match self.keys.binary_search_by_key(&key, |entry| entry.key) {
+            Ok(idx) => idx,
+            Err(_) => return Ok(false),
         };
-        self.keys.remove(key_idx);
-        self.persist_header(disk)?;
+        let entry = &self.keys[idx];
+        let end = entry.offset + entry.value_len;
+        if entry.flags != 0 || entry.value_len == 0 || !self.free.take_at(end, extra.len() as u64) {
+            return Ok(false);
+        }
+        self.write_at(end, extra, db)?;
+        self.keys[idx].value_len += extra.len() as u64;
+        self.persist_header_offset(db, idx)?;
         Ok(true)
     }
 
     fn quick_insert<D: Disk>(
         &mut self,
-        key: Key,
+        mut entry: LeafPageEntry,
         data: &[u8],
         db: &mut Database<D>,
-        end_offset: Option<u64>,
+        value_offset: u64,
     ) -> io::Result<()> {
-        let page_size = db.block_size();
-        let disk = &mut db.disk;
-        let end_offset = end_offset.unwrap_or_else(|| {
-            self.keys
-                .iter()
-                .map(|entry| entry.offset)
-                .min()
-                .unwrap_or(page_size)
-        });
-        let entry = LeafPageEntry {
-            offset: end_offset - data.len() as u64,
-            key,
-            value_len: data.len() as u64,
-        };
-        disk.seek(SeekFrom::Start(self.offset + entry.offset))?;
-        disk.write_all(data)?;
+        let key = entry.key;
+        if !entry.has_flag(LeafPageEntry::FLAG_EMBEDDED) {
+            entry.offset = value_offset;
+            self.write_at(entry.offset, data, db)?;
+        }
         match self.keys.binary_search_by_key(&key, |entry| entry.key) {
             Ok(_) => unreachable!(),
             Err(idx) => {
                 self.keys.insert(idx, entry);
-                self.persist_header_offset(disk, idx)?;
+                self.persist_header_offset(db, idx)?;
             }
         }
         log::debug!("INSERT_COMMIT [offset={}][key={}]", self.offset, key);
-        return Ok(());
+        Ok(())
     }
 
-    fn defragment<D: Disk>(&mut self, db: &mut Database<D>) -> io::Result<()> {
-        log::debug!("DEFRAGMENT");
-        let pairs = self
-            .keys
+    fn entries_with_values(
+        &self,
+        disk: &mut impl Disk,
+    ) -> io::Result<Vec<(LeafPageEntry, Vec<u8>)>> {
+        self.keys
             .iter()
-            .map(|entry| {
-                Ok((
-                    entry.key,
-                    self.lookup_value_alloc(entry.key, &mut db.disk)?.unwrap(),
-                ))
-            })
-            .collect::<io::Result<Vec<(Key, Vec<u8>)>>>()?;
+            .map(|entry| Ok((entry.clone(), self.read_stored_value(entry, disk)?)))
+            .collect()
+    }
+
+    /// Replaces the contents of the page with `entries`, which must be
+    /// sorted by key and fit in the page, packing the values tightly.
+    fn rewrite<D: Disk>(
+        &mut self,
+        entries: Vec<(LeafPageEntry, Vec<u8>)>,
+        next_leaf: PageOffset,
+        db: &mut Database<D>,
+    ) -> io::Result<()> {
+        let data_len: u64 = entries.iter().map(|(entry, _)| entry.stored_len()).sum();
+        let mut value_offset = Page::content_end(db.block_size()) - data_len;
+        let mut data = Vec::with_capacity(data_len as usize);
         self.keys.clear();
-        for (key, value) in pairs {
-            self.quick_insert(key, &value, db, None)?;
+        self.next_leaf = next_leaf;
+        for (entry, value) in entries {
+            if entry.has_flag(LeafPageEntry::FLAG_EMBEDDED) {
+                self.keys.push(entry);
+                continue;
+            }
+            // an empty value sits at the end of the page, as `upsert_value`
+            // puts it, so the header can never grow past it
+            let at = if value.is_empty() {
+                Page::content_end(db.block_size())
+            } else {
+                value_offset
+            };
+            self.keys.push(LeafPageEntry {
+                offset: at,
+                value_len: value.len() as u64,
+                ..entry
+            });
+            value_offset += value.len() as u64;
+            data.extend_from_slice(&value);
         }
-        Ok(())
+        self.free = LeafPage::free_space_of(&self.keys, db.block_size());
+        // all the values go out in one write, then the header
+        self.write_at(value_offset - data_len, &data, db)?;
+        self.persist_header(db)
+    }
+
+    /// Bytes of the value region lost to holes left by deleted or moved
+    /// values.
+    pub fn fragmented_len(&self) -> u64 {
+        self.free.hole_len()
+    }
+
+    /// Packs the values back against the end of the page, closing up every
+    /// hole.
+    pub(crate) fn defragment<D: Disk>(&mut self, db: &mut Database<D>) -> io::Result<()> {
+        log::debug!("DEFRAGMENT");
+        let entries = self.entries_with_values(&mut db.disk)?;
+        self.rewrite(entries, self.next_leaf, db)
+    }
+
+    /// Evens out two neighbouring leaves, `right` being the next leaf after
+    /// `left`. If everything fits in one page it all moves into `left` and
+    /// `None` is returned, leaving `right` unlinked from the leaf chain.
+    /// Otherwise the entries are split as evenly as the values allow and the
+    /// new last key of `left` is returned, for use as the separator.
+    pub(crate) fn rebalance<D: Disk>(
+        left: &mut LeafPage,
+        right: &mut LeafPage,
+        db: &mut Database<D>,
+    ) -> io::Result<Option<Key>> {
+        let usable = Page::content_end(db.block_size());
+        let left_len = left.keys.len();
+        let mut entries = left.entries_with_values(&mut db.disk)?;
+        entries.extend(right.entries_with_values(&mut db.disk)?);
+        if LeafPage::space_for(entries.iter().map(|(entry, _)| entry)) <= usable {
+            log::debug!("LEAF_MERGE [left={}][right={}]", left.offset, right.offset);
+            left.rewrite(entries, right.next_leaf, db)?;
+            if let Some(next_leaf) = right.next_leaf_offset() {
+                LeafPage::relink_prev(next_leaf, left.offset, db)?;
+            }
+            return Ok(None);
+        }
+        // a running total of the value bytes left of each split point, so
+        // trying them all stays linear
+        let total: u64 = entries.iter().map(|(entry, _)| entry.stored_len()).sum();
+        let mut left_values = 0;
+        let split_idx = (1..entries.len())
+            .filter_map(|idx| {
+                left_values += entries[idx - 1].0.stored_len();
+                let left_space = LeafPage::header_len_for(idx as u64) + left_values;
+                let right_space =
+                    LeafPage::header_len_for((entries.len() - idx) as u64) + (total - left_values);
+                if left_space <= usable && right_space <= usable {
+                    Some((idx, (left_space as i64 - right_space as i64).abs()))
+                } else {
+                    None
+                }
+            })
+            .min_by_key(|&(_, imbalance)| imbalance)
+            .map_or(left_len, |(idx, _)| idx);
+        if split_idx == left_len {
+            // already as even as it gets
+            return Ok(left.keys.last().map(|entry| entry.key));
+        }
+        log::debug!(
+            "LEAF_REDISTRIBUTE [left={}][right={}][split_idx={}]",
+            left.offset,
+            right.offset,
+            split_idx
+        );
+        let right_entries = entries.split_off(split_idx);
+        left.rewrite(entries, left.next_leaf, db)?;
+        right.rewrite(right_entries, right.next_leaf, db)?;
+        Ok(left.keys.last().map(|entry| entry.key))
     }
 
     pub(crate) fn upsert_value<D: Disk>(
         &mut self,
         key: Key,
         data: &[u8],
+        flags: u8,
         db: &mut Database<D>,
     ) -> io::Result<()> {
         log::debug!(
@@ -213,25 +799,46 @@ impl LeafPage {
             key,
             self.keys.len()
         );
-        if self.keys.iter().any(|entry| entry.key == key) {
-            self.delete_value(key, &mut db.disk)?;
-            return self.upsert_value(key, data, db);
+        let page_size = db.block_size();
+        let len = data.len() as u64;
+        let entry = LeafPageEntry::embedding(key, data, flags, db.embed_threshold());
+        // a value being replaced gives its space back
+        let max = self.room_for_value(page_size)
+            + self.entry(key).map_or(0, |entry| {
+                entry.stored_len() + LeafPageEntry::size_of_entry()
+            });
+        if entry.stored_len() > max {
+            return Err(ValueTooLarge { len, max }.into());
+        }
+        if self.entry(key).is_some() {
+            self.delete_value(key, db)?;
+            return self.upsert_value(key, data, flags, db);
         }
 
-        let page_size = db.block_size();
-        assert!(self.can_accommodate(data.len() as u64, page_size));
-        let end_offset = self
-            .keys
-            .iter()
-            .map(|entry| entry.offset)
-            .min()
-            .unwrap_or(page_size);
-        let start_offset = self.header_len() + LeafPageEntry::size_of_entry();
-        if start_offset > end_offset || (end_offset - start_offset < data.len() as u64) {
+        if db
+            .defragment_threshold()
+            .is_some_and(|threshold| self.fragmented_len() as f64 > threshold * page_size as f64)
+        {
             self.defragment(db)?;
-            return self.upsert_value(key, data, db);
         }
-        return self.quick_insert(key, data, db, Some(end_offset));
+        // the header grows by an entry, which has to stay clear of the values
+        let header_end = self.header_len() + LeafPageEntry::size_of_entry();
+        let value_offset = if header_end > self.free.data_start() {
+            None
+        } else if entry.stored_len() == 0 {
+            Some(Page::content_end(page_size))
+        } else {
+            self.free
+                .take_hole(len)
+                .or_else(|| self.free.take_below(len, header_end))
+        };
+        match value_offset {
+            Some(value_offset) => self.quick_insert(entry, data, db, value_offset),
+            None => {
+                self.defragment(db)?;
+                self.upsert_value(key, data, flags, db)
+            }
+        }
     }
     pub(crate) fn init<D: Disk>(db: &mut Database<D>) -> io::Result<LeafPage> {
         let page_size = db.block_size();
@@ -239,24 +846,183 @@ impl LeafPage {
         // idk we just need to write a nice page_size buffer to the disk
         let mut buf = vec![0u8; page_size as usize];
         buf[0] = Page::LEAF_TAG;
+        Page::seal(&mut buf);
         db.write(offset, &buf)?;
         Ok(LeafPage {
             offset,
+            page_size,
+            next_leaf: 0,
+            prev_leaf: 0,
             keys: vec![],
+            free: LeafPage::free_space_of(&[], page_size),
+            image: buf,
         })
     }
-    pub fn split_in_half<D: Disk>(&mut self, db: &mut Database<D>) -> io::Result<LeafPage> {
+    /// Writes `entries`, which must be sorted by key, into freshly allocated
+    /// leaves, each packed as full as it goes and linked to its neighbours.
+    /// Each entry comes with its flags. Values too long to store inline go to
+    /// overflow pages. Returns the last key and the offset of every leaf, in
+    /// key order.
+    pub(crate) fn bulk_load<D: Disk>(
+        db: &mut Database<D>,
+        entries: impl Iterator<Item = (Key, Vec<u8>, u8)>,
+    ) -> io::Result<Vec<(Key, PageOffset)>> {
+        let usable = Page::content_end(db.block_size());
+        let mut leaves = vec![];
+        let mut pending: Vec<(LeafPageEntry, Vec<u8>)> = vec![];
+        let mut data_len = 0;
+        let mut last_key = None;
+        let mut offset = db.allocate_block()?;
+        let mut prev_leaf = 0;
+        for (key, value, flags) in entries {
+            if last_key.is_some_and(|last_key| last_key >= key) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("key {} is out of order", key),
+                ));
+            }
+            last_key = Some(key);
+            let (value, flags) = LeafPage::stored_form(&value, flags, db)?;
+            let value = value.into_owned();
+            let entry = LeafPageEntry::embedding(key, &value, flags, db.embed_threshold());
+            let value_len = entry.stored_len();
+            let space = LeafPage::header_len_for(pending.len() as u64 + 1) + data_len + value_len;
+            if space > usable {
+                let next_leaf = db.allocate_block()?;
+                let entries = std::mem::take(&mut pending);
+                leaves.push((entries[entries.len() - 1].0.key, offset));
+                LeafPage::write_packed(db, offset, prev_leaf, next_leaf, entries)?;
+                prev_leaf = offset;
+                offset = next_leaf;
+                data_len = 0;
+            }
+            pending.push((entry, value));
+            data_len += value_len;
+        }
+        if let Some((entry, _)) = pending.last() {
+            leaves.push((entry.key, offset));
+        }
+        LeafPage::write_packed(db, offset, prev_leaf, 0, pending)?;
+        Ok(leaves)
+    }
+    /// Writes a whole leaf holding `entries` to the block at `offset` in one
+    /// go, with the values packed against the end of the page.
+    fn write_packed<D: Disk>(
+        db: &mut Database<D>,
+        offset: PageOffset,
+        prev_leaf: PageOffset,
+        next_leaf: PageOffset,
+        entries: Vec<(LeafPageEntry, Vec<u8>)>,
+    ) -> io::Result<()> {
+        let page_size = db.block_size();
+        let data_len: u64 = entries.iter().map(|(entry, _)| entry.stored_len()).sum();
+        let mut value_offset = Page::content_end(page_size) - data_len;
+        let mut leaf = LeafPage {
+            offset,
+            page_size,
+            next_leaf,
+            prev_leaf,
+            keys: Vec::with_capacity(entries.len()),
+            free: FreeSpace::default(),
+            image: vec![0u8; page_size as usize],
+        };
+        for (entry, value) in entries {
+            if entry.has_flag(LeafPageEntry::FLAG_EMBEDDED) {
+                leaf.keys.push(entry);
+                continue;
+            }
+            if value.is_empty() {
+                leaf.keys.push(LeafPageEntry {
+                    offset: Page::content_end(page_size),
+                    value_len: 0,
+                    ..entry
+                });
+                continue;
+            }
+            let start = value_offset as usize;
+            leaf.image[start..start + value.len()].copy_from_slice(&value);
+            leaf.keys.push(LeafPageEntry {
+                offset: value_offset,
+                value_len: value.len() as u64,
+                ..entry
+            });
+            value_offset += value.len() as u64;
+        }
+        let mut header = Vec::with_capacity(leaf.header_len() as usize);
+        leaf.write_header_prefix(&mut header)?;
+        LeafPage::write_entries(&mut header, &leaf.keys)?;
+        leaf.image[..header.len()].copy_from_slice(&header);
+        Page::seal(&mut leaf.image);
+        db.write(offset, &leaf.image)
+    }
+    /// The first index past half the bytes the entries and their values
+    /// take up, kept clear of either end so neither half is empty.
+    fn size_split_idx(&self) -> usize {
         let keys_len = self.keys.len();
-        let split_idx = keys_len / 2;
+        if keys_len < 2 {
+            return keys_len / 2;
+        }
+        let entry_len = |entry: &LeafPageEntry| entry.stored_len() + LeafPageEntry::size_of_entry();
+        let total: u64 = self.keys.iter().map(entry_len).sum();
+        let mut taken = 0;
+        let past_half = self
+            .keys
+            .iter()
+            .position(|entry| {
+                taken += entry_len(entry);
+                taken * 2 >= total
+            })
+            .map_or(keys_len, |idx| idx + 1);
+        past_half.clamp(1, keys_len - 1)
+    }
+    /// Whether the half of a split at `split_idx` that `key` would go to has
+    /// room for a `data_len` byte value.
+    fn half_has_room(&self, split_idx: usize, key: Key, data_len: u64, page_size: u64) -> bool {
+        let goes_left = self.keys[..split_idx]
+            .last()
+            .is_some_and(|last| key <= last.key);
+        let half = if goes_left {
+            &self.keys[..split_idx]
+        } else {
+            &self.keys[split_idx..]
+        };
+        LeafPage::space_for(half.iter()) + LeafPageEntry::size_of_entry() + data_len
+            <= Page::content_end(page_size)
+    }
+    /// Splits the page to make room for `key` with a `data_len` byte value,
+    /// where the split policy says unless that leaves the half `key` goes to
+    /// still too full. Then it splits by size, which leaves both halves room
+    /// for any value short enough to store inline, so a split never has to
+    /// be followed by another.
+    pub fn split_in_half<D: Disk>(
+        &mut self,
+        key: Key,
+        data_len: u64,
+        db: &mut Database<D>,
+    ) -> io::Result<LeafPage> {
+        let keys_len = self.keys.len();
+        let mut split_idx = match db.split_policy() {
+            SplitPolicy::ByCount => keys_len / 2,
+            SplitPolicy::BySize => self.size_split_idx(),
+        };
+        if !self.half_has_room(split_idx, key, data_len, db.block_size()) {
+            split_idx = self.size_split_idx();
+        }
+        let moved = self.keys[split_idx..]
+            .iter()
+            .map(|entry| Ok((entry.clone(), self.read_stored_value(entry, &mut db.disk)?)))
+            .collect::<io::Result<Vec<_>>>()?;
         let mut new_right_sibling = LeafPage::init(db)?;
-        let mut buf = vec![];
-        for entry in &self.keys[split_idx..] {
-            let value = self.lookup_value(entry.key, &mut buf, &mut db.disk)?;
-            value.expect("could not lookup value");
-            new_right_sibling.quick_insert(entry.key, &buf, db, None)?;
+        new_right_sibling.prev_leaf = self.offset;
+        new_right_sibling.rewrite(moved, self.next_leaf, db)?;
+        if let Some(next_leaf) = self.next_leaf_offset() {
+            LeafPage::relink_prev(next_leaf, new_right_sibling.offset, db)?;
+        }
+        for entry in self.keys.drain(split_idx..) {
+            self.free.release(entry.offset, entry.stored_len());
         }
-        self.keys.truncate(split_idx);
-        self.persist_header(&mut db.disk)?;
+        self.next_leaf = new_right_sibling.offset;
+        self.persist_header_offset(db, split_idx)?;
         log::debug!(
             "SPLIT_IN_HALF [offset={}][split_idx={}][old_len={}][new_len={}]",
             self.offset,
@@ -279,15 +1045,193 @@ mod tests_leafpage {
 
         let mut page = LeafPage::init(&mut db)?;
         for i in 0..5 {
-            page.upsert_value(i, &[0, 1, 2, 3], &mut db)?;
+            page.upsert_value(i, &[0, 1, 2, 3], 0, &mut db)?;
         }
         for i in 2..4 {
             let mut buf = vec![];
-            page.lookup_value(i, &mut buf, &mut db.disk)?;
+            page.lookup_value(i, &mut buf, &mut db)?;
             assert_eq!(buf, &[0, 1, 2, 3]);
         }
         for i in 3..5 {
-            assert!(page.delete_value(i, &mut db.disk)?);
+            assert!(page.delete_value(i, &mut db)?);
+        }
+        Ok(())
+    }
+    #[test]
+    fn leaves_fill_to_the_last_byte_before_the_checksum() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let page_size = db.block_size();
+        let mut page = LeafPage::init(&mut db)?;
+        for i in 0..10 {
+            page.upsert_value(i, &[i as u8; 100], 0, &mut db)?;
+        }
+        // 8192 bytes less the checksum and entry count, the header with room
+        // for an eleventh entry, and the values already there
+        assert_eq!(
+            page.room_for_value(page_size),
+            8180 - (25 + 33 * 11) - 1_000
+        );
+        assert!(page.can_accommodate(6_792, page_size));
+        assert!(!page.can_accommodate(6_793, page_size));
+        page.upsert_value(10, &[10; 6_792], 0, &mut db)?;
+        assert_eq!(page.used_space(), Page::content_end(page_size));
+        assert_eq!(page.room_for_value(page_size), 0);
+        assert!(!page.can_accommodate(1, page_size));
+        // not even an empty value fits, as its entry doesn't
+        assert!(!page.can_accommodate(0, page_size));
+        Ok(())
+    }
+
+    #[test]
+    fn trees_split_a_leaf_only_once_the_value_does_not_fit() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = crate::BTree::init(&mut db)?;
+        // an entry of 100 bytes takes 133 with its header entry, so the root
+        // leaf holds 61 of them, leaving 9 bytes spare
+        for key in 0..61 {
+            tree.insert(key, &[0; 100], &mut db)?;
+        }
+        assert_eq!(tree.depth(&mut db)?, 1);
+        tree.insert(61, &[0; 100], &mut db)?;
+        assert_eq!(tree.depth(&mut db)?, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn trees_split_a_full_leaf_for_an_empty_value() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = crate::BTree::init(&mut db)?;
+        for key in 0..61 {
+            tree.insert(key, &[0; 100], &mut db)?;
+        }
+        // the 9 bytes spare, leaving no room for another entry
+        tree.insert(61, &[0; 9], &mut db)?;
+        assert_eq!(tree.depth(&mut db)?, 1);
+        tree.insert(62, &[], &mut db)?;
+        assert_eq!(tree.depth(&mut db)?, 2);
+        assert_eq!(tree.lookup(62, &mut db)?, Some(vec![]));
+        assert_eq!(tree.len(&mut db)?, 63);
+        Ok(())
+    }
+
+    #[test]
+    fn free_space_is_zeroed_when_asked() -> io::Result<()> {
+        // the bytes of the page used by neither the header nor a value
+        fn stale_bytes(zero_free_space: bool) -> io::Result<usize> {
+            let mut db = Database::initialize(Cursor::new(vec![]))?;
+            db.set_zero_free_space(zero_free_space);
+            let mut page = LeafPage::init(&mut db)?;
+            for i in 0..10 {
+                page.upsert_value(i, &[0xaa; 50], 0, &mut db)?;
+            }
+            page.delete_value(9, &mut db)?;
+            page.delete_value(3, &mut db)?;
+            page.upsert_value(5, &[0xbb; 10], 0, &mut db)?;
+            // short enough to be embedded in their entries
+            page.upsert_value(10, &[0xcc; 8], 0, &mut db)?;
+            page.upsert_value(11, &[0xdd], 0, &mut db)?;
+
+            let image = Page::read_block(page.offset, &mut db)?;
+            let mut used = vec![false; Page::content_end(db.block_size()) as usize];
+            used[..page.header_len() as usize].fill(true);
+            for entry in page.keys().iter().filter(|entry| entry.stored_len() > 0) {
+                used[entry.offset as usize..(entry.offset + entry.value_len) as usize].fill(true);
+            }
+            assert_eq!(page.lookup_value_alloc(5, &mut db)?, Some(vec![0xbb; 10]));
+            assert_eq!(page.lookup_value_alloc(10, &mut db)?, Some(vec![0xcc; 8]));
+            assert_eq!(page.lookup_value_alloc(11, &mut db)?, Some(vec![0xdd]));
+            Ok(used
+                .iter()
+                .zip(&image)
+                .filter(|&(&used, &byte)| !used && byte != 0)
+                .count())
+        }
+        assert!(stale_bytes(false)? > 0);
+        assert_eq!(stale_bytes(true)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn deleting_from_the_middle_keeps_the_other_entries() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut page = LeafPage::init(&mut db)?;
+        for i in 0..10 {
+            page.upsert_value(i, &[i as u8; 4], 0, &mut db)?;
+        }
+        assert!(page.delete_value(4, &mut db)?);
+
+        let page_size = db.block_size();
+        db.disk.seek(SeekFrom::Start(page.offset))?;
+        let page = LeafPage::read_header(&mut db.disk, page_size)?;
+        let keys: Vec<Key> = page.keys().iter().map(|entry| entry.key).collect();
+        assert_eq!(keys, vec![0, 1, 2, 3, 5, 6, 7, 8, 9]);
+        for i in keys {
+            assert_eq!(page.lookup_value_alloc(i, &mut db)?, Some(vec![i as u8; 4]));
+        }
+        Ok(())
+    }
+    #[test]
+    fn freed_values_are_reused_best_fit() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut page = LeafPage::init(&mut db)?;
+        for (key, len) in [(0, 10), (1, 30), (2, 10), (3, 20), (4, 10)] {
+            page.upsert_value(key, &vec![key as u8; len], 0, &mut db)?;
+        }
+        let offset_of = |page: &LeafPage, key| page.entry(key).unwrap().offset;
+        let (hole_30, hole_20) = (offset_of(&page, 1), offset_of(&page, 3));
+        let data_start = page.free.data_start();
+        page.delete_value(1, &mut db)?;
+        page.delete_value(3, &mut db)?;
+
+        // the 20 byte hole is the tightest fit
+        page.upsert_value(5, &[5; 15], 0, &mut db)?;
+        assert_eq!(offset_of(&page, 5), hole_20);
+        page.upsert_value(6, &[6; 25], 0, &mut db)?;
+        assert_eq!(offset_of(&page, 6), hole_30);
+
+        // 6 and 2 were next to each other and to what was left over of both
+        // holes, so deleting them leaves one 45 byte hole
+        page.delete_value(6, &mut db)?;
+        page.delete_value(2, &mut db)?;
+        page.upsert_value(7, &[7; 45], 0, &mut db)?;
+        assert_eq!(offset_of(&page, 7), hole_20 + 15);
+        assert_eq!(page.free.data_start(), data_start);
+
+        for (key, len) in [(0, 10), (4, 10), (5, 15), (7, 45)] {
+            let value = page.lookup_value_alloc(key, &mut db)?;
+            assert_eq!(value, Some(vec![key as u8; len]));
+        }
+        Ok(())
+    }
+    #[test]
+    fn fragmented_leaves_are_compacted_past_the_threshold() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut page = LeafPage::init(&mut db)?;
+        for key in 0..20 {
+            let len = if key % 2 == 0 { 300 } else { 10 };
+            page.upsert_value(key, &vec![key as u8; len], 0, &mut db)?;
+        }
+        for key in (0..20).step_by(4) {
+            page.delete_value(key, &mut db)?;
+        }
+        assert_eq!(page.fragmented_len(), 5 * 300);
+
+        // under the threshold, the next value just fills a hole
+        db.set_defragment_threshold(Some(0.25));
+        page.upsert_value(20, &[20; 100], 0, &mut db)?;
+        assert_eq!(page.fragmented_len(), 5 * 300 - 100);
+
+        db.set_defragment_threshold(Some(0.1));
+        page.upsert_value(21, &[21; 100], 0, &mut db)?;
+        assert_eq!(page.fragmented_len(), 0);
+        for key in (1..22).filter(|key| key % 4 != 0) {
+            let len = match key {
+                20 | 21 => 100,
+                _ if key % 2 == 0 => 300,
+                _ => 10,
+            };
+            let value = page.lookup_value_alloc(key, &mut db)?;
+            assert_eq!(value, Some(vec![key as u8; len]));
         }
         Ok(())
     }
@@ -295,36 +1239,166 @@ mod tests_leafpage {
     fn test_upsert() -> io::Result<()> {
         let mut db = Database::initialize(Cursor::new(vec![]))?;
         let mut page = LeafPage::init(&mut db)?;
-        page.upsert_value(0, &[0, 1, 2, 3], &mut db)?;
-        page.upsert_value(0, &[1, 2], &mut db)?;
+        page.upsert_value(0, &[0, 1, 2, 3], 0, &mut db)?;
+        page.upsert_value(0, &[1, 2], 0, &mut db)?;
 
         let mut buf = vec![];
-        page.lookup_value(0, &mut buf, &mut db.disk)?;
+        page.lookup_value(0, &mut buf, &mut db)?;
         assert_eq!(buf, &[1, 2]);
 
-        page.upsert_value(0, &[2, 3, 4, 5], &mut db)?;
+        page.upsert_value(0, &[2, 3, 4, 5], 0, &mut db)?;
 
-        page.lookup_value(0, &mut buf, &mut db.disk)?;
+        page.lookup_value(0, &mut buf, &mut db)?;
         assert_eq!(buf, &[2, 3, 4, 5]);
 
         Ok(())
     }
     #[test]
+    fn values_too_long_for_the_page_are_errors() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut page = LeafPage::init(&mut db)?;
+        page.upsert_value(0, &[0; 1_000], 0, &mut db)?;
+        let max = page.room_for_value(db.block_size());
+        let err = page
+            .upsert_value(1, &vec![1; max as usize + 1], 0, &mut db)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let too_large = err.get_ref().unwrap().downcast_ref::<ValueTooLarge>();
+        assert_eq!(too_large, Some(&ValueTooLarge { len: max + 1, max }));
+        // nothing changed, and a replacement can use the space it frees
+        assert_eq!(page.lookup_value_alloc(0, &mut db)?, Some(vec![0; 1_000]));
+        page.upsert_value(0, &vec![2; max as usize + 1_000], 0, &mut db)?;
+        page.upsert_value(1, &[], 0, &mut db)?;
+        assert_eq!(page.keys().len(), 2);
+        Ok(())
+    }
+    #[test]
+    fn size_splits_even_out_the_bytes() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        db.set_split_policy(SplitPolicy::BySize);
+        let mut page = LeafPage::init(&mut db)?;
+        for i in 0..20 {
+            page.upsert_value(i, &[i as u8; 4], 0, &mut db)?;
+        }
+        for i in 20..22 {
+            page.upsert_value(i, &[i as u8; 1_500], 0, &mut db)?;
+        }
+        let right = page.split_in_half(0, 0, &mut db)?;
+        // by count, 11 small values would have gone each way, leaving both
+        // large ones on the right
+        assert_eq!(right.keys().len(), 1);
+        assert_eq!(right.keys()[0].key, 21);
+        assert_eq!(page.keys().len(), 21);
+        assert_eq!(page.lookup_value_alloc(20, &mut db)?, Some(vec![20; 1_500]));
+        Ok(())
+    }
+    #[test]
     fn test_split() -> io::Result<()> {
         let mut db = Database::initialize(Cursor::new(vec![]))?;
         let mut page = LeafPage::init(&mut db)?;
         for i in 0..100 {
-            page.upsert_value(i, &[0, 1, 2, 3], &mut db)?;
+            page.upsert_value(i, &[0, 1, 2, 3], 0, &mut db)?;
         }
-        let new_right_sibling = page.split_in_half(&mut db)?;
+        let new_right_sibling = page.split_in_half(0, 0, &mut db)?;
+        let page_size = db.block_size();
         db.disk.seek(SeekFrom::Start(page.offset))?;
-        let page = LeafPage::read_header(&mut db.disk)?;
+        let page = LeafPage::read_header(&mut db.disk, page_size)?;
         assert_eq!(page.keys.len(), 50);
 
         db.disk.seek(SeekFrom::Start(new_right_sibling.offset))?;
-        let new_right_sibling = LeafPage::read_header(&mut db.disk)?;
+        let new_right_sibling = LeafPage::read_header(&mut db.disk, page_size)?;
         assert_eq!(new_right_sibling.keys.len(), 50);
+        assert_eq!(page.next_leaf_offset(), Some(new_right_sibling.offset));
+        assert_eq!(new_right_sibling.next_leaf_offset(), None);
+
+        Ok(())
+    }
+    #[test]
+    fn torn_header_write_is_reported_as_corruption() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut page = LeafPage::init(&mut db)?;
+        for i in 0..10 {
+            page.upsert_value(i, &[0, 1, 2, 3], 0, &mut db)?;
+        }
+        // simulate a crash that persisted the new count of a 20 entry header
+        // but none of the entries past the first 10
+        db.disk
+            .seek(SeekFrom::Start(page.offset + size_of::<u8>() as u64))?;
+        db.disk.write_u64::<BigEndian>(20)?;
+
+        let page_size = db.block_size();
+        db.disk.seek(SeekFrom::Start(page.offset))?;
+        let err = LeafPage::read_header(&mut db.disk, page_size)
+            .err()
+            .expect("torn header was read back without complaint");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    #[test]
+    fn values_outside_the_page_are_reported_as_corruption() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        // an embedded value's offset is its bytes, not a place in the page
+        db.set_embed_threshold(0);
+        let mut page = LeafPage::init(&mut db)?;
+        page.upsert_value(1, &[0, 1, 2, 3], 0, &mut db)?;
+        let page_size = db.block_size();
+        let entry_offset = LeafPage::header_len_for(0) as usize + size_of::<Key>();
+        // a checksum that still matches, so only the bounds give it away
+        for &bad in [page_size * 4, 0, page_size - 2, u64::MAX].iter() {
+            let mut image = Page::read_block(page.offset, &mut db)?;
+            image[entry_offset..entry_offset + 8].copy_from_slice(&bad.to_be_bytes());
+            Page::seal(&mut image);
+            db.write(page.offset, &image)?;
+
+            db.disk.seek(SeekFrom::Start(page.offset))?;
+            let err = LeafPage::read_header(&mut db.disk, page_size)
+                .err()
+                .expect("out of range value was read back without complaint");
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+        Ok(())
+    }
 
+    #[test]
+    fn short_values_are_embedded_in_their_entries() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut page = LeafPage::init(&mut db)?;
+        page.upsert_value(1, &7u64.to_be_bytes(), 0, &mut db)?;
+        page.upsert_value(2, b"abc", 0, &mut db)?;
+        page.upsert_value(3, &[9; 9], 0, &mut db)?;
+        page.upsert_value(4, &[], 0, &mut db)?;
+        let embedded = |page: &LeafPage, key| {
+            page.entry(key)
+                .unwrap()
+                .has_flag(LeafPageEntry::FLAG_EMBEDDED)
+        };
+        assert!(embedded(&page, 1) && embedded(&page, 2));
+        assert!(!embedded(&page, 3) && !embedded(&page, 4));
+        // only the nine byte value takes up any of the value region
+        assert_eq!(page.used_space(), LeafPage::header_len_for(4) + 9);
+
+        page.defragment(&mut db)?;
+        let page_size = db.block_size();
+        db.disk.seek(SeekFrom::Start(page.offset))?;
+        let page = LeafPage::read_header(&mut db.disk, page_size)?;
+        assert_eq!(
+            page.lookup_value_alloc(1, &mut db)?,
+            Some(7u64.to_be_bytes().to_vec())
+        );
+        assert_eq!(page.lookup_value_alloc(2, &mut db)?, Some(b"abc".to_vec()));
+        assert_eq!(page.lookup_value_alloc(3, &mut db)?, Some(vec![9; 9]));
+        assert_eq!(page.lookup_value_alloc(4, &mut db)?, Some(vec![]));
+        let mut prefix = [0; 2];
+        let entry = page.entry(2).unwrap();
+        assert_eq!(page.read_value_prefix(entry, &mut prefix, &mut db)?, 2);
+        assert_eq!(&prefix, b"ab");
+
+        db.set_embed_threshold(0);
+        let mut page = LeafPage::init(&mut db)?;
+        page.upsert_value(1, b"abc", 0, &mut db)?;
+        assert!(!embedded(&page, 1));
+        assert_eq!(page.used_space(), LeafPage::header_len_for(1) + 3);
         Ok(())
     }
 }
@@ -1,53 +1,254 @@
-use crate::{Database, Disk};
+use crate::error::CorruptPage;
+use crate::{BlockAllocator, Database, Disk};
 
-use byteorder::ReadBytesExt;
-use std::io;
-use std::io::SeekFrom;
+use byteorder::{BigEndian, ReadBytesExt};
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
 mod btree;
+mod bucket;
+mod dups;
+mod free_space;
+mod inspect;
 mod internal_page;
+mod layout;
 mod leaf_page;
+mod overflow;
+mod verify;
 
-pub use btree::BTree;
+pub use btree::{
+    BTree, InsertOutcome, Iter, Keys, PrefixScan, Scan, TreeCursor, TreeStats, ValueSource,
+};
+pub(crate) use inspect::inspect;
+pub use inspect::{LeafEntryInfo, PageInfo};
 use internal_page::InternalPage;
-use leaf_page::LeafPage;
+pub use layout::PageLayout;
+pub(crate) use leaf_page::LeafPage;
+pub(crate) use leaf_page::LeafPageEntry;
+use overflow::OverflowRef;
+pub(crate) use verify::is_corruption;
+pub use verify::Inconsistency;
 
-type PageOffset = u64;
+pub(crate) type PageOffset = u64;
 use crate::Key;
 
+/// Every leaf and internal page ends in the number of entries in the tree,
+/// which only a tree's root page keeps up to date...
+const ENTRY_COUNT_LEN: u64 = 8;
+/// ...and then a CRC32 of everything before it, the count included.
+const CHECKSUM_LEN: u64 = 4;
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &byte| {
+        CRC32_TABLE[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// The CRC32 of `bytes` with neither the initial nor the final inversion,
+/// which is what XORing `bytes` into the end of a message does to its
+/// `crc32`. That lets a few bytes at the end of a page change without
+/// reading the rest of it back to checksum it again.
+fn crc32_delta(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0u32, |crc, &byte| {
+        CRC32_TABLE[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+pub(crate) fn corrupt_page(offset: PageOffset, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        CorruptPage {
+            offset,
+            reason: reason.to_string(),
+        },
+    )
+}
+
+/// Why an insert into a leaf failed when the value is longer than the leaf
+/// has room for. The public operations report it as
+/// `Error::ValueTooLarge`; inside the crate it travels in an `io::Error` of
+/// kind `InvalidInput`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ValueTooLarge {
+    pub len: u64,
+    /// The longest value there was room for.
+    pub max: u64,
+}
+
+impl fmt::Display for ValueTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "value of {} bytes is longer than the {} there's room for",
+            self.len, self.max
+        )
+    }
+}
+
+impl std::error::Error for ValueTooLarge {}
+
+impl From<ValueTooLarge> for io::Error {
+    fn from(err: ValueTooLarge) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    }
+}
+
 enum Page {
     Internal(InternalPage),
     Leaf(LeafPage),
 }
 
-impl Into<Page> for LeafPage {
-    fn into(self) -> Page {
-        Page::Leaf(self)
+impl From<LeafPage> for Page {
+    fn from(page: LeafPage) -> Page {
+        Page::Leaf(page)
     }
 }
 
-impl Into<Page> for InternalPage {
-    fn into(self) -> Page {
-        Page::Internal(self)
+impl From<InternalPage> for Page {
+    fn from(page: InternalPage) -> Page {
+        Page::Internal(page)
     }
 }
 
 impl Page {
     const LEAF_TAG: u8 = 0x01;
     const INTERNAL_TAG: u8 = 0x02;
+    const OVERFLOW_TAG: u8 = 0x03;
+    const FREE_TAG: u8 = 0x04;
     fn load<D: Disk>(offset: u64, db: &mut Database<D>) -> io::Result<Page> {
+        let page_size = db.block_size();
         let disk = &mut db.disk;
         disk.seek(SeekFrom::Start(offset))?;
         let tag = disk.read_u8()?;
         disk.seek(SeekFrom::Start(offset))?;
         let page: Page = match tag {
-            Page::LEAF_TAG => LeafPage::read_header(disk)?.into(),
+            Page::LEAF_TAG => LeafPage::read_header(disk, page_size)?.into(),
             Page::INTERNAL_TAG => InternalPage::load(db)?.into(),
-            n => {
-                panic!("Unknown page tag {}", n);
+            Page::OVERFLOW_TAG => {
+                return Err(corrupt_page(
+                    offset,
+                    "expected a tree page, found an overflow page",
+                ))
             }
+            Page::FREE_TAG => {
+                return Err(corrupt_page(
+                    offset,
+                    "expected a tree page, found a free block",
+                ))
+            }
+            n => return Err(corrupt_page(offset, &format!("unknown page tag {}", n))),
         };
         Ok(page)
     }
+    /// Copies the raw image of the block at `from` over the block at `to`.
+    /// Leaf entry offsets are relative to the page start, so the copy is a
+    /// valid page in its new location.
+    fn copy_block<D: Disk>(
+        from: PageOffset,
+        to: PageOffset,
+        db: &mut Database<D>,
+    ) -> io::Result<()> {
+        let image = Page::read_block(from, db)?;
+        db.write(to, &image)
+    }
+    fn read_block<D: Disk>(offset: PageOffset, db: &mut Database<D>) -> io::Result<Vec<u8>> {
+        let block_size = db.block_size();
+        let mut buf = Vec::with_capacity(block_size as usize);
+        db.disk.seek(SeekFrom::Start(offset))?;
+        (&mut db.disk).take(block_size).read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+    /// Where the page's own contents have to stop, ahead of the entry count
+    /// and checksum.
+    fn content_end(page_size: u64) -> u64 {
+        page_size - ENTRY_COUNT_LEN - CHECKSUM_LEN
+    }
+    /// Where a root page keeps its tree's entry count.
+    fn entry_count_pos(page_size: u64) -> u64 {
+        Page::content_end(page_size)
+    }
+    /// Where the checksum starts, at the very end of the page.
+    fn checksum_pos(page_size: u64) -> u64 {
+        page_size - CHECKSUM_LEN
+    }
+    /// Writes the checksum of `image`, a whole page, into its place at the
+    /// end. It covers the entry count, so the count has to be in place
+    /// first.
+    fn seal(image: &mut [u8]) {
+        let pos = Page::checksum_pos(image.len() as u64) as usize;
+        let checksum = crc32(&image[..pos]);
+        image[pos..pos + CHECKSUM_LEN as usize].copy_from_slice(&checksum.to_be_bytes());
+    }
+    /// The entry count kept in the root page at `offset`, read on its own.
+    /// The checksum isn't checked, which is left to whatever loads the page.
+    fn read_entry_count<D: Disk>(offset: PageOffset, db: &mut Database<D>) -> io::Result<u64> {
+        let page_size = db.block_size();
+        db.disk
+            .seek(SeekFrom::Start(offset + Page::entry_count_pos(page_size)))?;
+        db.disk.read_u64::<BigEndian>()
+    }
+    /// Writes `count` into the root page at `offset`, patching the checksum
+    /// for the bytes that changed rather than reading the page to checksum
+    /// it again. A page that failed its checksum before still fails it.
+    fn write_entry_count<D: Disk>(
+        offset: PageOffset,
+        count: u64,
+        db: &mut Database<D>,
+    ) -> io::Result<()> {
+        let pos = offset + Page::entry_count_pos(db.block_size());
+        let mut tail = [0u8; (ENTRY_COUNT_LEN + CHECKSUM_LEN) as usize];
+        db.disk.seek(SeekFrom::Start(pos))?;
+        db.disk.read_exact(&mut tail)?;
+        let (old_count, old_checksum) = tail.split_at(ENTRY_COUNT_LEN as usize);
+        let old_count = u64::from_be_bytes(old_count.try_into().unwrap());
+        let old_checksum = u32::from_be_bytes(old_checksum.try_into().unwrap());
+        let checksum = old_checksum ^ crc32_delta(&(old_count ^ count).to_be_bytes());
+        tail[..ENTRY_COUNT_LEN as usize].copy_from_slice(&count.to_be_bytes());
+        tail[ENTRY_COUNT_LEN as usize..].copy_from_slice(&checksum.to_be_bytes());
+        db.write(pos, &tail)
+    }
+    /// Reads the whole page at the current disk position, failing if it
+    /// doesn't match its checksum.
+    fn read_verified(disk: &mut impl Disk, page_size: u64) -> io::Result<Vec<u8>> {
+        let offset = disk.stream_position()?;
+        let mut image = vec![0u8; page_size as usize];
+        disk.read_exact(&mut image)?;
+        let pos = Page::checksum_pos(page_size) as usize;
+        let stored = (&image[pos..]).read_u32::<BigEndian>()?;
+        let actual = crc32(&image[..pos]);
+        if stored != actual {
+            return Err(corrupt_page(
+                offset,
+                &format!(
+                    "checksum mismatch (stored {:08x}, computed {:08x})",
+                    stored, actual
+                ),
+            ));
+        }
+        Ok(image)
+    }
     fn can_accommodate(&self, data_len: u64, page_size: u64) -> bool {
         match self {
             Page::Internal(internal) => internal.can_accommodate(page_size),
@@ -55,3 +256,117 @@ impl Page {
         }
     }
 }
+
+/// Overwrites the block at `offset` with a free list node pointing at `next`.
+pub(crate) fn write_free_block<D: Disk>(
+    offset: PageOffset,
+    next: PageOffset,
+    db: &mut Database<D>,
+) -> io::Result<()> {
+    let mut buf = vec![Page::FREE_TAG];
+    buf.extend_from_slice(&next.to_be_bytes());
+    db.write(offset, &buf)
+}
+
+/// Reads the free list node at `offset`, returning the next free block.
+pub(crate) fn read_free_block<D: Disk>(
+    offset: PageOffset,
+    db: &mut Database<D>,
+) -> io::Result<PageOffset> {
+    db.disk.seek(SeekFrom::Start(offset))?;
+    if db.disk.read_u8()? != Page::FREE_TAG {
+        return Err(corrupt_page(offset, "free list points at a block in use"));
+    }
+    db.disk.read_u64::<BigEndian>()
+}
+
+/// Whether the block at `offset` holds a leaf or internal page that passes
+/// its checks, rather than a free block or whatever was there before.
+pub(crate) fn holds_tree_page<D: Disk>(
+    offset: PageOffset,
+    db: &mut Database<D>,
+) -> io::Result<bool> {
+    match Page::load(offset, db) {
+        Ok(_) => Ok(true),
+        Err(err) if is_corruption(&err) => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Rough upper bound on the number of blocks needed to store values of the
+/// given lengths in new pages. Splits leave pages half full, so that's what
+/// we plan for at every level of the tree.
+pub(crate) fn estimate_blocks(value_lens: impl Iterator<Item = u64>, block_size: u64) -> u64 {
+    let leaf_space = (Page::content_end(block_size) - LeafPage::header_len_for(0)) / 2;
+    let mut pages = 0u64;
+    let mut page_used = leaf_space;
+    for len in value_lens {
+        let entry_len = len + LeafPageEntry::size_of_entry();
+        if page_used + entry_len > leaf_space {
+            pages += 1;
+            page_used = 0;
+        }
+        page_used += entry_len;
+    }
+    let mut total = pages;
+    let fanout = InternalPage::max_children_capacity(block_size) / 2;
+    while pages > 1 {
+        pages = pages.div_ceil(fanout);
+        total += pages;
+    }
+    total
+}
+
+#[cfg(test)]
+mod page_tests {
+    use super::*;
+
+    #[test]
+    fn crc32_matches_the_standard_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn patching_the_entry_count_keeps_the_checksum_right() -> io::Result<()> {
+        let mut db = Database::initialize(std::io::Cursor::new(vec![]))?;
+        let page_size = db.block_size();
+        let leaf = LeafPage::init(&mut db)?;
+        for count in [1, 0xff, 1 << 40, u64::MAX, 0] {
+            Page::write_entry_count(leaf.offset(), count, &mut db)?;
+            assert_eq!(Page::read_entry_count(leaf.offset(), &mut db)?, count);
+            db.disk.seek(SeekFrom::Start(leaf.offset()))?;
+            Page::read_verified(&mut db.disk, page_size)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_and_mismatched_tags_are_errors() -> io::Result<()> {
+        let mut db = Database::initialize(std::io::Cursor::new(vec![]))?;
+        let page_size = db.block_size();
+        let offset = db.allocate_block()?;
+        let mut image = vec![0u8; page_size as usize];
+        image[0] = 0x7f;
+        Page::seal(&mut image);
+        db.write(offset, &image)?;
+        let err = Page::load(offset, &mut db).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let message = err.to_string();
+        assert!(message.contains(&offset.to_string()) && message.contains("127"));
+
+        image[0] = Page::INTERNAL_TAG;
+        Page::seal(&mut image);
+        db.write(offset, &image)?;
+        db.disk.seek(SeekFrom::Start(offset))?;
+        let err = LeafPage::read_header(&mut db.disk, page_size)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let leaf = LeafPage::init(&mut db)?;
+        db.disk.seek(SeekFrom::Start(leaf.offset()))?;
+        let err = InternalPage::load(&mut db).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        Ok(())
+    }
+}
@@ -0,0 +1,212 @@
+use super::{corrupt_page, Page, PageOffset};
+use crate::{BlockAllocator, Database, Disk};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use std::{
+    cmp::min,
+    io::{self, Read, SeekFrom, Write},
+    mem::size_of,
+};
+
+/// What a leaf stores in place of a value too big to keep inline: the
+/// value's length and the first block of the chain of overflow pages
+/// holding it. Each overflow page is laid out as the tag, the offset of the
+/// next page in the chain (0 for the last), the number of value bytes in
+/// this page, then those bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct OverflowRef {
+    pub len: u64,
+    pub first: PageOffset,
+}
+
+impl OverflowRef {
+    /// Length of the reference as stored in the leaf.
+    pub const SIZE: usize = size_of::<u64>() * 2;
+
+    pub fn header_len() -> u64 {
+        (size_of::<u8>() + size_of::<u64>() * 2) as u64
+    }
+
+    pub fn to_bytes(self) -> [u8; OverflowRef::SIZE] {
+        let mut buf = [0u8; OverflowRef::SIZE];
+        buf[..8].copy_from_slice(&self.len.to_be_bytes());
+        buf[8..].copy_from_slice(&self.first.to_be_bytes());
+        buf
+    }
+
+    pub fn from_bytes(mut buf: &[u8]) -> Option<OverflowRef> {
+        if buf.len() != OverflowRef::SIZE {
+            return None;
+        }
+        let len = buf.read_u64::<BigEndian>().ok()?;
+        let first = buf.read_u64::<BigEndian>().ok()?;
+        Some(OverflowRef { len, first })
+    }
+
+    /// Writes `data` out to a new chain of overflow pages.
+    pub fn write<D: Disk>(data: &[u8], db: &mut Database<D>) -> io::Result<OverflowRef> {
+        let chunk_len = (db.block_size() - OverflowRef::header_len()) as usize;
+        let chunks: Vec<&[u8]> = data.chunks(chunk_len).collect();
+        let offsets = chunks
+            .iter()
+            .map(|_| db.allocate_block())
+            .collect::<io::Result<Vec<PageOffset>>>()?;
+        for (i, chunk) in chunks.iter().enumerate() {
+            let next = offsets.get(i + 1).cloned().unwrap_or(0);
+            OverflowRef::write_page(offsets[i], next, chunk, db)?;
+        }
+        log::debug!(
+            "OVERFLOW_WRITE [len={}][pages={}]",
+            data.len(),
+            offsets.len()
+        );
+        Ok(OverflowRef {
+            len: data.len() as u64,
+            first: offsets.first().cloned().unwrap_or(0),
+        })
+    }
+
+    /// Like `write`, but the value is `prefix` followed by whatever's left
+    /// in `reader`, and no more than a couple of pages of it are held in
+    /// memory at once. Its length is checked against the database's
+    /// `max_value_len` as it's read. If anything fails, the pages written so
+    /// far are freed again.
+    pub fn write_from<D: Disk>(
+        prefix: &[u8],
+        reader: &mut impl Read,
+        db: &mut Database<D>,
+    ) -> io::Result<OverflowRef> {
+        let mut pages = vec![];
+        let written = OverflowRef::write_chain(prefix.chain(reader), db, &mut pages);
+        if written.is_err() {
+            for page in pages {
+                db.free_block(page)?;
+            }
+        }
+        written
+    }
+
+    fn write_chain<D: Disk>(
+        mut input: impl Read,
+        db: &mut Database<D>,
+        pages: &mut Vec<PageOffset>,
+    ) -> io::Result<OverflowRef> {
+        let chunk_len = db.block_size() - OverflowRef::header_len();
+        let mut read_chunk = || -> io::Result<Vec<u8>> {
+            let mut chunk = vec![];
+            input.by_ref().take(chunk_len).read_to_end(&mut chunk)?;
+            Ok(chunk)
+        };
+        let mut chunk = read_chunk()?;
+        let mut len = 0;
+        let mut offset = 0;
+        if !chunk.is_empty() {
+            offset = db.allocate_block()?;
+            pages.push(offset);
+        }
+        let first = offset;
+        while !chunk.is_empty() {
+            len += chunk.len() as u64;
+            db.check_value_len(len)?;
+            let following = read_chunk()?;
+            let mut next = 0;
+            if !following.is_empty() {
+                next = db.allocate_block()?;
+                pages.push(next);
+            }
+            OverflowRef::write_page(offset, next, &chunk, db)?;
+            chunk = following;
+            offset = next;
+        }
+        log::debug!("OVERFLOW_WRITE [len={}][pages={}]", len, pages.len());
+        Ok(OverflowRef { len, first })
+    }
+
+    fn write_page<D: Disk>(
+        offset: PageOffset,
+        next: PageOffset,
+        chunk: &[u8],
+        db: &mut Database<D>,
+    ) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(OverflowRef::header_len() as usize + chunk.len());
+        buf.write_u8(Page::OVERFLOW_TAG)?;
+        buf.write_u64::<BigEndian>(next)?;
+        buf.write_u64::<BigEndian>(chunk.len() as u64)?;
+        buf.extend_from_slice(chunk);
+        db.write(offset, &buf)
+    }
+
+    /// Copies the value to `out` a page at a time, returning its length.
+    pub fn copy_to(&self, out: &mut impl Write, disk: &mut impl Disk) -> io::Result<u64> {
+        let mut copied = 0;
+        let mut next = self.first;
+        let mut buf = vec![];
+        while copied < self.len {
+            if next == 0 {
+                return Err(corrupt_page(self.first, "overflow chain ends early"));
+            }
+            let (following, len) = OverflowRef::read_header(next, disk)?;
+            buf.resize(min(len, self.len - copied) as usize, 0);
+            disk.read_exact(&mut buf)?;
+            out.write_all(&buf)?;
+            copied += buf.len() as u64;
+            next = following;
+        }
+        Ok(copied)
+    }
+
+    /// Reads the first `limit` bytes of the value, or all of it if it's
+    /// shorter than that.
+    pub fn read(&self, limit: u64, disk: &mut impl Disk) -> io::Result<Vec<u8>> {
+        let wanted = min(self.len, limit) as usize;
+        let mut buf = Vec::with_capacity(wanted);
+        let mut next = self.first;
+        while buf.len() < wanted {
+            if next == 0 {
+                return Err(corrupt_page(self.first, "overflow chain ends early"));
+            }
+            let (following, len) = OverflowRef::read_header(next, disk)?;
+            let start = buf.len();
+            buf.resize(start + min(len as usize, wanted - start), 0);
+            disk.read_exact(&mut buf[start..])?;
+            next = following;
+        }
+        Ok(buf)
+    }
+
+    /// Offsets of every page in the chain, in order. A chain with more pages
+    /// than the value needs, as one that loops back on itself has, is
+    /// reported as corrupt.
+    pub fn pages(&self, block_size: u64, disk: &mut impl Disk) -> io::Result<Vec<PageOffset>> {
+        let most = self.len.div_ceil(block_size - OverflowRef::header_len());
+        let mut pages = vec![];
+        let mut next = self.first;
+        while next != 0 {
+            if pages.len() as u64 == most {
+                return Err(corrupt_page(
+                    self.first,
+                    "overflow chain is longer than its value",
+                ));
+            }
+            pages.push(next);
+            next = OverflowRef::read_header(next, disk)?.0;
+        }
+        Ok(pages)
+    }
+
+    /// Reads the header of the overflow page at `offset`, returning the next
+    /// page in the chain and the number of value bytes this page holds. The
+    /// disk is left at the start of those bytes.
+    pub(super) fn read_header(
+        offset: PageOffset,
+        disk: &mut impl Disk,
+    ) -> io::Result<(PageOffset, u64)> {
+        disk.seek(SeekFrom::Start(offset))?;
+        if disk.read_u8()? != Page::OVERFLOW_TAG {
+            return Err(corrupt_page(offset, "expected an overflow page"));
+        }
+        let next = disk.read_u64::<BigEndian>()?;
+        let len = disk.read_u64::<BigEndian>()?;
+        Ok((next, len))
+    }
+}
@@ -0,0 +1,318 @@
+use super::{BTree, Key, Page, PageOffset};
+use crate::error::CorruptPage;
+use crate::{Database, Disk, Result};
+
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+
+/// A broken invariant found by `BTree::verify` or `Database::verify`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Inconsistency {
+    /// The block the problem was found in.
+    pub offset: PageOffset,
+    pub reason: String,
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "block {}: {}", self.offset, self.reason)
+    }
+}
+
+/// Whether `err` says the file is damaged, as opposed to the disk failing.
+pub(crate) fn is_corruption(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof
+    )
+}
+
+/// The state of a walk over one tree.
+struct Check<'a> {
+    block_size: u64,
+    end: u64,
+    seen: &'a mut HashSet<PageOffset>,
+    found: &'a mut Vec<Inconsistency>,
+    leaf_depth: Option<usize>,
+    /// Each leaf with its links, in key order.
+    leaves: Vec<(PageOffset, Option<PageOffset>, Option<PageOffset>)>,
+    entries: u64,
+}
+
+fn in_range(key: Key, lower: Option<Key>, upper: Option<Key>) -> bool {
+    lower.is_none_or(|lower| key > lower) && upper.is_none_or(|upper| key <= upper)
+}
+
+impl Check<'_> {
+    fn report(&mut self, offset: PageOffset, reason: String) {
+        self.found.push(Inconsistency { offset, reason });
+    }
+
+    /// Marks `offset` as used, reporting it if it isn't a block of the file
+    /// or something else already uses it.
+    fn claim(&mut self, offset: PageOffset) -> bool {
+        if offset == 0 || !offset.is_multiple_of(self.block_size) || offset >= self.end {
+            self.report(offset, "is not an allocated block".to_string());
+            false
+        } else if !self.seen.insert(offset) {
+            self.report(offset, "is referenced more than once".to_string());
+            false
+        } else {
+            true
+        }
+    }
+
+    fn unreadable(&mut self, offset: PageOffset, err: io::Error) -> io::Result<()> {
+        if !is_corruption(&err) {
+            return Err(err);
+        }
+        // the offset is reported alongside, so a corrupt page's own message
+        // would only repeat it
+        let reason = match err
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<CorruptPage>())
+        {
+            Some(corrupt) => corrupt.reason.clone(),
+            None => err.to_string(),
+        };
+        self.report(offset, reason);
+        Ok(())
+    }
+
+    /// Checks the page at `offset` and everything under it. Its keys have to
+    /// be above `lower` and no higher than `upper`.
+    fn page<D: Disk>(
+        &mut self,
+        db: &mut Database<D>,
+        offset: PageOffset,
+        lower: Option<Key>,
+        upper: Option<Key>,
+        depth: usize,
+    ) -> io::Result<()> {
+        if !self.claim(offset) {
+            return Ok(());
+        }
+        let page = match Page::load(offset, db) {
+            Ok(page) => page,
+            Err(err) => return self.unreadable(offset, err),
+        };
+        match page {
+            Page::Internal(page) => {
+                // the format stores one more pointer than keys, so there's
+                // no way for the two counts to disagree
+                let keys = page.keys();
+                if keys.windows(2).any(|pair| pair[0] >= pair[1]) {
+                    self.report(offset, "separator keys are not sorted".to_string());
+                }
+                if let Some(&key) = keys.iter().find(|&&key| !in_range(key, lower, upper)) {
+                    self.report(
+                        offset,
+                        format!("separator {} lies outside the page's key range", key),
+                    );
+                }
+                for (i, &pointer) in page.pointers().iter().enumerate() {
+                    let child_lower = if i == 0 { lower } else { Some(keys[i - 1]) };
+                    let child_upper = keys.get(i).copied().or(upper);
+                    self.page(db, pointer, child_lower, child_upper, depth + 1)?;
+                }
+            }
+            Page::Leaf(leaf) => {
+                match self.leaf_depth {
+                    Some(leaf_depth) if leaf_depth != depth => self.report(
+                        offset,
+                        format!("leaf at depth {}, other leaves at {}", depth, leaf_depth),
+                    ),
+                    _ => self.leaf_depth = Some(depth),
+                }
+                if let Some(entry) = leaf
+                    .keys()
+                    .iter()
+                    .find(|entry| !in_range(entry.key, lower, upper))
+                {
+                    self.report(
+                        offset,
+                        format!("key {} lies outside the separators above it", entry.key),
+                    );
+                }
+                let mut values: Vec<_> = leaf
+                    .keys()
+                    .iter()
+                    .filter(|entry| entry.stored_len() > 0)
+                    .collect();
+                values.sort_by_key(|entry| entry.offset);
+                for pair in values.windows(2) {
+                    if pair[0].offset + pair[0].value_len > pair[1].offset {
+                        self.report(
+                            offset,
+                            format!("values of keys {} and {} overlap", pair[0].key, pair[1].key),
+                        );
+                    }
+                }
+                for entry in leaf.keys() {
+                    let pages =
+                        leaf.overflow_ref(entry, &mut db.disk).and_then(
+                            |overflow| match overflow {
+                                Some(overflow) => overflow.pages(db.block_size(), &mut db.disk),
+                                None => Ok(vec![]),
+                            },
+                        );
+                    match pages {
+                        Ok(pages) => {
+                            for page in pages {
+                                self.claim(page);
+                            }
+                        }
+                        Err(err) => self.unreadable(offset, err)?,
+                    }
+                }
+                self.leaves
+                    .push((offset, leaf.prev_leaf_offset(), leaf.next_leaf_offset()));
+                self.entries += leaf.keys().len() as u64;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that each leaf links to the ones either side of it.
+    fn leaf_links(&mut self) {
+        let leaves = std::mem::take(&mut self.leaves);
+        for (i, &(offset, prev, next)) in leaves.iter().enumerate() {
+            let expected_prev = i.checked_sub(1).map(|i| leaves[i].0);
+            let expected_next = leaves.get(i + 1).map(|leaf| leaf.0);
+            if prev != expected_prev {
+                self.report(
+                    offset,
+                    format!("links back to {:?}, expected {:?}", prev, expected_prev),
+                );
+            }
+            if next != expected_next {
+                self.report(
+                    offset,
+                    format!("links on to {:?}, expected {:?}", next, expected_next),
+                );
+            }
+        }
+    }
+}
+
+impl BTree {
+    /// Checks the structure of the tree: that its keys are sorted and fall
+    /// between the separators above them, that the leaves all sit at the
+    /// same depth and are linked in order, that the values in a leaf don't
+    /// overlap, that no block is used twice, and that the root's entry count
+    /// matches the leaves. Problems are reported rather than raised, so that
+    /// a damaged tree can be looked over. Only errors from the disk itself
+    /// are returned as errors.
+    pub fn verify<D: Disk>(&self, db: &mut Database<D>) -> Result<Vec<Inconsistency>> {
+        let mut found = vec![];
+        self.verify_into(db, &mut HashSet::new(), &mut found)?;
+        Ok(found)
+    }
+
+    /// Like `verify`, but blocks already in `seen` count as used elsewhere,
+    /// and this tree's blocks are added to it. Returns whether the tree
+    /// turned out sound, and so whether its values can be trusted.
+    pub(crate) fn verify_into<D: Disk>(
+        &self,
+        db: &mut Database<D>,
+        seen: &mut HashSet<PageOffset>,
+        found: &mut Vec<Inconsistency>,
+    ) -> io::Result<bool> {
+        let before = found.len();
+        let mut check = Check {
+            block_size: db.block_size(),
+            end: db.num_blocks_allocated() * db.block_size(),
+            seen,
+            found,
+            leaf_depth: None,
+            leaves: vec![],
+            entries: 0,
+        };
+        check.page(db, self.offset(), None, None, 1)?;
+        check.leaf_links();
+        // the count is only worth comparing once the leaves are known good
+        let entries = check.entries;
+        if found.len() == before {
+            let len = self.len(db)?;
+            if len != entries {
+                found.push(Inconsistency {
+                    offset: self.offset(),
+                    reason: format!("entry count says {}, found {}", len, entries),
+                });
+            }
+        }
+        Ok(found.len() == before)
+    }
+}
+
+#[cfg(test)]
+mod verify_tests {
+    use super::*;
+    use crate::BlockAllocator;
+    use std::convert::TryInto;
+    use std::io::Cursor;
+
+    #[test]
+    fn sound_trees_have_nothing_to_report() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        for key in 0..3_000 {
+            tree.insert(key, &[key as u8; 40], &mut db)?;
+        }
+        tree.insert(1_500, &[0; 20_000], &mut db)?;
+        for key in (0..3_000).step_by(3) {
+            tree.delete(key, &mut db)?;
+        }
+        assert_eq!(tree.verify(&mut db)?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn shared_children_and_broken_links_are_reported() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        for key in 0..1_000 {
+            tree.insert(key, &[key as u8; 40], &mut db)?;
+        }
+        // point the root's second child at its first
+        let root = tree.offset();
+        let mut image = Page::read_block(root, &mut db)?;
+        let keys_len = u64::from_be_bytes(image[1..9].try_into().unwrap()) as usize;
+        let pointers = 9 + keys_len * 16;
+        let first: [u8; 8] = image[pointers..pointers + 8].try_into().unwrap();
+        image[pointers + 8..pointers + 16].copy_from_slice(&first);
+        Page::seal(&mut image);
+        db.write(root, &image)?;
+
+        let found = tree.verify(&mut db)?;
+        let first = u64::from_be_bytes(first);
+        assert!(found.contains(&Inconsistency {
+            offset: first,
+            reason: "is referenced more than once".to_string(),
+        }));
+        assert!(found
+            .iter()
+            .any(|inconsistency| inconsistency.reason.starts_with("links")));
+        Ok(())
+    }
+
+    #[test]
+    fn overflow_chains_that_loop_are_reported() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut tree = BTree::init(&mut db)?;
+        tree.insert(1, &[1; 20_000], &mut db)?;
+        // link the chain's first page to itself
+        let first = tree.overflow_pages(&mut db)?[0];
+        db.write(first + 1, &first.to_be_bytes())?;
+
+        assert_eq!(
+            tree.verify(&mut db)?,
+            vec![Inconsistency {
+                offset: tree.offset(),
+                reason: "overflow chain is longer than its value".to_string(),
+            }]
+        );
+        Ok(())
+    }
+}
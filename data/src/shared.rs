@@ -0,0 +1,240 @@
+use crate::database::ReadOnly;
+use crate::{Database, Disk, Error, Result};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// A database that can be shared between threads, doing its own locking.
+/// Reads take a read lock, so any number of them run at once, and writes
+/// take a write lock, waiting for the reads to finish first.
+///
+/// Reading moves the disk, so each read runs on a read-only database of its
+/// own, over a disk from the `open` function it was given. Those are kept
+/// for later reads, and opened again once something has been written, since
+/// what they have cached may be out of date. A disk from `open` has to see
+/// the writes made through the shared one, as separate handles to the same
+/// file do. Each reader decompresses with the compressor the shared database
+/// has at the time it's opened.
+///
+/// A write holds the whole database, not just the pages on its path. Pages
+/// are only ever changed under the write lock and each read has its own
+/// disk, so there's nothing for page-level latches to protect until writes
+/// stop shutting out reads; until then, latching each page on the way down
+/// would only add work.
+pub struct SharedDatabase<D: Disk> {
+    /// Held shared by reads and exclusively by writes.
+    lock: RwLock<()>,
+    writer: Mutex<Database<D>>,
+    open: Box<dyn Fn() -> io::Result<D> + Send + Sync>,
+    /// Idle readers, each with the generation it was opened in.
+    readers: Mutex<Vec<(u64, Database<ReadOnly<D>>)>>,
+    /// Counts the writes so far.
+    generation: AtomicU64,
+}
+
+impl<D: Disk> SharedDatabase<D> {
+    pub fn new(
+        db: Database<D>,
+        open: impl Fn() -> io::Result<D> + Send + Sync + 'static,
+    ) -> SharedDatabase<D> {
+        SharedDatabase {
+            lock: RwLock::new(()),
+            writer: Mutex::new(db),
+            open: Box::new(open),
+            readers: Mutex::new(vec![]),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Runs `f` on a read-only view of the database, alongside any other
+    /// reads. Lookups work as usual, but anything that would write, like
+    /// `get` on a key with no tree yet, fails with
+    /// `ErrorKind::PermissionDenied`.
+    pub fn read<T>(&self, f: impl FnOnce(&mut Database<ReadOnly<D>>) -> Result<T>) -> Result<T> {
+        let _guard = self.lock.read().map_err(|_| poisoned())?;
+        let generation = self.generation.load(Ordering::SeqCst);
+        let idle = self.readers.lock().map_err(|_| poisoned())?.pop();
+        let mut reader = match idle {
+            Some((opened_in, reader)) if opened_in == generation => reader,
+            _ => {
+                let mut reader = Database::open_read_only((self.open)()?)?;
+                let writer = self.writer.lock().map_err(|_| poisoned())?;
+                reader.compressor = Arc::clone(&writer.compressor);
+                reader
+            }
+        };
+        let result = f(&mut reader);
+        self.readers
+            .lock()
+            .map_err(|_| poisoned())?
+            .push((generation, reader));
+        result
+    }
+
+    /// Runs `f` on the database with every read and other write shut out.
+    pub fn write<T>(&self, f: impl FnOnce(&mut Database<D>) -> Result<T>) -> Result<T> {
+        let _guard = self.lock.write().map_err(|_| poisoned())?;
+        let mut db = self.writer.lock().map_err(|_| poisoned())?;
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        f(&mut db)
+    }
+
+    pub fn into_inner(self) -> Result<Database<D>> {
+        self.writer.into_inner().map_err(|_| poisoned())
+    }
+}
+
+fn poisoned() -> Error {
+    Error::Io(io::Error::other(
+        "a thread panicked while using the database",
+    ))
+}
+
+#[cfg(test)]
+mod shared_tests {
+    use super::*;
+    use crate::{Compressor, Key};
+    use std::fs::{self, File, OpenOptions};
+    use std::thread;
+
+    #[test]
+    fn reads_see_earlier_writes_from_any_thread() -> io::Result<()> {
+        const USERS: Key = 1;
+        let path = std::env::temp_dir().join(format!("tree-data-shared-{}", std::process::id()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        let reader_path = path.clone();
+        let shared = Arc::new(SharedDatabase::new(
+            Database::initialize(file)?,
+            move || File::open(&reader_path),
+        ));
+        shared.write(|db| db.get_or_create(USERS)?.set_value(1, &[1]))?;
+        assert_eq!(
+            shared.read(|db| db.get_existing(USERS)?.unwrap().value(1))?,
+            Some(vec![1])
+        );
+
+        let threads: Vec<_> = (0..4u8)
+            .map(|i| {
+                let shared = Arc::clone(&shared);
+                thread::spawn(move || -> io::Result<()> {
+                    for key in 0..50u8 {
+                        shared.write(|db| {
+                            db.get_or_create(USERS)?
+                                .set_value(Key::from(key), &[i, key])
+                        })?;
+                        let value = shared
+                            .read(|db| db.get_existing(USERS)?.unwrap().value(Key::from(key)))?;
+                        assert_eq!(value.map(|value| value[1]), Some(key));
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap()?;
+        }
+        assert_eq!(
+            shared
+                .read(|db| db.get_existing(USERS)?.unwrap().entries())?
+                .len(),
+            50
+        );
+
+        // readers can't write, even by accident
+        let err = shared
+            .read(|db| db.get_or_create(2).map(|_| ()))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        fs::remove_file(&path)
+    }
+
+    #[test]
+    fn reads_run_alongside_one_another() -> io::Result<()> {
+        let path = std::env::temp_dir().join(format!("tree-data-readers-{}", std::process::id()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        let reader_path = path.clone();
+        let shared = Arc::new(SharedDatabase::new(
+            Database::initialize(file)?,
+            move || File::open(&reader_path),
+        ));
+        shared.write(|db| db.get_or_create(1)?.set_value(1, &[1]))?;
+
+        // each read waits inside for the other, so they'd deadlock if reads
+        // took turns
+        let barrier = Arc::new(std::sync::Barrier::new(2));
+        let threads: Vec<_> = (0..2)
+            .map(|_| {
+                let shared = Arc::clone(&shared);
+                let barrier = Arc::clone(&barrier);
+                thread::spawn(move || {
+                    shared.read(|db| {
+                        barrier.wait();
+                        db.get_existing(1)?.unwrap().value(1)
+                    })
+                })
+            })
+            .collect();
+        for thread in threads {
+            assert_eq!(thread.join().unwrap()?, Some(vec![1]));
+        }
+        fs::remove_file(&path)
+    }
+
+    /// Stores each run of a byte as its length and the byte.
+    struct RunLength;
+
+    impl Compressor for RunLength {
+        fn compress(&self, data: &[u8]) -> Vec<u8> {
+            let mut out = vec![];
+            for run in data.chunk_by(|a, b| a == b) {
+                for part in run.chunks(255) {
+                    out.extend_from_slice(&[part.len() as u8, part[0]]);
+                }
+            }
+            out
+        }
+        fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+            if !data.len().is_multiple_of(2) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "odd run"));
+            }
+            Ok(data
+                .chunks(2)
+                .flat_map(|run| std::iter::repeat_n(run[1], run[0].into()))
+                .collect())
+        }
+    }
+
+    #[test]
+    fn reads_use_the_writers_compressor() -> io::Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("tree-data-compressor-{}", std::process::id()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        let mut db = Database::initialize(file)?;
+        db.set_compression(Some(0));
+        db.set_compressor(RunLength);
+        let reader_path = path.clone();
+        let shared = SharedDatabase::new(db, move || File::open(&reader_path));
+        let value = [[1; 300], [2; 300]].concat();
+        shared.write(|db| db.get_or_create(1)?.set_value(1, &value))?;
+        assert_eq!(
+            shared.read(|db| db.get_existing(1)?.unwrap().value(1))?,
+            Some(value)
+        );
+        fs::remove_file(&path)
+    }
+}
@@ -0,0 +1,148 @@
+use crate::codec::{Codec, Json};
+use crate::{BTree, Database, Disk, Key, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+
+/// A `BTree` holding `V`s under keys made from `K`s, with the values
+/// encoded by a `Codec`, JSON unless given another. It's only a view: the
+/// tree itself stores plain bytes, and can still be reached with `tree`.
+pub struct Table<K, V, C = Json> {
+    tree: BTree,
+    codec: C,
+    types: PhantomData<fn(K) -> V>,
+}
+
+impl<K: Into<Key> + Clone, V: Serialize + DeserializeOwned> Table<K, V> {
+    pub fn new(tree: BTree) -> Self {
+        Table::with_codec(tree, Json)
+    }
+}
+
+impl<K: Into<Key> + Clone, V: Serialize + DeserializeOwned, C: Codec> Table<K, V, C> {
+    pub fn with_codec(tree: BTree, codec: C) -> Self {
+        Table {
+            tree,
+            codec,
+            types: PhantomData,
+        }
+    }
+
+    pub fn tree(&self) -> &BTree {
+        &self.tree
+    }
+
+    pub fn get<D: Disk>(&self, key: K, db: &mut Database<D>) -> Result<Option<V>> {
+        match self.tree.lookup(key.into(), db)? {
+            Some(data) => Ok(Some(self.codec.decode(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn insert<D: Disk>(&mut self, key: K, value: &V, db: &mut Database<D>) -> Result<()> {
+        let data = self.codec.encode(value)?;
+        self.tree.insert(key.into(), &data, db)
+    }
+
+    /// Removes the value under `key`, returning it if there was one.
+    pub fn remove<D: Disk>(&mut self, key: K, db: &mut Database<D>) -> Result<Option<V>> {
+        match self.tree.delete(key.into(), db)? {
+            Some(data) => Ok(Some(self.codec.decode(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The values whose keys fall in `range`, in key order. The keys come
+    /// back as they're stored, since there's no way back from a `Key` to a
+    /// `K` in general.
+    pub fn range<D: Disk>(
+        &self,
+        range: impl RangeBounds<K>,
+        db: &mut Database<D>,
+    ) -> Result<Vec<(Key, V)>> {
+        let first = match range.start_bound() {
+            Bound::Included(key) => key.clone().into(),
+            Bound::Excluded(key) => match key.clone().into().checked_add(1) {
+                Some(key) => key,
+                None => return Ok(vec![]),
+            },
+            Bound::Unbounded => Key::MIN,
+        };
+        let last = match range.end_bound() {
+            Bound::Included(key) => key.clone().into(),
+            Bound::Excluded(key) => match key.clone().into().checked_sub(1) {
+                Some(key) => key,
+                None => return Ok(vec![]),
+            },
+            Bound::Unbounded => Key::MAX,
+        };
+        // the scan stops on the first key past `last` without reading its
+        // value
+        let mut scan = self.tree.scan(first..=last);
+        let mut found = vec![];
+        while let Some(entry) = scan.next(db) {
+            let (key, data) = entry?;
+            found.push((key, self.codec.decode(&data)?));
+        }
+        Ok(found)
+    }
+}
+
+#[cfg(test)]
+mod table_tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::io::{self, Cursor};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Expense {
+        name: String,
+        cents: i64,
+    }
+
+    fn expense(name: &str, cents: i64) -> Expense {
+        Expense {
+            name: name.to_string(),
+            cents,
+        }
+    }
+
+    #[test]
+    fn tables_encode_and_decode_values() -> io::Result<()> {
+        let mut db = Database::initialize(Cursor::new(vec![]))?;
+        let mut expenses: Table<u64, Expense> = Table::new(BTree::init(&mut db)?);
+        for id in 0..10 {
+            expenses.insert(id, &expense("rent", id as i64 * 100), &mut db)?;
+        }
+        assert_eq!(expenses.get(3, &mut db)?, Some(expense("rent", 300)));
+        assert_eq!(expenses.get(10, &mut db)?, None);
+        assert_eq!(expenses.remove(3, &mut db)?, Some(expense("rent", 300)));
+        assert_eq!(expenses.remove(3, &mut db)?, None);
+
+        let ids = |found: Vec<(Key, Expense)>| -> Vec<Key> {
+            found.into_iter().map(|(key, _)| key).collect()
+        };
+        assert_eq!(ids(expenses.range(2..5, &mut db)?), vec![2, 4]);
+        assert_eq!(ids(expenses.range(8.., &mut db)?), vec![8, 9]);
+        assert_eq!(ids(expenses.range(..=1, &mut db)?), vec![0, 1]);
+        assert_eq!(ids(expenses.range(..0, &mut db)?), vec![]);
+
+        // values that aren't `Expense`s are reported rather than misread
+        let mut tree = BTree::from_offset(expenses.tree().offset());
+        tree.insert(0, b"not json", &mut db)?;
+        let err = expenses.get(0, &mut db).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // a range stops before reading the value past its end
+        let blob = vec![7; 16 * 1024];
+        tree.insert(10, &blob, &mut db)?;
+        let overflow = tree.overflow_pages(&mut db)?[0];
+        let mut disk = db.into_disk();
+        disk.get_mut()[overflow as usize] ^= 0xff;
+        let mut db = Database::from_existing(disk)?;
+        assert_eq!(ids(expenses.range(8..10, &mut db)?), vec![8, 9]);
+        assert!(expenses.range(8..=10, &mut db).is_err());
+        Ok(())
+    }
+}
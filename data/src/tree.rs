@@ -1,4 +1,9 @@
-use crate::{BTree, Database, Disk, Key};
+use crate::page::LeafPage;
+use crate::{BTree, Database, Disk, Key, Keys, Result};
+#[cfg(feature = "serde")]
+use crate::{Codec, Json};
+#[cfg(feature = "serde")]
+use serde::{de::DeserializeOwned, Serialize};
 use std::convert::TryInto;
 use std::io;
 
@@ -7,17 +12,30 @@ pub struct TreeEntry<'d, D: Disk> {
     pub(crate) offset: u64,
 }
 
-fn read_be_u64(input: &[u8]) -> u64 {
-    let (int_bytes, rest) = input.split_at(std::mem::size_of::<u64>());
+pub(crate) fn read_be_u64(input: &[u8]) -> u64 {
+    let (int_bytes, _rest) = input.split_at(std::mem::size_of::<u64>());
     u64::from_be_bytes(int_bytes.try_into().unwrap())
 }
 
+/// What a key at some level holds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    Value,
+    Child,
+    Both,
+}
+
+/// The stored form of an entry: the child tree's offset, or zero for none,
+/// then, if the entry has a value, a 1 byte followed by the value. The extra
+/// byte lets an empty value be told apart from no value.
 struct TreeEntryValue {
     child_offset: Option<std::num::NonZeroU64>,
     data: Option<Vec<u8>>,
 }
 
 impl TreeEntryValue {
+    const HAS_DATA: u8 = 1;
+
     fn from_data(mut data: Vec<u8>) -> TreeEntryValue {
         if data.len() < 8 {
             data.resize_with(8, Default::default);
@@ -39,7 +57,18 @@ impl TreeEntryValue {
         let child_offset = std::num::NonZeroU64::new(child_offset);
         TreeEntryValue {
             child_offset,
-            data: if data.len() > 0 { Some(data) } else { None },
+            data: match data.first() {
+                Some(&TreeEntryValue::HAS_DATA) => Some(data[1..].to_vec()),
+                _ => None,
+            },
+        }
+    }
+    fn kind(&self) -> Option<EntryKind> {
+        match (self.data.is_some(), self.child_offset.is_some()) {
+            (true, false) => Some(EntryKind::Value),
+            (false, true) => Some(EntryKind::Child),
+            (true, true) => Some(EntryKind::Both),
+            (false, false) => None,
         }
     }
     fn new() -> TreeEntryValue {
@@ -58,18 +87,254 @@ impl TreeEntryValue {
                 .as_ref(),
         );
         if let Some(data) = self.data {
-            buf.extend_from_slice(&data.as_slice());
+            buf.push(TreeEntryValue::HAS_DATA);
+            buf.extend_from_slice(data.as_slice());
         }
         buf
     }
 }
 
+/// Merges the level at `other_offset` in `other_db` into the level at
+/// `offset` in `db`, recursing into child trees.
+fn merge_level<D: Disk, O: Disk>(
+    db: &mut Database<D>,
+    offset: u64,
+    other_db: &mut Database<O>,
+    other_offset: u64,
+) -> io::Result<()> {
+    BTree::from_offset(other_offset).for_each_leaf(other_db, |leaf, other_db| {
+        for entry in leaf.keys() {
+            let other_value = TreeEntryValue::from_data(leaf.read_value(entry, other_db)?);
+            if let Some(data) = other_value.data {
+                TreeEntry {
+                    db: &mut *db,
+                    offset,
+                }
+                .set_value(entry.key, &data)?;
+            }
+            if let Some(other_child) = other_value.child_offset {
+                let child = TreeEntry {
+                    db: &mut *db,
+                    offset,
+                }
+                .get_or_create(entry.key)?
+                .offset;
+                merge_level(db, child, other_db, other_child.get())?;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Copies the level at `offset` into new blocks, along with the child trees
+/// under it, and returns the offset of the copy.
+fn copy_level<D: Disk>(db: &mut Database<D>, offset: u64) -> io::Result<u64> {
+    let mut entries = BTree::from_offset(offset).flagged_entries(db)?;
+    for (_, value, _) in &mut entries {
+        let mut entry = TreeEntryValue::from_data(std::mem::take(value));
+        if let Some(child) = entry.child_offset {
+            entry.child_offset = std::num::NonZeroU64::new(copy_level(db, child.get())?);
+        }
+        *value = entry.into_buf();
+    }
+    Ok(BTree::bulk_load_flagged(db, entries.into_iter())?.offset())
+}
+
+/// Frees every block of the level at `offset`, including its overflow pages
+/// and the child trees under it.
+fn free_level<D: Disk>(db: &mut Database<D>, offset: u64) -> io::Result<()> {
+    for child in (TreeEntry {
+        db: &mut *db,
+        offset,
+    })
+    .child_offsets()?
+    {
+        free_level(db, child)?;
+    }
+    BTree::from_offset(offset).free_all(db)?;
+    Ok(())
+}
+
+/// Copies the level at `offset` in `db` into `target`, rebuilt with
+/// `BTree::bulk_load` once its child trees have been copied, so the copy's
+/// pages are packed and point at the copied children. Returns the offset of
+/// the copy. The level's entries are held in memory while it's rebuilt.
+pub(crate) fn vacuum_level<D: Disk, T: Disk>(
+    db: &mut Database<D>,
+    offset: u64,
+    target: &mut Database<T>,
+) -> io::Result<u64> {
+    let mut entries = vec![];
+    BTree::from_offset(offset).for_each_leaf(db, |leaf, db| {
+        for entry in leaf.keys() {
+            entries.push((entry.key, leaf.read_value(entry, db)?));
+        }
+        Ok(())
+    })?;
+    for (_, value) in entries.iter_mut() {
+        let mut entry = TreeEntryValue::from_data(std::mem::take(value));
+        if let Some(child) = entry.child_offset {
+            let copied = vacuum_level(db, child.get(), target)?;
+            entry.child_offset = std::num::NonZeroU64::new(copied);
+        }
+        *value = entry.into_buf();
+    }
+    Ok(BTree::bulk_load(target, entries.into_iter())?.offset())
+}
+
+/// 64 bit FNV-1a. Unlike `DefaultHasher`, its output is fixed, so hashes
+/// can be compared across builds and machines.
+pub(crate) struct ContentHasher(u64);
+
+impl ContentHasher {
+    pub(crate) fn new() -> ContentHasher {
+        ContentHasher(0xcbf2_9ce4_8422_2325)
+    }
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+    pub(crate) fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Feeds every `(path, value)` pair under the level at `offset` into
+/// `hasher`, in key order.
+pub(crate) fn hash_level<D: Disk>(
+    db: &mut Database<D>,
+    offset: u64,
+    path: &mut Vec<Key>,
+    hasher: &mut ContentHasher,
+) -> io::Result<()> {
+    BTree::from_offset(offset).for_each_leaf(db, |leaf, db| {
+        for entry in leaf.keys() {
+            let value = TreeEntryValue::from_data(leaf.read_value(entry, db)?);
+            path.push(entry.key);
+            if let Some(data) = value.data {
+                hasher.write(&(path.len() as u64).to_be_bytes());
+                for key in path.iter() {
+                    hasher.write(&key.to_be_bytes());
+                }
+                hasher.write(&(data.len() as u64).to_be_bytes());
+                hasher.write(&data);
+            }
+            if let Some(child) = value.child_offset {
+                hash_level(db, child.get(), path, hasher)?;
+            }
+            path.pop();
+        }
+        Ok(())
+    })
+}
+
+/// Feeds the name of a catalog table and every `(key, value)` pair of its
+/// tree at `offset` into `hasher`, in key order.
+pub(crate) fn hash_table<D: Disk>(
+    db: &mut Database<D>,
+    name: &[u8],
+    offset: u64,
+    hasher: &mut ContentHasher,
+) -> io::Result<()> {
+    hasher.write(&(name.len() as u64).to_be_bytes());
+    hasher.write(name);
+    BTree::from_offset(offset).for_each_leaf(db, |leaf, db| {
+        for entry in leaf.keys() {
+            let data = leaf.read_value(entry, db)?;
+            hasher.write(&entry.key.to_be_bytes());
+            hasher.write(&(data.len() as u64).to_be_bytes());
+            hasher.write(&data);
+        }
+        Ok(())
+    })
+}
+
+/// The state of `TreeEntry::walk`: for each level being walked, the path
+/// to it and where in its leaves the walk has got to.
+struct Walk<'a, D: Disk> {
+    db: &'a mut Database<D>,
+    levels: Vec<WalkLevel>,
+}
+
+struct WalkLevel {
+    path: Vec<Key>,
+    /// The leaf being walked, or `None` once the level is done.
+    leaf: Option<LeafPage>,
+    /// How many entries of `leaf` have been stepped past.
+    taken: usize,
+}
+
+impl WalkLevel {
+    fn new<D: Disk>(path: Vec<Key>, offset: u64, db: &mut Database<D>) -> io::Result<WalkLevel> {
+        Ok(WalkLevel {
+            path,
+            leaf: Some(BTree::from_offset(offset).leftmost_leaf(db)?),
+            taken: 0,
+        })
+    }
+}
+
+impl<D: Disk> Walk<'_, D> {
+    fn step(&mut self) -> io::Result<Option<(Vec<Key>, Vec<u8>)>> {
+        loop {
+            let level = match self.levels.last_mut() {
+                Some(level) => level,
+                None => return Ok(None),
+            };
+            let leaf = match &level.leaf {
+                Some(leaf) => leaf,
+                None => {
+                    self.levels.pop();
+                    continue;
+                }
+            };
+            if level.taken == leaf.keys().len() {
+                level.leaf = leaf.next_leaf(self.db)?;
+                level.taken = 0;
+                continue;
+            }
+            let entry = &leaf.keys()[level.taken];
+            level.taken += 1;
+            let mut path = level.path.clone();
+            path.push(entry.key);
+            let value = TreeEntryValue::from_data(leaf.read_value(entry, self.db)?);
+            if let Some(child) = value.child_offset {
+                let child = WalkLevel::new(path.clone(), child.get(), self.db)?;
+                self.levels.push(child);
+            }
+            if let Some(data) = value.data {
+                return Ok(Some((path, data)));
+            }
+        }
+    }
+}
+
+impl<D: Disk> Iterator for Walk<'_, D> {
+    type Item = Result<(Vec<Key>, Vec<u8>)>;
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.step() {
+            Ok(entry) => entry.map(Ok),
+            Err(err) => {
+                self.levels.clear();
+                Some(Err(err.into()))
+            }
+        }
+    }
+}
+
 impl<'d, D: Disk> TreeEntry<'d, D> {
-    fn tree(&self) -> BTree {
+    pub(crate) fn tree(&self) -> BTree {
         BTree::from_offset(self.offset)
     }
     fn insert_child_tree(&mut self, key: Key) -> io::Result<BTree> {
         let child = BTree::init(self.db)?;
+        self.set_child_offset(key, child.offset())?;
+        Ok(child)
+    }
+    /// Points `key` at the child tree rooted at `offset`, keeping its value.
+    pub(crate) fn set_child_offset(&mut self, key: Key, offset: u64) -> io::Result<()> {
         let mut tree = self.tree();
         let existing_value = tree.lookup(key, self.db)?;
         let mut entry = match existing_value {
@@ -77,11 +342,62 @@ impl<'d, D: Disk> TreeEntry<'d, D> {
             None => TreeEntryValue::new(),
         };
 
-        entry.child_offset = std::num::NonZeroU64::new(child.offset());
-        tree.insert(key, &entry.into_buf(), self.db)?;
-        Ok(child)
+        entry.child_offset = std::num::NonZeroU64::new(offset);
+        Ok(tree.insert(key, &entry.into_buf(), self.db)?)
+    }
+    /// The root offset of `key`'s child tree, without creating one.
+    pub(crate) fn child_offset(&mut self, key: Key) -> io::Result<Option<u64>> {
+        Ok(self
+            .tree()
+            .lookup(key, self.db)?
+            .and_then(|data| TreeEntryValue::from_data(data).child_offset)
+            .map(|offset| offset.get()))
+    }
+    /// The root offsets of every child tree at this level. Only the child
+    /// pointer of each entry is read.
+    pub(crate) fn child_offsets(&mut self) -> io::Result<Vec<u64>> {
+        let mut offsets = vec![];
+        self.tree().for_each_leaf(self.db, |leaf, db| {
+            for entry in leaf.keys() {
+                let mut child_offset = [0u8; 8];
+                leaf.read_value_prefix(entry, &mut child_offset, db)?;
+                match read_be_u64(&child_offset) {
+                    0 => {}
+                    offset => offsets.push(offset),
+                }
+            }
+            Ok(())
+        })?;
+        Ok(offsets)
+    }
+    /// Borrows this entry for one call to a method that takes it by value,
+    /// like `user.by_ref().value(NAME)`, so that a level found once can be
+    /// read and written many times without going back to the root to find it
+    /// each time.
+    pub fn by_ref(&mut self) -> TreeEntry<'_, D> {
+        TreeEntry {
+            db: self.db,
+            offset: self.offset,
+        }
+    }
+    #[deprecated(note = "use `get_or_create`, or `get_existing` to read without writing")]
+    pub fn get(self, key: Key) -> Result<Self> {
+        self.get_or_create(key)
+    }
+    /// `key`'s child tree, or `None` if it hasn't got one. Unlike
+    /// `get_or_create`, this never writes to the file.
+    pub fn get_existing(mut self, key: Key) -> Result<Option<Self>> {
+        match self.child_offset(key)? {
+            Some(offset) => Ok(Some(TreeEntry {
+                db: self.db,
+                offset,
+            })),
+            None => Ok(None),
+        }
     }
-    pub fn get(mut self, key: Key) -> io::Result<Self> {
+    /// `key`'s child tree, created first if it hasn't got one, so reaching a
+    /// new key writes to the file.
+    pub fn get_or_create(mut self, key: Key) -> Result<Self> {
         let tree = self.tree();
         let offset = match tree.lookup(key, self.db)? {
             Some(buf) => match TreeEntryValue::from_data(buf).child_offset {
@@ -95,7 +411,13 @@ impl<'d, D: Disk> TreeEntry<'d, D> {
             offset,
         })
     }
-    pub fn set_value(self, key: Key, data: &[u8]) -> io::Result<()> {
+    /// Merges everything under `other` into this entry, recursing into child
+    /// trees. Where both sides have a value for a key, `other`'s value wins;
+    /// keys only present on this side are left alone.
+    pub fn merge_from<O: Disk>(self, other: TreeEntry<'_, O>) -> Result<()> {
+        Ok(merge_level(self.db, self.offset, other.db, other.offset)?)
+    }
+    pub fn set_value(self, key: Key, data: &[u8]) -> Result<()> {
         let mut tree = BTree::from_offset(self.offset);
         let mut entry = match tree.lookup(key, self.db)? {
             Some(data) => TreeEntryValue::from_data(data),
@@ -104,12 +426,238 @@ impl<'d, D: Disk> TreeEntry<'d, D> {
         entry.data = Some(data.to_vec());
         tree.insert(key, &entry.into_buf(), self.db)
     }
-    pub fn value(self, key: Key) -> io::Result<Option<Vec<u8>>> {
+    /// Sets every value in `entries` as `set_value` would, in one
+    /// transaction and with one pass down the tree for each run of keys
+    /// sharing a leaf, both to read what's already stored under them and to
+    /// write them back. Where a key appears more than once, the last value
+    /// wins.
+    pub fn set_values(self, entries: &[(Key, &[u8])]) -> Result<()> {
+        let mut tree = self.tree();
+        let mut sorted: Vec<&(Key, &[u8])> = entries.iter().collect();
+        sorted.sort_by_key(|&&(key, _)| key);
+        self.db.atomically(|db| {
+            let mut bufs = Vec::with_capacity(sorted.len());
+            let mut rest = &sorted[..];
+            while let Some(&&(first, _)) = rest.first() {
+                let (leaf, upper) = tree.find_leaf_bounded(first, db)?;
+                let run = rest
+                    .iter()
+                    .take_while(|&&&(key, _)| upper.is_none_or(|upper| key <= upper))
+                    .count();
+                for &&(key, data) in &rest[..run] {
+                    let mut entry = match leaf.lookup_value_alloc(key, db)? {
+                        Some(data) => TreeEntryValue::from_data(data),
+                        None => TreeEntryValue::new(),
+                    };
+                    entry.data = Some(data.to_vec());
+                    bufs.push((key, entry.into_buf()));
+                }
+                rest = &rest[run..];
+            }
+            let bufs: Vec<(Key, &[u8])> = bufs.iter().map(|(key, buf)| (*key, &buf[..])).collect();
+            tree.insert_many(&bufs, db)?;
+            Ok(())
+        })?;
+        Ok(())
+    }
+    /// Removes the value stored under `key`, leaving its child tree, if it
+    /// has one, in place. Returns whether there was a value to remove.
+    pub fn delete_value(self, key: Key) -> Result<bool> {
+        let mut tree = self.tree();
+        Ok(self.db.atomically(|db| {
+            let mut entry = match tree.lookup(key, db)? {
+                Some(data) => TreeEntryValue::from_data(data),
+                None => return Ok(false),
+            };
+            if entry.data.take().is_none() {
+                return Ok(false);
+            }
+            match entry.child_offset {
+                Some(_) => tree.insert(key, &entry.into_buf(), db)?,
+                None => {
+                    tree.delete(key, db)?;
+                }
+            }
+            Ok(true)
+        })?)
+    }
+    /// Removes `key`'s child tree and everything under it, freeing their
+    /// blocks. Any value stored under `key` itself is kept.
+    pub fn delete_child(self, key: Key) -> Result<()> {
+        let mut tree = self.tree();
+        Ok(self.db.atomically(|db| {
+            let mut entry = match tree.lookup(key, db)? {
+                Some(data) => TreeEntryValue::from_data(data),
+                None => return Ok(()),
+            };
+            let child = match entry.child_offset.take() {
+                Some(child) => child.get(),
+                None => return Ok(()),
+            };
+            match entry.data {
+                Some(_) => tree.insert(key, &entry.into_buf(), db)?,
+                None => {
+                    tree.delete(key, db)?;
+                }
+            }
+            free_level(db, child)
+        })?)
+    }
+    /// Copies `from`'s child tree, and every tree under it, to be `to`'s
+    /// child tree, e.g. to keep a table as it was before a bulk import. The
+    /// copy shares no blocks with the original, so either can be changed or
+    /// deleted without touching the other. Any child tree `to` had is freed
+    /// and its value kept. Returns whether `from` had a child tree to copy.
+    pub fn copy_child(mut self, from: Key, to: Key) -> Result<bool> {
+        let source = match self.child_offset(from)? {
+            Some(offset) => offset,
+            None => return Ok(false),
+        };
+        let offset = self.offset;
+        Ok(self.db.atomically(|db| {
+            let mut entry = TreeEntry { db, offset };
+            let replaced = entry.child_offset(to)?;
+            let copy = copy_level(entry.db, source)?;
+            entry.set_child_offset(to, copy)?;
+            if let Some(replaced) = replaced {
+                free_level(entry.db, replaced)?;
+            }
+            Ok(true)
+        })?)
+    }
+    /// Iterates over every key at this level in ascending order, e.g. the
+    /// rows of a table. That includes keys with a value, a child tree, or
+    /// both.
+    pub fn keys(&mut self) -> Result<Keys<'_, D>> {
+        self.tree().keys(self.db)
+    }
+    /// Every key at this level in ascending order, with whether it holds a
+    /// value, a child tree, or both. Only the start of each value is read.
+    pub fn entries(&mut self) -> Result<Vec<(Key, EntryKind)>> {
+        let mut entries = vec![];
+        self.tree().for_each_leaf(self.db, |leaf, db| {
+            for entry in leaf.keys() {
+                // the child pointer, and a byte to show whether there's data
+                let mut prefix = vec![0u8; 9];
+                let len = leaf.read_value_prefix(entry, &mut prefix, db)?;
+                prefix.truncate(len);
+                if let Some(kind) = TreeEntryValue::from_data(prefix).kind() {
+                    entries.push((entry.key, kind));
+                }
+            }
+            Ok(())
+        })?;
+        Ok(entries)
+    }
+    /// Counts the keys at this level that have a child tree, e.g. the number
+    /// of rows in a table. Only the child pointer of each entry is read; the
+    /// child trees themselves are never visited.
+    pub fn child_count(mut self) -> Result<u64> {
+        Ok(self.child_offsets()?.len() as u64)
+    }
+    pub fn value(self, key: Key) -> Result<Option<Vec<u8>>> {
         let tree = BTree::from_offset(self.offset);
         Ok(tree
             .lookup(key, self.db)?
             .and_then(|data| TreeEntryValue::from_data(data).data))
     }
+    /// Stores `value` as 8 bytes in the database's value endianness.
+    pub fn set_u64(self, key: Key, value: u64) -> Result<()> {
+        let bytes = self.db.value_endianness().encode_u64(value);
+        self.set_value(key, &bytes)
+    }
+    /// Reads a value written by `set_u64`, failing with `InvalidData` if the
+    /// stored value isn't 8 bytes long.
+    pub fn get_u64(self, key: Key) -> Result<Option<u64>> {
+        let endianness = self.db.value_endianness();
+        self.value(key)?
+            .map(|data| {
+                let bytes = data.as_slice().try_into().map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("expected an 8 byte value, found {} bytes", data.len()),
+                    )
+                })?;
+                Ok(endianness.decode_u64(bytes))
+            })
+            .transpose()
+    }
+    pub fn set_i64(self, key: Key, value: i64) -> Result<()> {
+        self.set_u64(key, value as u64)
+    }
+    pub fn get_i64(self, key: Key) -> Result<Option<i64>> {
+        Ok(self.get_u64(key)?.map(|value| value as i64))
+    }
+    /// Every value at this level and in the child trees under it, each with
+    /// the path of keys leading to it, depth first and in key order. A key
+    /// holding both a value and a child tree gives its value before
+    /// anything under it. Each level is read a leaf at a time, following
+    /// the leaf chain, rather than all up front.
+    pub fn walk(&mut self) -> Result<impl Iterator<Item = Result<(Vec<Key>, Vec<u8>)>> + '_> {
+        let top = WalkLevel::new(vec![], self.offset, self.db)?;
+        Ok(Walk {
+            db: self.db,
+            levels: vec![top],
+        })
+    }
+    /// This level and everything under it as JSON, for looking over by
+    /// hand. Each key with anything stored maps to an object with its
+    /// value, in hex, under `"value"`, and its child tree, in the same
+    /// shape, under `"children"`. A key holding both has both. The keys come
+    /// out in key order, so 9 comes before 10.
+    ///
+    /// It's `json` rather than `to_json` because it takes `&mut self` to
+    /// read the database, and clippy's `wrong_self_convention` wants a
+    /// `to_` method to take `self` or `&self`.
+    #[cfg(feature = "json")]
+    pub fn json(&mut self) -> Result<serde_json::Value> {
+        let entries = self.tree().iter(self.db)?.collect::<Result<Vec<_>>>()?;
+        let mut level = serde_json::Map::new();
+        for (key, data) in entries {
+            let entry = TreeEntryValue::from_data(data);
+            let mut fields = serde_json::Map::new();
+            if let Some(data) = entry.data {
+                let hex: String = data.iter().map(|byte| format!("{:02x}", byte)).collect();
+                fields.insert("value".to_string(), hex.into());
+            }
+            if let Some(offset) = entry.child_offset {
+                let mut child = TreeEntry {
+                    db: self.db,
+                    offset: offset.get(),
+                };
+                fields.insert("children".to_string(), child.json()?);
+            }
+            if !fields.is_empty() {
+                level.insert(key.to_string(), fields.into());
+            }
+        }
+        Ok(level.into())
+    }
+    /// Stores `value` under `key` as JSON.
+    #[cfg(feature = "serde")]
+    pub fn set_serialized<T: Serialize>(self, key: Key, value: &T) -> Result<()> {
+        self.set_encoded(key, value, &Json)
+    }
+    /// Reads a value written by `set_serialized`, failing with
+    /// `InvalidData` if it doesn't decode as a `T`.
+    #[cfg(feature = "serde")]
+    pub fn deserialized<T: DeserializeOwned>(self, key: Key) -> Result<Option<T>> {
+        self.decoded(key, &Json)
+    }
+    /// Stores `value` under `key` as encoded by `codec`.
+    #[cfg(feature = "serde")]
+    pub fn set_encoded<T: Serialize>(self, key: Key, value: &T, codec: &impl Codec) -> Result<()> {
+        let data = codec.encode(value)?;
+        self.set_value(key, &data)
+    }
+    /// Reads a value written by `set_encoded` with the same `codec`.
+    #[cfg(feature = "serde")]
+    pub fn decoded<T: DeserializeOwned>(self, key: Key, codec: &impl Codec) -> Result<Option<T>> {
+        match self.value(key)? {
+            Some(data) => Ok(Some(codec.decode(&data)?)),
+            None => Ok(None),
+        }
+    }
 }
 
 #[test]
@@ -121,14 +669,185 @@ fn test_tree() -> io::Result<()> {
     let expected_value = &[1, 2, 3, 4];
 
     let user_id = 40;
-    db.get(USERS)?
-        .get(user_id)?
+    db.get_or_create(USERS)?
+        .get_or_create(user_id)?
         .set_value(USERNAME, expected_value)?;
-    let value = db.get(USERS)?.get(user_id)?.value(USERNAME)?.unwrap();
+    let value = db
+        .get_existing(USERS)?
+        .unwrap()
+        .get_existing(user_id)?
+        .unwrap()
+        .value(USERNAME)?
+        .unwrap();
     assert_eq!(value.as_slice(), expected_value);
     Ok(())
 }
 
+#[test]
+fn levels_can_be_used_many_times_once_found() -> io::Result<()> {
+    use std::io::Cursor;
+    let mut db = Database::initialize(Cursor::new(vec![]))?;
+    const USERS: u128 = 10;
+    const USERNAME: u128 = 40;
+    const VISITS: u128 = 41;
+
+    let mut user = db.get_or_create(USERS)?.get_or_create(7)?;
+    user.by_ref().set_value(USERNAME, b"ada")?;
+    user.by_ref().set_u64(VISITS, 1)?;
+    let visits = user.by_ref().get_u64(VISITS)?.unwrap();
+    user.by_ref().set_u64(VISITS, visits + 1)?;
+    user.by_ref().get_or_create(1)?.set_value(2, b"nested")?;
+    assert_eq!(user.by_ref().value(USERNAME)?, Some(b"ada".to_vec()));
+    assert_eq!(user.by_ref().child_count()?, 1);
+
+    assert_eq!(
+        db.get_or_create(USERS)?
+            .get_existing(7)?
+            .unwrap()
+            .get_u64(VISITS)?,
+        Some(2)
+    );
+    assert_eq!(
+        db.get_or_create(USERS)?
+            .get_existing(7)?
+            .unwrap()
+            .get_existing(1)?
+            .unwrap()
+            .value(2)?,
+        Some(b"nested".to_vec())
+    );
+    Ok(())
+}
+
+#[test]
+fn reading_a_missing_child_writes_nothing() -> io::Result<()> {
+    use std::io::Cursor;
+    let mut db = Database::initialize(Cursor::new(vec![]))?;
+    const USERS: u128 = 10;
+    assert!(db.get_existing(USERS)?.is_none());
+    db.get_or_create(USERS)?.set_value(1, b"alice")?;
+
+    let blocks = db.num_blocks_allocated();
+    assert!(db.get_existing(USERS)?.unwrap().get_existing(1)?.is_none());
+    assert!(db.get_existing(11)?.is_none());
+    assert_eq!(db.num_blocks_allocated(), blocks);
+    assert_eq!(
+        db.get_existing(USERS)?.unwrap().value(1)?,
+        Some(b"alice".to_vec())
+    );
+
+    db.get_or_create(USERS)?
+        .get_or_create(1)?
+        .set_value(2, b"nested")?;
+    assert!(db.num_blocks_allocated() > blocks);
+    let user = db.get_existing(USERS)?.unwrap().get_existing(1)?.unwrap();
+    assert_eq!(user.value(2)?, Some(b"nested".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn empty_values_are_kept() -> io::Result<()> {
+    use std::io::Cursor;
+    let mut db = Database::initialize(Cursor::new(vec![]))?;
+    const USERS: u128 = 10;
+    const NICKNAME: u128 = 41;
+    db.get_or_create(USERS)?.set_value(NICKNAME, &[])?;
+    assert_eq!(db.get_or_create(USERS)?.value(NICKNAME)?, Some(vec![]));
+    assert_eq!(
+        db.get_or_create(USERS)?.entries()?,
+        vec![(NICKNAME, EntryKind::Value)]
+    );
+    db.get_or_create(USERS)?.get_or_create(NICKNAME)?;
+    assert_eq!(db.get_or_create(USERS)?.value(NICKNAME)?, Some(vec![]));
+    assert_eq!(
+        db.get_or_create(USERS)?.entries()?,
+        vec![(NICKNAME, EntryKind::Both)]
+    );
+    assert!(db.get_or_create(USERS)?.delete_value(NICKNAME)?);
+    assert_eq!(db.get_or_create(USERS)?.value(NICKNAME)?, None);
+    Ok(())
+}
+
+#[test]
+fn absent_values_are_none() -> io::Result<()> {
+    use std::io::Cursor;
+    let mut db = Database::initialize(Cursor::new(vec![]))?;
+    const USERS: u128 = 10;
+    const NICKNAME: u128 = 41;
+    assert_eq!(db.get_or_create(USERS)?.value(NICKNAME)?, None);
+    // a key with only a child tree has no value either
+    db.get_or_create(USERS)?.get_or_create(NICKNAME)?;
+    assert_eq!(db.get_or_create(USERS)?.value(NICKNAME)?, None);
+    assert_eq!(
+        db.get_or_create(USERS)?.entries()?,
+        vec![(NICKNAME, EntryKind::Child)]
+    );
+    assert!(!db.get_or_create(USERS)?.delete_value(NICKNAME)?);
+    Ok(())
+}
+
+#[test]
+fn set_values_writes_every_field_of_a_row() -> io::Result<()> {
+    use std::io::Cursor;
+    let mut db = Database::initialize(Cursor::new(vec![]))?;
+    const EXPENSES: u128 = 2;
+    const UUID: u128 = 1;
+    const AMOUNT: u128 = 2;
+    db.get_or_create(EXPENSES)?
+        .get_or_create(7)?
+        .get_or_create(UUID)?;
+    db.get_or_create(EXPENSES)?.get_or_create(7)?.set_values(&[
+        (UUID, &[1, 2, 3]),
+        (AMOUNT, &[4]),
+        (UUID, &[5, 6]),
+    ])?;
+    let mut row = db.get_or_create(EXPENSES)?.get_existing(7)?.unwrap();
+    assert_eq!(
+        row.entries()?,
+        vec![(UUID, EntryKind::Both), (AMOUNT, EntryKind::Value)]
+    );
+    assert_eq!(row.value(UUID)?, Some(vec![5, 6]));
+    assert_eq!(
+        db.get_or_create(EXPENSES)?
+            .get_existing(7)?
+            .unwrap()
+            .value(AMOUNT)?,
+        Some(vec![4])
+    );
+
+    // a batch spread over many leaves, out of order, keeps every child tree
+    const ARCHIVE: u128 = 3;
+    for expense_id in (0..600).step_by(3) {
+        db.get_or_create(ARCHIVE)?
+            .get_or_create(expense_id)?
+            .set_u64(AMOUNT, 1)?;
+    }
+    let values: Vec<(Key, Vec<u8>)> = (0..600)
+        .rev()
+        .map(|expense_id| (expense_id, vec![expense_id as u8; 20]))
+        .collect();
+    let entries: Vec<(Key, &[u8])> = values
+        .iter()
+        .map(|(key, value)| (*key, &value[..]))
+        .collect();
+    db.get_or_create(ARCHIVE)?.set_values(&entries)?;
+    let kinds = db.get_or_create(ARCHIVE)?.entries()?;
+    assert_eq!(kinds.len(), 600);
+    for (expense_id, kind) in kinds {
+        let expected = if expense_id % 3 == 0 {
+            EntryKind::Both
+        } else {
+            EntryKind::Value
+        };
+        assert_eq!(kind, expected);
+        assert_eq!(
+            db.get_or_create(ARCHIVE)?.value(expense_id)?,
+            Some(vec![expense_id as u8; 20])
+        );
+    }
+    Ok(())
+}
+
 #[test]
 fn cannot_mix_children_and_values() -> io::Result<()> {
     use std::io::Cursor;
@@ -139,19 +858,20 @@ fn cannot_mix_children_and_values() -> io::Result<()> {
     let username_buf = &[6, 7, 8];
 
     let user_id = 40;
-    db.get(USERS)?.set_value(user_id, all_user_buf)?;
-    db.get(USERS)?
-        .get(user_id)?
+    db.get_or_create(USERS)?.set_value(user_id, all_user_buf)?;
+    db.get_or_create(USERS)?
+        .get_or_create(user_id)?
         .set_value(USERNAME, username_buf)?;
 
     assert_eq!(
-        db.get(USERS)?.value(user_id)?.unwrap().as_slice(),
+        db.get_or_create(USERS)?.value(user_id)?.unwrap().as_slice(),
         all_user_buf
     );
 
     assert_eq!(
-        db.get(USERS)?
-            .get(user_id)?
+        db.get_or_create(USERS)?
+            .get_existing(user_id)?
+            .unwrap()
             .value(USERNAME)?
             .unwrap()
             .as_slice(),
@@ -160,3 +880,340 @@ fn cannot_mix_children_and_values() -> io::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn child_count_ignores_value_only_entries() -> io::Result<()> {
+    use std::io::Cursor;
+    let mut db = Database::initialize(Cursor::new(vec![]))?;
+    const EXPENSES: u128 = 10;
+    const AMOUNT: u128 = 1;
+
+    for expense_id in 0..500 {
+        db.get_or_create(EXPENSES)?
+            .get_or_create(expense_id)?
+            .set_value(AMOUNT, &[1, 2, 3])?;
+    }
+    for expense_id in 500..600 {
+        db.get_or_create(EXPENSES)?.set_value(expense_id, &[4, 5])?;
+    }
+    // a key with both a value and a child still counts as a child
+    db.get_or_create(EXPENSES)?.set_value(0, &[6])?;
+
+    assert_eq!(db.get_or_create(EXPENSES)?.child_count()?, 500);
+    for expense_id in 0..500 {
+        assert_eq!(
+            db.get_or_create(EXPENSES)?
+                .get_existing(expense_id)?
+                .unwrap()
+                .value(AMOUNT)?,
+            Some(vec![1, 2, 3])
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn typed_values_follow_the_header_endianness() -> io::Result<()> {
+    use crate::Endianness;
+    use std::io::Cursor;
+    let mut db = Database::initialize_with_endianness(Cursor::new(vec![]), Endianness::Little)?;
+    const ACCOUNTS: u128 = 3;
+    const BALANCE: u128 = 1;
+    let account_ids = [1, 256, 65_536, 1 << 64, 7];
+    for &account_id in &account_ids {
+        db.get_or_create(ACCOUNTS)?
+            .get_or_create(account_id)?
+            .set_i64(BALANCE, -42)?;
+    }
+
+    let mut db = Database::from_existing(db.into_disk())?;
+    assert_eq!(db.value_endianness(), Endianness::Little);
+    let raw = db
+        .get_existing(ACCOUNTS)?
+        .unwrap()
+        .get_existing(7)?
+        .unwrap()
+        .value(BALANCE)?
+        .unwrap();
+    assert_eq!(raw, (-42i64).to_le_bytes());
+    assert_eq!(
+        db.get_or_create(ACCOUNTS)?
+            .get_existing(7)?
+            .unwrap()
+            .get_i64(BALANCE)?,
+        Some(-42)
+    );
+
+    // keys are unaffected by the flag and still come back in numeric order
+    let mut keys = vec![];
+    let accounts = db.get_or_create(ACCOUNTS)?;
+    accounts.tree().for_each_leaf(accounts.db, |leaf, _| {
+        keys.extend(leaf.keys().iter().map(|entry| entry.key));
+        Ok(())
+    })?;
+    let mut expected = account_ids.to_vec();
+    expected.sort();
+    assert_eq!(keys, expected);
+    Ok(())
+}
+
+#[test]
+fn deleting_a_value_keeps_the_child_and_vice_versa() -> io::Result<()> {
+    use std::io::Cursor;
+    let mut db = Database::initialize(Cursor::new(vec![]))?;
+    const USERS: u128 = 10;
+    const USERNAME: u128 = 40;
+    db.get_or_create(USERS)?.set_value(1, &[1])?;
+    db.get_or_create(USERS)?
+        .get_or_create(1)?
+        .set_value(USERNAME, &[2])?;
+    db.get_or_create(USERS)?.set_value(2, &[3])?;
+
+    assert!(db.get_or_create(USERS)?.delete_value(1)?);
+    assert!(!db.get_or_create(USERS)?.delete_value(1)?);
+    assert_eq!(db.get_or_create(USERS)?.value(1)?, None);
+    assert_eq!(
+        db.get_or_create(USERS)?
+            .get_existing(1)?
+            .unwrap()
+            .value(USERNAME)?,
+        Some(vec![2])
+    );
+
+    assert!(db.get_or_create(USERS)?.delete_value(2)?);
+    // with neither a value nor a child left, the key itself goes
+    let users = db.get_or_create(USERS)?.offset;
+    assert!(!BTree::from_offset(users).contains_key(2, &mut db)?);
+    Ok(())
+}
+
+#[test]
+fn deleting_a_child_frees_its_whole_subtree() -> io::Result<()> {
+    use std::io::Cursor;
+    let mut db = Database::initialize(Cursor::new(vec![]))?;
+    const EXPENSES: u128 = 2;
+    const NOTES: u128 = 5;
+    db.get_or_create(EXPENSES)?.set_value(7, &[7])?;
+    let blocks = db.num_blocks_allocated();
+    for note in 0..1_000 {
+        db.get_or_create(EXPENSES)?
+            .get_or_create(7)?
+            .get_or_create(NOTES)?
+            .set_value(note, &[0; 50])?;
+    }
+    db.get_or_create(EXPENSES)?
+        .get_or_create(7)?
+        .set_value(1, &[1; 10_000])?;
+    let grown = db.num_blocks_allocated();
+
+    db.get_or_create(EXPENSES)?.delete_child(7)?;
+    assert_eq!(db.get_or_create(EXPENSES)?.value(7)?, Some(vec![7]));
+    assert_eq!(db.get_or_create(EXPENSES)?.child_count()?, 0);
+    // every block of the subtree is reused before the file grows again
+    for note in 0..1_000 {
+        db.get_or_create(EXPENSES)?
+            .get_or_create(7)?
+            .get_or_create(NOTES)?
+            .set_value(note, &[0; 50])?;
+    }
+    db.get_or_create(EXPENSES)?
+        .get_or_create(7)?
+        .set_value(1, &[1; 10_000])?;
+    assert!(grown > blocks);
+    assert_eq!(db.num_blocks_allocated(), grown);
+    Ok(())
+}
+
+#[test]
+fn copied_children_share_nothing_with_the_original() -> io::Result<()> {
+    use std::io::Cursor;
+    let mut db = Database::initialize(Cursor::new(vec![]))?;
+    const EXPENSES: u128 = 2;
+    const BACKUP: u128 = 3;
+    const AMOUNT: u128 = 1;
+    const NOTES: u128 = 5;
+    for expense_id in 0..300 {
+        let mut expense = db.get_or_create(EXPENSES)?.get_or_create(expense_id)?;
+        expense.by_ref().set_u64(AMOUNT, expense_id as u64)?;
+        expense.get_or_create(NOTES)?.set_value(1, &[0; 50])?;
+    }
+    assert!(db.lookup_or_create()?.copy_child(EXPENSES, BACKUP)?);
+    assert!(!db.lookup_or_create()?.copy_child(99, BACKUP)?);
+
+    // a bulk import to roll back
+    for expense_id in 0..300 {
+        db.get_or_create(EXPENSES)?.delete_child(expense_id)?;
+    }
+    db.get_or_create(EXPENSES)?
+        .get_or_create(1_000)?
+        .set_u64(AMOUNT, 1)?;
+    assert!(db.lookup_or_create()?.copy_child(BACKUP, EXPENSES)?);
+    db.lookup_or_create()?.delete_child(BACKUP)?;
+
+    assert_eq!(db.get_or_create(EXPENSES)?.child_count()?, 300);
+    for expense_id in 0..300 {
+        let mut expense = db
+            .get_or_create(EXPENSES)?
+            .get_existing(expense_id)?
+            .unwrap();
+        assert_eq!(expense.by_ref().get_u64(AMOUNT)?, Some(expense_id as u64));
+        let notes = expense.get_existing(NOTES)?.unwrap();
+        assert_eq!(notes.value(1)?, Some(vec![0; 50]));
+    }
+    assert!(db.verify()?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn keys_lists_values_and_children_alike() -> io::Result<()> {
+    use std::io::Cursor;
+    let mut db = Database::initialize(Cursor::new(vec![]))?;
+    const EXPENSES: u128 = 2;
+    const AMOUNT: u128 = 1;
+    for expense_id in (0..300).rev() {
+        db.get_or_create(EXPENSES)?
+            .get_or_create(expense_id)?
+            .set_u64(AMOUNT, 5)?;
+    }
+    db.get_or_create(EXPENSES)?.set_value(1_000, &[1])?;
+
+    let keys = db
+        .get_or_create(EXPENSES)?
+        .keys()?
+        .collect::<Result<Vec<Key>>>()?;
+    let mut expected: Vec<Key> = (0..300).collect();
+    expected.push(1_000);
+    assert_eq!(keys, expected);
+    Ok(())
+}
+
+#[test]
+fn entries_tell_values_from_children() -> io::Result<()> {
+    use std::io::Cursor;
+    let mut db = Database::initialize(Cursor::new(vec![]))?;
+    const USERS: u128 = 10;
+    const USERNAME: u128 = 40;
+    db.get_or_create(USERS)?.set_value(1, &[1])?;
+    db.get_or_create(USERS)?
+        .get_or_create(2)?
+        .set_value(USERNAME, &[2])?;
+    db.get_or_create(USERS)?.set_value(3, &[3; 5_000])?;
+    db.get_or_create(USERS)?
+        .get_or_create(3)?
+        .set_value(USERNAME, &[3])?;
+
+    assert_eq!(
+        db.get_or_create(USERS)?.entries()?,
+        vec![
+            (1, EntryKind::Value),
+            (2, EntryKind::Child),
+            (3, EntryKind::Both)
+        ]
+    );
+    Ok(())
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serialized_values_read_back_as_written() -> io::Result<()> {
+    use std::io::Cursor;
+    let mut db = Database::initialize(Cursor::new(vec![]))?;
+    let expense = ("groceries".to_string(), -4_250i64, vec![3u32, 14]);
+    db.get_or_create(1)?.set_serialized(7, &expense)?;
+    let found: Option<(String, i64, Vec<u32>)> = db.get_or_create(1)?.deserialized(7)?;
+    assert_eq!(found, Some(expense));
+    let missing: Option<u64> = db.get_or_create(1)?.deserialized(8)?;
+    assert_eq!(missing, None);
+
+    let err = db.get_or_create(1)?.deserialized::<u64>(7).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    Ok(())
+}
+
+#[test]
+fn walk_visits_every_value_with_its_path() -> io::Result<()> {
+    use std::io::Cursor;
+    let mut db = Database::initialize(Cursor::new(vec![]))?;
+    const USERS: u128 = 10;
+    const EXPENSES: u128 = 11;
+    const USERNAME: u128 = 40;
+    db.get_or_create(USERS)?.set_value(40, &[1])?;
+    db.get_or_create(USERS)?
+        .get_or_create(40)?
+        .set_value(USERNAME, &[2])?;
+    db.get_or_create(USERS)?
+        .get_or_create(41)?
+        .set_value(USERNAME, &[3])?;
+    db.get_or_create(EXPENSES)?
+        .get_or_create(7)?
+        .get_or_create(8)?
+        .set_value(9, &[4])?;
+
+    let found = db.lookup_or_create()?.walk()?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        found,
+        vec![
+            (vec![USERS, 40], vec![1]),
+            (vec![USERS, 40, USERNAME], vec![2]),
+            (vec![USERS, 41, USERNAME], vec![3]),
+            (vec![EXPENSES, 7, 8, 9], vec![4]),
+        ]
+    );
+    let users = db.get_or_create(USERS)?.walk()?.count();
+    assert_eq!(users, 3);
+
+    // enough values that the level spans many leaves
+    for user_id in 100..1_100 {
+        db.get_or_create(USERS)?.set_value(user_id, &[5; 30])?;
+    }
+    let paths = db
+        .get_existing(USERS)?
+        .unwrap()
+        .walk()?
+        .map(|entry| entry.map(|(path, _)| path))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(paths.len(), 1_003);
+    assert_eq!(paths[3], vec![100]);
+    assert_eq!(paths[1_002], vec![1_099]);
+    Ok(())
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn json_shows_values_and_children_apart() -> io::Result<()> {
+    use std::io::Cursor;
+    let mut db = Database::initialize(Cursor::new(vec![]))?;
+    const USERS: u128 = 10;
+    const USERNAME: u128 = 40;
+    db.get_or_create(USERS)?.set_value(40, &[1, 2, 0xab])?;
+    db.get_or_create(USERS)?
+        .get_or_create(40)?
+        .set_value(USERNAME, &[6, 7])?;
+    db.get_or_create(USERS)?
+        .get_or_create(41)?
+        .set_value(USERNAME, &[8])?;
+
+    let json = db.get_or_create(USERS)?.json()?;
+    assert_eq!(
+        json,
+        serde_json::json!({
+            "40": {"value": "0102ab", "children": {"40": {"value": "0607"}}},
+            "41": {"children": {"40": {"value": "08"}}},
+        })
+    );
+
+    // numbers, not strings, set the order
+    db.get_or_create(USERS)?.set_value(9, &[9])?;
+    db.get_or_create(USERS)?.set_value(100, &[100])?;
+    let keys: Vec<String> = db
+        .get_existing(USERS)?
+        .unwrap()
+        .json()?
+        .as_object()
+        .unwrap()
+        .keys()
+        .cloned()
+        .collect();
+    assert_eq!(keys, vec!["9", "40", "41", "100"]);
+    Ok(())
+}